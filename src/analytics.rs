@@ -17,111 +17,387 @@
 
 //! This module runs analytics on the enemy snakes to try
 //! and figure out what kind of moves they are likely to make
-//! in the future.
+//! in the future. Without the analytics feature enemies are never
+//! matched to a profile, and only the priors they start with are
+//! predicted.
 
+#[cfg(feature = "analytics")]
 use log::info;
+use log::warn;
+#[cfg(feature = "analytics")]
+use std::cmp::Ordering;
+#[cfg(feature = "recording")]
+use std::collections::BTreeMap;
 use std::collections::HashMap;
-use std::{error::Error, fs::File, io::prelude::*, path::Path};
+#[cfg(feature = "recording")]
+use std::{fs::File, io::prelude::*};
 
-use super::game::{Dir, State};
-use super::profile::{string_to_profile, Profile};
+#[cfg(feature = "recording")]
+use super::config;
+#[cfg(feature = "recording")]
+use super::schema::v1;
+#[cfg(feature = "recording")]
+use serde::Serialize;
+use super::game::{Dir, Snake, State};
+use super::history::GameHistory;
+use super::opponents::RiskPosture;
+use super::profile::SearchReport;
+#[cfg(feature = "analytics")]
+use super::profile::{string_to_profile, Profile, TimeBudget};
+use super::shadow::{ShadowReport, ShadowTurn};
 
-const MATCH_THRESH: usize = 9;
+#[cfg(feature = "analytics")]
 const MOVE_BUFFER_SIZE: usize = 10;
 
+/// How much a move counts for compared to the one made a turn later,
+/// so old predictions matter less than recent ones
+#[cfg(feature = "analytics")]
+const MATCH_DECAY: f64 = 0.85;
+
+/// Fraction of the weighted moves a profile must predict to match
+#[cfg(feature = "analytics")]
+const MATCH_THRESH: f64 = 0.8;
+
+/// An enemy that stops playing like its matched profile is rematched
+/// to a profile that predicted this many of its latest moves in a row
+#[cfg(feature = "analytics")]
+const REMATCH_WINDOW: usize = 5;
+
+const WIN_PROB_DROP_ALERT: f64 = 0.2;
+
+/// Exploration only kicks in when we're healthy and the win
+/// probability has stayed within this range for this many turns
+const EXPLORE_MIN_HEALTH: u8 = 60;
+const STABLE_WIN_PROB_RANGE: f64 = 0.1;
+const STABLE_WIN_PROB_TURNS: usize = 5;
+
 /// The Analytics struct holds information for the analyzer
 /// as well as any matches it finds
 pub struct Analytics {
+    #[cfg(feature = "analytics")]
     real_moves: HashMap<String, Vec<Dir>>,
+    #[cfg(feature = "analytics")]
     expected_moves: HashMap<String, HashMap<String, Vec<Dir>>>,
-    pub matches: HashMap<String, String>,
+    #[cfg(feature = "analytics")]
+    matches: HashMap<String, String>,
+    priors: HashMap<String, String>,
+    pub names: HashMap<String, String>,
+    pub postures: HashMap<String, RiskPosture>,
+    pub history: GameHistory,
+    #[cfg(feature = "analytics")]
     algs: HashMap<String, Box<dyn Profile>>,
-    full_game: Vec<String>,
+    #[cfg(feature = "recording")]
+    full_game: BTreeMap<u32, String>,
+    /// Where the game and its traces are written when it is dropped
+    #[cfg(feature = "recording")]
+    pub samples_dir: String,
+    win_probs: Vec<(u32, f64)>,
+    searches: Vec<SearchReport>,
+    shadow: Vec<ShadowTurn>,
+    last_turn: Option<u32>,
     id: String,
 }
 
 impl Analytics {
     /// Creates a new instance of the Analytics struct
     pub fn new(st: &State, algs: &[&'static str]) -> Self {
-        let mut real_moves = HashMap::<String, Vec<Dir>>::new();
-        let mut expected_moves = HashMap::<String, HashMap<String, Vec<Dir>>>::new();
+        // Without analytics no profiles are run, so nothing is matched
+        #[cfg(not(feature = "analytics"))]
+        let _ = algs;
 
-        for (id, _) in &st.board.snakes {
-            let mut alg_moves = HashMap::<String, Vec<Dir>>::new();
+        let mut analytics = Self {
+            #[cfg(feature = "analytics")]
+            real_moves: HashMap::new(),
+            #[cfg(feature = "analytics")]
+            expected_moves: HashMap::new(),
+            #[cfg(feature = "analytics")]
+            algs: algs
+                .iter()
+                .map(|alg| (alg.to_string(), string_to_profile(alg)))
+                .collect(),
+            #[cfg(feature = "analytics")]
+            matches: HashMap::<String, String>::new(),
+            priors: HashMap::<String, String>::new(),
+            names: HashMap::<String, String>::new(),
+            postures: HashMap::<String, RiskPosture>::new(),
+            history: GameHistory::default(),
+            #[cfg(feature = "recording")]
+            full_game: BTreeMap::new(),
+            #[cfg(feature = "recording")]
+            samples_dir: config::get().samples_dir.clone(),
+            win_probs: vec![],
+            searches: vec![],
+            shadow: vec![],
+            last_turn: None,
+            id: st.game.id.clone(),
+        };
 
-            real_moves.insert(id.clone(), vec![Dir::Up; MOVE_BUFFER_SIZE]);
+        analytics.reset_buffers(st);
+        analytics
+    }
 
-            for alg in algs {
-                alg_moves.insert(alg.to_string(), vec![Dir::Down; MOVE_BUFFER_SIZE]);
-            }
+    /// Fills the move buffers with placeholder moves that can never
+    /// produce a match, so matching starts over from this state
+    #[cfg(feature = "analytics")]
+    fn reset_buffers(&mut self, st: &State) {
+        self.real_moves.clear();
+        self.expected_moves.clear();
+
+        for id in st.board.snakes.keys() {
+            let alg_moves = self
+                .algs
+                .keys()
+                .map(|alg| (alg.clone(), vec![Dir::Down; MOVE_BUFFER_SIZE]))
+                .collect::<HashMap<String, Vec<Dir>>>();
 
-            expected_moves.insert(id.clone(), alg_moves.clone());
+            self.real_moves
+                .insert(id.clone(), vec![Dir::Up; MOVE_BUFFER_SIZE]);
+            self.expected_moves.insert(id.clone(), alg_moves);
         }
+    }
 
-        let mut algs_map = HashMap::<String, Box<dyn Profile>>::new();
+    /// Saves the raw request for a turn. A turn delivered more than
+    /// once replaces the earlier copy, so the saved game always has
+    /// one request per turn in turn order.
+    #[cfg(feature = "recording")]
+    pub fn update_full_game(&mut self, turn: u32, buffer: &str) {
+        self.full_game.insert(turn, String::from(buffer));
+    }
 
-        for alg in algs {
-            algs_map.insert(alg.to_string(), string_to_profile(alg));
-        }
+    #[cfg(not(feature = "recording"))]
+    pub fn update_full_game(&mut self, _turn: u32, _buffer: &str) {}
 
-        Self {
-            real_moves,
-            expected_moves,
-            algs: algs_map,
-            matches: HashMap::<String, String>::new(),
-            full_game: vec![],
-            id: st.game.id.clone(),
-        }
+    /// Sets the profile an enemy is assumed to play until the
+    /// analyzer finds a match of its own
+    pub fn set_prior(&mut self, s_id: &str, profile: &str) {
+        self.priors.insert(s_id.to_string(), profile.to_string());
     }
 
-    pub fn update_full_game(&mut self, buffer: &str) {
-        self.full_game.push(String::from(buffer));
+    /// Returns the predicted profile of each enemy, with matches found
+    /// by the analyzer layered over the configured priors
+    #[cfg(feature = "analytics")]
+    pub fn predicted_profiles(&self) -> HashMap<String, String> {
+        let mut profiles = self.priors.clone();
+        profiles.extend(self.matches.clone());
+        profiles
     }
 
-    /// Updates the analytics. This function will update the moves
-    /// that the enemies made, compare them against the existing
-    /// expected moves, and calculate the next set of expected moves.
-    pub fn fire(&mut self, s_id: &str, st: &State) {
-        // Update the real moves for each of the snakes
-        for (id, s) in &st.board.snakes {
-            if let Some(d) = s.body[1].dir_to(s.body[0]) {
-                let entry = self.real_moves.get_mut(id).unwrap();
+    #[cfg(not(feature = "analytics"))]
+    pub fn predicted_profiles(&self) -> HashMap<String, String> {
+        self.priors.clone()
+    }
 
-                entry.insert(0, d);
-                entry.pop();
+    /// The profile the analyzer matched each enemy to
+    #[cfg(feature = "analytics")]
+    pub fn matches(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.matches.iter()
+    }
+
+    #[cfg(not(feature = "analytics"))]
+    pub fn matches(&self) -> impl Iterator<Item = (&String, &String)> {
+        std::iter::empty()
+    }
+
+    /// Records the search's estimated win probability for a turn,
+    /// warning if it dropped sharply since the previous turn
+    pub fn record_win_probability(&mut self, turn: u32, prob: f64) {
+        // Turns can arrive out of order, so keep the trace sorted and
+        // compare against whichever turn really came before this one
+        let idx = match self.win_probs.binary_search_by_key(&turn, |w| w.0) {
+            Ok(i) => {
+                self.win_probs[i].1 = prob;
+                return;
             }
+            Err(i) => i,
+        };
+
+        let before = idx.checked_sub(1).map(|i| self.win_probs[i]);
+        if let Some((prev_turn, prev)) = before {
+            if prev - prob >= WIN_PROB_DROP_ALERT {
+                warn!(
+                    "Win probability dropped from {:.2} (turn {}) to {:.2} (turn {})",
+                    prev, prev_turn, prob, turn
+                );
+            }
+        }
+
+        self.win_probs.insert(idx, (turn, prob));
+    }
+
+    /// Records what the search found in a turn
+    pub fn record_search(&mut self, report: SearchReport) {
+        self.searches.push(report);
+    }
+
+    /// Records the shadow scoring of a turn
+    pub fn record_shadow(&mut self, turn: ShadowTurn) {
+        self.shadow.push(turn);
+    }
+
+    /// Compares the active and candidate heuristics over the turns
+    /// recorded so far, or None if shadow scoring was off
+    pub fn shadow_report(&self, won: bool) -> Option<ShadowReport> {
+        if self.shadow.is_empty() {
+            None
+        } else {
+            Some(ShadowReport::new(&self.shadow, won))
+        }
+    }
+
+    /// Whether the win probability has held steady over the last
+    /// few turns
+    fn win_prob_stable(&self) -> bool {
+        if self.win_probs.len() < STABLE_WIN_PROB_TURNS {
+            return false;
+        }
+
+        let start = self.win_probs.len() - STABLE_WIN_PROB_TURNS;
+        let recent = &self.win_probs[start..];
+        let max = recent.iter().map(|w| w.1).fold(f64::MIN, f64::max);
+        let min = recent.iter().map(|w| w.1).fold(f64::MAX, f64::min);
+
+        max - min <= STABLE_WIN_PROB_RANGE
+    }
+
+    /// Nudges the chosen move away from heavily revisited parts of the
+    /// board, but only when nothing is pressing: we're healthy and the
+    /// game isn't swinging either way
+    pub fn explore(&self, dir: Dir, s: &Snake, st: &State) -> Dir {
+        if s.health < EXPLORE_MIN_HEALTH || !self.win_prob_stable() {
+            return dir;
         }
 
-        // Check for matches
+        self.history.explore(dir, s, st)
+    }
+
+    /// Matches each enemy to the profile that best predicted its
+    /// recent moves. A matched enemy that changes its behaviour loses
+    /// the match, and is rematched as soon as another profile has
+    /// predicted its last few moves.
+    #[cfg(feature = "analytics")]
+    fn update_matches(&mut self, s_id: &str) {
         for (snake_id, alg_map) in &self.expected_moves {
             if *snake_id == s_id {
                 continue;
             }
 
-            for (alg_id, exp_moves) in alg_map {
-                let real_moves = self.real_moves.get(snake_id).unwrap();
+            let real_moves = &self.real_moves[snake_id];
+            let was_matched = self.matches.contains_key(snake_id);
+
+            let best = alg_map
+                .iter()
+                .map(|(alg_id, exp_moves)| {
+                    let score = match_score(real_moves, exp_moves);
+                    let streak = match_streak(real_moves, exp_moves);
+                    (alg_id, score, streak)
+                })
+                .filter(|(_, score, streak)| {
+                    *score >= MATCH_THRESH
+                        || (was_matched && *streak >= REMATCH_WINDOW)
+                })
+                .max_by(|a, b| {
+                    a.1.partial_cmp(&b.1)
+                        .unwrap_or(Ordering::Equal)
+                        .then_with(|| b.0.cmp(a.0))
+                });
 
-                let mut match_score = 0;
-                for (index, item) in real_moves.iter().enumerate() {
-                    if exp_moves[index] == *item {
-                        match_score += 1;
+            match best {
+                Some((alg_id, score, _)) => {
+                    match self.matches.insert(snake_id.clone(), alg_id.clone())
+                    {
+                        Some(ref prev) if prev != alg_id => info!(
+                            "Rematched snake from {} to {} profile ({:.2})",
+                            prev, alg_id, score
+                        ),
+                        Some(_) => {}
+                        None => info!(
+                            "Matched snake as {} profile ({:.2})",
+                            alg_id, score
+                        ),
                     }
                 }
-
-                if match_score >= MATCH_THRESH {
-                    info!("Matched snake as {} profile", alg_id);
-                    self.matches.insert(snake_id.clone(), alg_id.clone());
-                } else {
-                    self.matches.remove(snake_id);
+                None => {
+                    if let Some(prev) = self.matches.remove(snake_id) {
+                        info!("Snake no longer matches {} profile", prev);
+                    }
                 }
             }
         }
+    }
+
+    /// Updates the analytics. This function will update the moves
+    /// that the enemies made, compare them against the existing
+    /// expected moves, and calculate the next set of expected moves.
+    ///
+    /// The expected moves are only comparable with the real ones when
+    /// turns arrive in sequence. A turn we've already seen is ignored,
+    /// and after a gap the buffers are rebuilt from this state.
+    pub fn fire(&mut self, s_id: &str, st: &State) {
+        let in_sequence = match self.last_turn {
+            Some(last) if st.turn <= last => {
+                warn!(
+                    "Ignoring turn {} of game {}, already at turn {}",
+                    st.turn, self.id, last
+                );
+                return;
+            }
+            Some(last) if st.turn > last + 1 => {
+                warn!(
+                    "Missed turns {} to {} of game {}, resyncing analytics",
+                    last + 1,
+                    st.turn - 1,
+                    self.id
+                );
+                false
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        self.last_turn = Some(st.turn);
+
+        self.history.observe(s_id, st);
+        if let Some(us) = st.board.snakes.get(s_id) {
+            self.history.record(us.body[0]);
+        }
+
+        self.update(s_id, st, in_sequence);
+    }
+
+    /// Adds the moves the enemies just made to the buffers, rematches
+    /// them, and predicts their next moves with each profile. If the
+    /// turn doesn't follow the last one, the buffers start over
+    /// instead.
+    #[cfg(feature = "analytics")]
+    fn update(&mut self, s_id: &str, st: &State, in_sequence: bool) {
+        if !in_sequence {
+            self.reset_buffers(st);
+        }
+
+        for (id, s) in st.board.snakes.iter().filter(|_| in_sequence) {
+            let entry = match self.real_moves.get_mut(id) {
+                Some(e) => e,
+                None => continue,
+            };
+
+            if let Some(d) = s.last_move() {
+                entry.insert(0, d);
+                entry.pop();
+            }
+        }
+
+        self.update_matches(s_id);
 
         // Get the new expected moves for the next turn
+        let budget = TimeBudget::untimed();
         for (s_id, s) in &st.board.snakes {
+            let move_map = match self.expected_moves.get_mut(s_id) {
+                Some(m) => m,
+                None => continue,
+            };
+
             for (alg_id, alg) in &mut self.algs {
-                let expected_move = alg.get_move(s, st);
-                let move_map = self.expected_moves.get_mut(s_id).unwrap();
+                let expected_move = alg.get_move(s, st, &budget);
 
                 let alg_vec = move_map.get_mut(alg_id).unwrap();
                 alg_vec.insert(0, expected_move);
@@ -129,27 +405,205 @@ impl Analytics {
             }
         }
     }
+
+    #[cfg(not(feature = "analytics"))]
+    fn reset_buffers(&mut self, _st: &State) {}
+
+    #[cfg(not(feature = "analytics"))]
+    fn update(&mut self, _s_id: &str, _st: &State, _in_sequence: bool) {}
+}
+
+/// The fraction of the real moves a profile predicted, with each move
+/// weighted by how recent it is. Index 0 is the latest move.
+#[cfg(feature = "analytics")]
+fn match_score(real: &[Dir], expected: &[Dir]) -> f64 {
+    let mut weight = 1.0;
+    let mut matched = 0.0;
+    let mut total = 0.0;
+
+    for (r, e) in real.iter().zip(expected) {
+        if r == e {
+            matched += weight;
+        }
+
+        total += weight;
+        weight *= MATCH_DECAY;
+    }
+
+    if total > 0.0 {
+        matched / total
+    } else {
+        0.0
+    }
+}
+
+/// How many of the latest real moves a profile predicted in a row
+#[cfg(feature = "analytics")]
+fn match_streak(real: &[Dir], expected: &[Dir]) -> usize {
+    real.iter()
+        .zip(expected)
+        .take_while(|(r, e)| r == e)
+        .count()
 }
 
+#[cfg(feature = "recording")]
 impl Drop for Analytics {
     fn drop(&mut self) {
-        let path = format!("samples/{}.txt", self.id);
-        let path = Path::new(&path);
-        let display = path.display();
-
-        File::create(&path)
-            .map_err(|why| format!("Couldn't create {}: {}", display, why.description()))
-            .and_then(|mut file| {
-                let mut buffer = String::new();
-
-                self.full_game.iter().for_each(|state| {
-                    buffer.push_str(&state);
-                    buffer.push('\n');
-                });
+        if self.full_game.is_empty() {
+            return;
+        }
+
+        let path = format!("{}/{}.txt", self.samples_dir, self.id);
+        let mut buffer = String::new();
+
+        self.full_game.values().for_each(|state| {
+            buffer.push_str(state);
+            buffer.push('\n');
+        });
+
+        if let Err(why) = File::create(&path)
+            .and_then(|mut file| file.write_all(buffer.as_bytes()))
+        {
+            warn!("Couldn't record the game to {}: {}", path, why);
+        }
+
+        if !self.win_probs.is_empty() {
+            let lines = self.win_probs.iter().map(|(turn, prob)| {
+                v1::WinProbability {
+                    turn: *turn,
+                    win_probability: *prob,
+                }
+            });
+            self.write_trace("winprob", lines);
+        }
+
+        if !self.searches.is_empty() {
+            let lines = self.searches.iter().map(v1::SearchReport::from);
+            self.write_trace("search", lines);
+        }
+
+        if !self.shadow.is_empty() {
+            let lines = self.shadow.iter().map(v1::ShadowTurn::from);
+            self.write_trace("shadow", lines);
+        }
+    }
+}
+
+#[cfg(feature = "recording")]
+impl Analytics {
+    /// Writes a per-turn trace next to the game's recording, one JSON
+    /// record per line
+    fn write_trace<T: Serialize>(
+        &self,
+        kind: &str,
+        lines: impl Iterator<Item = T>,
+    ) {
+        let path = format!("{}/{}.{}.txt", self.samples_dir, self.id, kind);
+        let buffer = lines
+            .filter_map(|l| serde_json::to_string(&l).ok())
+            .map(|l| l + "\n")
+            .collect::<String>();
+
+        if let Err(why) = File::create(&path)
+            .and_then(|mut file| file.write_all(buffer.as_bytes()))
+        {
+            warn!("Couldn't write {}: {}", path, why);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "analytics"))]
+mod tests {
+    use super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_out_of_order_turns() {
+        let (you, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(3, 3), (4, 3), (5, 3)]),
+            ],
+            &[],
+        );
+        let mut analytics = Analytics::new(&st, &["cautious"]);
+
+        st.turn = 1;
+        analytics.fire(&you.id, &st);
+        st.turn = 2;
+        analytics.fire(&you.id, &st);
+        assert_eq!(analytics.real_moves["other"][0], Dir::Left);
+        let seen = analytics.real_moves.clone();
+
+        // Retried and stale turns must not be counted twice
+        analytics.fire(&you.id, &st);
+        st.turn = 1;
+        analytics.fire(&you.id, &st);
+        assert_eq!(analytics.real_moves, seen);
+
+        // After a gap the history starts over
+        st.turn = 6;
+        analytics.fire(&you.id, &st);
+        assert_eq!(analytics.real_moves["other"], vec![Dir::Up; MOVE_BUFFER_SIZE]);
+        assert_eq!(analytics.last_turn, Some(6));
+
+        analytics.record_win_probability(3, 0.5);
+        analytics.record_win_probability(1, 0.6);
+        analytics.record_win_probability(2, 0.55);
+        analytics.record_win_probability(2, 0.4);
+        assert_eq!(analytics.win_probs, vec![(1, 0.6), (2, 0.4), (3, 0.5)]);
+    }
+
+    #[test]
+    fn test_match_decay() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(3, 3), (4, 3), (5, 3)]),
+            ],
+            &[],
+        );
+        let mut analytics = Analytics::new(&st, &[]);
+
+        let moves = |recent: Dir, old: Dir| {
+            let mut m = vec![recent; REMATCH_WINDOW];
+            m.extend(vec![old; MOVE_BUFFER_SIZE - REMATCH_WINDOW]);
+            m
+        };
+        let set = |analytics: &mut Analytics, alg: &str, m: Vec<Dir>| {
+            analytics
+                .expected_moves
+                .get_mut("other")
+                .unwrap()
+                .insert(alg.to_string(), m);
+        };
+
+        // A single miss on the latest move doesn't lose the match
+        let mut real = vec![Dir::Up; MOVE_BUFFER_SIZE];
+        real[0] = Dir::Left;
+        analytics.real_moves.insert("other".to_string(), real);
+        set(&mut analytics, "cautious", vec![Dir::Up; MOVE_BUFFER_SIZE]);
+        analytics.update_matches(&you.id);
+        assert_eq!(analytics.matches["other"], "cautious");
+
+        // The enemy switches from playing cautious to aggressive
+        let real = moves(Dir::Left, Dir::Up);
+        analytics.real_moves.insert("other".to_string(), real.clone());
+        set(&mut analytics, "cautious", moves(Dir::Right, Dir::Up));
+        set(&mut analytics, "aggressive", moves(Dir::Left, Dir::Down));
+
+        let aggressive = &analytics.expected_moves["other"]["aggressive"];
+        assert!(match_score(&real, aggressive) < MATCH_THRESH);
+        analytics.update_matches(&you.id);
+        assert_eq!(analytics.matches["other"], "aggressive");
 
-                file.write_all(buffer.as_bytes())
-                    .map_err(|why| format!("Couldn't create {}: {}", display, why.description()))
-            })
-            .unwrap();
+        // Without an earlier match the window isn't enough on its own
+        analytics.matches.clear();
+        analytics.update_matches(&you.id);
+        assert!(!analytics.matches.contains_key("other"));
     }
 }
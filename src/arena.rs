@@ -0,0 +1,170 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! A local arena that plays profiles against each other without the
+//! server. Each turn every controller is asked for a move in turn and
+//! given as long as it needs, so interactive controllers like the
+//! Human profile can take part.
+
+use std::collections::{HashMap, HashSet};
+
+use super::game::{Board, Dir, Game, GameMode, Point, Snake, State};
+use super::profile::{
+    string_to_profile, Human, Profile, Seed, TimeBudget, PROFILE_NAMES,
+};
+use super::replay::render;
+use super::simulator::{
+    Adjudication, Event, GameSession, Outcome, Rules,
+};
+
+const BOARD_SIZE: i8 = 11;
+const START_LENGTH: usize = 3;
+/// Games still going after this many turns go to the longest snake
+const MAX_TURNS: u32 = 1000;
+/// Food is topped up to this many pieces before each turn
+const MIN_FOOD: usize = 3;
+
+/// Where the snakes start, in order
+const STARTS: [(i8, i8); 4] = [(1, 1), (9, 9), (9, 1), (1, 9)];
+
+/// Creates the controller for an arena snake. `human` is only
+/// available here, never to the server.
+fn controller(name: &str) -> Result<Box<dyn Profile>, String> {
+    if name == "human" {
+        Ok(Box::new(Human::new()))
+    } else if PROFILE_NAMES.contains(&name) {
+        Ok(string_to_profile(name))
+    } else {
+        Err(format!("Unknown profile {}", name))
+    }
+}
+
+/// The starting state for the given number of snakes, named after
+/// their controllers
+fn initial_state(ids: &[String]) -> State {
+    let snakes = ids
+        .iter()
+        .zip(STARTS.iter())
+        .map(|(id, &(x, y))| {
+            let snake = Snake {
+                id: id.clone(),
+                health: 100,
+                body: vec![Point { x, y }; START_LENGTH],
+                squad: None,
+            };
+            (id.clone(), snake)
+        })
+        .collect::<HashMap<String, Snake>>();
+
+    State {
+        game: Game {
+            // A fresh id gives every game its own `Seed`, unless the
+            // config sets one
+            id: format!("arena-{:08x}", rand::random::<u32>()),
+            ruleset: None,
+            timeout: None,
+        },
+        turn: 0,
+        board: Board {
+            height: BOARD_SIZE,
+            width: BOARD_SIZE,
+            food: HashSet::new(),
+            snakes,
+            hazards: HashSet::new(),
+            mode: GameMode::Standard,
+        },
+        meta: Default::default(),
+        distances: Default::default(),
+    }
+}
+
+/// Plays a game between the given profiles and prints the result
+pub fn run(profiles: &[String]) {
+    if profiles.len() < 2 || profiles.len() > STARTS.len() {
+        eprintln!("The arena needs 2 to {} profiles", STARTS.len());
+        return;
+    }
+
+    // Snakes are named after their controller, numbered to keep
+    // repeated profiles apart
+    let ids = profiles
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("{}-{}", p, i + 1))
+        .collect::<Vec<String>>();
+
+    let mut controllers = HashMap::new();
+    for (id, name) in ids.iter().zip(profiles) {
+        match controller(name) {
+            Ok(c) => controllers.insert(id.clone(), c),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+    }
+
+    // No snake is the protagonist, so every dead snake is removed
+    let rules = Rules {
+        min_food: MIN_FOOD,
+        food_spawn_chance: 0,
+        max_turns: Some(MAX_TURNS),
+        adjudication: Adjudication::Length,
+    };
+    // The food spawns from the game's seed too, so setting `rng_seed`
+    // to it replays the whole game
+    let st = initial_state(&ids);
+    let seed = Seed::for_game(&st);
+    println!("Seed {}", seed);
+    let mut game = GameSession::new(st, "", rules, seed.0);
+
+    for (id, c) in controllers.iter_mut() {
+        c.init(game.state(), id.clone());
+    }
+
+    let outcome = loop {
+        if let Some(outcome) = game.outcome() {
+            break outcome;
+        }
+
+        let st = game.state();
+        let mut moves = HashMap::<String, Dir>::new();
+        let budget = TimeBudget::untimed();
+        for (id, snake) in &st.board.snakes {
+            let controller = controllers.get_mut(id).unwrap();
+            let dir = controller.get_move(snake, st, &budget);
+            moves.insert(id.clone(), dir);
+        }
+
+        game.step(&moves);
+    };
+
+    for (turn, event) in game.events() {
+        if let Event::Died { id } = event {
+            println!("{} was eliminated on turn {}", id, turn);
+        }
+    }
+
+    let st = game.state();
+    match outcome {
+        Outcome::Draw => println!("Draw after {} turns", st.turn),
+        Outcome::Winner(id) => {
+            println!("{}", render(&st.board.snakes[&id], st));
+            println!("{} wins after {} turns", id, st.turn);
+        }
+    }
+}
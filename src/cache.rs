@@ -0,0 +1,229 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The cache directory, where everything that outlives a run is kept.
+//! Each kind of data has its own subdirectory with one file per schema
+//! version, `<cache_dir>/<schema>/v<version>.json`, so an upgrade can
+//! still read what the last version wrote.
+//!
+//! Files hold the data along with a checksum of it, and are written to
+//! a temporary file first and renamed into place. A file that fails
+//! its checksum or can't be read is moved aside to
+//! `<file>.corrupt-<unix time>` and treated as missing, so a bad file
+//! costs what it held and nothing more.
+
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::config;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// FNV-1a, which unlike the std hashers is stable across builds
+fn checksum(data: &str) -> String {
+    let hash = data.bytes().fold(FNV_OFFSET, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    });
+    format!("{:016x}", hash)
+}
+
+/// The data of a cache file, if its checksum matches
+fn unwrap_entry(contents: &str) -> Result<Value, String> {
+    let mut entry =
+        serde_json::from_str::<Value>(contents).map_err(|e| e.to_string())?;
+    let expected = entry["checksum"]
+        .as_str()
+        .ok_or_else(|| String::from("Missing checksum"))?
+        .to_string();
+    let data = entry
+        .get_mut("data")
+        .map(Value::take)
+        .ok_or_else(|| String::from("Missing data"))?;
+
+    // serde_json sorts object keys, so this is the text that was
+    // checksummed when the file was written
+    if checksum(&data.to_string()) != expected {
+        return Err(String::from("Checksum mismatch"));
+    }
+
+    Ok(data)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheDir {
+    root: PathBuf,
+}
+
+impl CacheDir {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// The cache directory in the config
+    pub fn configured() -> Self {
+        Self::new(&config::get().cache_dir)
+    }
+
+    /// The file a version of a schema is kept in
+    pub fn path(&self, schema: &str, version: u64) -> PathBuf {
+        self.root.join(schema).join(format!("v{}.json", version))
+    }
+
+    /// Loads the newest readable version of a schema up to `version`,
+    /// along with the version it was, for the caller to migrate
+    pub fn load_raw(&self, schema: &str, version: u64) -> Option<(u64, Value)> {
+        for v in (1..=version).rev() {
+            let path = self.path(schema, v);
+            let contents = match fs::read_to_string(&path) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+
+            match unwrap_entry(&contents) {
+                Ok(data) => return Some((v, data)),
+                Err(e) => quarantine(&path, &e),
+            }
+        }
+
+        info!("Nothing cached for {}", schema);
+        None
+    }
+
+    /// Loads the current version of a schema. Older versions are
+    /// ignored, for data that is cheap to rebuild.
+    pub fn load<T: DeserializeOwned>(
+        &self,
+        schema: &str,
+        version: u64,
+    ) -> Option<T> {
+        match self.load_raw(schema, version)? {
+            (v, data) if v == version => self.parse(schema, version, data),
+            (v, _) => {
+                info!("Ignoring version {} of {}", v, schema);
+                None
+            }
+        }
+    }
+
+    /// Converts loaded data to its type, quarantining the file if it
+    /// doesn't fit
+    pub fn parse<T: DeserializeOwned>(
+        &self,
+        schema: &str,
+        version: u64,
+        data: Value,
+    ) -> Option<T> {
+        match serde_json::from_value(data) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                quarantine(&self.path(schema, version), &e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Saves a version of a schema, replacing the old file only once
+    /// the new one is completely written
+    pub fn save<T: Serialize>(
+        &self,
+        schema: &str,
+        version: u64,
+        value: &T,
+    ) -> Result<(), String> {
+        let path = self.path(schema, version);
+        let error = |e: &dyn ToString| {
+            format!("Couldn't save {}: {}", path.display(), e.to_string())
+        };
+
+        let data = serde_json::to_value(value).map_err(|e| error(&e))?;
+        let entry = json!({
+            "checksum": checksum(&data.to_string()),
+            "data": data,
+        });
+        let contents =
+            serde_json::to_string_pretty(&entry).map_err(|e| error(&e))?;
+
+        let tmp = path.with_extension("json.tmp");
+        fs::create_dir_all(path.parent().unwrap_or(&self.root))
+            .and_then(|_| fs::write(&tmp, contents))
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| error(&e))
+    }
+}
+
+/// Moves an unreadable file aside so the next load doesn't trip on it
+/// and it can still be looked at
+pub fn quarantine(path: &Path, reason: &str) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let moved = format!("{}.corrupt-{}", path.display(), secs);
+
+    warn!(
+        "{} is unreadable ({}), moving it to {}",
+        path.display(),
+        reason,
+        moved
+    );
+
+    if let Err(e) = fs::rename(path, &moved) {
+        warn!("Couldn't move {} aside: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_cache_dir() {
+        let root = env::temp_dir()
+            .join(format!("battlesnake-cache-{}", std::process::id()));
+        let cache = CacheDir::new(&root);
+
+        assert_eq!(cache.load::<Vec<u32>>("numbers", 2), None);
+        cache.save("numbers", 1, &vec![1, 2]).unwrap();
+
+        // Older versions are there for migrations, but not typed loads
+        assert_eq!(cache.load::<Vec<u32>>("numbers", 2), None);
+        assert_eq!(cache.load_raw("numbers", 2), Some((1, json!([1, 2]))));
+
+        cache.save("numbers", 2, &vec![3]).unwrap();
+        assert_eq!(cache.load::<Vec<u32>>("numbers", 2), Some(vec![3]));
+
+        // A tampered file is moved aside, and the older one used
+        let path = cache.path("numbers", 2);
+        let tampered = fs::read_to_string(&path).unwrap().replace('3', "4");
+        fs::write(&path, tampered).unwrap();
+        assert_eq!(cache.load_raw("numbers", 2), Some((1, json!([1, 2]))));
+        assert!(!path.exists());
+
+        // and so is one that doesn't fit its type
+        assert_eq!(cache.load::<String>("numbers", 1), None);
+        assert_eq!(cache.load_raw("numbers", 2), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Configuration for the snake. Every setting can come from, in
+//! order of precedence, a `--kebab-case` CLI flag, an environment
+//! variable, the TOML config file, or the built in default.
+//!
+//! The config file is `battlesnake.toml` in the working directory
+//! unless another path is given with `--config` or `CONFIG`.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::OnceLock;
+use toml::value::{Table, Value};
+
+use super::logging::LogFormat;
+use super::opponents::RiskPosture;
+use super::profile::{
+    check_controllers, FinalMove, LeafScore, SearchMode, PROFILE_NAMES,
+};
+use super::routes::ApiVersion;
+
+const DEFAULT_CONFIG_PATH: &str = "battlesnake.toml";
+const LOG_LEVELS: [&str; 6] =
+    ["off", "error", "warn", "info", "debug", "trace"];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The port the server listens on
+    pub port: u16,
+    /// How many requests the server handles at once
+    pub workers: u64,
+    /// The snake's color, as a hex string
+    pub color: String,
+    /// The snake's author, shown by the engine
+    pub author: String,
+    /// The snake's head and tail types, by the engine's names for them
+    pub head_type: String,
+    pub tail_type: String,
+    /// The log level, in `RUST_LOG` syntax
+    pub log_level: String,
+    /// "text" for human readable logs, or "json" for one JSON object
+    /// per line tagged with the game and turn
+    pub log_format: LogFormat,
+    /// Directory containing the per-opponent TOML files
+    pub opponents_dir: String,
+    /// Directory that game samples and debug output are written to
+    pub samples_dir: String,
+    /// Directory for everything kept between runs: the opponent store
+    /// and the MonteCarlo tuning for this host
+    pub cache_dir: String,
+    /// File the opponent store was saved to before the cache
+    /// directory. It is imported into the cache the first time there
+    /// is no store there, then renamed with an `.imported` suffix.
+    pub opponent_store: String,
+    /// The profile games are played with, by its `string_to_profile`
+    /// name. A game's /start request can pick another one with a
+    /// `?profile=` query parameter.
+    pub profile: String,
+    /// How long the Sim profile searches for each move with the
+    /// default 500ms timeout. Games with another timeout scale it.
+    pub sim_budget_millis: u64,
+    /// How long the MonteCarlo profile searches for each move, scaled
+    /// like `sim_budget_millis`
+    pub mcts_budget_millis: u64,
+    /// How MonteCarlo picks its move once the search is done, "robust"
+    /// for the most simulated or "win_rate" for the best win rate
+    pub mcts_final_move: FinalMove,
+    /// Profiles Sim controls our snake with in its branches
+    pub sim_self_profiles: Vec<String>,
+    /// Profiles Sim controls the enemies with, in order of preference.
+    /// Only the first three are used unless pruned first moves leave
+    /// spare room for more branches.
+    pub sim_enemy_profiles: Vec<String>,
+    /// How much risk we take against opponents without their own
+    /// configured posture
+    pub risk_posture: RiskPosture,
+    /// Fraction of a first move's score Sim takes off for moving onto
+    /// the outer ring in the open board. Raise it for rulesets where
+    /// the edges turn into hazards.
+    pub edge_penalty: f64,
+    /// How AlphaBeta scores the positions at the bottom of its search,
+    /// "flood" for the room each snake can reach, "territory" for the
+    /// squares each reaches first or "weighted" for the weighted sum of
+    /// several features
+    pub alpha_beta_leaf: LeafScore,
+    /// Which snakes AlphaBeta searches the moves of with more than one
+    /// enemy, "paranoid" for all of them or "duel" for the nearest
+    pub alpha_beta_search: SearchMode,
+    /// TOML file of the weights of AlphaBeta's "weighted" leaf score,
+    /// or empty for the defaults
+    pub alpha_beta_weights: String,
+    /// Sim and MonteCarlo top the food up to this many pieces after
+    /// every simulated turn, like the engine's minimum food setting
+    pub sim_min_food: usize,
+    /// The percent chance Sim and MonteCarlo spawn a piece of food
+    /// after a simulated turn with enough food, like the engine's food
+    /// spawn chance setting
+    pub sim_food_spawn_chance: u8,
+    /// Break ties between equally scored moves randomly, seeded by the
+    /// game and turn. Turn it off for reproducibility runs.
+    pub random_tie_break: bool,
+    /// The seed every game's searches draw their random numbers from,
+    /// or 0 to derive one from each game's id. Games log their seed
+    /// when they start, so setting it here replays one.
+    pub rng_seed: u64,
+    /// Minutes without a game request before the server goes idle and
+    /// shuts down its search threads, or 0 to never go idle
+    pub idle_timeout_mins: u64,
+    /// TOML file of candidate AlphaBeta evaluation weights to score
+    /// each turn against `alpha_beta_weights` without affecting the
+    /// move, or empty to turn shadow scoring off
+    pub shadow_weights: String,
+    /// Add the move's confidence, our handler time and the request's
+    /// correlation id to every /move response, for a proxy in front
+    /// of the server. Debug builds also add them when asked for with
+    /// a request header.
+    pub response_tail: bool,
+    /// The version of the engine API to expect, "2019" or "2020"
+    pub api_version: ApiVersion,
+    /// Opponents for local games, as comma separated `name=url` pairs
+    pub play_opponents: String,
+    /// Board width for local games
+    pub play_width: u8,
+    /// Board height for local games
+    pub play_height: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 5000,
+            workers: 4,
+            color: String::from("#111111"),
+            author: String::from("jayden-chan"),
+            head_type: String::from("default"),
+            tail_type: String::from("default"),
+            log_level: String::from("info"),
+            log_format: LogFormat::Text,
+            opponents_dir: String::from("opponents"),
+            samples_dir: String::from("samples"),
+            cache_dir: String::from("cache"),
+            opponent_store: String::from("opponent_store.json"),
+            profile: String::from("monte_carlo"),
+            sim_budget_millis: 450,
+            mcts_budget_millis: 390,
+            mcts_final_move: FinalMove::Robust,
+            sim_self_profiles: names(&[
+                "astarbasic",
+                "cautious",
+                "straight",
+                "aggressive",
+                "notsuck",
+                "follow",
+            ]),
+            sim_enemy_profiles: names(&[
+                "astarbasic",
+                "cautious",
+                "aggressive",
+                "notsuck",
+                "straight",
+                "follow",
+            ]),
+            risk_posture: RiskPosture::Neutral,
+            edge_penalty: 0.2,
+            alpha_beta_leaf: LeafScore::Flood,
+            alpha_beta_search: SearchMode::Paranoid,
+            alpha_beta_weights: String::new(),
+            sim_min_food: 1,
+            sim_food_spawn_chance: 15,
+            random_tie_break: true,
+            rng_seed: 0,
+            idle_timeout_mins: 15,
+            shadow_weights: String::new(),
+            response_tail: false,
+            api_version: ApiVersion::V2019,
+            play_opponents: String::new(),
+            play_width: 11,
+            play_height: 11,
+        }
+    }
+}
+
+fn names(list: &[&str]) -> Vec<String> {
+    list.iter().map(|n| n.to_string()).collect()
+}
+
+/// Returns the global config, or the defaults if `init` was never called
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Sets the global config. Must be called before the first `get`.
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("Config initialized twice");
+    }
+}
+
+/// The environment variable a setting is read from
+fn env_name(key: &str) -> String {
+    match key {
+        "log_level" => String::from("RUST_LOG"),
+        _ => key.to_uppercase(),
+    }
+}
+
+/// Converts a string from the environment or the command line to a
+/// value of the same type as the setting's default. Lists are comma
+/// separated.
+fn typed_value(key: &str, raw: &str, default: &Value) -> Result<Value, String> {
+    let invalid = || format!("Invalid value for {}: {}", key, raw);
+
+    match default {
+        Value::Integer(_) => {
+            raw.parse().map(Value::Integer).map_err(|_| invalid())
+        }
+        Value::Float(_) => raw.parse().map(Value::Float).map_err(|_| invalid()),
+        Value::Boolean(_) => {
+            raw.parse().map(Value::Boolean).map_err(|_| invalid())
+        }
+        Value::Array(_) => Ok(Value::Array(
+            raw.split(',')
+                .map(|v| Value::String(v.trim().to_string()))
+                .collect(),
+        )),
+        _ => Ok(Value::String(raw.to_string())),
+    }
+}
+
+/// Collects `--flag value` pairs from the command line
+fn cli_flags(args: &[String]) -> HashMap<String, String> {
+    args.windows(2)
+        .filter(|w| w[0].starts_with("--"))
+        .map(|w| (w[0][2..].replace('-', "_"), w[1].clone()))
+        .collect()
+}
+
+impl Config {
+    /// Loads the config from the command line, the environment and
+    /// the config file
+    pub fn load(
+        args: &[String],
+        env: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let flags = cli_flags(args);
+        let path = flags.get("config").or_else(|| env.get("CONFIG")).cloned();
+
+        let file = match &path {
+            Some(p) => Some(
+                fs::read_to_string(p)
+                    .map_err(|e| format!("Couldn't read {}: {}", p, e))?,
+            ),
+            None => fs::read_to_string(DEFAULT_CONFIG_PATH).ok(),
+        };
+
+        Self::resolve(file.as_ref().map(|f| f.as_str()), env, &flags)
+    }
+
+    /// Layers the sources over the defaults and validates the result
+    fn resolve(
+        file: Option<&str>,
+        env: &HashMap<String, String>,
+        flags: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let defaults = match Value::try_from(Config::default()) {
+            Ok(Value::Table(t)) => t,
+            _ => unreachable!(),
+        };
+
+        let mut merged: Table = defaults.clone();
+
+        if let Some(contents) = file {
+            let table = contents
+                .parse::<Value>()
+                .map_err(|e| format!("Invalid config file: {}", e))?;
+
+            if let Value::Table(t) = table {
+                merged.extend(t);
+            }
+        }
+
+        for (key, default) in &defaults {
+            if let Some(raw) = env.get(&env_name(key)) {
+                merged.insert(key.clone(), typed_value(key, raw, default)?);
+            }
+        }
+
+        for (key, raw) in flags {
+            if key == "config" {
+                continue;
+            }
+
+            match defaults.get(key) {
+                Some(default) => {
+                    merged.insert(key.clone(), typed_value(key, raw, default)?);
+                }
+                None => return Err(format!("Unknown flag --{}", key)),
+            }
+        }
+
+        let config = Value::Table(merged)
+            .try_into::<Config>()
+            .map_err(|e| format!("Invalid config: {}", e))?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let hex = self.color.len() == 7
+            && self.color.starts_with('#')
+            && self.color[1..].chars().all(|c| c.is_ascii_hexdigit());
+
+        if !hex {
+            return Err(format!("Invalid color {}", self.color));
+        }
+
+        // RUST_LOG may also hold per-module directives, only check the
+        // level when it is a plain one
+        if !self.log_level.contains('=')
+            && !LOG_LEVELS.contains(&self.log_level.to_lowercase().as_str())
+        {
+            return Err(format!("Invalid log level {}", self.log_level));
+        }
+
+        if self.workers == 0 {
+            return Err(String::from("Need at least one worker"));
+        }
+
+        if !PROFILE_NAMES.contains(&self.profile.as_str()) {
+            return Err(format!("Unknown profile {}", self.profile));
+        }
+
+        if self.sim_budget_millis == 0 || self.mcts_budget_millis == 0 {
+            return Err(String::from("Search budgets must be non-zero"));
+        }
+
+        check_controllers(&self.sim_self_profiles)
+            .map_err(|e| format!("Invalid sim_self_profiles: {}", e))?;
+        check_controllers(&self.sim_enemy_profiles)
+            .map_err(|e| format!("Invalid sim_enemy_profiles: {}", e))?;
+
+        if self.edge_penalty < 0.0 || self.edge_penalty > 1.0 {
+            return Err(format!(
+                "Invalid edge penalty {}, expected 0 to 1",
+                self.edge_penalty
+            ));
+        }
+
+        if self.sim_food_spawn_chance > 100 {
+            return Err(format!(
+                "Invalid food spawn chance {}, expected 0 to 100",
+                self.sim_food_spawn_chance
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The effective config, formatted for the startup log
+    pub fn dump(&self) -> String {
+        toml::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let file = "port = 6000\ncolor = \"#ff0000\"\nsamples_dir = \"out\"";
+        let env = map(&[("PORT", "7000"), ("RUST_LOG", "debug")]);
+        let flags = map(&[("port", "8000")]);
+
+        let config = Config::resolve(Some(file), &env, &flags).unwrap();
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.color, "#ff0000");
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.samples_dir, "out");
+        assert_eq!(config.sim_budget_millis, 450);
+
+        let config = Config::resolve(Some(file), &env, &map(&[])).unwrap();
+        assert_eq!(config.port, 7000);
+
+        let config = Config::resolve(None, &map(&[]), &map(&[])).unwrap();
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_validation() {
+        let none = map(&[]);
+
+        assert!(
+            Config::resolve(Some("colour = \"#fff\""), &none, &none).is_err()
+        );
+        assert!(Config::resolve(Some("color = \"red\""), &none, &none).is_err());
+        assert!(
+            Config::resolve(None, &map(&[("RISK_POSTURE", "brave")]), &none)
+                .is_err()
+        );
+        assert!(Config::resolve(None, &map(&[("PORT", "abc")]), &none).is_err());
+        assert!(
+            Config::resolve(Some("edge_penalty = 1.5"), &none, &none).is_err()
+        );
+        let chance = "sim_food_spawn_chance = 101";
+        assert!(Config::resolve(Some(chance), &none, &none).is_err());
+        assert!(Config::resolve(None, &none, &map(&[("speed", "1")])).is_err());
+        assert!(Config::resolve(
+            Some("sim_enemy_profiles = [\"cautious\", \"sim\"]"),
+            &none,
+            &none
+        )
+        .is_err());
+        assert!(
+            Config::resolve(None, &map(&[("PROFILE", "best")]), &none).is_err()
+        );
+        assert!(Config::resolve(
+            None,
+            &map(&[("SIM_SELF_PROFILES", "")]),
+            &none
+        )
+        .is_err());
+
+        let config = Config::resolve(
+            None,
+            &none,
+            &map(&[("sim_self_profiles", "cautious, follow")]),
+        )
+        .unwrap();
+        assert_eq!(config.sim_self_profiles, vec!["cautious", "follow"]);
+        assert!(Config::resolve(
+            None,
+            &map(&[("RUST_LOG", "info,x=debug")]),
+            &none
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_cli_flags() {
+        let args: Vec<String> =
+            vec!["snake", "--samples-dir", "out", "--port", "1"]
+                .into_iter()
+                .map(String::from)
+                .collect();
+
+        let flags = cli_flags(&args);
+        assert_eq!(flags["samples_dir"], "out");
+        assert_eq!(flags["port"], "1");
+    }
+}
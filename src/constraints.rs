@@ -0,0 +1,595 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Hard constraints applied to the move chosen by a profile. These
+//! run after any search and can override its result when the move
+//! breaks a rule we never want to break.
+
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::time::Instant;
+
+use super::config;
+use super::game::analysis;
+use super::game::{Contest, Dir, DirOutcome, Point, SafetyIndex, Snake, State};
+use super::opponents::RiskPosture;
+use super::profile::TimeBudget;
+use super::simulator::process_step;
+
+const ALL_DIRS: [Dir; 4] = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+/// How far an enemy head can be from the square we move to and still
+/// cut off every escape with its reply
+const CORNER_REACH: usize = 3;
+
+/// How many turns ahead the duel verifier looks for a forced loss
+const DUEL_VERIFY_PLIES: u32 = 3;
+
+/// Whether a move fails the blunder tests: it is immediately fatal,
+/// or it enters a closed region too small to hold our body
+pub fn is_blunder(dir: Dir, s: &Snake, st: &State) -> bool {
+    DirOutcome::new(dir, s, st).is_blunder()
+}
+
+/// Whether moving in `dir` lets an equal or longer enemy corner us:
+/// some reply of theirs leaves every one of our next moves a blunder
+/// or onto a square they can contest. The reply is simulated with
+/// `process_step`, rather than matching board patterns like
+/// `Dir::is_corner_risky` does. Snakes out of reach don't move.
+pub fn is_cornered(dir: Dir, s: &Snake, st: &State) -> bool {
+    let head = dir.resulting_point(s.body[0], st);
+
+    if is_blunder(dir, s, st) {
+        return false;
+    }
+
+    st.board
+        .snakes
+        .values()
+        .filter(|e| {
+            e.id != s.id
+                && e.body.len() >= s.body.len()
+                && analysis::reach(e, st, CORNER_REACH)
+                    .within(CORNER_REACH)
+                    .contains(head)
+        })
+        .any(|e| {
+            ALL_DIRS
+                .iter()
+                .filter(|d| !d.is_safety_index(e, st, &SafetyIndex::Unsafe))
+                .any(|d| {
+                    let mut next = st.clone();
+                    let mut moves = HashMap::new();
+                    moves.insert(s.id.clone(), dir);
+                    moves.insert(e.id.clone(), *d);
+
+                    if !process_step(&mut next, &s.id, &moves).alive {
+                        return false;
+                    }
+
+                    let us = &next.board.snakes[&s.id];
+                    ALL_DIRS.iter().all(|m| {
+                        let o = DirOutcome::new(*m, us, &next);
+                        let contested = o.contest == Contest::Equal
+                            || o.contest == Contest::Losing;
+                        o.is_blunder() || contested
+                    })
+                })
+        })
+}
+
+/// Whether `s` is strictly longer than every other snake
+pub fn is_longest(s: &Snake, st: &State) -> bool {
+    st.board
+        .snakes
+        .iter()
+        .all(|(id, other)| *id == s.id || other.body.len() < s.body.len())
+}
+
+/// Whether any other snake is strictly longer than `s`
+fn is_behind(s: &Snake, st: &State) -> bool {
+    st.board
+        .snakes
+        .values()
+        .any(|other| other.body.len() > s.body.len())
+}
+
+/// A square that both we and an equal length enemy are forced onto,
+/// so both heads will meet there
+#[derive(Debug, Clone, PartialEq)]
+pub struct MutualDestruction {
+    pub square: Point,
+    pub enemy: String,
+}
+
+/// Finds the mutual destruction square we are forced towards, if any.
+/// Every other move of ours must fail the blunder tests, and the
+/// enemy's only move that isn't immediately fatal must be the same
+/// square.
+pub fn mutual_destruction(s: &Snake, st: &State) -> Option<MutualDestruction> {
+    let head = s.body[0];
+
+    ALL_DIRS
+        .iter()
+        .filter(|d| !d.is_safety_index(s, st, &SafetyIndex::Unsafe))
+        .find_map(|d| {
+            let forced = ALL_DIRS
+                .iter()
+                .filter(|o| *o != d)
+                .all(|o| is_blunder(*o, s, st));
+
+            if !forced {
+                return None;
+            }
+
+            let square = d.resulting_point(head, st);
+            let reach = analysis::reach_all(st, 1);
+            st.board
+                .snakes
+                .values()
+                .find(|e| {
+                    e.id != s.id
+                        && e.body.len() == s.body.len()
+                        && reach[&e.id].at(1).unwrap().contains(square)
+                        && ALL_DIRS
+                            .iter()
+                            .filter(|m| {
+                                !m.is_safety_index(e, st, &SafetyIndex::Unsafe)
+                            })
+                            .all(|m| m.resulting_point(e.body[0], st) == square)
+                })
+                .map(|e| MutualDestruction {
+                    square,
+                    enemy: e.id.clone(),
+                })
+        })
+}
+
+/// Applies every hard constraint to the chosen move, returning the
+/// move that should actually be played. `postures` holds the risk
+/// posture configured for each enemy, by snake id.
+pub fn apply(
+    dir: Dir,
+    s: &Snake,
+    st: &State,
+    postures: &HashMap<String, RiskPosture>,
+) -> Dir {
+    if let Some(md) = mutual_destruction(s, st) {
+        let posture = postures
+            .get(&md.enemy)
+            .cloned()
+            .unwrap_or(config::get().risk_posture);
+
+        return resolve_mutual_destruction(dir, &md, posture, s, st);
+    }
+
+    avoid_head_on(dir, s, st)
+}
+
+/// Decides between taking a mutual destruction square and stalling
+/// in the largest space left to us. Stalling is only worth it if it
+/// keeps us alive longer than the enemy once it takes the square.
+fn resolve_mutual_destruction(
+    dir: Dir,
+    md: &MutualDestruction,
+    posture: RiskPosture,
+    s: &Snake,
+    st: &State,
+) -> Dir {
+    let head = s.body[0];
+    let area = (st.board.width as u16) * (st.board.height as u16);
+
+    let stall = ALL_DIRS
+        .iter()
+        .filter(|d| d.resulting_point(head, st) != md.square)
+        .filter(|d| !d.is_safety_index(s, st, &SafetyIndex::Unsafe))
+        .map(|d| {
+            let room = d.resulting_point(head, st).flood_fill(s, st, area);
+            (*d, room.len())
+        })
+        .max_by_key(|(_, size)| *size);
+
+    let accept = match (stall, posture) {
+        // Refusing would be certain death on our own
+        (None, _) => true,
+        (Some(_), RiskPosture::Aggressive) => true,
+        (Some(_), RiskPosture::Cautious) => false,
+        (Some((_, size)), RiskPosture::Neutral) => {
+            let enemy = &st.board.snakes[&md.enemy];
+            let enemy_space =
+                md.square.flood_fill(enemy, st, size as u16).len();
+
+            (st.board.snakes.len() >= 3 && is_behind(s, st))
+                || size <= enemy_space
+        }
+    };
+
+    let chosen = match stall {
+        Some((stall_dir, _)) if !accept => stall_dir,
+        _ => head.step_dir(md.square).unwrap_or(dir),
+    };
+
+    if chosen == dir {
+        return dir;
+    }
+    let reason = format!(
+        "{} mutual destruction at {:?} against {}",
+        if accept { "refuses" } else { "risks" },
+        md.square,
+        md.enemy
+    );
+    overridden(dir, chosen, &reason)
+}
+
+/// Logs a constraint overriding `dir` with `chosen` for `reason`, and
+/// returns `chosen`
+fn overridden(dir: Dir, chosen: Dir, reason: &str) -> Dir {
+    warn!(
+        "Constraint override: {:?} {}, playing {:?}",
+        dir, reason, chosen
+    );
+    chosen
+}
+
+/// Unless we are the longest snake, never move onto a square an
+/// equal or longer enemy head can also reach this turn, unless every
+/// alternative is certainly fatal
+fn avoid_head_on(dir: Dir, s: &Snake, st: &State) -> Dir {
+    if is_longest(s, st) || !dir.is_safety_index(s, st, &SafetyIndex::Risky) {
+        return dir;
+    }
+
+    // Safe squares aren't contested by an equal or longer head
+    let alternative = ALL_DIRS
+        .iter()
+        .filter(|d| d.is_safety_index(s, st, &SafetyIndex::Safe))
+        .filter(|d| !is_blunder(**d, s, st))
+        .max_by_key(|d| {
+            let p = d.resulting_point(s.body[0], st);
+            p.flood_fill(s, st, s.body.len() as u16).len()
+        });
+
+    match alternative {
+        Some(alt) => overridden(dir, *alt, "is contested by a longer head"),
+        None => dir,
+    }
+}
+
+/// The moves a snake could make without dying on the spot
+fn candidate_moves(s: &Snake, st: &State) -> Vec<Dir> {
+    ALL_DIRS
+        .iter()
+        .filter(|d| !d.is_safety_index(s, st, &SafetyIndex::Unsafe))
+        .cloned()
+        .collect()
+}
+
+/// Whether every enemy reply we haven't ruled out lets the enemy force
+/// a loss on us within `plies` turns once we play `dir`. Dying in a
+/// head-on collision that kills both counts as a loss. Returns None if
+/// the deadline passes before the search finishes.
+fn refuted(
+    dir: Dir,
+    st: &State,
+    us: &str,
+    them: &str,
+    plies: u32,
+    deadline: Instant,
+) -> Option<bool> {
+    let enemy = &st.board.snakes[them];
+    let mut replies = candidate_moves(enemy, st);
+    if replies.is_empty() {
+        // It dies whatever it does
+        replies.push(Dir::Up);
+    }
+
+    for reply in replies {
+        if Instant::now() >= deadline {
+            return None;
+        }
+
+        let mut next = st.clone();
+        let mut moves = HashMap::new();
+        moves.insert(us.to_string(), dir);
+        moves.insert(them.to_string(), reply);
+
+        if !process_step(&mut next, us, &moves).alive {
+            return Some(true);
+        }
+
+        if next.board.snakes.contains_key(them)
+            && forced_loss(&next, us, them, plies - 1, deadline)?
+        {
+            return Some(true);
+        }
+    }
+
+    Some(false)
+}
+
+/// Whether the enemy can force a loss on us within `plies` turns
+/// whatever we play
+fn forced_loss(
+    st: &State,
+    us: &str,
+    them: &str,
+    plies: u32,
+    deadline: Instant,
+) -> Option<bool> {
+    if plies == 0 {
+        return Some(false);
+    }
+
+    for dir in candidate_moves(&st.board.snakes[us], st) {
+        if !refuted(dir, st, us, them, plies, deadline)? {
+            return Some(false);
+        }
+    }
+
+    Some(true)
+}
+
+/// Final check of the chosen move in a duel. Every line of play is
+/// searched exhaustively for `DUEL_VERIFY_PLIES` turns, and if the
+/// enemy can force a loss after `dir` we switch to a move that is
+/// proven not to lose, preferring the most space. The move is kept if
+/// there is no such move, or if the search can't finish within the
+/// budget the scheduler granted. A mutual destruction was already
+/// settled by the risk posture in `apply`, so its move is kept too.
+pub fn verify_duel(
+    dir: Dir,
+    s: &Snake,
+    st: &State,
+    budget: &TimeBudget,
+) -> Dir {
+    if st.board.snakes.len() != 2 {
+        return dir;
+    }
+
+    let them = match st.board.snakes.keys().find(|id| **id != s.id) {
+        Some(id) => id,
+        None => return dir,
+    };
+
+    // The search counts trading heads as a loss, which the posture
+    // may have chosen on purpose
+    if let Some(md) = mutual_destruction(s, st) {
+        debug!("Keeping {:?}, settled by the posture to {}", dir, md.enemy);
+        return dir;
+    }
+
+    let deadline = budget.soft_deadline();
+    let plies = DUEL_VERIFY_PLIES;
+
+    match refuted(dir, st, &s.id, them, plies, deadline) {
+        Some(true) => {}
+        Some(false) => return dir,
+        None => {
+            debug!("Ran out of time verifying {:?}", dir);
+            return dir;
+        }
+    }
+
+    let mut alternatives = candidate_moves(s, st)
+        .into_iter()
+        .filter(|d| *d != dir)
+        .collect::<Vec<Dir>>();
+    alternatives.sort_by_key(|d| {
+        let p = d.resulting_point(s.body[0], st);
+        std::cmp::Reverse(p.flood_fill(s, st, s.body.len() as u16).len())
+    });
+
+    let safe = alternatives.into_iter().find(|d| {
+        refuted(*d, st, &s.id, them, plies, deadline) == Some(false)
+    });
+
+    match safe {
+        Some(alt) => {
+            let reason = format!("loses by force within {} turns", plies);
+            overridden(dir, alt, &reason)
+        }
+        None => dir,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::game::build_state;
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_avoid_head_on() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(5, 3), (5, 2), (5, 1)]),
+            ],
+            &[],
+        );
+
+        // Equal length, so (5, 4) must be avoided
+        assert_ne!(apply(Dir::Up, &you, &st, &HashMap::new()), Dir::Up);
+        assert_eq!(apply(Dir::Left, &you, &st, &HashMap::new()), Dir::Left);
+
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7), (5, 8)]),
+                ("other", &[(5, 3), (5, 2), (5, 1)]),
+            ],
+            &[],
+        );
+
+        // We're longer, so the contest is fine
+        assert_eq!(apply(Dir::Up, &you, &st, &HashMap::new()), Dir::Up);
+
+        // Left is the only square out of the longer enemy's reach, and a
+        // pocket too small to hold us, so the contest is still the best
+        // chance
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(1, 1), (1, 2), (0, 2), (0, 3)]),
+                ("other", &[(2, 0), (3, 0), (4, 0), (5, 0), (6, 0)]),
+            ],
+            &[],
+        );
+        assert!(is_blunder(Dir::Left, &you, &st));
+        assert_eq!(apply(Dir::Up, &you, &st, &HashMap::new()), Dir::Up);
+    }
+
+    #[test]
+    fn test_mutual_destruction() {
+        // Both heads are forced onto (2, 0). Taking it leaves the enemy
+        // a long column, while our pocket on the left is tiny.
+        let (you, st) = build_state(
+            4,
+            8,
+            &[
+                (
+                    "you",
+                    &[
+                        (1, 0),
+                        (1, 1),
+                        (1, 2),
+                        (1, 3),
+                        (0, 3),
+                        (0, 4),
+                        (0, 5),
+                        (0, 6),
+                    ],
+                ),
+                (
+                    "other",
+                    &[
+                        (3, 0),
+                        (3, 1),
+                        (3, 2),
+                        (3, 3),
+                        (3, 4),
+                        (3, 5),
+                        (3, 6),
+                        (3, 7),
+                    ],
+                ),
+            ],
+            &[],
+        );
+
+        let md = mutual_destruction(&you, &st).unwrap();
+        assert_eq!(md.square, Point { x: 2, y: 0 });
+
+        let mut postures = HashMap::new();
+        assert_eq!(apply(Dir::Left, &you, &st, &postures), Dir::Right);
+
+        postures.insert(String::from("other"), RiskPosture::Cautious);
+        assert_eq!(apply(Dir::Right, &you, &st, &postures), Dir::Left);
+
+        // Here the enemy is boxed in after taking the square, so we
+        // can outlast it by stalling in our pocket
+        let (you, st) = build_state(
+            4,
+            5,
+            &[
+                ("you", &[(1, 0), (1, 1), (1, 2), (1, 3), (1, 4), (2, 4)]),
+                ("other", &[(3, 0), (3, 1), (2, 1), (2, 2), (3, 2), (3, 3)]),
+            ],
+            &[],
+        );
+
+        assert!(mutual_destruction(&you, &st).is_some());
+        assert_eq!(apply(Dir::Right, &you, &st, &HashMap::new()), Dir::Left);
+
+        postures.insert(String::from("other"), RiskPosture::Aggressive);
+        assert_eq!(apply(Dir::Left, &you, &st, &postures), Dir::Right);
+
+        // and the duel verifier leaves the trade the posture chose
+        let budget = TimeBudget::untimed();
+        assert_eq!(verify_duel(Dir::Right, &you, &st, &budget), Dir::Right);
+    }
+
+    #[test]
+    fn test_is_cornered() {
+        // Running along the top wall next to an equal length enemy, which
+        // can move up in front of us
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(3, 0), (2, 0), (1, 0), (0, 0)]),
+                ("other", &[(5, 1), (4, 1), (3, 1), (2, 1)]),
+            ],
+            &[],
+        );
+        assert!(is_cornered(Dir::Right, &you, &st));
+
+        // The same run with the enemy too far away to cut us off
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(3, 0), (2, 0), (1, 0), (0, 0)]),
+                ("other", &[(9, 6), (9, 7), (9, 8), (9, 9)]),
+            ],
+            &[],
+        );
+        assert!(!is_cornered(Dir::Right, &you, &st));
+        assert!(!is_cornered(Dir::Down, &you, &st));
+    }
+
+    #[test]
+    fn test_verify_duel() {
+        // Up leads into a two square pocket walled off by the enemy's
+        // body, where we die on the third turn
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(0, 2), (1, 2), (2, 2), (3, 2)]),
+                (
+                    "other",
+                    &[
+                        (4, 1),
+                        (3, 1),
+                        (2, 1),
+                        (1, 1),
+                        (1, 0),
+                        (2, 0),
+                        (3, 0),
+                        (4, 0),
+                        (5, 0),
+                    ],
+                ),
+            ],
+            &[],
+        );
+
+        let budget = TimeBudget::untimed();
+        assert_eq!(verify_duel(Dir::Up, &you, &st, &budget), Dir::Down);
+        assert_eq!(verify_duel(Dir::Down, &you, &st, &budget), Dir::Down);
+
+        // Without the time to verify, the move is kept
+        let start = Instant::now() - Duration::from_millis(100);
+        let spent = TimeBudget::for_move(start).capped(50);
+        assert_eq!(verify_duel(Dir::Up, &you, &st, &spent), Dir::Up);
+    }
+}
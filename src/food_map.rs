@@ -0,0 +1,230 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Which part of the board food is likely to be in. Each quadrant is
+//! scored by the food on it now plus the food that has spawned on it
+//! so far this game, older spawns counting for less. On larger boards
+//! the snake is nudged to stay within reach of the best quadrant
+//! through the opening and midgame, so it isn't stranded on the wrong
+//! side of the board when it gets hungry.
+
+use log::debug;
+
+use super::game::{Contest, Dir, Point, Snake, State, TurnContext};
+
+/// Boards with a side shorter than this are too small for their
+/// quadrants to matter
+const MIN_SIDE: i8 = 11;
+
+/// The turn the midgame ends, after which we stop committing to a
+/// quadrant
+const MIDGAME_END_TURN: u32 = 150;
+
+/// How much a spawn counts for compared to food on the board now, and
+/// how many turns it takes for that to halve
+const SPAWN_WEIGHT: f64 = 0.5;
+const SPAWN_HALF_LIFE_TURNS: f64 = 50.0;
+
+/// How much better the best quadrant must score than the next best to
+/// be worth committing to
+const COMMIT_RATIO: f64 = 1.5;
+
+/// How far the head may be from the best quadrant and still count as
+/// within reach of it
+const QUADRANT_REACH: u32 = 3;
+
+/// A rectangle of the board, bounds inclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quadrant {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Quadrant {
+    pub fn contains(self, p: Point) -> bool {
+        p.x >= self.min.x
+            && p.x <= self.max.x
+            && p.y >= self.min.y
+            && p.y <= self.max.y
+    }
+
+    /// The distance from `p` to the closest cell of the quadrant
+    pub fn distance(self, p: Point) -> u32 {
+        let closest = Point {
+            x: p.x.max(self.min.x).min(self.max.x),
+            y: p.y.max(self.min.y).min(self.max.y),
+        };
+
+        p.manhattan(closest)
+    }
+}
+
+fn quadrants(st: &State) -> [Quadrant; 4] {
+    let (w, h) = (st.board.width, st.board.height);
+    let (hw, hh) = (w / 2, h / 2);
+    let quadrant = |x0, y0, x1, y1| Quadrant {
+        min: Point { x: x0, y: y0 },
+        max: Point { x: x1, y: y1 },
+    };
+
+    [
+        quadrant(0, 0, hw - 1, hh - 1),
+        quadrant(hw, 0, w - 1, hh - 1),
+        quadrant(0, hh, hw - 1, h - 1),
+        quadrant(hw, hh, w - 1, h - 1),
+    ]
+}
+
+/// Every quadrant with its expected food availability, best first.
+/// `spawns` holds where and on which turn food has spawned.
+pub fn score_quadrants(
+    st: &State,
+    spawns: &[(Point, u32)],
+) -> Vec<(Quadrant, f64)> {
+    let mut scored = quadrants(st)
+        .iter()
+        .map(|q| {
+            let current = st.board.food.iter().filter(|f| q.contains(**f));
+            let spawned = spawns
+                .iter()
+                .filter(|(p, _)| q.contains(*p))
+                .map(|(_, turn)| {
+                    let age = f64::from(st.turn.saturating_sub(*turn));
+                    SPAWN_WEIGHT * 0.5_f64.powf(age / SPAWN_HALF_LIFE_TURNS)
+                })
+                .sum::<f64>();
+
+            (*q, current.count() as f64 + spawned)
+        })
+        .collect::<Vec<(Quadrant, f64)>>();
+
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    scored
+}
+
+/// The quadrant worth committing to, if the board is large enough and
+/// one quadrant clearly has the most food
+pub fn best_quadrant(st: &State, spawns: &[(Point, u32)]) -> Option<Quadrant> {
+    if st.board.width < MIN_SIDE || st.board.height < MIN_SIDE {
+        return None;
+    }
+
+    let scored = score_quadrants(st, spawns);
+    let (best, score) = scored[0];
+
+    if score > 0.0 && score >= scored[1].1 * COMMIT_RATIO {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+/// Swaps the chosen move for one towards the best quadrant if our head
+/// is out of its reach and the chosen move doesn't close the distance.
+/// Only root moves with as much room and no worse a contest qualify,
+/// so the bias never costs us safety.
+pub fn commit(
+    dir: Dir,
+    s: &Snake,
+    st: &State,
+    ctx: &TurnContext,
+    spawns: &[(Point, u32)],
+) -> Dir {
+    if st.turn >= MIDGAME_END_TURN {
+        return dir;
+    }
+
+    let quadrant = match best_quadrant(st, spawns) {
+        Some(q) => q,
+        None => return dir,
+    };
+
+    let head = s.body[0];
+    let distance = quadrant.distance(head);
+    let closer =
+        |d: Dir| quadrant.distance(d.resulting_point(head, st)) < distance;
+
+    if distance <= QUADRANT_REACH || closer(dir) {
+        return dir;
+    }
+
+    let chosen = ctx.get(dir);
+    let risk = |c: Contest| match c {
+        Contest::Uncontested | Contest::Winning => 0,
+        Contest::Equal => 1,
+        Contest::Losing => 2,
+    };
+
+    let alt = ctx
+        .root_moves()
+        .into_iter()
+        .filter(|d| closer(*d))
+        .filter(|d| {
+            let o = ctx.get(*d);
+            o.area >= chosen.area && risk(o.contest) <= risk(chosen.contest)
+        })
+        .max_by_key(|d| ctx.get(*d).area);
+
+    match alt {
+        Some(alt) => {
+            debug!(
+                "Committing to the quadrant at {:?}: {:?} instead of {:?}",
+                quadrant.min, alt, dir
+            );
+            alt
+        }
+        None => dir,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_commit() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[("you", &[(8, 5), (8, 6), (8, 7)])],
+            &[(1, 1), (2, 2), (3, 1)],
+        );
+        let ctx = TurnContext::new(&you, &st);
+        let spawns = [(Point { x: 9, y: 9 }, 0)];
+
+        let best = best_quadrant(&st, &spawns).unwrap();
+        assert_eq!(best.min, Point { x: 0, y: 0 });
+        assert_eq!(best.distance(you.body[0]), 5);
+
+        // Heading away from the food is swapped for heading towards it
+        assert_eq!(commit(Dir::Right, &you, &st, &ctx, &spawns), Dir::Left);
+        assert_eq!(commit(Dir::Up, &you, &st, &ctx, &spawns), Dir::Up);
+
+        // Past the midgame we go where we were going
+        let mut late = st.clone();
+        late.turn = MIDGAME_END_TURN;
+        assert_eq!(commit(Dir::Right, &you, &late, &ctx, &spawns), Dir::Right);
+
+        // Nothing to commit to on a small board
+        let (you, st) =
+            build_state(7, 7, &[("you", &[(5, 3), (5, 4)])], &[(1, 1)]);
+        assert!(best_quadrant(&st, &[]).is_none());
+        let ctx = TurnContext::new(&you, &st);
+        assert_eq!(commit(Dir::Right, &you, &st, &ctx, &[]), Dir::Right);
+    }
+}
@@ -0,0 +1,332 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Differential fuzzing of the simulator. This module contains a
+//! transcription of the official engine's standard ruleset and a
+//! harness that runs it side by side with `process_step` on random
+//! states, shrinking any disagreement down to a minimal counterexample.
+//!
+//! Only compiled for `cargo test --features rules-fuzz`. The number of
+//! cases can be set with the `FUZZ_CASES` environment variable.
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+use crate::game::{Board, Dir, Game, GameMode, Point, Snake, State};
+use crate::simulator::process_step;
+
+const DEFAULT_CASES: usize = 5000;
+const SEED: u64 = 0x00b5_2020;
+
+/// The observable result of a single turn
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    eliminated: Vec<String>,
+    survivors: Vec<(String, u8, Vec<Point>)>,
+    food: Vec<(i8, i8)>,
+}
+
+/// A single fuzz case: a state and the move of every snake
+#[derive(Clone, Debug)]
+struct Case {
+    state: State,
+    moves: HashMap<String, Dir>,
+}
+
+/// Transcription of the engine's `standard` ruleset for a single turn.
+/// Moves snakes, reduces health, feeds, then eliminates in the same
+/// order as the engine: starvation and out of bounds first, then
+/// collisions against the snakes that are still in the game.
+fn reference_step(case: &Case) -> Outcome {
+    let st = &case.state;
+    let mut snakes: Vec<Snake> = st.board.snakes.values().cloned().collect();
+    snakes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    for snake in &mut snakes {
+        let head = case.moves[&snake.id].resulting_point(snake.body[0], st);
+        snake.body.insert(0, head);
+        snake.body.pop();
+        snake.health = snake.health.saturating_sub(1);
+    }
+
+    let mut food = st.board.food.clone();
+    for snake in &mut snakes {
+        if st.board.food.contains(&snake.body[0]) {
+            let tail = *snake.body.last().unwrap();
+            snake.body.push(tail);
+            snake.health = 100;
+            food.remove(&snake.body[0]);
+        }
+    }
+
+    let mut eliminated = HashSet::new();
+    for snake in &snakes {
+        if snake.health == 0 || !snake.body[0].in_bounds(st) {
+            eliminated.insert(snake.id.clone());
+        }
+    }
+
+    let mut collided = Vec::new();
+    for snake in &snakes {
+        if eliminated.contains(&snake.id) {
+            continue;
+        }
+
+        let head = snake.body[0];
+        let body_hit = snakes
+            .iter()
+            .filter(|o| !eliminated.contains(&o.id))
+            .any(|o| o.body.iter().skip(1).any(|p| *p == head));
+
+        let head_hit = snakes.iter().any(|o| {
+            o.id != snake.id
+                && !eliminated.contains(&o.id)
+                && o.body[0] == head
+                && snake.body.len() <= o.body.len()
+        });
+
+        if body_hit || head_hit {
+            collided.push(snake.id.clone());
+        }
+    }
+    eliminated.extend(collided);
+
+    let mut eliminated: Vec<String> = eliminated.into_iter().collect();
+    eliminated.sort();
+
+    Outcome {
+        survivors: snakes
+            .into_iter()
+            .filter(|s| !eliminated.contains(&s.id))
+            .map(|s| (s.id, s.health, s.body))
+            .collect(),
+        eliminated,
+        food: sorted_food(&food),
+    }
+}
+
+/// Runs the case through our own simulator
+fn simulator_step(case: &Case) -> Outcome {
+    let mut st = case.state.clone();
+    let mut ids: Vec<&String> = case.state.board.snakes.keys().collect();
+    ids.sort();
+    let self_id = ids[0].clone();
+
+    let future = process_step(&mut st, &self_id, &case.moves);
+
+    let mut eliminated: Vec<String> = case
+        .state
+        .board
+        .snakes
+        .keys()
+        .filter(|id| !st.board.snakes.contains_key(*id))
+        .cloned()
+        .collect();
+
+    if !future.alive {
+        st.board.snakes.remove(&self_id);
+        eliminated.push(self_id);
+    }
+    eliminated.sort();
+
+    let mut survivors: Vec<(String, u8, Vec<Point>)> = st
+        .board
+        .snakes
+        .into_iter()
+        .map(|(id, s)| (id, s.health, s.body))
+        .collect();
+    survivors.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Outcome {
+        eliminated,
+        survivors,
+        food: sorted_food(&st.board.food),
+    }
+}
+
+fn sorted_food(food: &HashSet<Point>) -> Vec<(i8, i8)> {
+    let mut food: Vec<(i8, i8)> = food.iter().map(|p| (p.x, p.y)).collect();
+    food.sort();
+    food
+}
+
+fn disagrees(case: &Case) -> bool {
+    reference_step(case) != simulator_step(case)
+}
+
+/// Generates a random, legal start-of-turn state along with a random
+/// move for every snake. Bodies never overlap each other, but tails
+/// may be stacked to mimic a snake that has just eaten.
+fn random_case(rng: &mut StdRng) -> Case {
+    let width = rng.gen_range(5, 12);
+    let height = rng.gen_range(5, 12);
+    let mut occupied = HashSet::new();
+    let mut snakes = HashMap::new();
+    let mut moves = HashMap::new();
+    let dirs = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+    for i in 0..rng.gen_range(1, 5) {
+        let id = format!("snake-{}", i);
+        let len = rng.gen_range(2, 9);
+        let mut body = Vec::with_capacity(len);
+        let mut curr = Point {
+            x: rng.gen_range(0, width),
+            y: rng.gen_range(0, height),
+        };
+
+        while body.len() < len && !occupied.contains(&curr) {
+            body.push(curr);
+            occupied.insert(curr);
+
+            let next = [(0, -1), (0, 1), (-1, 0), (1, 0)]
+                .iter()
+                .map(|(dx, dy)| curr.offset(*dx, *dy))
+                .filter(|p| {
+                    p.x >= 0
+                        && p.y >= 0
+                        && p.x < width
+                        && p.y < height
+                        && !occupied.contains(p)
+                })
+                .collect::<Vec<Point>>();
+
+            match next.choose(rng) {
+                Some(p) => curr = *p,
+                None => break,
+            }
+        }
+
+        if body.is_empty() {
+            continue;
+        }
+
+        if rng.gen_bool(0.2) {
+            let tail = *body.last().unwrap();
+            body.push(tail);
+        }
+
+        moves.insert(id.clone(), *dirs.choose(rng).unwrap());
+        snakes.insert(
+            id.clone(),
+            Snake {
+                id,
+                health: rng.gen_range(1, 101),
+                body,
+                squad: None,
+            },
+        );
+    }
+
+    let mut food = HashSet::new();
+    for _ in 0..rng.gen_range(0, 6) {
+        let p = Point {
+            x: rng.gen_range(0, width),
+            y: rng.gen_range(0, height),
+        };
+
+        if !occupied.contains(&p) {
+            food.insert(p);
+        }
+    }
+
+    Case {
+        state: State {
+            game: Game {
+                id: String::from("fuzz"),
+                ruleset: None,
+                timeout: None,
+            },
+            turn: rng.gen_range(0, 300),
+            board: Board {
+                height,
+                width,
+                food,
+                snakes,
+                hazards: HashSet::new(),
+                mode: GameMode::Standard,
+            },
+            meta: Default::default(),
+            distances: Default::default(),
+        },
+        moves,
+    }
+}
+
+/// Greedily shrinks a failing case by removing food, removing snakes
+/// and shortening bodies for as long as the disagreement persists.
+fn minimize(case: Case) -> Case {
+    let mut best = case;
+
+    loop {
+        let mut candidates = Vec::new();
+
+        for food in &best.state.board.food {
+            let mut c = best.clone();
+            c.state.board.food.remove(food);
+            candidates.push(c);
+        }
+
+        for id in best.state.board.snakes.keys() {
+            if best.state.board.snakes.len() > 1 {
+                let mut c = best.clone();
+                c.state.board.snakes.remove(id);
+                c.moves.remove(id);
+                candidates.push(c);
+            }
+
+            if best.state.board.snakes[id].body.len() > 1 {
+                let mut c = best.clone();
+                c.state.board.snakes.get_mut(id).unwrap().body.pop();
+                candidates.push(c);
+            }
+        }
+
+        match candidates.into_iter().find(disagrees) {
+            Some(smaller) => best = smaller,
+            None => return best,
+        }
+    }
+}
+
+#[test]
+fn process_step_matches_reference_rules() {
+    let cases = env::var("FUZZ_CASES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CASES);
+    let mut rng = StdRng::seed_from_u64(SEED);
+
+    for i in 0..cases {
+        let case = random_case(&mut rng);
+
+        if disagrees(&case) {
+            let min = minimize(case);
+            panic!(
+                "Case {} disagrees with the reference rules\n\
+                 state: {}\nmoves: {:?}\nreference: {:?}\nsimulator: {:?}",
+                i,
+                serde_json::to_string(&min.state).unwrap(),
+                min.moves,
+                reference_step(&min),
+                simulator_step(&min),
+            );
+        }
+    }
+}
@@ -0,0 +1,240 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Where each snake's head could be over the next few turns. The
+//! reachable set after t turns is found by a breadth first search
+//! from the head, where a body segment blocks its cell until the
+//! segments behind it have moved off, i.e. segment i of a snake of
+//! length n is in the way for n - i turns. Growth from food and
+//! collisions between the snakes are ignored, so the sets can only
+//! be too large, never too small.
+
+use std::collections::HashMap;
+
+use super::{Contest, Point, Snake, State};
+
+/// How many turns of reach `TurnContext` works out for every snake
+pub const CONTEXT_REACH_TURNS: usize = 4;
+
+/// A set of cells of a board, one bit per cell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellSet {
+    width: i8,
+    height: i8,
+    bits: Vec<u64>,
+}
+
+impl CellSet {
+    pub fn new(st: &State) -> Self {
+        let (width, height) = (st.board.width, st.board.height);
+        let cells = (width.max(0) as usize) * (height.max(0) as usize);
+
+        Self {
+            width,
+            height,
+            bits: vec![0; cells.div_ceil(64)],
+        }
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        let inside =
+            p.x >= 0 && p.y >= 0 && p.x < self.width && p.y < self.height;
+
+        if inside {
+            Some(p.y as usize * self.width as usize + p.x as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Adds a cell. Cells off the board are ignored.
+    pub fn insert(&mut self, p: Point) {
+        if let Some(i) = self.index(p) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn contains(&self, p: Point) -> bool {
+        match self.index(p) {
+            Some(i) => self.bits[i / 64] & (1 << (i % 64)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn union_with(&mut self, other: &Self) {
+        for (a, b) in self.bits.iter_mut().zip(&other.bits) {
+            *a |= b;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Point> + '_ {
+        let width = self.width as usize;
+
+        (0..self.bits.len() * 64)
+            .filter(move |i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+            .map(move |i| Point {
+                x: (i % width) as i8,
+                y: (i / width) as i8,
+            })
+    }
+}
+
+/// The cells a snake's head could be on after each of the next few
+/// turns. Layer 0 is the head itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reach {
+    layers: Vec<CellSet>,
+}
+
+impl Reach {
+    /// The cells the head could be on after exactly `turns` turns, or
+    /// None if that is further ahead than was worked out
+    pub fn at(&self, turns: usize) -> Option<&CellSet> {
+        self.layers.get(turns)
+    }
+
+    /// The cells the head could move onto at some point in the next
+    /// `turns` turns, as far ahead as was worked out
+    pub fn within(&self, turns: usize) -> CellSet {
+        let mut set = self.layers[0].clone();
+        set.bits.iter_mut().for_each(|b| *b = 0);
+
+        for layer in self.layers.iter().skip(1).take(turns) {
+            set.union_with(layer);
+        }
+
+        set
+    }
+}
+
+/// Reach for every snake, by id
+pub type ReachMap = HashMap<String, Reach>;
+
+/// The number of turns until each cell is free, in row order
+fn free_after(st: &State) -> Vec<usize> {
+    let width = st.board.width as usize;
+    let mut free = vec![0; width * st.board.height.max(0) as usize];
+
+    for s in st.board.snakes.values() {
+        for (i, p) in s.body.iter().enumerate() {
+            if p.in_bounds(st) {
+                let cell = p.y as usize * width + p.x as usize;
+                free[cell] = free[cell].max(s.body.len() - i);
+            }
+        }
+    }
+
+    free
+}
+
+fn reach_with(s: &Snake, st: &State, turns: usize, free: &[usize]) -> Reach {
+    let width = st.board.width as usize;
+    let mut head = CellSet::new(st);
+    head.insert(s.body[0]);
+
+    let mut layers = vec![head];
+    for t in 1..=turns {
+        let mut next = CellSet::new(st);
+
+        for p in layers[t - 1].iter() {
+            for n in p.neighbors(st) {
+                if free[n.y as usize * width + n.x as usize] <= t {
+                    next.insert(n);
+                }
+            }
+        }
+
+        layers.push(next);
+    }
+
+    Reach { layers }
+}
+
+/// Where the head of `s` could be over the next `turns` turns
+pub fn reach(s: &Snake, st: &State, turns: usize) -> Reach {
+    reach_with(s, st, turns, &free_after(st))
+}
+
+/// `reach` for every snake on the board
+pub fn reach_all(st: &State, turns: usize) -> ReachMap {
+    let free = free_after(st);
+
+    st.board
+        .snakes
+        .iter()
+        .map(|(id, s)| (id.clone(), reach_with(s, st, turns, &free)))
+        .collect()
+}
+
+/// The head-on contest for `s` moving onto `p`, against every enemy
+/// whose head can reach it next turn
+pub fn head_on(p: Point, s: &Snake, st: &State, reach: &ReachMap) -> Contest {
+    Contest::against(
+        s,
+        st.board.snakes.values().filter(|e| {
+            e.id != s.id
+                && match reach.get(&e.id).and_then(|r| r.at(1)) {
+                    Some(layer) => layer.contains(p),
+                    None => false,
+                }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_reach() {
+        // The enemy's body walls off the column to our right until its
+        // tail end moves away
+        let (you, st) = build_state(
+            5,
+            5,
+            &[
+                ("you", &[(0, 2), (0, 3), (0, 4)]),
+                ("other", &[(1, 0), (1, 1), (1, 2), (1, 3), (1, 4)]),
+            ],
+            &[],
+        );
+        let reach = reach_all(&st, CONTEXT_REACH_TURNS);
+        let ours = &reach["you"];
+
+        assert_eq!(
+            ours.at(1).unwrap().iter().collect::<Vec<Point>>(),
+            vec![Point { x: 0, y: 1 }]
+        );
+        // We can only go back and forth in the corner until the enemy's
+        // body clears (1, 1) on the fourth turn
+        assert!(!ours.within(3).contains(Point { x: 1, y: 1 }));
+        assert!(ours.within(4).contains(Point { x: 1, y: 1 }));
+        assert!(!ours.within(4).contains(Point { x: 2, y: 2 }));
+        assert!(ours.at(5).is_none());
+
+        // Only the enemy can reach (2, 0) next turn, and it is longer
+        assert_eq!(
+            head_on(Point { x: 2, y: 0 }, &you, &st, &reach),
+            Contest::Losing
+        );
+        assert_eq!(
+            head_on(Point { x: 0, y: 1 }, &you, &st, &reach),
+            Contest::Uncontested
+        );
+    }
+}
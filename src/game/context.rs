@@ -0,0 +1,224 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+use std::fmt;
+
+use super::analysis::{self, ReachMap, CONTEXT_REACH_TURNS};
+use super::{Contest, Dir, SafetyIndex, Snake, State};
+
+const ALL_DIRS: [Dir; 4] = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+/// What happens right away if a snake makes a move. Cheap enough to
+/// work out for every direction on every turn.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirOutcome {
+    pub dir: Dir,
+    /// The move doesn't leave the board or run into a body
+    pub legal: bool,
+    /// The move turns back into our own neck
+    pub reverses_neck: bool,
+    /// The move leaves the board
+    pub hits_wall: bool,
+    /// Who wins if enemy heads also move onto the square
+    pub contest: Contest,
+    /// The free space reachable from the square, up to our length
+    pub area: usize,
+    /// The square leads into a pocket too small to hold us, either a
+    /// closed region or one side of the region it cuts in two
+    pub enclosed: bool,
+}
+
+impl DirOutcome {
+    pub fn new(dir: Dir, s: &Snake, st: &State) -> Self {
+        let p = dir.resulting_point(s.body[0], st);
+        let legal = p.safety_index(s, st) != SafetyIndex::Unsafe;
+
+        let (area, enclosed) = if legal {
+            let area = p.flood_fill(s, st, s.body.len() as u16).len();
+            let enclosed = p.pocket_size_exact(s, st) < s.body.len();
+            (area, enclosed)
+        } else {
+            (0, false)
+        };
+
+        Self {
+            dir,
+            legal,
+            reverses_neck: s.body.len() > 1 && s.body[1] == p,
+            hits_wall: !p.in_bounds(st),
+            contest: p.contest(s, st),
+            area,
+            enclosed,
+        }
+    }
+
+    /// Like `new`, but with the contest decided by every snake's
+    /// reachable set instead of the distance to its head
+    pub fn with_reach(
+        dir: Dir,
+        s: &Snake,
+        st: &State,
+        reach: &ReachMap,
+    ) -> Self {
+        let p = dir.resulting_point(s.body[0], st);
+
+        Self {
+            contest: analysis::head_on(p, s, st, reach),
+            ..Self::new(dir, s, st)
+        }
+    }
+
+    /// Fails the blunder tests: immediately fatal, or into a space
+    /// too small to survive in
+    pub fn is_blunder(&self) -> bool {
+        !self.legal || self.enclosed
+    }
+}
+
+impl fmt::Display for DirOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}:", self.dir)?;
+
+        if self.hits_wall {
+            write!(f, " wall")?;
+        } else if self.reverses_neck {
+            write!(f, " neck")?;
+        } else if !self.legal {
+            write!(f, " body")?;
+        } else {
+            write!(f, " {:?}, area {}", self.contest, self.area)?;
+
+            if self.enclosed {
+                write!(f, ", enclosed")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The immediate outcome of each of our moves for a turn, worked out
+/// once before any search so every stage of picking a move can share
+/// it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnContext {
+    pub outcomes: Vec<DirOutcome>,
+    /// Where every snake's head could be over the next few turns
+    pub reach: ReachMap,
+}
+
+impl TurnContext {
+    pub fn new(s: &Snake, st: &State) -> Self {
+        let reach = analysis::reach_all(st, CONTEXT_REACH_TURNS);
+
+        Self {
+            outcomes: ALL_DIRS
+                .iter()
+                .map(|d| DirOutcome::with_reach(*d, s, st, &reach))
+                .collect(),
+            reach,
+        }
+    }
+
+    pub fn get(&self, dir: Dir) -> &DirOutcome {
+        self.outcomes.iter().find(|o| o.dir == dir).unwrap()
+    }
+
+    /// The moves worth searching. Blunders are dropped, and so are
+    /// squares a longer head can take, unless that leaves nothing. If
+    /// every move is fatal, all of them are returned.
+    pub fn root_moves(&self) -> Vec<Dir> {
+        let pick = |keep: &dyn Fn(&DirOutcome) -> bool| {
+            self.outcomes
+                .iter()
+                .filter(|o| keep(o))
+                .map(|o| o.dir)
+                .collect::<Vec<Dir>>()
+        };
+
+        let candidates = [
+            pick(&|o| !o.is_blunder() && o.contest != Contest::Losing),
+            pick(&|o| !o.is_blunder()),
+            pick(&|o| o.legal),
+        ];
+
+        candidates
+            .iter()
+            .find(|c| !c.is_empty())
+            .cloned()
+            .unwrap_or_else(|| ALL_DIRS.to_vec())
+    }
+
+    /// Returns `dir` if it is one of the root moves, or else the root
+    /// move with the most room
+    pub fn prune(&self, dir: Dir) -> Dir {
+        let root_moves = self.root_moves();
+
+        if root_moves.contains(&dir) {
+            return dir;
+        }
+
+        root_moves
+            .iter()
+            .max_by_key(|d| self.get(**d).area)
+            .cloned()
+            .unwrap_or(dir)
+    }
+}
+
+impl fmt::Display for TurnContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outcomes = self
+            .outcomes
+            .iter()
+            .map(|o| o.to_string())
+            .collect::<Vec<String>>();
+
+        write!(f, "{}", outcomes.join(" | "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_turn_context() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(0, 5), (1, 5), (2, 5)]),
+                ("other", &[(1, 4), (2, 4), (3, 4), (4, 4)]),
+            ],
+            &[],
+        );
+        let ctx = TurnContext::new(&you, &st);
+
+        assert!(ctx.get(Dir::Left).hits_wall && !ctx.get(Dir::Left).legal);
+        assert!(ctx.get(Dir::Right).reverses_neck);
+        assert_eq!(ctx.get(Dir::Up).contest, Contest::Losing);
+        assert_eq!(ctx.get(Dir::Down).contest, Contest::Uncontested);
+        assert!(ctx.get(Dir::Down).area > you.body.len());
+
+        // Up is legal but a longer head can take it
+        assert_eq!(ctx.root_moves(), vec![Dir::Down]);
+        assert_eq!(ctx.prune(Dir::Left), Dir::Down);
+        assert_eq!(ctx.prune(Dir::Down), Dir::Down);
+    }
+}
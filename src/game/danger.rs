@@ -0,0 +1,159 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! A heat map of how dangerous each cell is for a snake, from 0 for a
+//! cell nobody can contest to 1 for one it can't move onto. It is
+//! built from the same safety index and reachable sets the move
+//! logic uses, so shading a board with it shows what the risk model
+//! thinks rather than a separate estimate.
+
+use serde_derive::Serialize;
+
+use super::analysis::ReachMap;
+use super::{Point, SafetyIndex, Snake, State};
+
+/// The danger of a cell an enemy at least our length can move onto
+/// next turn. Each turn further out halves it.
+const REACH_DANGER: f64 = 0.8;
+
+/// How much an enemy shorter than us counts for, since it can only
+/// get in our way, not win a head-on
+const SHORTER_WEIGHT: f64 = 0.25;
+
+/// The danger of a hazard square we can afford to enter
+const HAZARD_DANGER: f64 = 0.5;
+
+/// The danger of every cell for one snake, in rows from the top of the
+/// board
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DangerMap {
+    pub turn: u32,
+    pub width: i8,
+    pub height: i8,
+    pub cells: Vec<Vec<f64>>,
+}
+
+impl DangerMap {
+    /// Works out the danger map for `s`. `reach` needs every enemy.
+    pub fn new(s: &Snake, st: &State, reach: &ReachMap) -> Self {
+        let cells = (0..st.board.height)
+            .map(|y| {
+                (0..st.board.width)
+                    .map(|x| round(cell_danger(Point { x, y }, s, st, reach)))
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            turn: st.turn,
+            width: st.board.width,
+            height: st.board.height,
+            cells,
+        }
+    }
+
+    /// The danger of a cell, or None if it is off the board
+    pub fn at(&self, p: Point) -> Option<f64> {
+        if p.x < 0 || p.y < 0 {
+            return None;
+        }
+
+        self.cells
+            .get(p.y as usize)
+            .and_then(|row| row.get(p.x as usize))
+            .cloned()
+    }
+}
+
+fn cell_danger(p: Point, s: &Snake, st: &State, reach: &ReachMap) -> f64 {
+    match p.safety_index(s, st) {
+        SafetyIndex::Unsafe => return 1.0,
+        SafetyIndex::Risky | SafetyIndex::Safe => {}
+    }
+
+    let hazard = if st.board.hazards.contains(&p) {
+        HAZARD_DANGER
+    } else {
+        0.0
+    };
+
+    st.board
+        .snakes
+        .values()
+        .filter(|e| e.id != s.id)
+        .filter_map(|e| {
+            let layers = reach.get(&e.id)?;
+            let first = (1..)
+                .map_while(|t| layers.at(t))
+                .position(|l| l.contains(p))?;
+            let weight = if e.body.len() >= s.body.len() {
+                1.0
+            } else {
+                SHORTER_WEIGHT
+            };
+
+            Some(weight * REACH_DANGER * 0.5_f64.powi(first as i32))
+        })
+        .fold(hazard, f64::max)
+}
+
+/// Rounds to two decimals, which is all a heat map needs, to keep the
+/// exported JSON short
+fn round(danger: f64) -> f64 {
+    (danger * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::analysis::{reach_all, CONTEXT_REACH_TURNS};
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_danger_map() {
+        let (you, mut st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (1, 4), (1, 5)]),
+                ("other", &[(5, 3), (5, 4), (5, 5), (5, 6)]),
+            ],
+            &[],
+        );
+        st.board.hazards.insert(Point { x: 0, y: 0 });
+        let map = DangerMap::new(&you, &st, &reach_all(&st, 2));
+
+        assert_eq!((map.width, map.height, map.cells.len()), (7, 7, 7));
+        // Our own body and the enemy's are off limits
+        assert_eq!(map.at(Point { x: 1, y: 4 }), Some(1.0));
+        assert_eq!(map.at(Point { x: 5, y: 4 }), Some(1.0));
+        // Next to the longer enemy's head, then a turn further out
+        assert_eq!(map.at(Point { x: 4, y: 3 }), Some(REACH_DANGER));
+        assert_eq!(map.at(Point { x: 3, y: 3 }), Some(REACH_DANGER / 2.0));
+        // Out of its reach
+        assert_eq!(map.at(Point { x: 1, y: 2 }), Some(0.0));
+        assert_eq!(map.at(Point { x: 0, y: 0 }), Some(HAZARD_DANGER));
+        assert_eq!(map.at(Point { x: 7, y: 0 }), None);
+
+        // A shorter enemy is less of a threat
+        let reach = reach_all(&st, CONTEXT_REACH_TURNS);
+        let other = st.board.snakes["other"].clone();
+        let theirs = DangerMap::new(&other, &st, &reach);
+        let near_us = Point { x: 1, y: 2 };
+        assert_eq!(theirs.at(near_us), Some(SHORTER_WEIGHT * REACH_DANGER));
+    }
+}
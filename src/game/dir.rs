@@ -18,7 +18,7 @@
 use log::info;
 use std::collections::HashSet;
 
-use super::{Move, Point, SafetyIndex, Snake, State};
+use super::{Edges, Move, Point, SafetyIndex, Snake, State};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Dir {
@@ -40,13 +40,46 @@ impl Dir {
     }
 
     /// Resulting point returns the point that the direction
-    /// points to from point p
-    pub fn resulting_point(self, p: Point) -> Point {
+    /// points to from point p. It wraps around the edges of a wrapped
+    /// board, and may be off any other board.
+    pub fn resulting_point(self, p: Point, st: &State) -> Point {
+        self.step(p, st.board.edges())
+    }
+
+    /// `resulting_point` for when only the edges of the board are at
+    /// hand
+    pub fn step(self, p: Point, edges: Edges) -> Point {
+        let (dx, dy) = self.delta();
+        edges.wrap(p.offset(dx, dy))
+    }
+
+    /// The direction facing the other way
+    pub fn opposite(self) -> Self {
         match self {
-            Dir::Up => Point { x: p.x, y: p.y - 1 },
-            Dir::Down => Point { x: p.x, y: p.y + 1 },
-            Dir::Left => Point { x: p.x - 1, y: p.y },
-            Dir::Right => Point { x: p.x + 1, y: p.y },
+            Dir::Up => Dir::Down,
+            Dir::Down => Dir::Up,
+            Dir::Left => Dir::Right,
+            Dir::Right => Dir::Left,
+        }
+    }
+
+    /// The offset of one step in this direction
+    fn delta(self) -> (i8, i8) {
+        match self {
+            Dir::Up => (0, -1),
+            Dir::Down => (0, 1),
+            Dir::Left => (-1, 0),
+            Dir::Right => (1, 0),
+        }
+    }
+
+    /// Rotates an offset given for `Dir::Up` to face this direction
+    fn rotate(self, (dx, dy): (i8, i8)) -> (i8, i8) {
+        match self {
+            Dir::Up => (dx, dy),
+            Dir::Down => (-dx, -dy),
+            Dir::Left => (dy, -dx),
+            Dir::Right => (-dy, dx),
         }
     }
 
@@ -58,174 +91,41 @@ impl Dir {
         se: &SafetyIndex,
     ) -> bool {
         let head = s.body[0];
-        self.resulting_point(head).safety_index(s, st) == *se
+        self.resulting_point(head, st).safety_index(s, st) == *se
     }
 
     /// Whether this move will cause the snake to collect food
-    pub fn will_collect_food(self, s: &Snake, food: &HashSet<Point>) -> bool {
-        let head = s.body[0];
-        food.contains(&self.resulting_point(head))
+    pub fn will_collect_food(
+        self,
+        s: &Snake,
+        food: &HashSet<Point>,
+        edges: Edges,
+    ) -> bool {
+        food.contains(&self.step(s.body[0], edges))
     }
 
     /// This function tests to see if a move could result
     /// in the snake being corner-adjacent to another larger snake
     pub fn is_corner_risky(self, s: &Snake, st: &State) -> bool {
-        let mut diagonal_points = Vec::with_capacity(2);
-        let mut outer_points = Vec::with_capacity(4);
-        let mut blocker_points = Vec::with_capacity(2);
-
         let head = s.body[0];
-
-        match self {
-            Dir::Up => {
-                // verified
-                diagonal_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y - 2,
-                });
-                diagonal_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y - 2,
-                });
-
-                outer_points.push(Point {
-                    x: head.x - 2,
-                    y: head.y - 2,
-                });
-                outer_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y - 3,
-                });
-                outer_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y - 3,
-                });
-                outer_points.push(Point {
-                    x: head.x + 2,
-                    y: head.y - 2,
-                });
-
-                blocker_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y - 1,
-                });
-                blocker_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y - 1,
-                });
-            }
-            Dir::Down => {
-                // verified
-                diagonal_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y + 2,
-                });
-                diagonal_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y + 2,
-                });
-
-                outer_points.push(Point {
-                    x: head.x + 2,
-                    y: head.y + 2,
-                });
-                outer_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y + 3,
-                });
-                outer_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y + 3,
-                });
-                outer_points.push(Point {
-                    x: head.x - 2,
-                    y: head.y + 2,
-                });
-
-                blocker_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y + 1,
-                });
-                blocker_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y + 1,
-                });
-            }
-            Dir::Left => {
-                // verified
-                diagonal_points.push(Point {
-                    x: head.x - 2,
-                    y: head.y + 1,
-                });
-                diagonal_points.push(Point {
-                    x: head.x - 2,
-                    y: head.y - 1,
-                });
-
-                outer_points.push(Point {
-                    x: head.x - 2,
-                    y: head.y + 2,
-                });
-                outer_points.push(Point {
-                    x: head.x - 3,
-                    y: head.y + 1,
-                });
-                outer_points.push(Point {
-                    x: head.x - 3,
-                    y: head.y - 1,
-                });
-                outer_points.push(Point {
-                    x: head.x - 2,
-                    y: head.y - 2,
-                });
-
-                blocker_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y + 1,
-                });
-                blocker_points.push(Point {
-                    x: head.x - 1,
-                    y: head.y - 1,
-                });
-            }
-            Dir::Right => {
-                // verified
-                diagonal_points.push(Point {
-                    x: head.x + 2,
-                    y: head.y - 1,
-                });
-                diagonal_points.push(Point {
-                    x: head.x + 2,
-                    y: head.y + 1,
-                });
-
-                outer_points.push(Point {
-                    x: head.x + 2,
-                    y: head.y - 2,
-                });
-                outer_points.push(Point {
-                    x: head.x + 3,
-                    y: head.y - 1,
-                });
-                outer_points.push(Point {
-                    x: head.x + 3,
-                    y: head.y + 1,
-                });
-                outer_points.push(Point {
-                    x: head.x + 2,
-                    y: head.y + 2,
-                });
-
-                blocker_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y - 1,
-                });
-                blocker_points.push(Point {
-                    x: head.x + 1,
-                    y: head.y + 1,
-                });
-            }
-        }
+        let points = |offsets: &[(i8, i8)]| {
+            offsets
+                .iter()
+                .map(|o| {
+                    let (dx, dy) = self.rotate(*o);
+                    head.offset_checked(dx, dy, st)
+                })
+                .collect::<Vec<Option<Point>>>()
+        };
+
+        // Offsets for moving up, rotated to the direction of the move.
+        // The order within each list matters for the checks below.
+        let mut diagonal_points = points(&[(-1, -2), (1, -2)])
+            .into_iter()
+            .flatten()
+            .collect::<Vec<Point>>();
+        let outer_points = points(&[(-2, -2), (-1, -3), (1, -3), (2, -2)]);
+        let blocker_points = points(&[(-1, -1), (1, -1)]);
 
         for (_, snake) in &st.board.snakes {
             for point in &snake.body {
@@ -233,8 +133,6 @@ impl Dir {
             }
         }
 
-        diagonal_points.retain(|p| p.in_bounds(&st));
-
         if diagonal_points.len() == 0 {
             info!("All points are occupied, returning false");
             return false;
@@ -245,21 +143,22 @@ impl Dir {
                 continue;
             }
 
+            // Off the board counts as blocked
+            let blocked = |p: Option<Point>| match p {
+                Some(p) => p.safety_index(s, st) == SafetyIndex::Unsafe,
+                None => true,
+            };
+            let head = Some(snake.body[0]);
+
             if snake.body.len() >= s.body.len() {
-                if outer_points[0] == snake.body[0]
-                    || outer_points[1] == snake.body[0]
-                {
+                if outer_points[0] == head || outer_points[1] == head {
                     info!("returning safety_index from corner adj");
-                    return blocker_points[1].safety_index(s, st)
-                        == SafetyIndex::Unsafe;
+                    return blocked(blocker_points[1]);
                 }
 
-                if outer_points[2] == snake.body[0]
-                    || outer_points[3] == snake.body[0]
-                {
+                if outer_points[2] == head || outer_points[3] == head {
                     info!("returning safety_index from corner adj");
-                    return blocker_points[0].safety_index(s, st)
-                        == SafetyIndex::Unsafe;
+                    return blocked(blocker_points[0]);
                 }
             }
         }
@@ -271,7 +170,7 @@ impl Dir {
 
 #[cfg(test)]
 mod tests {
-    use super::super::load_sample_data;
+    use super::super::{build_state, load_sample_data, GameMode};
     use super::*;
 
     const SELF_ID: &str = "2d397b8c-8b3f-416d-bb16-6bc85ab3226e";
@@ -288,22 +187,20 @@ mod tests {
 
     #[test]
     fn test_resulting_point() {
-        assert_eq!(
-            Dir::Up.resulting_point(Point { x: 10, y: 10 }),
-            Point { x: 10, y: 9 }
-        );
-        assert_eq!(
-            Dir::Down.resulting_point(Point { x: 10, y: 10 }),
-            Point { x: 10, y: 11 }
-        );
-        assert_eq!(
-            Dir::Left.resulting_point(Point { x: 10, y: 10 }),
-            Point { x: 9, y: 10 }
-        );
-        assert_eq!(
-            Dir::Right.resulting_point(Point { x: 10, y: 10 }),
-            Point { x: 11, y: 10 }
-        );
+        let (_, mut st) = build_state(11, 11, &[("you", &[(0, 0)])], &[]);
+        let p = Point { x: 10, y: 10 };
+
+        assert_eq!(Dir::Up.resulting_point(p, &st), Point { x: 10, y: 9 });
+        assert_eq!(Dir::Down.resulting_point(p, &st), Point { x: 10, y: 11 });
+        assert_eq!(Dir::Left.resulting_point(p, &st), Point { x: 9, y: 10 });
+        assert_eq!(Dir::Right.resulting_point(p, &st), Point { x: 11, y: 10 });
+
+        // Wrapped boards have no walls
+        st.board.mode = GameMode::Wrapped;
+        assert_eq!(Dir::Down.resulting_point(p, &st), Point { x: 10, y: 0 });
+        assert_eq!(Dir::Right.resulting_point(p, &st), Point { x: 0, y: 10 });
+        let corner = Point { x: 0, y: 0 };
+        assert_eq!(Dir::Up.resulting_point(corner, &st), Point { x: 0, y: 10 });
     }
 
     #[test]
@@ -377,84 +274,96 @@ mod tests {
         assert_eq!(
             Dir::Up.will_collect_food(
                 &data.1.board.snakes[SELF_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             true
         );
         assert_eq!(
             Dir::Down.will_collect_food(
                 &data.1.board.snakes[SELF_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Left.will_collect_food(
                 &data.1.board.snakes[SELF_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Right.will_collect_food(
                 &data.1.board.snakes[SELF_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Up.will_collect_food(
                 &data.1.board.snakes[ALEX_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Down.will_collect_food(
                 &data.1.board.snakes[ALEX_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Left.will_collect_food(
                 &data.1.board.snakes[ALEX_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             true
         );
         assert_eq!(
             Dir::Right.will_collect_food(
                 &data.1.board.snakes[ALEX_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Up.will_collect_food(
                 &data.1.board.snakes[SBOT_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             true
         );
         assert_eq!(
             Dir::Down.will_collect_food(
                 &data.1.board.snakes[SBOT_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Left.will_collect_food(
                 &data.1.board.snakes[SBOT_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
         assert_eq!(
             Dir::Right.will_collect_food(
                 &data.1.board.snakes[SBOT_ID],
-                &data.1.board.food
+                &data.1.board.food,
+                data.1.board.edges()
             ),
             false
         );
@@ -0,0 +1,228 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Distance fields: the length of the shortest path from a snake's
+//! head to every square, over the same moves `find_path` takes. One
+//! breadth first search answers every path query for the snake in a
+//! state, so the cheap profiles look their paths up in a field
+//! instead of each running their own A* search.
+//!
+//! Fields are cached on the state they were built from, per snake.
+//! `process_step` drops the cache, and each field also remembers the
+//! position it was built for, so a state edited some other way gets a
+//! new field rather than a stale one.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::hash::{mix, zobrist_hash};
+use super::{Point, Snake, State};
+
+/// Shortest path lengths from a snake's head
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceField {
+    width: usize,
+    /// Moves to each square, by index, or `u16::MAX` if it can't be
+    /// reached
+    dist: Vec<u16>,
+    /// What the field was built from, see `fingerprint`
+    fingerprint: u64,
+}
+
+/// Identifies everything a field depends on: the position, the
+/// hazards, and the snake's own head, length and health, which decide
+/// what it can move onto
+fn fingerprint(s: &Snake, st: &State) -> u64 {
+    let cell = |p: Point| u64::from(p.x as u8) | u64::from(p.y as u8) << 8;
+    let hazards = st
+        .board
+        .hazards
+        .iter()
+        .fold(0, |h, p| h ^ mix(cell(*p) | 1 << 48));
+    let snake = cell(s.body[0])
+        | u64::from(s.health) << 16
+        | (s.body.len() as u64) << 24;
+
+    zobrist_hash(st) ^ hazards ^ mix(snake)
+}
+
+impl DistanceField {
+    pub fn new(s: &Snake, st: &State) -> Self {
+        let width = st.board.width as usize;
+        let index = |p: Point| p.y as usize * width + p.x as usize;
+        let grid = st.occupancy();
+
+        let mut dist = vec![u16::MAX; st.board.cells()];
+        let mut queue = VecDeque::new();
+        if s.body[0].in_bounds(st) {
+            dist[index(s.body[0])] = 0;
+            queue.push_back(s.body[0]);
+        }
+
+        while let Some(p) = queue.pop_front() {
+            let d = dist[index(p)];
+            for (n, _) in grid.successors(p, s, st) {
+                if dist[index(n)] == u16::MAX {
+                    dist[index(n)] = d + 1;
+                    queue.push_back(n);
+                }
+            }
+        }
+
+        Self {
+            width,
+            dist,
+            fingerprint: fingerprint(s, st),
+        }
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        if p.x < 0 || p.y < 0 || p.x as usize >= self.width {
+            return None;
+        }
+
+        let i = p.y as usize * self.width + p.x as usize;
+        Some(i).filter(|i| *i < self.dist.len())
+    }
+
+    /// The moves a shortest path from the head to `p` takes, if there
+    /// is one
+    pub fn distance(&self, p: Point) -> Option<u16> {
+        self.index(p)
+            .map(|i| self.dist[i])
+            .filter(|d| *d != u16::MAX)
+    }
+
+    /// A shortest path to `goal`, including the head, if the goal can
+    /// be reached
+    pub fn path(&self, goal: Point, st: &State) -> Option<Vec<Point>> {
+        let mut d = self.distance(goal)?;
+        let mut path = vec![goal];
+
+        // Walk back down the distances to the head
+        while d > 0 {
+            let prev = path[path.len() - 1]
+                .neighbors(st)
+                .into_iter()
+                .find(|n| self.distance(*n) == Some(d - 1))?;
+            path.push(prev);
+            d -= 1;
+        }
+
+        path.reverse();
+        Some(path)
+    }
+
+    /// The square a shortest path to `goal` moves onto first, if the
+    /// goal can be reached and isn't the head
+    pub fn first_step(&self, goal: Point, st: &State) -> Option<Point> {
+        self.path(goal, st)?.get(1).cloned()
+    }
+
+    /// The closest of `goals` by path length
+    pub fn nearest<'a>(
+        &self,
+        goals: impl IntoIterator<Item = &'a Point>,
+    ) -> Option<Point> {
+        goals
+            .into_iter()
+            .filter_map(|g| self.distance(*g).map(|d| (d, *g)))
+            .min_by_key(|(d, g)| (*d, g.x, g.y))
+            .map(|(_, g)| g)
+    }
+}
+
+/// The distance fields built for a state, by snake id
+#[derive(Default)]
+pub struct DistanceCache(Mutex<HashMap<String, Arc<DistanceField>>>);
+
+/// A clone starts with the fields built so far but caches its own, so
+/// searches that clone a state and step it don't fight over one cache
+impl Clone for DistanceCache {
+    fn clone(&self) -> Self {
+        match self.0.lock() {
+            Ok(fields) => Self(Mutex::new(fields.clone())),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+impl fmt::Debug for DistanceCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DistanceCache")
+    }
+}
+
+impl DistanceCache {
+    /// The field for `s` in `st`, built if it isn't cached yet
+    pub fn get(&self, s: &Snake, st: &State) -> Arc<DistanceField> {
+        let print = fingerprint(s, st);
+        let cached = match self.0.lock() {
+            Ok(fields) => fields.get(&s.id).cloned(),
+            Err(_) => None,
+        };
+
+        if let Some(field) = cached.filter(|f| f.fingerprint == print) {
+            return field;
+        }
+
+        let field = Arc::new(DistanceField::new(s, st));
+        if let Ok(mut fields) = self.0.lock() {
+            fields.insert(s.id.clone(), field.clone());
+        }
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{build_state, find_path, PathSearch};
+    use super::*;
+
+    #[test]
+    fn test_distance_field() {
+        // A wall with a single gap forces a detour to the right side
+        let wall: Vec<(i8, i8)> = (0..10).rev().map(|y| (5, y)).collect();
+        let (you, mut st) = build_state(
+            11,
+            11,
+            &[("you", &[(1, 5), (1, 6), (1, 7)]), ("wall", &wall)],
+            &[(9, 5), (0, 5)],
+        );
+
+        let field = st.distances(&you);
+        for goal in &[Point { x: 9, y: 5 }, Point { x: 1, y: 0 }] {
+            let (path, len) =
+                find_path(&you, &st, *goal, PathSearch::Exact).unwrap();
+            assert_eq!(field.distance(*goal), Some(len as u16));
+            let ours = field.path(*goal, &st).unwrap();
+            assert_eq!((ours[0], ours.len()), (path[0], path.len()));
+            let step = field.first_step(*goal, &st).unwrap();
+            assert_eq!(step, ours[1]);
+        }
+        assert_eq!(field.distance(Point { x: 5, y: 3 }), None);
+        assert_eq!(field.distance(Point { x: 11, y: 0 }), None);
+        assert_eq!(field.nearest(&st.board.food), Some(Point { x: 0, y: 5 }));
+
+        // The field is reused until the state changes
+        assert!(Arc::ptr_eq(&field, &st.distances(&you)));
+        st.board.food.clear();
+        assert!(!Arc::ptr_eq(&field, &st.distances(&you)));
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Who wins the race to each food. A food belongs to the snake with
+//! the shortest path to it, by the state's distance fields. Two snakes
+//! that get there on the same turn collide on it, so it goes to the
+//! longer one, or to nobody if they are the same length.
+//!
+//! Racing for a food someone else owns at best wastes the moves, and
+//! at worst ends in a head-on collision we lose.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use super::{Point, State};
+
+/// Whether a snake `d` moves from a food and `len` long gets to it
+/// before a rival `r` moves away and `rival_len` long
+fn arrives_first(d: u16, len: usize, r: u16, rival_len: usize) -> bool {
+    d < r || d == r && len > rival_len
+}
+
+/// The owner of every food on a board
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoodOwnership {
+    owners: HashMap<Point, Option<String>>,
+}
+
+impl FoodOwnership {
+    pub fn new(st: &State) -> Self {
+        let fields = st
+            .board
+            .snakes
+            .values()
+            .map(|s| (s, st.distances(s)))
+            .collect::<Vec<_>>();
+
+        let owners = st
+            .board
+            .food
+            .iter()
+            .map(|food| {
+                let mut racers = fields
+                    .iter()
+                    .filter_map(|(s, field)| Some((field.distance(*food)?, *s)))
+                    .collect::<Vec<_>>();
+                racers.sort_by_key(|(d, s)| (*d, Reverse(s.body.len()), &s.id));
+
+                // Squadmates don't collide, so only the closest enemy
+                // of the closest snake can tie with it
+                let owner = racers.first().filter(|(d, s)| {
+                    racers
+                        .iter()
+                        .find(|(_, e)| e.id != s.id && !s.is_squadmate(e))
+                        .is_none_or(|(r, e)| {
+                            arrives_first(*d, s.body.len(), *r, e.body.len())
+                        })
+                });
+
+                (*food, owner.map(|(_, s)| s.id.clone()))
+            })
+            .collect();
+
+        Self { owners }
+    }
+
+    /// The id of the snake that gets to `food` first, if anyone does
+    pub fn owner(&self, food: Point) -> Option<&str> {
+        self.owners.get(&food)?.as_deref()
+    }
+
+    /// The foods the snake with the given id gets to first
+    pub fn owned_by<'a>(
+        &'a self,
+        id: &'a str,
+    ) -> impl Iterator<Item = Point> + 'a {
+        self.owners
+            .iter()
+            .filter(move |(_, o)| o.as_deref() == Some(id))
+            .map(|(f, _)| *f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_food_ownership() {
+        let (you, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(2, 5), (1, 5), (0, 5)]),
+                ("enemy", &[(8, 5), (9, 5), (10, 5)]),
+            ],
+            &[(3, 5), (7, 6), (5, 5), (5, 0)],
+        );
+
+        // The middle food is as far from both, who are the same length
+        let even = FoodOwnership::new(&st);
+        assert_eq!(even.owner(Point { x: 3, y: 5 }), Some("you"));
+        assert_eq!(even.owner(Point { x: 7, y: 6 }), Some("enemy"));
+        assert_eq!(even.owner(Point { x: 5, y: 5 }), None);
+        assert_eq!(even.owner(Point { x: 5, y: 0 }), None);
+        assert_eq!(even.owner(Point { x: 0, y: 0 }), None);
+        assert!(you.owns_food(Point { x: 3, y: 5 }, &st));
+        assert!(!you.owns_food(Point { x: 5, y: 5 }, &st));
+
+        // Until one of them is longer
+        let enemy = st.board.snakes.get_mut("enemy").unwrap();
+        enemy.body.push(Point { x: 10, y: 6 });
+        let longer = FoodOwnership::new(&st);
+        assert_eq!(longer.owner(Point { x: 5, y: 5 }), Some("enemy"));
+        let mut owned = longer.owned_by("enemy").collect::<Vec<_>>();
+        owned.sort_by_key(|p| (p.x, p.y));
+        assert_eq!(
+            owned,
+            [
+                Point { x: 5, y: 0 },
+                Point { x: 5, y: 5 },
+                Point { x: 7, y: 6 }
+            ]
+        );
+        assert!(!you.owns_food(Point { x: 5, y: 0 }, &st));
+    }
+}
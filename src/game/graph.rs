@@ -0,0 +1,184 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! The board as a graph of the squares a snake can move onto, for
+//! finding dead ends. A flood fill from a square counts the room
+//! around it, but a square that is a cut vertex of the graph splits
+//! that room in two once our body is on it, and the snake only gets
+//! to use one side. The pocket a move enters is the largest of those
+//! sides, found exactly rather than up to a size limit.
+//!
+//! A pocket that holds the snake's own tail counts the rest of its
+//! body as room too, since following the tail frees the body one
+//! square at a time.
+
+use std::collections::VecDeque;
+
+use super::{Point, SafetyIndex, Snake, State};
+
+pub struct BoardGraph<'a> {
+    st: &'a State,
+    width: usize,
+    /// The squares the snake can move onto, by index
+    open: Vec<bool>,
+    tail: Option<Point>,
+    len: usize,
+}
+
+impl<'a> BoardGraph<'a> {
+    pub fn new(s: &Snake, st: &'a State) -> Self {
+        let grid = st.occupancy();
+        let width = st.board.width as usize;
+        let open = (0..st.board.height)
+            .flat_map(|y| (0..st.board.width).map(move |x| Point { x, y }))
+            .map(|p| grid.safety_index(p, s, st) != SafetyIndex::Unsafe)
+            .collect();
+
+        Self {
+            st,
+            width,
+            open,
+            tail: s.body.last().cloned().filter(|_| s.body.len() > 1),
+            len: s.body.len(),
+        }
+    }
+
+    fn index(&self, p: Point) -> usize {
+        p.y as usize * self.width + p.x as usize
+    }
+
+    fn is_open(&self, p: Point) -> bool {
+        p.in_bounds(self.st) && self.open[self.index(p)]
+    }
+
+    /// The squares connected to `from` without going through `cut`,
+    /// and whether our tail is one of them
+    fn region(&self, from: Point, cut: Point) -> (Vec<bool>, usize, bool) {
+        let mut seen = vec![false; self.open.len()];
+        seen[self.index(cut)] = true;
+        seen[self.index(from)] = true;
+
+        let mut size = 0;
+        let mut queue = VecDeque::from(vec![from]);
+        while let Some(p) = queue.pop_front() {
+            size += 1;
+            for n in p.neighbors(self.st) {
+                let i = self.index(n);
+                if self.open[i] && !seen[i] {
+                    seen[i] = true;
+                    queue.push_back(n);
+                }
+            }
+        }
+
+        let tail = self.tail.is_some_and(|t| t != cut && seen[self.index(t)]);
+        (seen, size, tail)
+    }
+
+    /// The room a snake whose head is on `p` has left: the largest
+    /// pocket it can move into without crossing `p` again
+    pub fn room_after(&self, p: Point) -> usize {
+        let exits = p
+            .neighbors(self.st)
+            .into_iter()
+            .filter(|n| self.is_open(*n))
+            .collect::<Vec<Point>>();
+
+        let mut room = 0;
+        let mut searched = vec![false; self.open.len()];
+        for n in exits {
+            // Exits the last region reached are on the same side, and
+            // if the first reaches them all `p` isn't a cut vertex
+            if searched[self.index(n)] {
+                continue;
+            }
+
+            let (seen, size, tail) = self.region(n, p);
+            let size = if tail { size + self.len - 1 } else { size };
+            room = room.max(size);
+
+            for (i, s) in seen.iter().enumerate() {
+                searched[i] |= *s && self.open[i];
+            }
+        }
+
+        room
+    }
+
+    /// The pocket moving onto `p` enters, including `p`, or 0 if the
+    /// snake can't move there
+    pub fn pocket_size(&self, p: Point) -> usize {
+        if !self.is_open(p) {
+            return 0;
+        }
+
+        let room = 1 + self.room_after(p);
+        if Some(p) == self.tail {
+            room.max(self.len)
+        } else {
+            room
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_pockets() {
+        // A wall across the board with a single gap at (3, 2), and our
+        // body across the left room, splitting it in two
+        let wall: (&str, &[(i8, i8)]) =
+            ("wall", &[(3, 0), (3, 1), (3, 3), (3, 4), (3, 4)]);
+        let (you, st) = build_state(
+            7,
+            5,
+            &[
+                (
+                    "you",
+                    &[(2, 2), (1, 2), (0, 2), (0, 3), (1, 3), (1, 4), (0, 4)],
+                ),
+                wall,
+            ],
+            &[],
+        );
+        let graph = BoardGraph::new(&you, &st);
+
+        // The gap is a cut vertex, and past it is the whole right room
+        let gap = Point { x: 3, y: 2 };
+        assert_eq!(gap.pocket_size_exact(&you, &st), 16);
+        assert_eq!(graph.pocket_size(Point { x: 3, y: 1 }), 0);
+        assert_eq!(graph.room_after(you.body[0]), 16);
+
+        // Both sides of the left room are too small for us
+        assert_eq!(graph.pocket_size(Point { x: 2, y: 1 }), 6);
+        assert_eq!(graph.pocket_size(Point { x: 2, y: 3 }), 2);
+
+        // unless our tail is in one, when our body is room too
+        let (you, st) = build_state(
+            7,
+            5,
+            &[("you", &[(2, 2), (1, 2), (0, 2), (0, 3), (0, 4)]), wall],
+            &[],
+        );
+        let lower = Point { x: 2, y: 3 };
+        assert_eq!(lower.pocket_size_exact(&you, &st), 1 + 4 + 4);
+    }
+}
@@ -0,0 +1,184 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Position hashing shared by everything that caches or compares
+//! states. A position is every snake's body and the food; health and
+//! the turn are left out so a game going in circles hashes the same
+//! each time around.
+//!
+//! The hash is a Zobrist hash: the XOR of a pseudo-random key for each
+//! feature of the position. Each body segment's key covers its cell
+//! and the direction to the next segment, so the body's exact shape
+//! is hashed, and each snake also has a key for its length. Keys are
+//! derived from the snake's id rather than its index, so a snake dying
+//! doesn't change anyone else's keys.
+//!
+//! Collisions: distinct positions collide with probability around
+//! 2^-64 per pair, so they can be treated as unique for caching but
+//! not for anything that must be exact. A position with the same
+//! snake cells under a different id assignment hashes differently.
+
+use super::{Point, Snake, State};
+
+/// Link values for a segment that isn't followed by an adjacent one
+const LINK_STACKED: u64 = 4;
+const LINK_END: u64 = 5;
+const LINK_INVALID: u64 = 6;
+
+const KIND_SEGMENT: u64 = 1;
+const KIND_FOOD: u64 = 2;
+const KIND_LENGTH: u64 = 3;
+
+/// The splitmix64 finalizer, spreads the bits of a feature's encoding
+/// over the whole key
+pub fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// FNV-1a over the snake's id
+fn id_key(id: &str) -> u64 {
+    id.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn feature_key(owner: u64, kind: u64, p: Point, extra: u64) -> u64 {
+    let cell = u64::from(p.x as u8) | u64::from(p.y as u8) << 8;
+    mix(owner ^ mix(cell | extra << 16 | kind << 32))
+}
+
+fn link(body: &[Point], i: usize) -> u64 {
+    match body.get(i + 1) {
+        None => LINK_END,
+        Some(next) if *next == body[i] => LINK_STACKED,
+        Some(next) => match body[i].step_dir(*next) {
+            Some(d) => d as u64,
+            None => LINK_INVALID,
+        },
+    }
+}
+
+fn segment_key(owner: u64, body: &[Point], i: usize) -> u64 {
+    feature_key(owner, KIND_SEGMENT, body[i], link(body, i))
+}
+
+fn length_key(owner: u64, len: usize) -> u64 {
+    feature_key(owner, KIND_LENGTH, Point { x: 0, y: 0 }, len as u64)
+}
+
+/// The key for a piece of food
+pub fn food_key(p: Point) -> u64 {
+    feature_key(0, KIND_FOOD, p, 0)
+}
+
+/// The part of the hash contributed by one snake
+pub fn snake_hash(id: &str, body: &[Point]) -> u64 {
+    let owner = id_key(id);
+
+    (0..body.len())
+        .map(|i| segment_key(owner, body, i))
+        .fold(length_key(owner, body.len()), |h, k| h ^ k)
+}
+
+/// Updates `hash` for a snake that moved one square from `old` to
+/// `new`, eating or not. Only the head and the last couple of
+/// segments change, so this is much cheaper than `snake_hash`. `new`
+/// must be `old` after a single move.
+pub fn update_snake(hash: u64, id: &str, old: &[Point], new: &[Point]) -> u64 {
+    hash ^ move_removed(id, old) ^ move_added(id, old.len(), new)
+}
+
+/// Old segments before this index keep their key after a move, one
+/// index further along. Only the old tail and the segment before it
+/// can change, since eating stacks a copy of the new tail.
+fn shared_segments(old_len: usize) -> usize {
+    old_len.saturating_sub(2)
+}
+
+/// The keys a move takes out of a snake's part of the hash. They only
+/// depend on the body before the move, so a body can be moved in place
+/// with this XORed in before and `move_added` after.
+pub fn move_removed(id: &str, old: &[Point]) -> u64 {
+    let owner = id_key(id);
+
+    (shared_segments(old.len())..old.len())
+        .map(|i| segment_key(owner, old, i))
+        .fold(length_key(owner, old.len()), |h, k| h ^ k)
+}
+
+/// The keys a move puts into the hash of a snake that was `old_len`
+/// long before it
+pub fn move_added(id: &str, old_len: usize, new: &[Point]) -> u64 {
+    let owner = id_key(id);
+
+    (shared_segments(old_len) + 1..new.len())
+        .chain(Some(0))
+        .map(|i| segment_key(owner, new, i))
+        .fold(length_key(owner, new.len()), |h, k| h ^ k)
+}
+
+/// The Zobrist hash of the position. Use `simulator::apply_moves` to
+/// keep it up to date while stepping a state instead of recomputing
+/// it every turn.
+pub fn zobrist_hash(st: &State) -> u64 {
+    let snakes = st
+        .board
+        .snakes
+        .values()
+        .map(|s: &Snake| snake_hash(&s.id, &s.body));
+
+    st.board
+        .food
+        .iter()
+        .map(|p| food_key(*p))
+        .chain(snakes)
+        .fold(0, |h, k| h ^ k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{build_state, Dir};
+    use super::*;
+
+    #[test]
+    fn test_update_snake() {
+        let (mut you, st) = build_state(
+            11,
+            11,
+            &[("you", &[(5, 5), (5, 6), (5, 7), (5, 7)])],
+            &[],
+        );
+        let food = [Point { x: 4, y: 4 }].iter().cloned().collect();
+
+        // Moving off a stacked tail, then eating
+        let mut hash = snake_hash(&you.id, &you.body);
+        for dir in &[Dir::Up, Dir::Left, Dir::Up] {
+            let old = you.body.clone();
+            you.update_from_move(*dir, &food, st.board.edges());
+            hash = update_snake(hash, &you.id, &old, &you.body);
+            assert_eq!(hash, snake_hash(&you.id, &you.body));
+        }
+
+        // Same cells, different length
+        let body = &you.body;
+        assert_ne!(snake_hash("you", body), snake_hash("you", &body[..3]));
+    }
+}
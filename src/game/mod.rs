@@ -15,31 +15,153 @@
  * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
  *
  */
+pub mod analysis;
+mod context;
+#[cfg(feature = "debug-endpoints")]
+pub mod danger;
 mod dir;
+mod distance;
+pub mod food;
+mod graph;
+pub mod hash;
+mod pathfind;
+mod occupancy;
 mod point;
 mod snake;
+pub mod territory;
+mod undo;
 
+pub use context::{DirOutcome, TurnContext};
 pub use dir::Dir;
+pub use distance::{DistanceCache, DistanceField};
+pub use graph::BoardGraph;
+pub use occupancy::{OccupancyGrid, MAX_SNAKES};
+pub use pathfind::{find_path, PathSearch};
 pub use point::Point;
-pub use snake::Snake;
+pub use snake::{deserialize_health, Snake};
+pub use undo::UndoToken;
 
 use serde_derive::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
+use std::sync::Arc;
 
 use super::routes::MoveRequest;
 
+/// How long the engine waits for a move, unless the request says
+pub const DEFAULT_TIMEOUT_MILLIS: u64 = 500;
+
+/// The health of a snake that has just eaten or spawned
+pub const MAX_HEALTH: u8 = 100;
+
+/// The health a snake loses, on top of the usual one, for each turn
+/// its head ends on a hazard without eating
+pub const HAZARD_DAMAGE: u8 = 14;
+
 #[derive(Serialize, Debug, Clone)]
 pub struct State {
     pub game: Game,
     pub turn: u32,
     pub board: Board,
+    /// Request fields for each snake that the game logic doesn't use,
+    /// shared between clones since searches never change them
+    #[serde(skip)]
+    pub meta: Arc<HashMap<String, SnakeMeta>>,
+    /// The distance fields built for this state, see `State::distances`
+    #[serde(skip)]
+    pub distances: DistanceCache,
+}
+
+impl State {
+    /// How long the engine waits for each move in this game
+    pub fn timeout_millis(&self) -> u64 {
+        self.game.timeout.map_or(DEFAULT_TIMEOUT_MILLIS, u64::from)
+    }
+
+    /// What is on each square, for looking up many squares of the
+    /// state as it is now
+    pub fn occupancy(&self) -> OccupancyGrid<'_> {
+        OccupancyGrid::new(self)
+    }
+
+    /// The shortest path lengths from `s`'s head, built once per state
+    /// and snake
+    pub fn distances(&self, s: &Snake) -> Arc<DistanceField> {
+        self.distances.get(s, self)
+    }
+}
+
+/// Fields the 2020 API sends for each snake
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct SnakeMeta {
+    /// The snake's response time for the last move, in milliseconds
+    pub latency: Option<u32>,
+    pub shout: Option<String>,
+    pub squad: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Game {
     pub id: String,
+    /// Only sent by newer engines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ruleset: Option<Ruleset>,
+    /// How long the engine waits for each move, in milliseconds. Only
+    /// sent by newer engines, see `State::timeout_millis`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
+}
+
+/// The rules the engine runs a game with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ruleset {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// How the edges of the board behave
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum GameMode {
+    /// The edges are walls
+    Standard,
+    /// Moving off an edge comes back on at the opposite one
+    Wrapped,
+}
+
+impl GameMode {
+    /// The mode of the game a request is for
+    pub fn of(game: &Game) -> Self {
+        match &game.ruleset {
+            Some(r) if r.name == "wrapped" => GameMode::Wrapped,
+            _ => GameMode::Standard,
+        }
+    }
+}
+
+/// The size of the board and how its edges behave. It is copied out
+/// of the board, so moves can be made while the snakes are borrowed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edges {
+    pub width: i8,
+    pub height: i8,
+    pub mode: GameMode,
+}
+
+impl Edges {
+    /// Where a point ends up on the board. On a wrapped board a point
+    /// off one edge comes back on at the other, otherwise the point
+    /// stays where it is, on the board or not.
+    pub fn wrap(self, p: Point) -> Point {
+        match self.mode {
+            GameMode::Standard => p,
+            GameMode::Wrapped => Point {
+                x: p.x.rem_euclid(self.width),
+                y: p.y.rem_euclid(self.height),
+            },
+        }
+    }
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -48,6 +170,33 @@ pub struct Board {
     pub width: i8,
     pub food: HashSet<Point>,
     pub snakes: HashMap<String, Snake>,
+    /// The squares inside the royale shrink zone, empty outside of
+    /// royale games
+    pub hazards: HashSet<Point>,
+    pub mode: GameMode,
+}
+
+impl Board {
+    pub fn edges(&self) -> Edges {
+        Edges {
+            width: self.width,
+            height: self.height,
+            mode: self.mode,
+        }
+    }
+
+    /// The number of cells on the board
+    pub fn cells(&self) -> usize {
+        self.width.max(0) as usize * self.height.max(0) as usize
+    }
+
+    /// The longest body that fits on the board. Segments only overlap
+    /// on a stacked tail, which is at most three deep, right after
+    /// spawning. After eating it is two deep, so a snake that has
+    /// filled the board has one segment more than there are cells.
+    pub fn max_body_len(&self) -> usize {
+        self.cells() + 2
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -63,6 +212,95 @@ pub enum SafetyIndex {
     Unsafe,
 }
 
+/// Who wins a head-on collision on a square that enemy heads can
+/// also reach this turn
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Contest {
+    Uncontested,
+    /// Only shorter enemy heads can reach the square
+    Winning,
+    /// An enemy head of equal length can reach the square
+    Equal,
+    /// A longer enemy head can reach the square
+    Losing,
+}
+
+impl Contest {
+    /// The contest for `s` against every enemy that can reach the
+    /// square, decided by the longest of them
+    pub fn against<'a>(
+        s: &Snake,
+        enemies: impl Iterator<Item = &'a Snake>,
+    ) -> Self {
+        enemies
+            .map(|e| {
+                if e.body.len() > s.body.len() {
+                    Contest::Losing
+                } else if e.body.len() == s.body.len() {
+                    Contest::Equal
+                } else {
+                    Contest::Winning
+                }
+            })
+            .fold(Contest::Uncontested, |curr, contest| {
+                match (curr, contest) {
+                    (Contest::Losing, _)
+                    | (_, Contest::Losing) => Contest::Losing,
+                    (Contest::Equal, _)
+                    | (_, Contest::Equal) => Contest::Equal,
+                    _ => Contest::Winning,
+                }
+            })
+    }
+}
+
+// Util function for the unit tests. Builds a state from a list of
+// (id, body) pairs, with the first snake being "you".
+#[allow(dead_code)]
+pub fn build_state(
+    width: i8,
+    height: i8,
+    snakes: &[(&str, &[(i8, i8)])],
+    food: &[(i8, i8)],
+) -> (Snake, State) {
+    let you_id = snakes[0].0.to_string();
+    let snakes = snakes
+        .iter()
+        .map(|(id, body)| {
+            let snake = Snake {
+                id: id.to_string(),
+                health: 100,
+                body: body.iter().map(|(x, y)| Point { x: *x, y: *y }).collect(),
+                squad: None,
+            };
+            (id.to_string(), snake)
+        })
+        .collect::<HashMap<String, Snake>>();
+
+    let you = snakes[&you_id].clone();
+
+    let state = State {
+        game: Game {
+            id: String::from("test"),
+            ruleset: None,
+            timeout: None,
+        },
+        turn: 0,
+        board: Board {
+            height,
+            width,
+            food: food.iter().map(|(x, y)| Point { x: *x, y: *y }).collect(),
+            snakes,
+            hazards: HashSet::new(),
+            mode: GameMode::Standard,
+        },
+        meta: Arc::default(),
+        distances: DistanceCache::default(),
+    };
+
+    (you, state)
+}
+
 // Util function for the unit tests
 #[allow(dead_code)]
 pub fn load_sample_data() -> Vec<(Snake, State)> {
@@ -95,6 +333,7 @@ pub fn load_sample_data() -> Vec<(Snake, State)> {
                 id: snake_json.id.clone(),
                 health: snake_json.health,
                 body: snake_json.body,
+                squad: snake_json.squad,
             };
 
             snakes.insert(snake_json.id, snake);
@@ -105,12 +344,16 @@ pub fn load_sample_data() -> Vec<(Snake, State)> {
             width: json.board.width,
             food: foods,
             snakes,
+            hazards: json.board.hazards.iter().cloned().collect(),
+            mode: GameMode::of(&json.game),
         };
 
         let state = State {
             game: json.game,
             turn: json.turn,
             board,
+            meta: Arc::default(),
+            distances: DistanceCache::default(),
         };
 
         requests.push((json.you, state));
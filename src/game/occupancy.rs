@@ -0,0 +1,245 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! An occupancy map of the board, so what is on a square can be looked
+//! up instead of found by walking every snake's body. A grid is a
+//! snapshot of the state it was built from and has to be rebuilt once
+//! the snakes move, so work that makes many queries of one state
+//! builds it once with `State::occupancy` and queries that.
+
+use super::{Edges, Point, SafetyIndex, Snake, State, HAZARD_DAMAGE};
+
+/// The most snakes a grid can tell apart, one bit each
+pub const MAX_SNAKES: usize = 64;
+
+/// The snakes on a square, as bits indexed like `OccupancyGrid::snakes`
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct Cell {
+    heads: u64,
+    /// Segments other than the head
+    bodies: u64,
+    /// Segments still there next turn, which is all of them but a tail
+    /// that isn't stacked
+    blocking: u64,
+}
+
+pub struct OccupancyGrid<'a> {
+    edges: Edges,
+    cells: Vec<Cell>,
+    snakes: Vec<&'a Snake>,
+}
+
+impl<'a> OccupancyGrid<'a> {
+    pub fn new(st: &'a State) -> Self {
+        let snakes = st.board.snakes.values().collect::<Vec<_>>();
+        assert!(snakes.len() <= MAX_SNAKES, "Too many snakes to track");
+
+        let mut grid = Self {
+            edges: st.board.edges(),
+            cells: vec![Cell::default(); st.board.cells()],
+            snakes: Vec::new(),
+        };
+
+        for (i, s) in snakes.iter().enumerate() {
+            let bit = 1 << i;
+            for (j, p) in s.body.iter().enumerate() {
+                if let Some(cell) = grid.cell_mut(*p) {
+                    if j == 0 {
+                        cell.heads |= bit;
+                    } else {
+                        cell.bodies |= bit;
+                    }
+                    cell.blocking |= bit;
+                }
+            }
+
+            // The tail moves out of the way unless it is stacked, as it
+            // is right after spawning or eating
+            if s.stacked_tail() <= 1 {
+                if let Some(cell) =
+                    s.body.last().and_then(|p| grid.cell_mut(*p))
+                {
+                    cell.blocking &= !bit;
+                }
+            }
+        }
+
+        grid.snakes = snakes;
+        grid
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        let Edges { width, height, .. } = self.edges;
+        if p.x < 0 || p.y < 0 || p.x >= width || p.y >= height {
+            return None;
+        }
+
+        Some(p.y as usize * width as usize + p.x as usize)
+    }
+
+    fn cell(&self, p: Point) -> Cell {
+        self.index(p).map(|i| self.cells[i]).unwrap_or_default()
+    }
+
+    fn cell_mut(&mut self, p: Point) -> Option<&mut Cell> {
+        let i = self.index(p)?;
+        Some(&mut self.cells[i])
+    }
+
+    /// Whether any of the snakes in `bits` passes `pred`
+    fn any(&self, bits: u64, pred: impl Fn(&Snake) -> bool) -> bool {
+        let mut bits = bits;
+        while bits != 0 {
+            if pred(self.snakes[bits.trailing_zeros() as usize]) {
+                return true;
+            }
+            bits &= bits - 1;
+        }
+
+        false
+    }
+
+    /// See `Point::safety_index`
+    pub fn safety_index(&self, p: Point, s: &Snake, st: &State) -> SafetyIndex {
+        // Squadmates move through each other's bodies
+        if self.any(self.cell(p).blocking, |e| !s.is_squadmate(e)) {
+            return SafetyIndex::Unsafe;
+        }
+
+        let mut curr = SafetyIndex::Safe;
+        let threatened = p.orthogonal(st).iter().any(|q| {
+            self.any(self.cell(*q).heads, |e| {
+                e.id != s.id && e.body.len() >= s.body.len()
+            })
+        });
+        if threatened {
+            curr = SafetyIndex::Risky;
+        }
+
+        // A hazard is only worth entering if we can afford the damage.
+        // Food on it makes up for it.
+        if st.board.hazards.contains(&p) && !st.board.food.contains(&p) {
+            if s.health <= HAZARD_DAMAGE + 1 {
+                return SafetyIndex::Unsafe;
+            }
+            curr = SafetyIndex::Risky;
+        }
+
+        if p.in_bounds(st) {
+            curr
+        } else {
+            SafetyIndex::Unsafe
+        }
+    }
+
+    /// See `Point::is_valid`
+    pub fn is_valid(&self, p: Point, s: &Snake, st: &State) -> bool {
+        let cell = self.cell(p);
+        let head_on = self
+            .any(cell.heads, |e| e.id != s.id && e.body.len() >= s.body.len());
+        let body = self.any(cell.bodies, |e| !s.is_squadmate(e));
+
+        !head_on && !body && p.in_bounds(st)
+    }
+
+    /// See `Point::successors`
+    pub fn successors(
+        &self,
+        p: Point,
+        s: &Snake,
+        st: &State,
+    ) -> Vec<(Point, u32)> {
+        p.neighbors(st)
+            .into_iter()
+            .filter(|n| self.safety_index(*n, s, st) != SafetyIndex::Unsafe)
+            .map(|n| (n, 1))
+            .collect()
+    }
+}
+
+/// The turn each square's last body segment moves off of it if nobody
+/// eats, indexed by `y * width + x`, or 0 for an empty square
+pub fn vacate_turns(st: &State) -> Vec<u16> {
+    let width = st.board.width as usize;
+    let mut turns = vec![0; st.board.cells()];
+
+    for s in st.board.snakes.values() {
+        for (i, p) in s.body.iter().enumerate() {
+            if p.in_bounds(st) {
+                let cell = &mut turns[p.y as usize * width + p.x as usize];
+                *cell = (*cell).max((s.body.len() - i) as u16);
+            }
+        }
+    }
+
+    turns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_occupancy_grid() {
+        let (mut you, mut st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 1), (1, 2), (1, 3)]),
+                ("big", &[(4, 1), (4, 2), (4, 3), (4, 4)]),
+                ("fed", &[(6, 6), (6, 5), (6, 5)]),
+                ("mate", &[(3, 6), (2, 6), (2, 5), (2, 4)]),
+            ],
+            &[],
+        );
+        let squad = Some(String::from("a"));
+        you.squad = squad.clone();
+        for id in &["you", "mate"] {
+            st.board.snakes.get_mut(*id).unwrap().squad = squad.clone();
+        }
+        let big = st.board.snakes["big"].clone();
+        let grid = st.occupancy();
+        let safety = |x, y| grid.safety_index(Point { x, y }, &you, &st);
+
+        assert_eq!(safety(4, 3), SafetyIndex::Unsafe);
+        assert_eq!(safety(4, 4), SafetyIndex::Safe);
+        assert_eq!(safety(6, 5), SafetyIndex::Unsafe);
+        assert_eq!(safety(3, 1), SafetyIndex::Risky);
+        assert_eq!(safety(2, 1), SafetyIndex::Safe);
+        assert_eq!(safety(-1, 0), SafetyIndex::Unsafe);
+
+        // Only squadmates pass through each other
+        assert_eq!(safety(2, 5), SafetyIndex::Safe);
+        let p = Point { x: 2, y: 5 };
+        assert_eq!(grid.safety_index(p, &big, &st), SafetyIndex::Unsafe);
+
+        // After the snakes have moved, a tail is still in the way
+        let valid = |x, y| grid.is_valid(Point { x, y }, &you, &st);
+        assert!(!valid(4, 1) && !valid(4, 4) && !valid(7, 0));
+        assert!(valid(0, 0) && valid(2, 5));
+
+        let mut successors = grid.successors(you.body[0], &you, &st);
+        successors.sort_by_key(|(p, _)| (p.x, p.y));
+        let points = [(0, 1), (1, 0), (2, 1)]
+            .iter()
+            .map(|(x, y)| (Point { x: *x, y: *y }, 1))
+            .collect::<Vec<_>>();
+        assert_eq!(successors, points);
+    }
+}
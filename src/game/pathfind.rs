@@ -0,0 +1,178 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Pathfinding over the board with a choice of exact or bounded
+//! suboptimal searches, so hot paths can trade path quality for speed
+//! when the move budget is running out.
+
+use pathfinding::prelude::astar;
+
+use super::{Point, Snake, State};
+
+/// Remaining budget below which the weighted search is used
+const WEIGHTED_BUDGET_MILLIS: u128 = 120;
+/// Remaining budget below which the greedy search is used
+const GREEDY_BUDGET_MILLIS: u128 = 40;
+/// Suboptimality bound used by `PathSearch::for_budget`
+pub const DEFAULT_WEIGHT: f32 = 1.5;
+/// Step costs are scaled so fractional heuristic weights survive
+/// the integer costs used by the A* implementation
+const COST_SCALE: u32 = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PathSearch {
+    /// Plain A*, always returns a shortest path
+    Exact,
+    /// Weighted A*, returns a path at most `w` times longer
+    /// than the shortest path
+    Weighted(f32),
+    /// Greedy best-first search, no bound on the path length
+    Greedy,
+}
+
+impl PathSearch {
+    /// Picks the search to use given the remaining move budget
+    pub fn for_budget(remaining_millis: u128) -> Self {
+        if remaining_millis < GREEDY_BUDGET_MILLIS {
+            PathSearch::Greedy
+        } else if remaining_millis < WEIGHTED_BUDGET_MILLIS {
+            PathSearch::Weighted(DEFAULT_WEIGHT)
+        } else {
+            PathSearch::Exact
+        }
+    }
+}
+
+/// Finds a path for snake `s` from its head to `goal`, returning
+/// the path (including the head) and its length in moves
+pub fn find_path(
+    s: &Snake,
+    st: &State,
+    goal: Point,
+    search: PathSearch,
+) -> Option<(Vec<Point>, u32)> {
+    let (step_cost, heuristic_scale) = match search {
+        PathSearch::Exact => (COST_SCALE, COST_SCALE),
+        PathSearch::Weighted(w) => {
+            (COST_SCALE, (COST_SCALE as f32 * w.max(1.0)) as u32)
+        }
+        PathSearch::Greedy => (0, COST_SCALE),
+    };
+
+    let grid = st.occupancy();
+    astar(
+        &s.body[0],
+        |p| {
+            grid.successors(*p, s, st)
+                .into_iter()
+                .map(|(n, _)| (n, step_cost))
+                .collect::<Vec<(Point, u32)>>()
+        },
+        |p| p.manhattan(goal) * heuristic_scale,
+        |p| *p == goal,
+    )
+    .map(|(path, _)| {
+        let len = path.len() as u32 - 1;
+        (path, len)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+    use std::time::SystemTime;
+
+    // A wall with a single gap forces a detour between the head and the goal
+    fn walled_state() -> (Snake, State) {
+        let wall: Vec<(i8, i8)> = (0..10).rev().map(|y| (5, y)).collect();
+        build_state(
+            11,
+            11,
+            &[("you", &[(1, 5), (1, 6), (1, 7)]), ("wall", &wall)],
+            &[],
+        )
+    }
+
+    #[test]
+    fn test_find_path_bounds() {
+        let (you, st) = walled_state();
+        let goal = Point { x: 9, y: 5 };
+
+        let (path, exact) =
+            find_path(&you, &st, goal, PathSearch::Exact).unwrap();
+        assert_eq!(path[0], you.body[0]);
+        assert_eq!(*path.last().unwrap(), goal);
+        assert_eq!(exact, 18);
+
+        let (_, weighted) =
+            find_path(&you, &st, goal, PathSearch::Weighted(2.0)).unwrap();
+        assert!(weighted >= exact && weighted as f32 <= exact as f32 * 2.0);
+
+        let (path, greedy) =
+            find_path(&you, &st, goal, PathSearch::Greedy).unwrap();
+        assert_eq!(*path.last().unwrap(), goal);
+        assert!(greedy >= exact);
+    }
+
+    #[test]
+    fn test_for_budget() {
+        assert_eq!(PathSearch::for_budget(300), PathSearch::Exact);
+        assert_eq!(
+            PathSearch::for_budget(100),
+            PathSearch::Weighted(DEFAULT_WEIGHT)
+        );
+        assert_eq!(PathSearch::for_budget(10), PathSearch::Greedy);
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_path_searches() {
+        let (you, st) = walled_state();
+        let searches = [
+            PathSearch::Exact,
+            PathSearch::Weighted(1.2),
+            PathSearch::Weighted(DEFAULT_WEIGHT),
+            PathSearch::Weighted(3.0),
+            PathSearch::Greedy,
+        ];
+
+        for search in &searches {
+            let start_time = SystemTime::now();
+            let mut total_len = 0;
+
+            for x in 6..11 {
+                for y in 0..11 {
+                    let goal = Point { x, y };
+                    if let Some((_, len)) = find_path(&you, &st, goal, *search)
+                    {
+                        total_len += len;
+                    }
+                }
+            }
+
+            println!(
+                "{:?}: total path length {} in {} \u{b5}s",
+                search,
+                total_len,
+                start_time.elapsed().unwrap().as_micros()
+            );
+        }
+    }
+}
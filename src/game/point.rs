@@ -17,7 +17,8 @@
  */
 use serde_derive::{Deserialize, Serialize};
 
-use super::{Dir, SafetyIndex, Snake, State};
+use super::occupancy::vacate_turns;
+use super::{BoardGraph, Contest, Dir, GameMode, SafetyIndex, Snake, State};
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Point {
@@ -28,65 +29,91 @@ pub struct Point {
 impl Point {
     /// Returns the manhattan distance between self and p
     pub fn manhattan(self, p: Self) -> u32 {
-        #![allow(clippy::pedantic)]
-        ((self.x - p.x).abs() + (self.y - p.y).abs()) as u32
+        let dx = i16::from(self.x) - i16::from(p.x);
+        let dy = i16::from(self.y) - i16::from(p.y);
+        (dx.abs() + dy.abs()) as u32
     }
 
     /// Returns the direction from this point to point p
     pub fn dir_to(self, p: Self) -> Option<Dir> {
-        if p.y - self.y > 0 {
+        if p.y > self.y {
             return Some(Dir::Down);
-        } else if p.y - self.y < 0 {
+        } else if p.y < self.y {
             return Some(Dir::Up);
-        } else if p.x - self.x > 0 {
+        } else if p.x > self.x {
             return Some(Dir::Right);
-        } else if p.x - self.x < 0 {
+        } else if p.x < self.x {
             return Some(Dir::Left);
         }
         None
     }
 
-    /// Returns the 4 adjacent points to self
-    pub fn orthogonal(self) -> [Self; 4] {
+    /// Returns the direction of a single move from self to the
+    /// adjacent point p. A move across the edge of a wrapped board
+    /// lands on the far side, so it points away from p.
+    pub fn step_dir(self, p: Self) -> Option<Dir> {
+        let dir = self.dir_to(p)?;
+        if self.manhattan(p) > 1 {
+            Some(dir.opposite())
+        } else {
+            Some(dir)
+        }
+    }
+
+    /// Returns self offset by (dx, dy), which may be off the board.
+    /// Saturates instead of wrapping, so an offset can never land back
+    /// on the board from the other side. Use `offset_checked` unless
+    /// the point off the board is needed, e.g. to see a move is fatal.
+    pub fn offset(self, dx: i8, dy: i8) -> Self {
+        Self {
+            x: self.x.saturating_add(dx),
+            y: self.y.saturating_add(dy),
+        }
+    }
+
+    /// Returns self offset by (dx, dy), or None if that is off the
+    /// board. Wraps around the edges of a wrapped board.
+    pub fn offset_checked(self, dx: i8, dy: i8, st: &State) -> Option<Self> {
+        let p = st.board.edges().wrap(Self {
+            x: self.x.checked_add(dx)?,
+            y: self.y.checked_add(dy)?,
+        });
+
+        if p.in_bounds(st) {
+            Some(p)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the 4 adjacent points to self, in the order up, down,
+    /// left, right. They may be off the board unless it is wrapped.
+    pub fn orthogonal(self, st: &State) -> [Self; 4] {
+        let edges = st.board.edges();
         [
-            Self {
-                x: self.x,
-                y: self.y - 1,
-            },
-            Self {
-                x: self.x,
-                y: self.y + 1,
-            },
-            Self {
-                x: self.x - 1,
-                y: self.y,
-            },
-            Self {
-                x: self.x + 1,
-                y: self.y,
-            },
+            edges.wrap(self.offset(0, -1)),
+            edges.wrap(self.offset(0, 1)),
+            edges.wrap(self.offset(-1, 0)),
+            edges.wrap(self.offset(1, 0)),
         ]
     }
 
+    /// Returns the adjacent points to self that are on the board
+    pub fn neighbors(self, st: &State) -> Vec<Self> {
+        [(0, -1), (0, 1), (-1, 0), (1, 0)]
+            .iter()
+            .filter_map(|(dx, dy)| self.offset_checked(*dx, *dy, st))
+            .collect()
+    }
+
     /// IsValid is a version of safety_index that is meant to
     /// be run on states where the snakes have already updated,
     /// not for future states.
     ///
-    /// TODO: Write a unit test for this funciton
+    /// Builds an occupancy grid for the one query, so checking many
+    /// points should use `State::occupancy` instead.
     pub fn is_valid(self, s: &Snake, st: &State) -> bool {
-        for (id, snake) in &st.board.snakes {
-            if self == snake.body[0] && *id != s.id {
-                if snake.body.len() >= s.body.len() {
-                    return false;
-                }
-            }
-
-            if snake.body.iter().skip(1).any(|p| *p == self) {
-                return false;
-            }
-        }
-
-        self.in_bounds(st)
+        st.occupancy().is_valid(self, s, st)
     }
 
     // Return the number of free spaces visable from the passed point
@@ -97,14 +124,15 @@ impl Point {
         st: &State,
         max_size: u16,
     ) -> Vec<Point> {
+        let grid = st.occupancy();
         let mut visited = vec![self];
         let mut to_visit = vec![self];
 
         while !to_visit.is_empty() {
             let curr = to_visit.pop();
-            for p in &curr.unwrap().orthogonal() {
+            for p in &curr.unwrap().neighbors(st) {
                 if !visited.contains(p)
-                    && p.safety_index(s, st) != SafetyIndex::Unsafe
+                    && grid.safety_index(*p, s, st) != SafetyIndex::Unsafe
                 {
                     visited.push(*p);
                     to_visit.push(*p);
@@ -117,53 +145,107 @@ impl Point {
         visited
     }
 
-    /// Returns the safety index of self.
-    ///
-    /// Safe: Empty point, in bounds, no snakes adjacent
-    /// Risky: Empty point, in bounds, larger snake adjacent
-    /// Unsafe: Occupied or OOB
-    pub fn safety_index(self, s: &Snake, st: &State) -> SafetyIndex {
-        let mut curr = SafetyIndex::Safe;
-        for snake in &st.board.snakes {
-            if snake.1.body.iter().any(|p| *p == self) {
-                let len = snake.1.body.len();
+    /// Flood fills from self like `flood_fill`, but a body segment
+    /// only blocks its square until the turn it moves off, if nobody
+    /// eats, so following a tail opens up the room behind it. A square
+    /// is filled if some path of at most `max_turns` moves reaches it
+    /// once it is free, counting each move on the path as a turn.
+    pub fn flood_fill_temporal(
+        self,
+        s: &Snake,
+        st: &State,
+        max_turns: u16,
+    ) -> Vec<Point> {
+        let grid = st.occupancy();
+        let vacate = vacate_turns(st);
+        let width = st.board.width as usize;
+        let index = |p: Point| p.y as usize * width + p.x as usize;
 
-                if self != snake.1.body[len - 1]
-                    || snake.1.body[len - 1] == snake.1.body[len - 2]
-                {
-                    return SafetyIndex::Unsafe;
+        let mut visited = vec![false; st.board.cells()];
+        if self.in_bounds(st) {
+            visited[index(self)] = true;
+        }
+
+        let mut cells = vec![self];
+        let mut frontier = vec![self];
+        for turn in 1..=max_turns {
+            let mut next = Vec::new();
+
+            for p in &frontier {
+                for n in p.neighbors(st) {
+                    let i = index(n);
+                    let free = match vacate[i] {
+                        0 => grid.safety_index(n, s, st) != SafetyIndex::Unsafe,
+                        t => turn >= t,
+                    };
+
+                    if free && !visited[i] {
+                        visited[i] = true;
+                        cells.push(n);
+                        next.push(n);
+                    }
                 }
             }
 
-            let contains = self
-                .orthogonal()
-                .iter()
-                .any(|p| p.y == snake.1.body[0].y && p.x == snake.1.body[0].x);
-            if snake.0 != &s.id
-                && contains
-                && snake.1.body.len() >= s.body.len()
-            {
-                curr = SafetyIndex::Risky;
+            if next.is_empty() {
+                break;
             }
+            frontier = next;
         }
 
-        if self.in_bounds(st) {
-            return curr;
-        } else {
-            SafetyIndex::Unsafe
-        }
+        cells
     }
 
-    /// Returns whether the point is inside the board
+    /// The size of the pocket moving onto self enters, found exactly
+    /// on the board graph. See `BoardGraph::pocket_size`.
+    pub fn pocket_size_exact(self, s: &Snake, st: &State) -> usize {
+        BoardGraph::new(s, st).pocket_size(self)
+    }
+
+    /// Returns the safety index of self.
+    ///
+    /// Safe: Empty point, in bounds, no snakes adjacent
+    /// Risky: Empty point, in bounds, larger snake adjacent
+    /// Unsafe: Occupied or OOB
+    ///
+    /// Builds an occupancy grid for the one query, so checking many
+    /// points should use `State::occupancy` instead.
+    pub fn safety_index(self, s: &Snake, st: &State) -> SafetyIndex {
+        st.occupancy().safety_index(self, s, st)
+    }
+
+    /// Classifies the head-on contest for self from the point of view
+    /// of snake s, based on the longest enemy head adjacent to it. A
+    /// cheap version of `analysis::head_on`, for searches.
+    pub fn contest(self, s: &Snake, st: &State) -> Contest {
+        Contest::against(
+            s,
+            st.board.snakes.values().filter(|e| {
+                e.id != s.id && self.orthogonal(st).contains(&e.body[0])
+            }),
+        )
+    }
+
+    /// Returns whether the point is inside the board. Every point is
+    /// on a wrapped board, at its position modulo the board size.
     pub fn in_bounds(self, st: &State) -> bool {
+        if st.board.mode == GameMode::Wrapped {
+            return true;
+        }
+
         self.x < st.board.width
             && self.x >= 0
             && self.y < st.board.height
             && self.y >= 0
     }
 
-    /// Returns whther the point is on the outer edge of the board
+    /// Returns whther the point is on the outer edge of the board. A
+    /// wrapped board has no edge to be trapped against.
     pub fn is_outer(self, st: &State) -> bool {
+        if st.board.mode == GameMode::Wrapped {
+            return false;
+        }
+
         self.x == 0
             || self.x == st.board.width - 1
             || self.y == 0
@@ -173,39 +255,18 @@ impl Point {
 
 // Implement methods for A*
 impl Point {
-    /// Returns the successors to self. Used for A*
+    /// Returns the successors to self. Used for A*, which should
+    /// build an occupancy grid once and use its `successors` instead
+    /// of calling this for every point.
     pub fn successors(self, s: &Snake, st: &State) -> Vec<(Self, u32)> {
-        vec![
-            Self {
-                x: self.x,
-                y: self.y - 1,
-            },
-            Self {
-                x: self.x,
-                y: self.y + 1,
-            },
-            Self {
-                x: self.x - 1,
-                y: self.y,
-            },
-            Self {
-                x: self.x + 1,
-                y: self.y,
-            },
-        ]
-        .into_iter()
-        .filter_map(|p| match p.safety_index(s, st) {
-            SafetyIndex::Safe | SafetyIndex::Risky => Some((p, 1)),
-            _ => None,
-        })
-        .collect()
+        st.occupancy().successors(self, s, st)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::load_sample_data;
     use super::super::Dir;
+    use super::super::{build_state, load_sample_data};
     use super::super::SafetyIndex;
     use super::*;
 
@@ -271,8 +332,9 @@ mod tests {
 
     #[test]
     fn test_orthogonal() {
+        let (_, mut st) = build_state(11, 11, &[("you", &[(1, 1)])], &[]);
         assert_eq!(
-            Point { x: 5, y: 5 }.orthogonal(),
+            Point { x: 5, y: 5 }.orthogonal(&st),
             [
                 Point { x: 5, y: 4 },
                 Point { x: 5, y: 6 },
@@ -280,6 +342,24 @@ mod tests {
                 Point { x: 6, y: 5 },
             ]
         );
+
+        // The corner's neighbours wrap to the far edges
+        let corner = Point { x: 0, y: 0 };
+        assert_eq!(corner.orthogonal(&st)[0], Point { x: 0, y: -1 });
+        st.board.mode = GameMode::Wrapped;
+        assert_eq!(
+            corner.orthogonal(&st),
+            [
+                Point { x: 0, y: 10 },
+                Point { x: 0, y: 1 },
+                Point { x: 10, y: 0 },
+                Point { x: 1, y: 0 },
+            ]
+        );
+        assert!(Point { x: -1, y: 0 }.in_bounds(&st));
+        assert!(!corner.is_outer(&st));
+        assert_eq!(corner.step_dir(Point { x: 0, y: 10 }), Some(Dir::Up));
+        assert_eq!(corner.step_dir(Point { x: 1, y: 0 }), Some(Dir::Right));
     }
 
     #[test]
@@ -307,6 +387,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_contest() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("short", &[(3, 5), (2, 5)]),
+                ("equal", &[(7, 5), (8, 5), (9, 5)]),
+                ("long", &[(5, 3), (5, 2), (5, 1), (5, 0)]),
+            ],
+            &[],
+        );
+
+        assert_eq!(
+            Point { x: 4, y: 5 }.contest(&you, &st),
+            Contest::Winning
+        );
+        assert_eq!(
+            Point { x: 6, y: 5 }.contest(&you, &st),
+            Contest::Equal
+        );
+        assert_eq!(
+            Point { x: 5, y: 4 }.contest(&you, &st),
+            Contest::Losing
+        );
+        assert_eq!(
+            Point { x: 5, y: 8 }.contest(&you, &st),
+            Contest::Uncontested
+        );
+        assert_eq!(
+            Point { x: 4, y: 5 }.safety_index(&you, &st),
+            SafetyIndex::Safe
+        );
+    }
+
     #[test]
     fn test_in_bounds() {
         let datas = load_sample_data();
@@ -338,4 +454,45 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_offset_checked() {
+        let (_, st) = build_state(11, 11, &[("you", &[(0, 0)])], &[]);
+        let corner = Point { x: 0, y: 0 };
+
+        assert_eq!(corner.offset_checked(0, -1, &st), None);
+        assert_eq!(corner.offset_checked(-3, 0, &st), None);
+        assert_eq!(
+            corner.offset_checked(2, 3, &st),
+            Some(Point { x: 2, y: 3 })
+        );
+        assert_eq!(
+            corner.neighbors(&st),
+            vec![Point { x: 0, y: 1 }, Point { x: 1, y: 0 }]
+        );
+
+        // Far off the board, nothing wraps around
+        let far = Point { x: 127, y: -128 };
+        assert_eq!(far.offset(1, -1), far);
+        assert_eq!(far.offset_checked(1, 0, &st), None);
+        assert_eq!(far.manhattan(corner), 255);
+        let off = Dir::Left.resulting_point(corner, &st);
+        assert_eq!(off, Point { x: -1, y: 0 });
+    }
+
+    #[test]
+    fn test_flood_fill_temporal() {
+        // Coiled around the centre of the board, with the tail right
+        // behind the head
+        let coil = [(0, 0), (1, 0), (2, 0), (2, 1), (2, 2), (1, 2), (0, 2)];
+        let body = [&coil[..], &[(0, 1)]].concat();
+        let (you, st) = build_state(3, 3, &[("you", &body)], &[]);
+        let head = you.body[0];
+
+        // The plain fill only sees the tail's square and the centre,
+        // but following the tail frees the whole board in time
+        assert_eq!(head.flood_fill(&you, &st, 8).len(), 3);
+        assert_eq!(head.flood_fill_temporal(&you, &st, 8).len(), 9);
+        assert_eq!(head.flood_fill_temporal(&you, &st, 3).len(), 5);
+    }
 }
@@ -15,16 +15,42 @@
  * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
  *
  */
+use log::warn;
+use serde::{Deserialize, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashSet;
 
-use super::{Dir, Point, SafetyIndex, State};
+use super::food::FoodOwnership;
+use super::{
+    Dir, Edges, Point, SafetyIndex, State, HAZARD_DAMAGE, MAX_HEALTH,
+};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Snake {
     pub id: String,
+    #[serde(deserialize_with = "deserialize_health")]
     pub health: u8,
     pub body: Vec<Point>,
+    /// The squad the snake plays for in squad games. Squadmates can
+    /// move through each other's bodies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub squad: Option<String>,
+}
+
+/// Reads a health value, clamped to between 0 and `MAX_HEALTH`. Buggy
+/// engines have sent health over the maximum, and a negative value
+/// would otherwise fail the whole request.
+pub fn deserialize_health<'de, D>(deserializer: D) -> Result<u8, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = i64::deserialize(deserializer)?;
+    let health = raw.max(0).min(i64::from(MAX_HEALTH));
+    if health != raw {
+        warn!("Clamped out of range health {} to {}", raw, health);
+    }
+
+    Ok(health as u8)
 }
 
 impl Snake {
@@ -44,6 +70,57 @@ impl Snake {
         nearest_food
     }
 
+    /// The food nearest to self by path length that nobody else gets
+    /// to first, and a shortest path to it including the head
+    pub fn best_food(&self, st: &State) -> Option<(Point, Vec<Point>)> {
+        let ours = st.distances(self);
+        let (_, food) = FoodOwnership::new(st)
+            .owned_by(&self.id)
+            .filter_map(|f| ours.distance(f).map(|d| (d, f)))
+            .min_by_key(|(d, f)| (*d, f.x, f.y))?;
+
+        ours.path(food, st).map(|path| (food, path))
+    }
+
+    /// Whether self gets to `food` before everyone else, see
+    /// `game::food`
+    pub fn owns_food(&self, food: Point, st: &State) -> bool {
+        FoodOwnership::new(st).owner(food) == Some(self.id.as_str())
+    }
+
+    /// How many turns self can put off eating before it starves: its
+    /// health less what getting to the best food costs, see
+    /// `best_food`, with each hazard on the way costing its damage
+    /// too. If self owns no food it can reach, the nearest food it can
+    /// reach is the one it has to go for. With no food in reach at
+    /// all, it's simply the snake's health.
+    pub fn turns_until_starvation(&self, st: &State) -> u32 {
+        if st.board.food.is_empty() {
+            return u32::from(self.health);
+        }
+
+        let path = match self.best_food(st) {
+            Some((_, path)) => path,
+            None => {
+                let field = st.distances(self);
+                match field.nearest(&st.board.food) {
+                    Some(food) => field.path(food, st).unwrap_or_default(),
+                    None => vec![],
+                }
+            }
+        };
+
+        // Eating on the last square restores the health the move to it
+        // costs, so only the squares before it can drain the snake
+        let cost = path.iter().skip(1).fold(0, |cost, p| {
+            let hazard = st.board.hazards.contains(p)
+                && !st.board.food.contains(p);
+            cost + 1 + if hazard { u32::from(HAZARD_DAMAGE) } else { 0 }
+        });
+
+        u32::from(self.health).saturating_sub(cost)
+    }
+
     /// Returns the location of the nearest snake to self
     pub fn nearest_snake<'a>(&self, st: &'a State) -> Option<&'a Self> {
         let mut nearest_dist = 99;
@@ -62,16 +139,48 @@ impl Snake {
         nearest_snake
     }
 
+    /// Whether the snake has run out of health. It is removed at the
+    /// end of the turn this happens on.
+    pub fn starved(&self) -> bool {
+        self.health == 0
+    }
+
+    /// Whether `other` is another snake on the same squad
+    pub fn is_squadmate(&self, other: &Snake) -> bool {
+        self.id != other.id && self.squad.is_some() && self.squad == other.squad
+    }
+
+    /// The direction of the snake's last move, or None if it hasn't
+    /// moved yet. Snakes spawn with every segment stacked on one cell,
+    /// so there is no neck to tell the direction from until the first
+    /// move.
+    pub fn last_move(&self) -> Option<Dir> {
+        self.body.get(1).and_then(|neck| neck.step_dir(self.body[0]))
+    }
+
+    /// The number of segments stacked on the tail's cell. The tail
+    /// only frees its cell on the next move if this is 1, which isn't
+    /// the case right after spawning or eating.
+    pub fn stacked_tail(&self) -> usize {
+        match self.body.last() {
+            Some(tail) => {
+                self.body.iter().rev().take_while(|p| *p == tail).count()
+            }
+            None => 0,
+        }
+    }
+
     /// Finds a safe space to move to. If there are no safe
     /// spaces this function defaults to "up"
     pub fn find_safe_move(&self, st: &State) -> Dir {
         let dirs = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
         let levels = [SafetyIndex::Safe, SafetyIndex::Risky];
-        let orthogonal = self.body[0].orthogonal();
+        let orthogonal = self.body[0].orthogonal(st);
+        let grid = st.occupancy();
 
         for level in &levels {
             for (i, dir) in dirs.iter().enumerate() {
-                if orthogonal[i].safety_index(&self, st) == *level {
+                if grid.safety_index(orthogonal[i], self, st) == *level {
                     return *dir;
                 }
             }
@@ -80,34 +189,22 @@ impl Snake {
         Dir::Up
     }
 
-    /// Updates the snake's body and health based on the provided move
-    pub fn update_from_move(&mut self, dir: Dir, food: &HashSet<Point>) -> (Point, Option<Point>) {
-        let collected = dir.will_collect_food(self, food);
-
-        let new_point = match dir {
-            Dir::Up => Point {
-                x: self.body[0].x,
-                y: self.body[0].y - 1,
-            },
-            Dir::Down => Point {
-                x: self.body[0].x,
-                y: self.body[0].y + 1,
-            },
-            Dir::Left => Point {
-                x: self.body[0].x - 1,
-                y: self.body[0].y,
-            },
-            Dir::Right => Point {
-                x: self.body[0].x + 1,
-                y: self.body[0].y,
-            },
-        };
+    /// Updates the snake's body and health based on the provided move.
+    /// `edges` are the edges of the board, see `Board::edges`.
+    pub fn update_from_move(
+        &mut self,
+        dir: Dir,
+        food: &HashSet<Point>,
+        edges: Edges,
+    ) -> (Point, Option<Point>) {
+        let collected = dir.will_collect_food(self, food, edges);
+        let new_point = dir.step(self.body[0], edges);
 
         self.body.insert(0, new_point);
         self.body.pop();
 
         if collected {
-            self.health = 100;
+            self.health = MAX_HEALTH;
 
             let last = self.body.last().cloned();
             if last.is_some() {
@@ -116,7 +213,9 @@ impl Snake {
 
             (new_point, Some(new_point))
         } else {
-            self.health -= 1;
+            // A starving snake is only removed after the move, see
+            // `starved`. Don't let its health wrap around before then.
+            self.health = self.health.saturating_sub(1);
             (new_point, None)
         }
     }
@@ -124,7 +223,7 @@ impl Snake {
 
 #[cfg(test)]
 mod tests {
-    use super::super::load_sample_data;
+    use super::super::{build_state, load_sample_data};
     use super::*;
 
     const SELF_ID: &str = "2d397b8c-8b3f-416d-bb16-6bc85ab3226e";
@@ -149,6 +248,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_best_food() {
+        // The nearest food is behind a wall, and the next nearest is
+        // one the enemy gets to first
+        let (you, mut st) = build_state(
+            9,
+            9,
+            &[
+                ("you", &[(4, 4), (4, 5), (4, 6)]),
+                ("wall", &[(3, 2), (4, 2), (5, 2), (5, 1), (5, 0)]),
+                ("enemy", &[(7, 5), (8, 5), (8, 6)]),
+            ],
+            &[(4, 1), (7, 3), (1, 5)],
+        );
+        assert_eq!(you.nearest_food(&st), Some(Point { x: 4, y: 1 }));
+
+        let (food, path) = you.best_food(&st).unwrap();
+        assert_eq!(food, Point { x: 1, y: 5 });
+        assert_eq!(path.len(), 5);
+        assert_eq!((path[0], path[4]), (you.body[0], food));
+
+        // With nothing left worth going for there is no best food
+        st.board.food.remove(&food);
+        assert_eq!(you.best_food(&st), None);
+    }
+
+    #[test]
+    fn test_turns_until_starvation() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[("you", &[(2, 5), (1, 5), (0, 5)])],
+            &[(6, 5)],
+        );
+        st.board.snakes.get_mut("you").unwrap().health = 20;
+        let you = st.board.snakes["you"].clone();
+        assert_eq!(you.turns_until_starvation(&st), 16);
+
+        // A hazard on the way drains the health much faster
+        st.board.hazards.insert(Point { x: 4, y: 5 });
+        assert_eq!(you.turns_until_starvation(&st), 2);
+        st.board.hazards.insert(Point { x: 5, y: 5 });
+        assert_eq!(you.turns_until_starvation(&st), 0);
+
+        // With nothing to eat, the health is all there is
+        st.board.food.clear();
+        assert_eq!(you.turns_until_starvation(&st), 20);
+    }
+
     #[test]
     fn test_find_safe_move() {
         let datas = load_sample_data();
@@ -170,10 +318,12 @@ mod tests {
     #[test]
     fn test_update_from_move() {
         let data = &mut load_sample_data()[0];
+        let edges = data.1.board.edges();
 
         let snake = &mut data.1.board.snakes.get_mut(SBOT_ID).unwrap();
 
-        let point = snake.update_from_move(Dir::Right, &data.1.board.food);
+        let point =
+            snake.update_from_move(Dir::Right, &data.1.board.food, edges);
         assert_eq!(point, (Point { x: 12, y: 2 }, None));
         assert_eq!(
             snake.body,
@@ -192,7 +342,8 @@ mod tests {
 
         assert_eq!(snake.health, 58);
 
-        let point = snake.update_from_move(Dir::Up, &data.1.board.food);
+        let point =
+            snake.update_from_move(Dir::Up, &data.1.board.food, edges);
         assert_eq!(point, (Point { x: 12, y: 1 }, None));
         assert_eq!(
             snake.body,
@@ -211,7 +362,8 @@ mod tests {
 
         assert_eq!(snake.health, 57);
 
-        let point = snake.update_from_move(Dir::Left, &data.1.board.food);
+        let point =
+            snake.update_from_move(Dir::Left, &data.1.board.food, edges);
         assert_eq!(point, (Point { x: 11, y: 1 }, Some(Point { x: 11, y: 1 })));
         assert_eq!(
             snake.body,
@@ -0,0 +1,159 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Territory, the Voronoi partition of the board between the snakes.
+//! A square belongs to the snake whose head reaches it first, found
+//! with one breadth first search from every head at once. A square two
+//! heads reach on the same turn goes to the longer snake, since it
+//! would win the collision, or to nobody if they are the same length.
+//!
+//! Bodies block a square until the turn their segment has moved off of
+//! it, so a snake can follow its own or another snake's tail. Unlike a
+//! flood fill, which counts every square a snake could get to, this
+//! only counts the squares it would get to before anyone else.
+
+use std::collections::HashMap;
+
+use super::occupancy::vacate_turns;
+use super::{Point, State};
+
+/// How many squares each snake's territory holds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Territory {
+    counts: HashMap<String, u16>,
+}
+
+impl Territory {
+    pub fn new(st: &State) -> Self {
+        let width = st.board.width as usize;
+        let cells = st.board.cells();
+        let index = |p: Point| p.y as usize * width + p.x as usize;
+
+        let mut snakes = st.board.snakes.values().collect::<Vec<_>>();
+        snakes.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let free_at = vacate_turns(st);
+
+        let mut dist = vec![u16::MAX; cells];
+        let mut owner = vec![None; cells];
+        // The length of the longest snake to reach each square first
+        let mut claim = vec![0; cells];
+        let mut frontier = Vec::with_capacity(snakes.len());
+        for (i, s) in snakes.iter().enumerate() {
+            dist[index(s.body[0])] = 0;
+            owner[index(s.body[0])] = Some(i);
+            frontier.push((s.body[0], i));
+        }
+
+        let mut counts = vec![0; snakes.len()];
+        let mut turn = 0;
+        while !frontier.is_empty() {
+            turn += 1;
+            let mut next = Vec::new();
+
+            for (p, i) in frontier {
+                // Squares lost to a longer snake, or that nobody
+                // won, lead nowhere for this one
+                if owner[index(p)] != Some(i) {
+                    continue;
+                }
+
+                for n in p.neighbors(st) {
+                    let c = index(n);
+                    if turn < free_at[c] {
+                        continue;
+                    }
+
+                    let len = snakes[i].body.len();
+                    if dist[c] == u16::MAX || dist[c] == turn && len > claim[c]
+                    {
+                        dist[c] = turn;
+                        owner[c] = Some(i);
+                        claim[c] = len;
+                        next.push((n, i));
+                    } else if dist[c] == turn
+                        && len == claim[c]
+                        && owner[c] != Some(i)
+                    {
+                        owner[c] = None;
+                    }
+                }
+            }
+
+            frontier = next;
+        }
+
+        for (c, o) in owner.iter().enumerate() {
+            if let (Some(i), true) = (o, dist[c] > 0) {
+                counts[*i] += 1;
+            }
+        }
+
+        Self {
+            counts: snakes
+                .iter()
+                .zip(counts)
+                .map(|(s, n)| (s.id.clone(), n))
+                .collect(),
+        }
+    }
+
+    /// The squares the snake with the given id reaches first, not
+    /// counting its head
+    pub fn score(&self, id: &str) -> u16 {
+        self.counts.get(id).cloned().unwrap_or(0)
+    }
+}
+
+/// The size of a snake's territory. Use `Territory::new` to score
+/// more than one snake on the same board.
+pub fn territory_score(st: &State, id: &str) -> u16 {
+    Territory::new(st).score(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_territory() {
+        let (_, mut st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (0, 3), (0, 4)]),
+                ("enemy", &[(5, 3), (6, 3), (6, 4)]),
+            ],
+            &[],
+        );
+
+        // Each side is its own, and the middle column is split evenly
+        // so nobody gets it. Bodies are passable once they move off.
+        let even = Territory::new(&st);
+        assert_eq!(even.score("you"), 20);
+        assert_eq!(even.score("enemy"), 20);
+        assert_eq!(territory_score(&st, "nobody"), 0);
+
+        // A longer snake wins the squares reached at the same time
+        let enemy = st.board.snakes.get_mut("enemy").unwrap();
+        enemy.body.push(Point { x: 6, y: 5 });
+        assert_eq!(territory_score(&st, "you"), 20);
+        assert_eq!(territory_score(&st, "enemy"), 27);
+    }
+}
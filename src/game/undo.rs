@@ -0,0 +1,171 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Make and unmake moves on a state, for searches that walk a tree of
+//! positions with one state instead of cloning it at every node.
+//! `State::apply_moves` moves snakes and eats food like a turn does,
+//! without resolving collisions, and returns what `State::undo` needs
+//! to take the moves back: each snake's old tail and health, the food
+//! eaten and any snakes removed since.
+//!
+//! Tokens have to be undone in the reverse order they were made in.
+
+use super::{Dir, Point, Snake, State};
+
+/// A snake's move, as far as undoing it goes
+#[derive(Debug, Clone)]
+struct Moved {
+    id: String,
+    /// The tail it moved off of
+    tail: Point,
+    health: u8,
+    /// Whether it ate, and grew a segment
+    grew: bool,
+}
+
+/// Everything `State::undo` needs to take moves back
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct UndoToken {
+    moved: Vec<Moved>,
+    eaten: Vec<Point>,
+    removed: Vec<Snake>,
+}
+
+impl UndoToken {
+    /// The food eaten by the moves
+    pub fn eaten(&self) -> &[Point] {
+        &self.eaten
+    }
+}
+
+impl State {
+    /// Moves the given snakes one square and eats the food they move
+    /// onto. Snakes that run into something or starve stay on the
+    /// board, for the caller to resolve.
+    pub fn apply_moves(&mut self, moves: &[(&str, Dir)]) -> UndoToken {
+        let mut token = UndoToken::default();
+        let edges = self.board.edges();
+
+        for (id, dir) in moves {
+            let snake = match self.board.snakes.get_mut(*id) {
+                Some(snake) => snake,
+                None => continue,
+            };
+
+            let tail = snake.body[snake.body.len() - 1];
+            let health = snake.health;
+            let (_, eaten) =
+                snake.update_from_move(*dir, &self.board.food, edges);
+
+            token.moved.push(Moved {
+                id: id.to_string(),
+                tail,
+                health,
+                grew: eaten.is_some(),
+            });
+            if let Some(p) = eaten {
+                token.eaten.push(p);
+            }
+        }
+
+        // Snakes moving onto the same food all eat it
+        for p in &token.eaten {
+            self.board.food.remove(p);
+        }
+        token
+    }
+
+    /// Takes the given snakes off the board, putting them back when
+    /// `token` is undone
+    pub fn remove_snakes(&mut self, ids: &[String], token: &mut UndoToken) {
+        for id in ids {
+            if let Some(snake) = self.board.snakes.remove(id) {
+                token.removed.push(snake);
+            }
+        }
+    }
+
+    /// Takes back the moves and removals `token` was made with
+    pub fn undo(&mut self, token: UndoToken) {
+        for snake in token.removed {
+            self.board.snakes.insert(snake.id.clone(), snake);
+        }
+
+        for m in token.moved.into_iter().rev() {
+            let snake = self.board.snakes.get_mut(&m.id).unwrap();
+            snake.body.remove(0);
+            if m.grew {
+                snake.body.pop();
+            }
+            snake.body.push(m.tail);
+            snake.health = m.health;
+        }
+
+        self.board.food.extend(token.eaten);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::build_state;
+    use super::*;
+
+    #[test]
+    fn test_undo() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("enemy", &[(1, 1), (1, 2), (1, 2)]),
+                ("other", &[(9, 9), (9, 8), (9, 7)]),
+            ],
+            &[(5, 4), (1, 0)],
+        );
+        st.board.snakes.get_mut("enemy").unwrap().health = 40;
+        let position = |st: &State| {
+            let mut snakes = st
+                .board
+                .snakes
+                .values()
+                .map(|s| (s.id.clone(), s.body.clone(), s.health))
+                .collect::<Vec<_>>();
+            snakes.sort_by(|a, b| a.0.cmp(&b.0));
+            (snakes, st.board.food.clone())
+        };
+        let before = position(&st);
+        let p = |x, y| Point { x, y };
+
+        // Both eat, and growing stacks the tail
+        let mut token = st.apply_moves(&[("you", Dir::Up), ("enemy", Dir::Up)]);
+        let you = &st.board.snakes["you"];
+        assert_eq!(you.body, vec![p(5, 4), p(5, 5), p(5, 6), p(5, 6)]);
+        assert_eq!(token.eaten().len(), 2);
+        assert!(st.board.food.is_empty());
+
+        st.remove_snakes(&[String::from("other")], &mut token);
+        let moved = st.apply_moves(&[("you", Dir::Left)]);
+        assert_eq!(st.board.snakes["you"].health, 99);
+        assert_eq!(st.board.snakes.len(), 2);
+
+        st.undo(moved);
+        st.undo(token);
+        assert_eq!(position(&st), before);
+    }
+}
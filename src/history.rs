@@ -0,0 +1,405 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Per-game history of where our snake has been. The heatmap is used
+//! to nudge the snake out of areas it keeps circling in long games.
+//!
+//! The history also keeps the previous turn's state, so when a snake
+//! disappears between two consecutive turns we can work out how it
+//! was eliminated, and where food has spawned so far.
+
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
+
+use super::constraints::is_blunder;
+use super::food_map;
+use super::game::{Dir, Point, SafetyIndex, Snake, State, TurnContext};
+use super::simulator::DeathCause;
+
+/// How much of each cell's heat is kept from one turn to the next
+const HEAT_DECAY: f64 = 0.95;
+
+/// How much cooler an alternative's neighbourhood must be before we
+/// play it instead of the chosen move
+const MIN_HEAT_GAIN: f64 = 3.0;
+
+const ALL_DIRS: [Dir; 4] = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+/// How a snake was eliminated, as far as we can tell from the states
+/// before and after
+#[derive(Debug, Clone, PartialEq)]
+pub struct Elimination {
+    pub id: String,
+    /// The first turn the snake was missing from
+    pub turn: u32,
+    pub cause: DeathCause,
+    /// The snake it ran into, when every cause that fits agrees on one
+    pub killer: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GameHistory {
+    heat: HashMap<Point, f64>,
+    prev: Option<State>,
+    pub eliminations: Vec<Elimination>,
+    /// Where food has appeared, with the first turn it was seen on
+    pub spawns: Vec<(Point, u32)>,
+}
+
+/// Whether `p` is covered by the body of `o` after it moves, given
+/// its body the turn before. The tail moves out of the way unless it
+/// is stacked.
+fn covers_after_move(o: &Snake, p: Point) -> bool {
+    let keep = if o.stacked_tail() > 1 {
+        o.body.len()
+    } else {
+        o.body.len() - 1
+    };
+
+    o.body[..keep].contains(&p)
+}
+
+/// What would kill snake `e` if its head went to `p`, and the snake it
+/// would run into, or None if it would survive. `prev` is the state it
+/// moved from, `curr` the state after the move.
+fn fatal_cause(
+    e: &Snake,
+    p: Point,
+    prev: &State,
+    curr: &State,
+) -> Option<(DeathCause, Option<String>)> {
+    if e.health <= 1 && !prev.board.food.contains(&p) {
+        return Some((DeathCause::Starvation, None));
+    }
+
+    if !p.in_bounds(prev) {
+        return Some((DeathCause::Wall, None));
+    }
+
+    if covers_after_move(e, p) {
+        return Some((DeathCause::SelfCollision, None));
+    }
+
+    for (id, o) in &prev.board.snakes {
+        if *id != e.id && covers_after_move(o, p) {
+            return Some((DeathCause::Body, Some(id.clone())));
+        }
+    }
+
+    // A head-on kills the shorter snake, by their lengths before the
+    // move, or both if they are the same length. The other head is
+    // where it moved to if it survived, or else one of the squares it
+    // could reach.
+    for (id, o) in &prev.board.snakes {
+        let met = match curr.board.snakes.get(id) {
+            _ if *id == e.id => false,
+            Some(after) => after.body[0] == p && o.body.len() >= e.body.len(),
+            None => {
+                o.body.len() == e.body.len()
+                    && o.body[0].orthogonal(prev).contains(&p)
+            }
+        };
+        if met {
+            return Some((DeathCause::HeadOn, Some(id.clone())));
+        }
+    }
+
+    None
+}
+
+/// Works out how `e`, alive in `prev` and gone from `curr`, died. The
+/// move it made isn't known, so every fatal move is considered and the
+/// cause is Unknown unless they all agree, though they can still agree
+/// on the killer. Moving back onto its own neck only counts if nothing
+/// else was fatal.
+fn infer_cause(
+    e: &Snake,
+    prev: &State,
+    curr: &State,
+) -> (DeathCause, Option<String>) {
+    let head = e.body[0];
+    let neck = e.body.get(1).cloned().filter(|n| *n != head);
+
+    let (forward, back): (Vec<Dir>, Vec<Dir>) = ALL_DIRS
+        .iter()
+        .partition(|d| Some(d.resulting_point(head, prev)) != neck);
+
+    let causes = |dirs: &[Dir]| {
+        dirs.iter()
+            .filter_map(|d| {
+                fatal_cause(e, d.resulting_point(head, prev), prev, curr)
+            })
+            .collect::<HashSet<(DeathCause, Option<String>)>>()
+    };
+
+    let mut fatal = causes(&forward);
+    if fatal.is_empty() {
+        fatal = causes(&back);
+    }
+
+    if fatal.len() == 1 {
+        return fatal.into_iter().next().unwrap();
+    }
+
+    let killers = fatal.into_iter().map(|(_, k)| k).collect::<HashSet<_>>();
+    let killer = match killers.len() {
+        1 => killers.into_iter().next().unwrap(),
+        _ => None,
+    };
+    (DeathCause::Unknown, killer)
+}
+
+/// Whether every move of `e` from `st` runs into a wall or a body, its
+/// own or another snake's, so it dies next turn whatever the others do
+#[cfg(feature = "debug-endpoints")]
+pub fn is_boxed_in(e: &Snake, st: &State) -> bool {
+    let head = e.body[0];
+
+    // With no state after the move, the others' heads haven't moved
+    // and no head-on is found
+    let trapped = [
+        DeathCause::Wall,
+        DeathCause::SelfCollision,
+        DeathCause::Body,
+    ];
+
+    ALL_DIRS.iter().all(|d| {
+        match fatal_cause(e, d.resulting_point(head, st), st, st) {
+            Some((cause, _)) => trapped.contains(&cause),
+            None => false,
+        }
+    })
+}
+
+impl GameHistory {
+    /// Records a turn spent with our head on `head`
+    pub fn record(&mut self, head: Point) {
+        for h in self.heat.values_mut() {
+            *h *= HEAT_DECAY;
+        }
+
+        *self.heat.entry(head).or_insert(0.0) += 1.0;
+    }
+
+    /// Records the state of a turn from our point of view. If it
+    /// follows the previous one, the snakes that went missing in
+    /// between are classified by cause of elimination.
+    pub fn observe(&mut self, you: &str, st: &State) {
+        for f in &st.board.food {
+            let new = match &self.prev {
+                Some(prev) => !prev.board.food.contains(f),
+                None => true,
+            };
+
+            if new {
+                self.spawns.push((*f, st.turn));
+            }
+        }
+
+        if let Some(prev) = &self.prev {
+            if st.turn == prev.turn + 1 {
+                for (id, e) in &prev.board.snakes {
+                    if *id == you || st.board.snakes.contains_key(id) {
+                        continue;
+                    }
+
+                    let (cause, killer) = infer_cause(e, prev, st);
+                    info!(
+                        "Snake {} was eliminated on turn {}: {:?} by {:?}",
+                        id, st.turn, cause, killer
+                    );
+                    self.eliminations.push(Elimination {
+                        id: id.clone(),
+                        turn: st.turn,
+                        cause,
+                        killer,
+                    });
+                }
+            }
+        }
+
+        self.prev = Some(st.clone());
+    }
+
+    /// The heat of a point and the cells around it
+    pub fn heat(&self, p: Point, st: &State) -> f64 {
+        let own = self.heat.get(&p).cloned().unwrap_or(0.0);
+
+        own + p
+            .orthogonal(st)
+            .iter()
+            .filter_map(|n| self.heat.get(n))
+            .sum::<f64>()
+    }
+
+    /// Nudges the chosen move towards the quadrant where food has been
+    /// most plentiful, see `food_map::commit`
+    pub fn commit(
+        &self,
+        dir: Dir,
+        s: &Snake,
+        st: &State,
+        ctx: &TurnContext,
+    ) -> Dir {
+        food_map::commit(dir, s, st, ctx, &self.spawns)
+    }
+
+    /// Swaps the chosen move for a safe one into a much less visited
+    /// part of the board, as long as it leaves us at least as much
+    /// room. Returns the chosen move if there isn't one.
+    pub fn explore(&self, dir: Dir, s: &Snake, st: &State) -> Dir {
+        let head = s.body[0];
+        let max_fill = s.body.len() as u16 * 2;
+        let room = |d: Dir| {
+            d.resulting_point(head, st).flood_fill(s, st, max_fill).len()
+        };
+
+        let chosen_heat = self.heat(dir.resulting_point(head, st), st);
+        let chosen_room = room(dir);
+
+        let coolest = ALL_DIRS
+            .iter()
+            .filter(|d| **d != dir)
+            .filter(|d| d.is_safety_index(s, st, &SafetyIndex::Safe))
+            .filter(|d| !is_blunder(**d, s, st) && room(**d) >= chosen_room)
+            .map(|d| (*d, self.heat(d.resulting_point(head, st), st)))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+        match coolest {
+            Some((alt, heat)) if chosen_heat - heat >= MIN_HEAT_GAIN => {
+                debug!(
+                    "Exploring: {:?} (heat {:.1}) instead of {:?} (heat {:.1})",
+                    alt, heat, dir, chosen_heat
+                );
+                alt
+            }
+            _ => dir,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_explore() {
+        let (you, st) =
+            build_state(11, 11, &[("you", &[(5, 5), (5, 6), (5, 7)])], &[]);
+        let mut history = GameHistory::default();
+
+        // Circle around the left of the head for a while
+        for _ in 0..10 {
+            for &(x, y) in &[(4, 4), (3, 4), (3, 5), (4, 5)] {
+                history.record(Point { x, y });
+            }
+        }
+
+        assert!(history.heat(Point { x: 4, y: 5 }, &st) > MIN_HEAT_GAIN);
+        assert_eq!(history.explore(Dir::Left, &you, &st), Dir::Right);
+
+        // No change without a strong enough reason
+        assert_eq!(
+            GameHistory::default().explore(Dir::Left, &you, &st),
+            Dir::Left
+        );
+    }
+
+    #[test]
+    fn test_eliminations() {
+        let (_, mut before) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7), (5, 8)]),
+                ("rammer", &[(4, 4), (3, 4), (2, 4)]),
+                ("waller", &[(0, 9), (1, 9), (2, 9)]),
+                ("starving", &[(8, 1), (8, 2), (8, 3)]),
+                ("survivor", &[(8, 8), (8, 9), (8, 10)]),
+            ],
+            &[],
+        );
+        before.board.snakes.get_mut("starving").unwrap().health = 1;
+
+        let mut history = GameHistory::default();
+        history.observe("you", &before);
+
+        // We moved up onto the only square that could have killed the
+        // rammer, and the waller's only fatal move was off the board
+        let mut after = before.clone();
+        after.turn += 1;
+        for id in &["rammer", "waller", "starving"] {
+            after.board.snakes.remove(*id);
+        }
+        let food = HashSet::new();
+        let us = after.board.snakes.get_mut("you").unwrap();
+        us.update_from_move(Dir::Up, &food, before.board.edges());
+        after.board.food.insert(Point { x: 0, y: 0 });
+        history.observe("you", &after);
+
+        let cause = |id: &str| {
+            history
+                .eliminations
+                .iter()
+                .find(|e| e.id == id)
+                .map(|e| (e.cause, e.killer.as_deref()))
+        };
+        assert_eq!(cause("rammer"), Some((DeathCause::HeadOn, Some("you"))));
+        assert_eq!(cause("waller"), Some((DeathCause::Wall, None)));
+        assert_eq!(cause("starving").unwrap().0, DeathCause::Starvation);
+        assert_eq!(cause("survivor"), None);
+        assert_eq!(history.eliminations.len(), 3);
+
+        // The food that appeared was recorded as a spawn
+        assert_eq!(history.spawns, vec![(Point { x: 0, y: 0 }, 1)]);
+    }
+
+    #[test]
+    fn test_mutual_head_on() {
+        let (_, before) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 9), (5, 10), (6, 10)]),
+                ("left", &[(3, 4), (2, 4), (1, 4)]),
+                ("right", &[(5, 4), (6, 4), (7, 4)]),
+            ],
+            &[],
+        );
+
+        let mut history = GameHistory::default();
+        history.observe("you", &before);
+
+        // Both met at (4, 4) and neither is left to see there, and
+        // every other move was safe for both
+        let mut after = before.clone();
+        after.turn += 1;
+        after.board.snakes.remove("left");
+        after.board.snakes.remove("right");
+        let us = after.board.snakes.get_mut("you").unwrap();
+        us.update_from_move(Dir::Up, &HashSet::new(), before.board.edges());
+        history.observe("you", &after);
+
+        assert_eq!(history.eliminations.len(), 2);
+        for e in &history.eliminations {
+            assert_eq!(e.cause, DeathCause::HeadOn);
+            let other = if e.id == "left" { "right" } else { "left" };
+            assert_eq!(e.killer.as_deref(), Some(other));
+        }
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Idle mode between games. The searches run on a thread pool owned
+//! by the server instead of rayon's global one, so it can be shut down
+//! when no game has sent a request for a while. The pool is rebuilt
+//! on the next game request, and the game start warm-up brings it
+//! back up to speed before the first move. Requests that don't wake
+//! the server get a single thread pool of their own while idle, which
+//! goes away with the request.
+
+use log::{info, warn};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde_derive::Serialize;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Power {
+    Active,
+    Idle,
+}
+
+/// The power state as shown in `/status`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PowerStatus {
+    pub state: Power,
+    /// Seconds spent in the current state
+    pub since_secs: u64,
+    /// Seconds since the last game request
+    pub last_request_secs: u64,
+    /// Number of times the server has gone idle
+    pub idle_count: u64,
+}
+
+pub struct IdleMonitor {
+    /// None disables idle mode
+    timeout: Option<Duration>,
+    state: Mutex<IdleState>,
+}
+
+/// Everything that changes, behind one lock so every worker thread
+/// can share the monitor
+struct IdleState {
+    pool: Option<Arc<ThreadPool>>,
+    last_request: Instant,
+    changed: Instant,
+    idle_count: u64,
+}
+
+/// Name of the threads of the pools built for requests while idle
+const SCRATCH_THREAD: &str = "idle-scratch";
+
+fn build_pool() -> Option<Arc<ThreadPool>> {
+    match ThreadPoolBuilder::new().build() {
+        Ok(pool) => Some(Arc::new(pool)),
+        Err(e) => {
+            warn!("Couldn't build the search thread pool: {}", e);
+            None
+        }
+    }
+}
+
+impl IdleMonitor {
+    /// Starts active. A timeout of zero never goes idle.
+    pub fn new(timeout: Duration) -> Self {
+        let now = Instant::now();
+
+        Self {
+            timeout: Some(timeout).filter(|t| *t > Duration::from_secs(0)),
+            state: Mutex::new(IdleState {
+                pool: build_pool(),
+                last_request: now,
+                changed: now,
+                idle_count: 0,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, IdleState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn state(&self) -> Power {
+        if self.lock().pool.is_some() {
+            Power::Active
+        } else {
+            Power::Idle
+        }
+    }
+
+    /// Records a game request, waking up if idle
+    pub fn touch(&self) {
+        let mut state = self.lock();
+        state.last_request = Instant::now();
+
+        if state.pool.is_none() {
+            state.pool = build_pool();
+            state.changed = Instant::now();
+            info!("Waking up from idle mode");
+        }
+    }
+
+    /// Goes idle if there has been no game request for the timeout.
+    /// Returns true if this call went idle.
+    pub fn check(&self) -> bool {
+        let mut state = self.lock();
+        let expired = match self.timeout {
+            Some(t) => state.last_request.elapsed() >= t,
+            None => false,
+        };
+
+        if !expired || state.pool.is_none() {
+            return false;
+        }
+
+        // Dropping the pool stops its threads once they finish, and
+        // once any search still running on it lets go of it
+        state.pool = None;
+        state.changed = Instant::now();
+        state.idle_count += 1;
+        info!(
+            "No games for {} s, going idle",
+            state.last_request.elapsed().as_secs()
+        );
+
+        true
+    }
+
+    /// Runs `op` on the search thread pool, so parallel iterators in
+    /// it use that pool. If idle, `op` runs on a one thread pool that
+    /// is dropped when it returns, so it doesn't start rayon's global
+    /// pool, which never shuts down. The lock is only held to find the
+    /// pool, so searches for different games run side by side.
+    pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        if let Some(pool) = self.pool() {
+            return pool.install(op);
+        }
+
+        let scratch = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .thread_name(|_| SCRATCH_THREAD.to_string())
+            .build();
+
+        match scratch {
+            Ok(pool) => pool.install(op),
+            Err(e) => {
+                warn!("Couldn't build a pool for an idle request: {}", e);
+                op()
+            }
+        }
+    }
+
+    /// The search thread pool, for work that runs on a thread of its
+    /// own, or None if idle
+    pub fn pool(&self) -> Option<Arc<ThreadPool>> {
+        self.lock().pool.clone()
+    }
+
+    pub fn status(&self) -> PowerStatus {
+        let power = self.state();
+        let state = self.lock();
+
+        PowerStatus {
+            state: power,
+            since_secs: state.changed.elapsed().as_secs(),
+            last_request_secs: state.last_request.elapsed().as_secs(),
+            idle_count: state.idle_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_monitor() {
+        let idle = IdleMonitor::new(Duration::from_millis(1));
+        assert_eq!(idle.state(), Power::Active);
+        assert_eq!(idle.install(|| 1 + 1), 2);
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(idle.check());
+        assert!(!idle.check());
+        assert_eq!(idle.state(), Power::Idle);
+        assert_eq!(idle.install(|| 1 + 1), 2);
+
+        idle.touch();
+        assert_eq!(idle.state(), Power::Active);
+        assert!(!idle.check());
+        assert_eq!(idle.status().idle_count, 1);
+
+        let never = IdleMonitor::new(Duration::from_secs(0));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!never.check());
+    }
+
+    /// Threads of this process with the given name
+    #[cfg(target_os = "linux")]
+    fn threads_named(name: &str) -> usize {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| {
+                std::fs::read_to_string(task.ok()?.path().join("comm")).ok()
+            })
+            .filter(|comm| comm.trim_end() == name)
+            .count()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_install_while_idle() {
+        let idle = IdleMonitor::new(Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(idle.check());
+
+        let threads = idle.install(|| {
+            assert_eq!(rayon::current_num_threads(), 1);
+            threads_named(SCRATCH_THREAD)
+        });
+        assert!(threads >= 1);
+
+        // The pool's thread exits on its own after the pool is dropped.
+        // Other tests going idle may have one up for a moment too.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while threads_named(SCRATCH_THREAD) > 0 {
+            assert!(Instant::now() < deadline, "idle pool thread leaked");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(idle.state(), Power::Idle);
+    }
+}
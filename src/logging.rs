@@ -0,0 +1,295 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Logging setup. With the `json` log format every line is a JSON
+//! object tagged with the game and turn the worker thread is handling,
+//! so the logs of concurrent games can be told apart after a
+//! tournament. Every move is also logged as one `MoveRecord` with the
+//! chosen move, the profile's scores and how long the move took.
+//!
+//! The game and turn are tracked per thread, so lines logged from the
+//! search thread pool carry neither.
+
+use log::{info, Level, Record};
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config::{self, Config};
+use super::game::Dir;
+use super::profile::SearchReport;
+
+/// The log target of move records, whose fields are spread into the
+/// JSON line rather than logged as a message
+pub const MOVE_TARGET: &str = "move";
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// env_logger's usual human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Context {
+    game_id: String,
+    turn: u32,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Tags the lines logged by this thread with a game and turn until it
+/// is dropped
+pub struct Scope {
+    previous: Option<Context>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Starts tagging this thread's log lines with the given game and turn
+pub fn scope(game_id: &str, turn: u32) -> Scope {
+    let context = Context {
+        game_id: game_id.to_string(),
+        turn,
+    };
+
+    Scope {
+        previous: CONTEXT.with(|c| c.borrow_mut().replace(context)),
+    }
+}
+
+/// Sets up the global logger with the configured level and format
+pub fn init(config: &Config) {
+    env::set_var("RUST_LOG", &config.log_level);
+    let mut builder = env_logger::Builder::from_default_env();
+
+    if config.log_format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+
+    builder.init();
+}
+
+/// The JSON object logged for a record
+fn json_line(record: &Record) -> Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+
+    let mut line = json!({
+        "ts": timestamp,
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "thread": thread::current().name(),
+    });
+
+    CONTEXT.with(|c| {
+        if let Some(context) = &*c.borrow() {
+            line["game_id"] = json!(context.game_id);
+            line["turn"] = json!(context.turn);
+        }
+    });
+
+    let message = record.args().to_string();
+    let fields = if record.target() == MOVE_TARGET {
+        serde_json::from_str::<Map<String, Value>>(&message).ok()
+    } else {
+        None
+    };
+
+    match fields {
+        Some(fields) => {
+            for (key, value) in fields {
+                line[key] = value;
+            }
+        }
+        None => line["msg"] = json!(message),
+    }
+
+    line
+}
+
+/// What was decided for a move and how long it took
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MoveRecord {
+    pub game_id: String,
+    pub turn: u32,
+    pub profile: String,
+    #[serde(rename = "move", serialize_with = "serialize_dir")]
+    pub dir: Dir,
+    /// The profile's score for each move it considered, in its own
+    /// units. Empty for profiles that don't score moves.
+    pub scores: BTreeMap<&'static str, f64>,
+    pub win_probability: Option<f64>,
+    /// What the profile's search found, if it reports on it
+    pub search: Option<SearchReport>,
+    pub elapsed_us: u64,
+}
+
+fn serialize_dir<S: Serializer>(dir: &Dir, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(dir.as_move().dir)
+}
+
+impl MoveRecord {
+    pub fn new(
+        game_id: &str,
+        turn: u32,
+        profile: String,
+        dir: Dir,
+        scores: &[(Dir, f64)],
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            game_id: game_id.to_string(),
+            turn,
+            profile,
+            dir,
+            scores: scores
+                .iter()
+                .map(|(d, score)| (d.as_move().dir, *score))
+                .collect(),
+            win_probability: None,
+            search: None,
+            elapsed_us: elapsed.as_micros() as u64,
+        }
+    }
+
+    /// Logs the record as JSON fields or a line of text, depending on
+    /// the log format
+    pub fn log(&self) {
+        if !log::log_enabled!(target: MOVE_TARGET, Level::Info) {
+            return;
+        }
+
+        match config::get().log_format {
+            LogFormat::Json => match serde_json::to_string(self) {
+                Ok(fields) => info!(target: MOVE_TARGET, "{}", fields),
+                Err(_) => info!(target: MOVE_TARGET, "{}", self),
+            },
+            LogFormat::Text => info!(target: MOVE_TARGET, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for MoveRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Turn: {} Move: {:?} ({}, {} \u{b5}s)",
+            self.turn, self.dir, self.profile, self.elapsed_us
+        )?;
+
+        for (dir, score) in &self.scores {
+            write!(f, " {}={:.2}", dir, score)?;
+        }
+
+        if let Some(prob) = self.win_probability {
+            write!(f, " win={:.2}", prob)?;
+        }
+
+        if let Some(search) = &self.search {
+            write!(
+                f,
+                " depth={} nodes={} pv={}",
+                search.depth,
+                search.nodes,
+                search
+                    .pv
+                    .iter()
+                    .map(|d| d.as_move().dir)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_line() {
+        let mut record = MoveRecord::new(
+            "g1",
+            7,
+            String::from("Sim"),
+            Dir::Left,
+            &[(Dir::Left, 12.5), (Dir::Up, -3.0)],
+            Duration::from_micros(1500),
+        );
+        record.search = Some(SearchReport {
+            turn: 7,
+            depth: 4,
+            nodes: 900,
+            score: 25,
+            pv: vec![Dir::Left, Dir::Up],
+            enemies: vec![String::from("enemy")],
+        });
+        let fields = serde_json::to_string(&record).unwrap();
+
+        let tagged = scope("g1", 7);
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("{}", fields))
+                .level(Level::Info)
+                .target(MOVE_TARGET)
+                .build(),
+        );
+
+        assert_eq!(line["game_id"], "g1");
+        assert_eq!(line["turn"], 7);
+        assert_eq!(line["move"], "left");
+        assert_eq!(line["scores"]["left"], 12.5);
+        assert_eq!(line["elapsed_us"], 1500);
+        assert_eq!(line["search"]["pv"], json!(["left", "up"]));
+        assert!(line.get("msg").is_none());
+
+        // Other records keep their message, and the tags go with the
+        // scope
+        drop(tagged);
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(Level::Warn)
+                .target("battlesnake")
+                .build(),
+        );
+        assert_eq!(line["msg"], "hello");
+        assert_eq!(line["level"], "WARN");
+        assert!(line.get("game_id").is_none());
+    }
+}
@@ -16,93 +16,537 @@
  *
  */
 
+// Declared first so its macros are visible to the other modules
+#[macro_use]
+mod metrics;
+
 mod analytics;
+#[cfg(feature = "debug-endpoints")]
+mod arena;
+mod cache;
+mod config;
+mod constraints;
+mod food_map;
+#[cfg(all(test, feature = "rules-fuzz"))]
+mod fuzz;
 mod game;
+mod history;
+mod idle;
+mod logging;
+mod opponent_store;
+mod opponents;
+#[cfg(feature = "debug-endpoints")]
+mod play;
 mod profile;
+#[cfg(feature = "debug-endpoints")]
+mod replay;
 mod routes;
+mod schedule;
+#[cfg(any(feature = "recording", feature = "debug-endpoints"))]
+mod schema;
+mod sessions;
+mod shadow;
 mod simulator;
+mod warmup;
+#[cfg(feature = "debug-endpoints")]
+mod watch;
+mod watchdog;
 
-use log::{error, info};
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::env;
-use std::time::SystemTime;
-use tiny_http::{Response, Server};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
 
-use analytics::Analytics;
+use cache::CacheDir;
+use config::Config;
+use game::DEFAULT_TIMEOUT_MILLIS;
+use idle::IdleMonitor;
+use opponent_store::OpponentStore;
+use opponents::OpponentConfig;
+use schedule::TurnScheduler;
+use sessions::{GameSession, Sessions};
 
 #[allow(unused_imports)]
 use profile::{
     AStarBasic, Aggressive, AlphaBeta, Cautious, Follow, MonteCarlo, NotSuck,
-    Profile, Sim, Straight,
+    string_to_profile, Profile, Sim, Straight, TimeBudget,
 };
 
+/// Requests from the game engine, which keep the server out of idle
+/// mode. Monitoring endpoints don't count.
+const GAME_ROUTES: [&str; 3] = ["/start", "/move", "/end"];
+
+/// How often to check whether the server should go idle
+const IDLE_POLL: Duration = Duration::from_secs(30);
+
+/// Threads reading requests and writing responses. They never block,
+/// so a couple keep up with any number of games.
+const IO_THREADS: usize = 2;
+
+/// Makes the profile each game is played with, unless its /start
+/// request asks for another
+fn game_profile() -> Box<dyn Profile> {
+    string_to_profile(&config::get().profile)
+}
+
+/// Everything the worker threads share
+struct Shared {
+    /// The status of the profile games are played with
+    profile: String,
+    idle: IdleMonitor,
+    sessions: Sessions,
+    move_responses: routes::MoveResponses,
+    opponents: HashMap<String, OpponentConfig>,
+    opponent_store: Mutex<OpponentStore>,
+    #[cfg(feature = "debug-endpoints")]
+    watchers: watch::Watchers,
+}
+
 fn main() {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info");
+    let args: Vec<String> = env::args().collect();
+    let config = match Config::load(&args, &env::vars().collect()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    config::init(config);
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 4 && args[1] == "replay" && args[2] == "debug" {
+        replay::debug(&args[3]);
+        return;
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 4 && args[1] == "replay" && args[2] == "danger" {
+        replay::export_danger(&args[3]);
+        return;
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 7 && args[1] == "replay" && args[2] == "diff" {
+        replay::diff_corpus(&args[3], &args[4], &args[5], &args[6]);
+        return;
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 2 && args[1] == "arena" {
+        arena::run(&args[2..]);
+        return;
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 2 && args[1] == "play" {
+        play::play(&args[2..]);
+        return;
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 4 && args[1] == "evaluate-corpus" {
+        replay::evaluate_corpus(&args[2], &args[3]);
+        return;
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    if args.len() >= 3 && args[1] == "verify-corners" {
+        replay::verify_corners(&args[2]);
+        return;
     }
 
-    let port = match env::var("PORT") {
-        Ok(v) => v,
-        Err(_) => String::from("5000"),
+    let config = config::get();
+    logging::init(config);
+
+    if args.len() >= 2 && args[1] == "tune-mcts" {
+        profile::tuning::run();
+        return;
+    }
+
+    info!("Effective config:\n{}", config.dump());
+
+    let port = config.port;
+    let mut profile = game_profile();
+    let idle =
+        IdleMonitor::new(Duration::from_secs(config.idle_timeout_mins * 60));
+    idle.install(|| warmup::on_startup(&mut *profile));
+
+    let shared = Arc::new(Shared {
+        profile: profile.get_status(),
+        idle,
+        sessions: Sessions::new(game_profile),
+        move_responses: routes::MoveResponses::new(),
+        opponents: opponents::load_dir(Path::new(&config.opponents_dir)),
+        opponent_store: Mutex::new(OpponentStore::load(
+            &CacheDir::configured(),
+            Path::new(&config.opponent_store),
+        )),
+        #[cfg(feature = "debug-endpoints")]
+        watchers: watch::Watchers::new(),
+    });
+
+    let runtime = runtime(config.workers as usize);
+    runtime.spawn(check_idle(shared.clone()));
+
+    info!(
+        "Battlesnake server running on port {} with {} workers",
+        port, config.workers
+    );
+    info!("Profile set to {}", shared.profile);
+
+    let service = make_service_fn(move |_| {
+        let shared = shared.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                respond(shared.clone(), request)
+            }))
+        }
+    });
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    runtime.block_on(async {
+        if let Err(e) = Server::bind(&addr).serve(service).await {
+            error!("The server stopped: {}", e);
+        }
+    });
+}
+
+/// The runtime requests are served on. Its async threads only move
+/// bytes, while the handlers run on `workers` blocking threads, so a
+/// long search never holds up another game's I/O. Requests beyond
+/// that many wait their turn.
+fn runtime(workers: usize) -> Runtime {
+    let next = AtomicUsize::new(0);
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(IO_THREADS)
+        .max_blocking_threads(workers)
+        .thread_name_fn(move || {
+            format!("worker-{}", next.fetch_add(1, Ordering::Relaxed))
+        })
+        .enable_all()
+        .build()
+        .expect("Couldn't start the runtime")
+}
+
+/// Checks whether the server should go idle every `IDLE_POLL`
+async fn check_idle(shared: Arc<Shared>) {
+    let mut interval = tokio::time::interval(IDLE_POLL);
+
+    loop {
+        interval.tick().await;
+        let games = shared.sessions.len();
+        if shared.idle.check() && games > 0 {
+            info!("Dropping {} games that never ended", games);
+            shared.sessions.clear();
+            gauge!(ACTIVE_GAMES, 0.0);
+        }
+    }
+}
+
+/// What `handle` needs from a request, once its body has arrived
+struct Incoming {
+    url: String,
+    headers: HeaderMap,
+    content: String,
+    start: Instant,
+}
+
+/// Reads a request's body and hands it to `handle` on a blocking
+/// thread, answering as soon as it has a response
+async fn respond(
+    shared: Arc<Shared>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    count!(REQUESTS);
+
+    // Streams stay open, so they can't wait on a blocking thread
+    #[cfg(feature = "debug-endpoints")]
+    if request.uri().path() == "/watch" {
+        let game = routes::query_param(request.uri().query(), "game");
+        return Ok(shared.watchers.stream(game.map(String::from)));
+    }
+
+    let (parts, body) = request.into_parts();
+    let content = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) => {
+            error!("Error occurred while receiving a request: {}", e);
+            return Ok(empty(StatusCode::BAD_REQUEST));
+        }
     };
 
-    env_logger::init();
+    let incoming = Incoming {
+        url: parts
+            .uri
+            .path_and_query()
+            .map_or("/", |p| p.as_str())
+            .to_string(),
+        headers: parts.headers,
+        content,
+        start,
+    };
 
-    let server = Server::http(format!("0.0.0.0:{}", port)).unwrap();
-    let mut profile = MonteCarlo::new();
-    let mut alpha_beta = AlphaBeta::new();
-    let mut analytics_profiles = HashMap::<String, Analytics>::new();
+    // The handler keeps going after it sends the response, for the
+    // work done after a move
+    let (reply, response) = oneshot::channel();
+    tokio::task::spawn_blocking(move || handle(&shared, incoming, reply));
 
-    info!("Battlesnake server running on port {}", port);
-    info!("Profile set to {}", profile.get_status());
+    Ok(response.await.unwrap_or_else(|_| {
+        error!("A request handler panicked");
+        empty(StatusCode::INTERNAL_SERVER_ERROR)
+    }))
+}
 
-    for mut request in server.incoming_requests() {
-        let start_time = SystemTime::now();
-        let mut content = String::new();
-        request.as_reader().read_to_string(&mut content).unwrap();
+/// An empty response with the given status
+fn empty(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
 
-        let response;
+/// A successful response with the given body
+fn reply_with<B: Into<Body>>(body: B) -> Response<Body> {
+    Response::new(body.into())
+}
 
-        match request.url() {
-            "/start" => {
-                let res = routes::start_handler(
-                    &content,
-                    &mut profile,
-                    &mut analytics_profiles,
-                );
-                response = Response::from_string(res);
-            }
-            "/move" => {
-                let res = routes::move_handler(
-                    &content,
-                    &mut profile,
-                    &mut alpha_beta,
-                    &mut analytics_profiles,
-                );
-                response = Response::from_string(res);
+fn handle(
+    shared: &Shared,
+    incoming: Incoming,
+    reply: oneshot::Sender<Response<Body>>,
+) {
+    let Incoming {
+        url,
+        headers,
+        content,
+        start,
+    } = incoming;
+    let (path, query) = routes::split_url(&url);
+
+    // Game requests are parsed once, here, and everything that handles
+    // them reads the parsed request
+    let request = if GAME_ROUTES.contains(&path) {
+        match routes::parse_request(&content) {
+            Ok(request) => Some(request),
+            Err(_) => {
+                let _ = reply.send(empty(StatusCode::BAD_REQUEST));
+                return;
             }
-            "/end" => {
-                info!("End of game");
-                routes::end_handler(&content, &mut analytics_profiles);
-                response = Response::from_string("OK");
+        }
+    } else {
+        None
+    };
+
+    let timeout = request
+        .as_ref()
+        .and_then(|r| r.game.timeout)
+        .map_or(DEFAULT_TIMEOUT_MILLIS, u64::from);
+    let mut schedule =
+        TurnScheduler::new(TimeBudget::for_timeout(start, timeout));
+
+    let mut response;
+    let mut decision = None;
+    let mut started = false;
+
+    let _tags = request.as_ref().map(|r| logging::scope(&r.game.id, r.turn));
+
+    // Game requests hold their game's session until all the work for
+    // them is done, so one game's requests never overlap
+    let session = request.as_ref().map(|r| {
+        shared.idle.touch();
+
+        let session = match path {
+            "/end" => shared.sessions.remove(&r.game.id),
+            _ => Some(shared.sessions.get(&r.game.id)),
+        };
+
+        // The end of a game we don't know still goes to its handler
+        session.unwrap_or_else(|| shared.sessions.detached())
+    });
+    let mut game = session.as_ref().map(|s| sessions::lock(s));
+
+    match (path, game.as_deref_mut().zip(request.as_ref())) {
+        ("/", _) => {
+            response = reply_with(routes::info_handler());
+        }
+        ("/start", Some((game, request))) => {
+            let res = routes::start_handler(
+                &content,
+                request,
+                routes::requested_profile(query),
+                &mut game.profile,
+                &mut game.analytics,
+                &shared.opponents,
+                &sessions::lock(&shared.opponent_store),
+            );
+            match res {
+                Ok(res) => {
+                    response = reply_with(res);
+                    started = true;
+                }
+                Err(e) => {
+                    warn!("Rejecting /start: {}", e);
+                    response = empty(StatusCode::BAD_REQUEST);
+                }
             }
-            _ => {
-                response = Response::from_string("OK");
+        }
+        ("/move", Some((game, request))) => {
+            let GameSession {
+                profile,
+                alpha_beta,
+                analytics,
+                latency,
+                watchdog,
+            } = game;
+            latency.observe(request);
+            schedule = TurnScheduler::new(latency.budget(start, timeout));
+            decision = routes::move_handler(
+                request,
+                profile,
+                alpha_beta,
+                analytics,
+                watchdog,
+                &schedule,
+                &shared.idle,
+            );
+            response = match &decision {
+                Some(d) => {
+                    let body = match routes::response_tail(
+                        &headers,
+                        &**profile,
+                        schedule.budget(),
+                    ) {
+                        Some(tail) => {
+                            shared.move_responses.with_tail(d.dir, &tail)
+                        }
+                        None => shared.move_responses.get(d.dir).to_vec(),
+                    };
+                    reply_with(body)
+                }
+                None => empty(StatusCode::BAD_REQUEST),
+            };
+        }
+        ("/end", Some((game, request))) => {
+            info!("End of game");
+            routes::end_handler(
+                request,
+                &mut game.analytics,
+                &mut sessions::lock(&shared.opponent_store),
+            );
+            response = reply_with("OK");
+        }
+        ("/metrics", _) => {
+            response = reply_with(metrics::render(&metrics::snapshot()));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(metrics::CONTENT_TYPE),
+            );
+        }
+        ("/stats", _) => {
+            response = reply_with(routes::stats_handler(
+                &sessions::lock(&shared.opponent_store),
+            ));
+        }
+        #[cfg(feature = "debug-endpoints")]
+        ("/danger", _) => {
+            response = reply_with(routes::danger_handler(&content));
+        }
+        #[cfg(feature = "debug-endpoints")]
+        ("/analyze", _) => {
+            let res = shared
+                .idle
+                .install(|| routes::analyze_handler(&content, query));
+            response = reply_with(res);
+        }
+        ("/ping", _) | ("/healthz", _) => {
+            let games = shared.sessions.len();
+            let (ok, res) = shared
+                .idle
+                .install(|| routes::ping_handler(&mut *game_profile(), games));
+            response = reply_with(res);
+            if !ok {
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
             }
         }
+        ("/status", _) => {
+            response = reply_with(routes::status_handler(
+                &shared.profile,
+                &shared.sessions.ids(),
+                &shared.idle,
+            ));
+        }
+        _ => {
+            response = reply_with("OK");
+        }
+    }
 
-        match request.respond(response) {
-            Ok(_) => {
-                let end_time = start_time.elapsed().unwrap();
+    match reply.send(response) {
+        Ok(_) => {
+            let end_time = start.elapsed();
+            if decision.is_some() {
+                // The move record logs the time along with the move
+                count!(MOVES);
+                observe!(MOVE_MILLIS, end_time.as_secs_f64() * 1000.0);
+                gauge!(LAST_MOVE_MILLIS, end_time.as_secs_f64() * 1000.0);
+                if end_time.as_millis() >= u128::from(timeout) {
+                    count!(TIMEOUTS);
+                }
+            } else {
                 info!(
                     "{} \u{b5}s {} ms",
                     end_time.as_micros(),
                     end_time.as_millis()
                 );
             }
-            Err(e) => {
-                error!("Error occurred while responding to request: {}", e);
-            }
+        }
+        Err(_) => {
+            error!("The request was dropped before it could be answered");
+        }
+    }
+
+    if started {
+        let parsed = request.as_ref().map(routes::request_state);
+        if let Some(Ok((you, st))) = parsed {
+            shared.idle.install(|| warmup::on_game_start(&you, &st));
         }
     }
+
+    if let (Some(decision), Some(game)) = (decision, game.as_deref_mut()) {
+        let elapsed = start.elapsed();
+        game.latency.responded(elapsed);
+
+        #[cfg(feature = "debug-endpoints")]
+        if let Some(request) = &request {
+            shared.watchers.publish(
+                &request.game.id,
+                request.turn,
+                decision.dir,
+                game.profile.win_probability(),
+            );
+        }
+
+        routes::after_move(
+            &content,
+            &decision,
+            &*game.profile,
+            &mut game.analytics,
+            &schedule,
+            elapsed,
+        );
+    }
+
+    drop(game);
+    gauge!(ACTIVE_GAMES, shared.sessions.len() as f64);
 }
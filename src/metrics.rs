@@ -0,0 +1,385 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Process wide metrics. Every metric is a static backed by atomics,
+//! so it can be updated from rayon workers in the middle of a search
+//! without taking a lock. `snapshot` reads them all for the
+//! `/metrics` and `/status` endpoints, and `render` formats them in
+//! the Prometheus text format for scraping.
+//!
+//! Instrumentation points use the `count!`, `gauge!` and `observe!`
+//! macros with the name of one of the statics below. A new metric
+//! must also be added to `REGISTRY` to show up in snapshots.
+
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Histograms can have at most this many buckets, including the
+/// overflow bucket
+const MAX_BUCKETS: usize = 12;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// Adds to a counter, by one if no amount is given
+macro_rules! count {
+    ($metric:ident) => {
+        $crate::metrics::$metric.add(1)
+    };
+    ($metric:ident, $n:expr) => {
+        $crate::metrics::$metric.add($n)
+    };
+}
+
+/// Sets a gauge
+macro_rules! gauge {
+    ($metric:ident, $v:expr) => {
+        $crate::metrics::$metric.set($v)
+    };
+}
+
+/// Records a value in a histogram
+macro_rules! observe {
+    ($metric:ident, $v:expr) => {
+        $crate::metrics::$metric.observe($v)
+    };
+}
+
+/// A value that only goes up
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: ZERO,
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can be set to anything
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    /// The bits of an f64
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: ZERO,
+        }
+    }
+
+    pub fn set(&self, v: f64) {
+        self.value.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.value.load(Ordering::Relaxed))
+    }
+}
+
+/// Counts observed values in buckets. A value goes in the first
+/// bucket whose upper bound it doesn't exceed, or the overflow bucket
+/// past the last bound.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    buckets: [AtomicU64; MAX_BUCKETS],
+    count: AtomicU64,
+    /// The bits of an f64
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        bounds: &'static [f64],
+    ) -> Self {
+        assert!(bounds.len() < MAX_BUCKETS);
+
+        Self {
+            name,
+            help,
+            bounds,
+            buckets: [ZERO; MAX_BUCKETS],
+            count: ZERO,
+            sum: ZERO,
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let idx = self
+            .bounds
+            .iter()
+            .position(|b| v <= *b)
+            .unwrap_or(self.bounds.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+
+        let mut sum = self.sum.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(sum) + v).to_bits();
+            match self.sum.compare_exchange_weak(
+                sum,
+                new,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => sum = current,
+            }
+        }
+    }
+
+    fn sample(&self) -> SampleValue {
+        let buckets = self
+            .bounds
+            .iter()
+            .cloned()
+            .chain(Some(f64::INFINITY))
+            .zip(self.buckets.iter())
+            .map(|(bound, n)| (bound, n.load(Ordering::Relaxed)))
+            .collect();
+
+        SampleValue::Histogram {
+            count: self.count.load(Ordering::Relaxed),
+            sum: f64::from_bits(self.sum.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+pub enum Metric {
+    Counter(&'static Counter),
+    Gauge(&'static Gauge),
+    Histogram(&'static Histogram),
+}
+
+impl Metric {
+    fn sample(&self) -> Sample {
+        let (name, help, value) = match self {
+            Metric::Counter(c) => {
+                (c.name, c.help, SampleValue::Counter(c.get()))
+            }
+            Metric::Gauge(g) => (g.name, g.help, SampleValue::Gauge(g.get())),
+            Metric::Histogram(h) => (h.name, h.help, h.sample()),
+        };
+
+        Sample { name, help, value }
+    }
+}
+
+/// The value of a metric when the snapshot was taken
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SampleValue {
+    Counter(u64),
+    Gauge(f64),
+    /// Bucket counts are per bucket, not cumulative. The last bucket's
+    /// bound is infinite, which is null in JSON.
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<(f64, u64)>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: SampleValue,
+}
+
+pub static REQUESTS: Counter =
+    Counter::new("requests", "Requests received by the server");
+pub static MOVES: Counter = Counter::new("moves", "Moves played");
+pub static MOVE_MILLIS: Histogram = Histogram::new(
+    "move_millis",
+    "Time taken to respond to a move request",
+    &[10.0, 25.0, 50.0, 100.0, 200.0, 300.0, 400.0, 450.0, 500.0],
+);
+pub static LAST_MOVE_MILLIS: Gauge = Gauge::new(
+    "last_move_millis",
+    "Time taken to respond to the last move request",
+);
+pub static TIMEOUTS: Counter = Counter::new(
+    "timeouts",
+    "Move responses sent after the game's move timeout",
+);
+pub static FALLBACK_MOVES: Counter = Counter::new(
+    "fallback_moves",
+    "Moves played because the search panicked or missed the watchdog",
+);
+pub static WINS: Counter = Counter::new("wins", "Games we survived");
+pub static LOSSES: Counter = Counter::new("losses", "Games we died in");
+pub static SIM_STEPS: Counter =
+    Counter::new("sim_steps", "Turns simulated by Sim branches");
+pub static SIM_BRANCHES: Counter = Counter::new(
+    "sim_branches",
+    "Sim branches simulated until we died or the game ended",
+);
+pub static MCTS_ROLLOUTS: Histogram = Histogram::new(
+    "mcts_rollouts",
+    "Rollouts run by MonteCarlo for each move",
+    &[
+        100.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 25000.0, 50000.0,
+        100000.0,
+    ],
+);
+pub static ACTIVE_GAMES: Gauge =
+    Gauge::new("active_games", "Games currently being played");
+
+static REGISTRY: [Metric; 12] = [
+    Metric::Counter(&REQUESTS),
+    Metric::Counter(&MOVES),
+    Metric::Histogram(&MOVE_MILLIS),
+    Metric::Gauge(&LAST_MOVE_MILLIS),
+    Metric::Counter(&TIMEOUTS),
+    Metric::Counter(&FALLBACK_MOVES),
+    Metric::Counter(&WINS),
+    Metric::Counter(&LOSSES),
+    Metric::Counter(&SIM_STEPS),
+    Metric::Counter(&SIM_BRANCHES),
+    Metric::Histogram(&MCTS_ROLLOUTS),
+    Metric::Gauge(&ACTIVE_GAMES),
+];
+
+/// Prefixed to every metric's name in the Prometheus format
+const PREFIX: &str = "battlesnake_";
+
+/// The content type of `render`'s output
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Reads every registered metric. Each metric is read atomically, but
+/// the snapshot as a whole isn't, so metrics updated mid-snapshot may
+/// be a little out of step with each other.
+pub fn snapshot() -> Vec<Sample> {
+    REGISTRY.iter().map(Metric::sample).collect()
+}
+
+/// Formats a bucket bound, with the overflow bucket as `+Inf`
+fn bound_label(bound: f64) -> String {
+    if bound.is_infinite() {
+        String::from("+Inf")
+    } else {
+        bound.to_string()
+    }
+}
+
+/// Formats a snapshot in the Prometheus text format. Histogram buckets
+/// are cumulative there, unlike in the snapshot.
+pub fn render(samples: &[Sample]) -> String {
+    let mut out = String::new();
+
+    for sample in samples {
+        let name = format!("{}{}", PREFIX, sample.name);
+        let kind = match sample.value {
+            SampleValue::Counter(_) => "counter",
+            SampleValue::Gauge(_) => "gauge",
+            SampleValue::Histogram { .. } => "histogram",
+        };
+        out.push_str(&format!("# HELP {} {}\n", name, sample.help));
+        out.push_str(&format!("# TYPE {} {}\n", name, kind));
+
+        match &sample.value {
+            SampleValue::Counter(v) => {
+                out.push_str(&format!("{} {}\n", name, v));
+            }
+            SampleValue::Gauge(v) => {
+                out.push_str(&format!("{} {}\n", name, v));
+            }
+            SampleValue::Histogram {
+                count,
+                sum,
+                buckets,
+            } => {
+                let mut total = 0;
+                for (bound, n) in buckets {
+                    total += n;
+                    out.push_str(&format!(
+                        "{}_bucket{{le=\"{}\"}} {}\n",
+                        name,
+                        bound_label(*bound),
+                        total
+                    ));
+                }
+                out.push_str(&format!("{}_count {}\n", name, count));
+                out.push_str(&format!("{}_sum {}\n", name, sum));
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    static TEST_COUNTER: Counter = Counter::new("test", "Test counter");
+    static TEST_HISTOGRAM: Histogram =
+        Histogram::new("test_hist", "Test histogram", &[1.0, 10.0]);
+
+    #[test]
+    fn test_metrics() {
+        (0..1000).into_par_iter().for_each(|i| {
+            TEST_COUNTER.add(1);
+            TEST_HISTOGRAM.observe(f64::from(i % 20));
+        });
+        assert_eq!(TEST_COUNTER.get(), 1000);
+
+        let sample = Metric::Histogram(&TEST_HISTOGRAM).sample();
+        let expected = SampleValue::Histogram {
+            count: 1000,
+            sum: 50.0 * (0..20).sum::<i32>() as f64,
+            buckets: vec![(1.0, 100), (10.0, 450), (f64::INFINITY, 450)],
+        };
+        assert_eq!(sample.value, expected);
+
+        // Rendered for Prometheus cumulative buckets
+        let text = render(&[sample]);
+        assert!(text.contains("# TYPE battlesnake_test_hist histogram\n"));
+        assert!(text.contains("test_hist_bucket{le=\"10\"} 550\n"));
+        assert!(text.contains("test_hist_bucket{le=\"+Inf\"} 1000\n"));
+        assert!(text.contains("battlesnake_test_hist_count 1000\n"));
+
+        assert_eq!(snapshot().len(), REGISTRY.len());
+    }
+}
@@ -0,0 +1,436 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The cross-game opponent store. Everything we learn about opponents
+//! and our own results is saved to the cache directory so it survives
+//! restarts and upgrades of the snake. The profile each opponent was
+//! matched to most is the prior for their next game.
+//!
+//! Older versions of the store are migrated on load. A store that
+//! can't be read is quarantined by the cache and an empty store is
+//! used instead. A store saved by releases from before the cache is
+//! imported into it once.
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::cache::{self, CacheDir};
+use super::simulator::DeathCause;
+
+/// The store's schema in the cache directory
+const STORE_SCHEMA: &str = "opponent_store";
+
+/// The current version of the store's file format
+pub const STORE_VERSION: u64 = 4;
+
+/// The oldest version of the store's file format that was released
+const OLDEST_VERSION: u64 = 2;
+
+/// What we know about a single opponent, keyed by snake name
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct OpponentRecord {
+    /// Games played against this opponent
+    pub games: u32,
+    /// Games this opponent outlived us in
+    pub losses: u32,
+    /// How many games the analyzer matched each profile to them
+    pub profile_matches: HashMap<String, u32>,
+    /// How they were eliminated, in the games we saw them die
+    pub deaths: HashMap<DeathCause, u32>,
+    /// Games we eliminated them in
+    pub kills: u32,
+}
+
+/// Statistics about our own games
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct MetaStats {
+    pub games: u32,
+    pub wins: u32,
+    pub total_turns: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpponentStore {
+    pub version: u64,
+    pub opponents: HashMap<String, OpponentRecord>,
+    pub meta: MetaStats,
+}
+
+impl Default for OpponentStore {
+    fn default() -> Self {
+        Self {
+            version: STORE_VERSION,
+            opponents: HashMap::new(),
+            meta: MetaStats::default(),
+        }
+    }
+}
+
+/// Upgrades the raw JSON of a store to the current version, one
+/// version at a time
+fn migrate(mut json: Value) -> Result<Value, String> {
+    let mut version = json
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| String::from("Missing schema version"))?;
+
+    if !(OLDEST_VERSION..=STORE_VERSION).contains(&version) {
+        return Err(format!("Unknown schema version {}", version));
+    }
+
+    while version < STORE_VERSION {
+        match version {
+            // Version 2 didn't record how opponents died
+            2 => {
+                let opponents = json
+                    .get_mut("opponents")
+                    .and_then(Value::as_object_mut)
+                    .ok_or_else(|| String::from("Missing opponents"))?;
+
+                for record in opponents.values_mut() {
+                    if let Some(r) = record.as_object_mut() {
+                        r.insert(
+                            String::from("deaths"),
+                            Value::Object(Default::default()),
+                        );
+                    }
+                }
+            }
+            // Version 3 only told our kills from enemies' kills, not
+            // how either happened
+            3 => {
+                let opponents = json
+                    .get_mut("opponents")
+                    .and_then(Value::as_object_mut)
+                    .ok_or_else(|| String::from("Missing opponents"))?;
+
+                for record in opponents.values_mut() {
+                    let deaths = record
+                        .get_mut("deaths")
+                        .and_then(Value::as_object_mut)
+                        .ok_or_else(|| String::from("Missing deaths"))?;
+
+                    let count = |v: Option<Value>| {
+                        v.as_ref().and_then(Value::as_u64).unwrap_or(0)
+                    };
+                    let ours = count(deaths.remove("our_kill"));
+                    let kills = ours + count(deaths.remove("enemy_kill"));
+                    if kills > 0 {
+                        let unknown = count(deaths.remove("unknown"));
+                        deaths.insert(
+                            String::from("unknown"),
+                            Value::from(unknown + kills),
+                        );
+                    }
+
+                    record["kills"] = Value::from(ours);
+                }
+            }
+            _ => return Err(format!("No migration from version {}", version)),
+        }
+
+        version += 1;
+        json["version"] = Value::from(version);
+    }
+
+    Ok(json)
+}
+
+impl OpponentStore {
+    /// Loads the store from the cache, migrating an older version of
+    /// it. With nothing in the cache, the store saved at `legacy` by
+    /// older releases is imported. A missing or unreadable store gives
+    /// an empty one.
+    pub fn load(cache: &CacheDir, legacy: &Path) -> Self {
+        let (version, json) = match cache.load_raw(STORE_SCHEMA, STORE_VERSION)
+        {
+            Some(loaded) => loaded,
+            None => return Self::import(cache, legacy),
+        };
+
+        match Self::parse(json) {
+            Ok(store) => {
+                info!(
+                    "Loaded opponent store with {} opponents",
+                    store.opponents.len()
+                );
+                store
+            }
+            Err(e) => {
+                cache::quarantine(&cache.path(STORE_SCHEMA, version), &e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Migrates and converts the raw JSON of a store
+    fn parse(json: Value) -> Result<Self, String> {
+        migrate(json).and_then(|json| {
+            serde_json::from_value::<Self>(json).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Imports the store older releases saved to a plain JSON file,
+    /// moving the file aside once the cache has a copy
+    fn import(cache: &CacheDir, legacy: &Path) -> Self {
+        let contents = match fs::read_to_string(legacy) {
+            Ok(c) => c,
+            Err(_) => return Self::default(),
+        };
+
+        let result = serde_json::from_str::<Value>(&contents)
+            .map_err(|e| e.to_string())
+            .and_then(Self::parse);
+
+        let store = match result {
+            Ok(store) => store,
+            Err(e) => {
+                cache::quarantine(legacy, &e);
+                return Self::default();
+            }
+        };
+
+        info!(
+            "Imported {} opponents from {}",
+            store.opponents.len(),
+            legacy.display()
+        );
+
+        let imported = PathBuf::from(format!("{}.imported", legacy.display()));
+        let moved = store.save(cache).and_then(|_| {
+            fs::rename(legacy, &imported).map_err(|e| e.to_string())
+        });
+        if let Err(e) = moved {
+            warn!("Couldn't finish importing {}: {}", legacy.display(), e);
+        }
+
+        store
+    }
+
+    /// Saves the store to the cache
+    pub fn save(&self, cache: &CacheDir) -> Result<(), String> {
+        cache.save(STORE_SCHEMA, STORE_VERSION, self)
+    }
+
+    /// Records the result of a finished game. `matches` and `deaths`
+    /// are keyed by opponent name, and `deaths` has whether we were
+    /// the one that killed them.
+    pub fn record_game(
+        &mut self,
+        turns: u32,
+        won: bool,
+        opponents: &[(String, bool)],
+        matches: &HashMap<String, String>,
+        deaths: &HashMap<String, (DeathCause, bool)>,
+    ) {
+        self.meta.games += 1;
+        self.meta.total_turns += u64::from(turns);
+        if won {
+            self.meta.wins += 1;
+        }
+
+        for (name, outlived_us) in opponents {
+            let record = self.opponents.entry(name.clone()).or_default();
+            record.games += 1;
+            if *outlived_us {
+                record.losses += 1;
+            }
+
+            if let Some(profile) = matches.get(name) {
+                *record.profile_matches.entry(profile.clone()).or_insert(0) +=
+                    1;
+            }
+
+            if let Some((cause, ours)) = deaths.get(name) {
+                *record.deaths.entry(*cause).or_insert(0) += 1;
+                if *ours {
+                    record.kills += 1;
+                }
+            }
+        }
+    }
+
+    /// The profile the analyzer matched an opponent to in the most
+    /// games, ties going to the first by name
+    pub fn likely_profile(&self, name: &str) -> Option<&str> {
+        let record = self.opponents.get(name)?;
+        record
+            .profile_matches
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(profile, _)| profile.as_str())
+    }
+
+    /// Opponent deaths by cause, over every opponent
+    pub fn deaths_by_cause(&self) -> HashMap<DeathCause, u32> {
+        let mut totals = HashMap::new();
+        for record in self.opponents.values() {
+            for (cause, n) in &record.deaths {
+                *totals.entry(*cause).or_insert(0) += n;
+            }
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::{env, fs};
+
+    fn temp_cache(name: &str) -> (CacheDir, PathBuf) {
+        let root = env::temp_dir().join(format!(
+            "battlesnake-store-{}-{}",
+            name,
+            std::process::id()
+        ));
+        (CacheDir::new(&root), root)
+    }
+
+    #[test]
+    fn test_migrate_v2() {
+        let (cache, root) = temp_cache("v2");
+        // Written by the first release of the store
+        let v2 = json!({
+            "version": 2,
+            "opponents": {
+                "snek": {
+                    "games": 3,
+                    "losses": 1,
+                    "profile_matches": {"cautious": 2, "aggressive": 1}
+                }
+            },
+            "meta": {"games": 3, "wins": 2, "total_turns": 410}
+        });
+        cache.save(STORE_SCHEMA, 2, &v2).unwrap();
+
+        let store = OpponentStore::load(&cache, &root.join("none.json"));
+        assert_eq!(store.version, STORE_VERSION);
+        assert_eq!(store.opponents["snek"].games, 3);
+        assert!(store.opponents["snek"].deaths.is_empty());
+        assert_eq!(store.meta.total_turns, 410);
+        assert_eq!(store.likely_profile("snek"), Some("cautious"));
+        assert_eq!(store.likely_profile("stranger"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_v3() {
+        let (cache, root) = temp_cache("v3");
+        let v3 = json!({
+            "version": 3,
+            "opponents": {
+                "snek": {
+                    "games": 4,
+                    "losses": 0,
+                    "profile_matches": {},
+                    "deaths": {"our_kill": 2, "enemy_kill": 1, "wall": 1}
+                }
+            },
+            "meta": {"games": 4, "wins": 4, "total_turns": 600}
+        });
+        cache.save(STORE_SCHEMA, 3, &v3).unwrap();
+
+        // Kills keep who made them, but not how
+        let store = OpponentStore::load(&cache, &root.join("none.json"));
+        let snek = &store.opponents["snek"];
+        assert_eq!(snek.kills, 2);
+        assert_eq!(snek.deaths[&DeathCause::Unknown], 3);
+        assert_eq!(snek.deaths[&DeathCause::Wall], 1);
+        assert_eq!(snek.deaths.len(), 2);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_quarantine_corrupt() {
+        let (cache, root) = temp_cache("corrupt");
+        let bad = json!({"version": 2, "opponents": 7});
+        cache.save(STORE_SCHEMA, 2, &bad).unwrap();
+
+        let store = OpponentStore::load(&cache, &root.join("none.json"));
+        assert_eq!(store, OpponentStore::default());
+        assert!(!cache.path(STORE_SCHEMA, 2).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let (cache, root) = temp_cache("round-trip");
+        let mut store = OpponentStore::default();
+        let mut matches = HashMap::new();
+        matches.insert(String::from("snek"), String::from("cautious"));
+
+        let mut deaths = HashMap::new();
+        deaths.insert(String::from("snek"), (DeathCause::HeadOn, true));
+
+        store.record_game(
+            120,
+            false,
+            &[(String::from("snek"), true)],
+            &matches,
+            &deaths,
+        );
+        store.save(&cache).unwrap();
+
+        let loaded = OpponentStore::load(&cache, &root.join("none.json"));
+        assert_eq!(loaded, store);
+        assert_eq!(loaded.opponents["snek"].profile_matches["cautious"], 1);
+        assert_eq!(loaded.deaths_by_cause()[&DeathCause::HeadOn], 1);
+        assert_eq!(loaded.opponents["snek"].kills, 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_import_legacy() {
+        let (cache, root) = temp_cache("legacy");
+        fs::create_dir_all(&root).unwrap();
+        let legacy = root.join("opponent_store.json");
+        let old = json!({
+            "version": 3,
+            "opponents": {
+                "snek": {
+                    "games": 4,
+                    "losses": 2,
+                    "profile_matches": {},
+                    "deaths": {}
+                }
+            },
+            "meta": {"games": 4, "wins": 2, "total_turns": 300}
+        });
+        fs::write(&legacy, old.to_string()).unwrap();
+
+        let store = OpponentStore::load(&cache, &legacy);
+        assert_eq!(store.opponents["snek"].losses, 2);
+        assert!(!legacy.exists());
+        assert!(root.join("opponent_store.json.imported").exists());
+
+        // Only the first load imports it
+        fs::write(&legacy, json!({"version": 3}).to_string()).unwrap();
+        assert_eq!(OpponentStore::load(&cache, &legacy), store);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
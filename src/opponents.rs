@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Hand-written counter-profiles for known opponents. Each opponent
+//! gets a TOML file in the opponents directory, for example:
+//!
+//! ```toml
+//! name = "Some Snake"
+//! profile = "monte_carlo"
+//! weight_preset = "small"
+//! risk_posture = "cautious"
+//! model = "cautious"
+//! quirks = ["never_contests_food", "tail_chases_when_longer"]
+//! ```
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// How much risk we are willing to take against an opponent
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskPosture {
+    Cautious,
+    Neutral,
+    Aggressive,
+}
+
+/// Known behavioural quirks of an opponent
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quirk {
+    NeverContestsFood,
+    TailChasesWhenLonger,
+    ChasesFood,
+    ChasesHeads,
+}
+
+impl Quirk {
+    /// The profile that best imitates an opponent with this quirk
+    pub fn as_profile(self) -> &'static str {
+        match self {
+            Quirk::NeverContestsFood => "cautious",
+            Quirk::TailChasesWhenLonger => "cautious",
+            Quirk::ChasesFood => "astarbasic",
+            Quirk::ChasesHeads => "aggressive",
+        }
+    }
+}
+
+/// The overrides for a single known opponent
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OpponentConfig {
+    /// The opponent's snake name, as sent by the engine
+    pub name: String,
+    /// The profile we should play with against this opponent, unless
+    /// the /start request asks for one
+    pub profile: Option<String>,
+    /// The name of the Sim weight preset to use against this opponent,
+    /// see `profile::Weights`
+    pub weight_preset: Option<String>,
+    /// How much risk we should take against this opponent
+    pub risk_posture: Option<RiskPosture>,
+    /// The profile used to predict this opponent's moves
+    pub model: Option<String>,
+    #[serde(default)]
+    pub quirks: Vec<Quirk>,
+}
+
+impl OpponentConfig {
+    /// The profile used to predict the opponent's moves. An explicit
+    /// model takes priority over one implied by the quirks.
+    pub fn model_profile(&self) -> Option<String> {
+        self.model
+            .clone()
+            .or_else(|| self.quirks.first().map(|q| q.as_profile().to_string()))
+    }
+}
+
+/// Loads every `*.toml` file in `dir`, keyed by opponent name. Files
+/// that cannot be read or parsed are skipped with a warning.
+pub fn load_dir(dir: &Path) -> HashMap<String, OpponentConfig> {
+    let mut configs = HashMap::new();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => {
+            info!("No opponent configs found in {}", dir.display());
+            return configs;
+        }
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+
+        match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| {
+                toml::from_str::<OpponentConfig>(&s).map_err(|e| e.to_string())
+            }) {
+            Ok(config) => {
+                info!("Loaded opponent config for {}", config.name);
+                configs.insert(config.name.clone(), config);
+            }
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = toml::from_str::<OpponentConfig>(
+            r#"
+            name = "Some Snake"
+            risk_posture = "cautious"
+            quirks = ["tail_chases_when_longer", "chases_heads"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.name, "Some Snake");
+        assert_eq!(config.profile, None);
+        assert_eq!(config.risk_posture, Some(RiskPosture::Cautious));
+        assert_eq!(config.model_profile(), Some(String::from("cautious")));
+
+        let config = toml::from_str::<OpponentConfig>(
+            r#"
+            name = "Other Snake"
+            model = "astarbasic"
+            quirks = ["chases_heads"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.quirks, vec![Quirk::ChasesHeads]);
+        assert_eq!(config.model_profile(), Some(String::from("astarbasic")));
+    }
+}
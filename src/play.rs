@@ -0,0 +1,244 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Local games through the official `battlesnake` CLI. The `play`
+//! command starts our server, runs `battlesnake play` against the
+//! configured opponents, and converts the CLI's game export into a
+//! replay that the replay debugger can open.
+//!
+//! The CLI's export puts the origin in the bottom left corner, so
+//! every point is flipped to our top left origin on the way in.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config;
+
+const CLI: &str = "battlesnake";
+const OUR_NAME: &str = "battlesnake-2020";
+
+/// Parses the `play_opponents` setting, a comma separated list of
+/// `name=url` pairs
+fn parse_opponents(raw: &str) -> Result<Vec<(String, String)>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(|o| match o.find('=') {
+            Some(i) => Ok((o[..i].to_string(), o[i + 1..].to_string())),
+            None => Err(format!("Invalid opponent {}, expected name=url", o)),
+        })
+        .collect()
+}
+
+fn flip_point(p: &Value, height: i64) -> Value {
+    json!({
+        "x": p["x"],
+        "y": height - 1 - p["y"].as_i64().unwrap_or(0),
+    })
+}
+
+fn flip_points(points: &Value, height: i64) -> Value {
+    Value::Array(
+        points
+            .as_array()
+            .map(|ps| ps.iter().map(|p| flip_point(p, height)).collect())
+            .unwrap_or_default(),
+    )
+}
+
+fn convert_snake(snake: &Value, height: i64) -> Value {
+    json!({
+        "id": snake["id"],
+        "name": snake["name"],
+        "health": snake["health"],
+        "body": flip_points(&snake["body"], height),
+    })
+}
+
+/// Converts one frame of a CLI game export into the request body our
+/// server would have received on that turn
+pub fn convert_frame(frame: &Value, our_name: &str) -> Option<Value> {
+    let board = frame.get("board")?;
+    let height = board["height"].as_i64()?;
+
+    let snakes = board["snakes"]
+        .as_array()?
+        .iter()
+        .map(|s| convert_snake(s, height))
+        .collect::<Vec<Value>>();
+    let you = snakes.iter().find(|s| s["name"] == our_name)?.clone();
+
+    Some(json!({
+        "game": { "id": frame["game"]["id"] },
+        "turn": frame["turn"],
+        "board": {
+            "height": height,
+            "width": board["width"],
+            "food": flip_points(&board["food"], height),
+            "snakes": snakes,
+        },
+        "you": you,
+    }))
+}
+
+/// Converts a CLI game export into a replay, returning the replay's
+/// contents and the id of the game. Frames after our snake was
+/// eliminated are dropped since we never saw them.
+pub fn convert_export(
+    export: &str,
+    our_name: &str,
+) -> Result<(String, String), String> {
+    let mut replay = String::new();
+    let mut id = None;
+
+    for line in export.lines().filter(|l| !l.trim().is_empty()) {
+        let frame = serde_json::from_str::<Value>(line)
+            .map_err(|e| format!("Invalid game export: {}", e))?;
+
+        if let Some(request) = convert_frame(&frame, our_name) {
+            id = request["game"]["id"].as_str().map(String::from);
+            replay.push_str(&request.to_string());
+            replay.push('\n');
+        }
+    }
+
+    match id {
+        Some(id) => Ok((replay, id)),
+        None => Err(format!("No frames with {} in the game export", our_name)),
+    }
+}
+
+/// Plays a local game with the official CLI and saves it as a replay.
+/// `server_args` are passed to the server we start, so it runs with
+/// the same config as this command.
+pub fn play(server_args: &[String]) {
+    let config = config::get();
+
+    let opponents = match parse_opponents(&config.play_opponents) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    if Command::new(CLI).arg("--help").output().is_err() {
+        eprintln!("The `{}` CLI wasn't found on PATH", CLI);
+        return;
+    }
+
+    let exe = env::current_exe().expect("Couldn't find our own binary");
+    let mut server = match Command::new(exe)
+        .args(server_args)
+        .stdout(Stdio::null())
+        .spawn()
+    {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Couldn't start the server: {}", e);
+            return;
+        }
+    };
+
+    // Give the server a moment to bind its port
+    thread::sleep(Duration::from_millis(500));
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let export_path = format!("{}/play-{}.jsonl", config.samples_dir, secs);
+
+    let mut cli = Command::new(CLI);
+    cli.arg("play")
+        .args(["-W", &config.play_width.to_string()])
+        .args(["-H", &config.play_height.to_string()])
+        .args(["--name", OUR_NAME])
+        .args(["--url", &format!("http://localhost:{}", config.port)])
+        .args(["--output", &export_path]);
+
+    for (name, url) in &opponents {
+        cli.args(["--name", name, "--url", url]);
+    }
+
+    let status = cli.status();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    match status {
+        Ok(s) if s.success() => {}
+        Ok(s) => {
+            eprintln!("`{} play` failed with {}", CLI, s);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Couldn't run `{} play`: {}", CLI, e);
+            return;
+        }
+    }
+
+    let result = fs::read_to_string(&export_path)
+        .map_err(|e| format!("Couldn't read {}: {}", export_path, e))
+        .and_then(|export| convert_export(&export, OUR_NAME))
+        .and_then(|(replay, id)| {
+            let path = format!("{}/{}.txt", config.samples_dir, id);
+            fs::write(&path, replay)
+                .map(|_| path.clone())
+                .map_err(|e| format!("Couldn't write {}: {}", path, e))
+        });
+
+    match result {
+        Ok(path) => {
+            println!("Saved replay to {}", path);
+            println!("Review it with: battlesnake-2020 replay debug {}", path);
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::routes::parse_body;
+    use super::*;
+
+    #[test]
+    fn test_convert_export() {
+        let export = r#"
+{"game":{"id":"abc"},"turn":0,"board":{"height":11,"width":11,"food":[{"x":0,"y":0}],"snakes":[{"id":"s1","name":"battlesnake-2020","health":100,"body":[{"x":1,"y":10},{"x":1,"y":9},{"x":1,"y":8}]},{"id":"s2","name":"other","health":90,"body":[{"x":5,"y":5},{"x":5,"y":4},{"x":5,"y":3}]}]}}
+{"game":{"id":"abc"},"turn":1,"board":{"height":11,"width":11,"food":[],"snakes":[{"id":"s2","name":"other","health":89,"body":[{"x":5,"y":6},{"x":5,"y":5},{"x":5,"y":4}]}]}}
+"#;
+
+        let (replay, id) = convert_export(export, OUR_NAME).unwrap();
+        assert_eq!(id, "abc");
+        assert_eq!(replay.lines().count(), 1);
+
+        let (you, st) = parse_body(replay.lines().next().unwrap()).unwrap();
+        assert_eq!(you.id, "s1");
+        assert_eq!(you.body[0].y, 0);
+        assert_eq!(you.body[1].y, 1);
+        assert!(st.board.food.iter().any(|f| f.y == 10));
+
+        assert!(convert_export("", OUR_NAME).is_err());
+        assert!(parse_opponents("a=http://x:1, b=http://y:2").is_ok());
+        assert!(parse_opponents("a").is_err());
+    }
+}
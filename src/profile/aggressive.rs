@@ -22,7 +22,7 @@ use log::debug;
 use pathfinding::prelude::astar;
 
 use super::super::game::{Dir, SafetyIndex, Snake, State};
-use super::Profile;
+use super::{Profile, TimeBudget};
 
 /// `Aggressive` is a basic algorithm that will simply navigate
 /// to the nearest snake's head using the A* pathfinding algorithm.
@@ -33,22 +33,28 @@ pub struct Aggressive {
 }
 
 impl Profile for Aggressive {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
         if let Some(nearest_snake) = s.nearest_snake(&st) {
             if nearest_snake.body.len() < s.body.len() {
                 let dest_point = nearest_snake
                     .find_safe_move(st)
-                    .resulting_point(nearest_snake.body[0]);
+                    .resulting_point(nearest_snake.body[0], st);
+                let grid = st.occupancy();
                 let result = astar(
                     &s.body[0],
-                    |p| p.successors(&s, &st),
+                    |p| grid.successors(*p, s, st),
                     |p| p.manhattan(dest_point),
                     |p| *p == dest_point,
                 );
 
                 if let Some((path, len)) = result {
                     if len > 0 {
-                        if let Some(dir) = s.body[0].dir_to(path[1]) {
+                        if let Some(dir) = s.body[0].step_dir(path[1]) {
                             if dir.is_safety_index(&s, &st, &SafetyIndex::Safe) {
                                 return dir;
                             }
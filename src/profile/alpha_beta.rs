@@ -17,39 +17,235 @@
  */
 
 use log::debug;
+use rayon::prelude::*;
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::atomic::{AtomicI16, Ordering};
+use std::sync::Arc;
 
-use super::super::game::{Dir, Point, Snake, State};
-use super::Profile;
-use std::{clone::Clone, cmp::max, cmp::min};
+use super::super::config;
+use super::super::game::hash::{food_key, update_snake, zobrist_hash};
+use super::super::game::territory::Territory;
+#[cfg(feature = "recording")]
+use super::super::schema::v1;
+use super::super::game::{
+    BoardGraph, Contest, Dir, Point, Snake, State, UndoToken,
+};
+use super::eval::{self, EvalWeights};
+use super::transposition::{self, Entry, TranspositionTable};
+use super::{Profile, Straight, TimeBudget};
+use std::time::{Duration, Instant};
+use std::{clone::Clone, cmp::max, cmp::min, cmp::Reverse};
 
 const MAX: i16 = 1000;
 const MIN: i16 = -1000;
 const HEAD_ON: i16 = -500;
-const MAX_DEPTH: u8 = 10;
+/// Deepening stops here even with time to spare, well past anything
+/// a real board leaves time for
+const MAX_DEPTH: u8 = 30;
+/// Depth of the first iterative deepening pass
+const MIN_DEPTH: u8 = 4;
+/// Rounds of loud moves searched past the depth limit
+const QUIESCENCE_ROUNDS: u8 = 1;
+/// Health at or below which eating is a loud move
+const HUNGRY: u8 = 25;
+/// How much longer each pass is assumed to take than the one before
+/// until two passes have been timed, and the bounds on the measured
+/// growth
+const GROWTH: f64 = 3.0;
+const MIN_GROWTH: f64 = 1.5;
+const MAX_GROWTH: f64 = 16.0;
+const KILL_CHANCE: i16 = 10;
+/// Penalty for a snake left in a pocket too small to hold it
+const DEAD_END: i16 = 200;
+/// Penalty for a snake that can't get to food before it starves
+const STARVING: i16 = 300;
+/// The transposition table has `2^TABLE_BITS` slots
+const TABLE_BITS: u32 = 16;
+/// How AlphaBeta scores the positions at the bottom of its search
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeafScore {
+    /// The room each snake can reach at all, by flood fill
+    Flood,
+    /// The squares each snake reaches before the other, see
+    /// `game::territory`
+    Territory,
+    /// The weighted sum of several features, see `EvalWeights`
+    Weighted,
+}
+
+/// Which of the other snakes AlphaBeta searches the moves of
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// Only the nearest enemy's, and the rest are bystanders
+    Duel,
+    /// Every other snake's, one after another, as if they all play
+    /// against us
+    Paranoid,
+}
+
+/// What AlphaBeta's search of a turn found
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SearchReport {
+    pub turn: u32,
+    /// The depth of the deepest pass that finished
+    pub depth: u8,
+    /// Positions searched over every pass
+    pub nodes: u64,
+    pub score: i16,
+    /// The moves the search expects, ours first and then each enemy
+    /// still alive in the order of `enemies`, round after round
+    #[serde(serialize_with = "serialize_pv")]
+    pub pv: Vec<Dir>,
+    pub enemies: Vec<String>,
+}
+
+fn serialize_pv<S: Serializer>(pv: &[Dir], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(pv.iter().map(|d| d.as_move().dir))
+}
+
+impl fmt::Display for SearchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pv = self
+            .pv
+            .iter()
+            .map(|d| d.as_move().dir)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        write!(
+            f,
+            "turn {} depth {} nodes {} score {} against {:?}: {}",
+            self.turn, self.depth, self.nodes, self.score, self.enemies, pv
+        )
+    }
+}
+
+#[cfg(feature = "recording")]
+impl From<&SearchReport> for v1::SearchReport {
+    fn from(report: &SearchReport) -> Self {
+        Self {
+            turn: report.turn,
+            depth: report.depth,
+            nodes: report.nodes,
+            score: report.score,
+            pv: report
+                .pv
+                .iter()
+                .map(|d| d.as_move().dir.to_string())
+                .collect(),
+            enemies: report.enemies.clone(),
+        }
+    }
+}
+
 ///
 /// This profile will be used in 1v1 situations. It implements MiniMax alpha beta pruning.
+/// With more than two snakes left the paranoid search has every enemy
+/// minimize our score in turn after we move, nearest first. The duel
+/// only searches the nearest enemy, and the others are moved along by
+/// a cheap controller after every round so they don't sit still as
+/// walls. Searched positions are kept in a transposition table between
+/// passes and turns. The first of our moves is searched on its own, and
+/// the rest in parallel with the best score so far as their floor.
+///
+/// Passes deepen a round of moves at a time until the deadline, and a pass
+/// that wouldn't finish in time isn't started. Each pass searches the
+/// principal variation of the one before first, and after that the
+/// moves that caused cutoffs elsewhere. Past the depth limit
+/// the search goes on for a while with only the loud moves, those that
+/// risk a head on collision, eat while hungry or enter a dead end, so
+/// a fight isn't cut off just before it is decided.
 ///
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct AlphaBeta {
     status: &'static str,
+    /// Depth limit of the current iterative deepening pass
+    max_depth: u8,
+    /// How many plies past `max_depth` loud moves are searched
+    quiescence: u8,
+    /// When the current search has to give up
+    deadline: Option<Instant>,
+    /// The minimax score of the move the last search found
+    best_score: Option<(Dir, i16)>,
+    /// What the last search found, if it finished a pass
+    report: Option<SearchReport>,
+    /// Positions searched so far by the current search
+    nodes: u64,
+    /// The depth of the deepest pass the last search finished
+    depth: u8,
+    /// The moves the last finished pass expects, ours first and then
+    /// each enemy's in turn
+    pv: Vec<Dir>,
+    /// The principal variation below each depth of the current pass
+    pv_table: Vec<Vec<Dir>>,
+    /// Whether the search is still on the line of `pv`
+    follow_pv: bool,
+    /// The last two moves that caused a cutoff at each depth of the
+    /// current search, most recent first
+    killers: Vec<[Option<Dir>; 2]>,
+    /// How much each snake moving onto each square has caused cutoffs
+    /// in the current search, weighted by the depth searched below
+    history: Vec<u32>,
+    /// The snakes searched against, in the order they move
+    enemies: Vec<String>,
+    /// Moves the snakes other than us and the enemies
+    bystanders: Straight,
+    /// Shared by the threads searching the root's moves, and by clones
+    table: Arc<TranspositionTable>,
+    leaf: LeafScore,
+    /// The weights of the `Weighted` leaf score
+    weights: EvalWeights,
+    mode: SearchMode,
 }
 
 impl Profile for AlphaBeta {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        budget: &TimeBudget,
+    ) -> Dir {
         if st.board.snakes.len() == 1 {
             panic!("Cannot initialize AlphaBeta with only 1 snake")
         };
-        let self_id = &s.id;
-        let mut enemy_id = String::from("Not Initalized");
-        for (pos_id, _) in &st.board.snakes {
-            if *pos_id != *self_id {
-                enemy_id = pos_id.to_string();
-            }
+        let mut enemies = st
+            .board
+            .snakes
+            .values()
+            .filter(|e| e.id != s.id)
+            .map(|e| (e.body[0].manhattan(s.body[0]), e.id.clone()))
+            .collect::<Vec<_>>();
+        enemies.sort();
+        if self.mode == SearchMode::Duel {
+            enemies.truncate(1);
         }
-        let (score, point) = self.minimax(self_id, &enemy_id, 1, st, true, MIN, MAX);
+        self.enemies = enemies.into_iter().map(|(_, id)| id).collect();
+
+        let deadline = budget.soft_deadline();
+        let (score, point) = self.search(&s.id, st, deadline);
+        self.report = Some(SearchReport {
+            turn: st.turn,
+            depth: self.depth,
+            nodes: self.nodes,
+            score,
+            pv: self.pv.clone(),
+            enemies: self.enemies.clone(),
+        })
+        .filter(|_| self.depth > 0);
+        if let Some(report) = &self.report {
+            debug!("AlphaBeta searched {}", report);
+        }
+
         if score > MIN {
-            s.body[0].dir_to(point).unwrap()
+            let dir = s.body[0].step_dir(point).unwrap();
+            self.best_score = Some((dir, score));
+            dir
         } else {
+            self.best_score = None;
             s.find_safe_move(&st)
         }
     }
@@ -57,6 +253,17 @@ impl Profile for AlphaBeta {
     fn get_status(&self) -> String {
         String::from(self.status)
     }
+
+    fn move_scores(&self) -> Vec<(Dir, f64)> {
+        self.best_score
+            .iter()
+            .map(|(dir, score)| (*dir, f64::from(*score)))
+            .collect()
+    }
+
+    fn search_report(&self) -> Option<SearchReport> {
+        self.report.clone()
+    }
 }
 
 impl AlphaBeta {
@@ -65,121 +272,919 @@ impl AlphaBeta {
         debug!("AlphaBeta profile initialized");
         Self {
             status: "AlphaBeta",
+            max_depth: MAX_DEPTH,
+            quiescence: 0,
+            deadline: None,
+            best_score: None,
+            report: None,
+            nodes: 0,
+            depth: 0,
+            pv: Vec::new(),
+            pv_table: vec![Vec::new(); MAX_DEPTH as usize + 2],
+            follow_pv: false,
+            killers: vec![[None; 2]; MAX_DEPTH as usize + 2],
+            history: Vec::new(),
+            enemies: Vec::new(),
+            bystanders: Straight::new(),
+            table: Arc::new(TranspositionTable::new(TABLE_BITS)),
+            leaf: config::get().alpha_beta_leaf,
+            weights: eval::configured().clone(),
+            mode: config::get().alpha_beta_search,
+        }
+    }
+
+    fn out_of_time(&self) -> bool {
+        match self.deadline {
+            Some(d) => Instant::now() >= d,
+            None => false,
+        }
+    }
+
+    /// Deepens the search against `enemies` until `deadline`, and
+    /// returns the score and move of the deepest pass that finished, or
+    /// a score of `MIN` if none did
+    fn search(
+        &mut self,
+        self_id: &str,
+        st: &State,
+        deadline: Instant,
+    ) -> (i16, Point) {
+        let mut best = (MIN, Point { x: 0, y: 0 });
+        self.deadline = Some(deadline);
+        // Passes end on whole rounds, when everyone has moved
+        let round = self.enemies.len() as u8 + 1;
+        self.max_depth = MIN_DEPTH.div_ceil(round) * round;
+        self.quiescence = QUIESCENCE_ROUNDS * round;
+        let plies = MAX_DEPTH + self.quiescence;
+        self.pv_table.resize(plies as usize + 2, Vec::new());
+        self.killers = vec![[None; 2]; plies as usize + 2];
+        self.history = vec![0; usize::from(round) * st.board.cells()];
+        self.depth = 0;
+        self.nodes = 0;
+        self.pv.clear();
+        let hash = zobrist_hash(st);
+        let mut last: Option<Duration> = None;
+        let mut growth = GROWTH;
+
+        while self.max_depth <= MAX_DEPTH {
+            // Don't start a pass there's no time to finish
+            let start = Instant::now();
+            if let Some(last) = last {
+                if start + last.mul_f64(growth) > deadline {
+                    debug!(
+                        "AlphaBeta stopped before depth {}, too little time",
+                        self.max_depth
+                    );
+                    break;
+                }
+            }
+
+            self.follow_pv = !self.pv.is_empty();
+            let result = self.root(self_id, st, hash);
+            if self.out_of_time() {
+                debug!("AlphaBeta ran out of time at depth {}", self.max_depth);
+                break;
+            }
+
+            best = result;
+            self.depth = self.max_depth;
+            self.pv = self.pv_table[1].clone();
+            // Every move loses, and deeper passes won't say otherwise
+            if best.0 == MIN {
+                break;
+            }
+
+            let took = start.elapsed();
+            if let Some(last) = last.filter(|l| *l > Duration::default()) {
+                let ratio = took.as_secs_f64() / last.as_secs_f64();
+                growth = ratio.clamp(MIN_GROWTH, MAX_GROWTH);
+            }
+            last = Some(took);
+            self.max_depth += round;
         }
+
+        best
+    }
+    /// Searches our moves in `st`, like `minimax` at depth 1. The first
+    /// move goes on this thread, so the rest have its score to prune
+    /// with, and then each of the rest goes on a clone of the search
+    /// with its own copy of the state. The clones share the table, and
+    /// raise the floor of the others' windows as they finish.
+    fn root(&mut self, self_id: &str, st: &State, hash: u64) -> (i16, Point) {
+        self.nodes += 1;
+        self.pv_table[1].clear();
+        let you = &st.board.snakes[self_id];
+        let head = you.body[0];
+        let key = table_key(hash, st, self_id, &self.enemies, 0);
+        let best = self.table.probe(key).and_then(|e| e.best);
+        let mut successors = head.successors(you, st);
+        self.order_moves(&mut successors, head, 1, 0, st, best);
+
+        let floor = AtomicI16::new(MIN);
+        let search = |search: &mut AlphaBeta, pos_move: Point| {
+            // Reward moves where only a shorter head can contest us
+            let contest = pos_move.contest(you, st);
+            let bonus = if contest == Contest::Winning {
+                KILL_CHANCE
+            } else {
+                0
+            };
+
+            let dir = head.step_dir(pos_move).unwrap();
+            let mut moved = st.clone();
+            let token = moved.apply_moves(&[(self_id, dir)]);
+            let snake = &moved.board.snakes[self_id];
+            if snake.starved() {
+                return None;
+            }
+            let mut new_hash =
+                update_snake(hash, self_id, &you.body, &snake.body);
+            for p in token.eaten() {
+                new_hash ^= food_key(*p);
+            }
+
+            let alpha = floor.load(Ordering::Relaxed) - bonus;
+            let ply = Ply {
+                depth: 2,
+                hash: new_hash,
+                turn: search.next_turn(&moved, 0),
+                alpha,
+                beta: MAX - bonus,
+            };
+            let (val, _) = search.minimax(self_id, &mut moved, ply);
+            floor.fetch_max(val + bonus, Ordering::Relaxed);
+            Some((val + bonus, pos_move, dir))
+        };
+
+        let mut moves = successors.into_iter().map(|(p, _)| p);
+        let first = moves.next().and_then(|p| search(self, p));
+        self.follow_pv = false;
+        let nodes = self.nodes;
+        let rest = moves
+            .collect::<Vec<Point>>()
+            .into_par_iter()
+            .map(|p| {
+                let mut clone = self.clone();
+                (search(&mut clone, p), clone)
+            })
+            .collect::<Vec<_>>();
+
+        // The earliest of the best moves wins, and its clone's line and
+        // cutoffs carry on to the next pass
+        let mut best_score = MIN;
+        let mut best_move = Point { x: 0, y: 0 };
+        let mut best_dir = None;
+        let mut winner = None;
+        self.nodes += rest.iter().map(|(_, c)| c.nodes - nodes).sum::<u64>();
+        let results = std::iter::once((first, None))
+            .chain(rest.into_iter().map(|(r, c)| (r, Some(c))));
+        for (result, clone) in results {
+            if let Some((val, pos_move, dir)) = result {
+                if val > best_score {
+                    best_score = val;
+                    best_move = pos_move;
+                    best_dir = Some(dir);
+                    winner = Some(clone);
+                }
+            }
+        }
+        if let Some(Some(clone)) = winner {
+            self.pv_table = clone.pv_table;
+            self.killers = clone.killers;
+            self.history = clone.history;
+        }
+        if let Some(dir) = best_dir {
+            self.record_pv(1, dir);
+        }
+
+        if !self.out_of_time() {
+            self.table.store(Entry {
+                key,
+                depth: self.max_depth,
+                score: best_score,
+                bound: Entry::bound(best_score, MIN, MAX),
+                best: best_dir,
+            });
+        }
+        (best_score, best_move)
     }
-    /// This recursive function simulates our snake and the enemy snake taking turns, with the
-    /// final nodes being the scores at the current board state.
+
+    /// This recursive function simulates our snake and the enemies
+    /// taking turns, with the final nodes being the scores at the current
+    /// board state.
     ///
     /// # Arguments
     /// `self_id` - The ID of the snake currently running this profile.
-    /// `enemy_id` - The ID of the snake not running this profile. If there are more than two snakes it
-    /// will be a random snake that is not running this profile.
-    /// `depth` - The current recursive depth.
     /// `st` - The current state of the board which moves will be made from.
-    /// `maximizing_player` - Boolean that is true when it is our turn and false when it is the enemies.
-    /// `alpha` - The current best score attained anywhere in the tree
-    /// `beta` - The current worst score found anywhere in the three.
+    /// Moves are made on it and taken back before returning.
+    /// `ply` - Where in the search `st` is, see `Ply`.
     fn minimax(
-        &self,
+        &mut self,
         self_id: &str,
-        enemy_id: &str,
-        depth: u8,
-        st: &State,
-        maximizing_player: bool,
-        alpha: i16,
-        beta: i16,
+        st: &mut State,
+        ply: Ply,
     ) -> (i16, Point) {
-        if depth > MAX_DEPTH {
-            return (
-                2 * self.get_flood_score(&st, self_id) - self.get_flood_score(&st, enemy_id),
-                Point { x: 0, y: 0 },
-            );
-        }
-        // Set the default score and best move
-        let (temp_snake, mut best_score) = if maximizing_player {
-            (st.board.snakes.get(self_id).unwrap(), MIN)
+        let Ply {
+            depth,
+            hash,
+            turn,
+            alpha,
+            beta,
+        } = ply;
+        self.nodes += 1;
+        self.pv_table[depth as usize].clear();
+        if self.alive(st).next().is_none() {
+            return (MAX, Point { x: 0, y: 0 });
+        }
+        let quiescent = depth > self.max_depth;
+        if depth > self.max_depth + self.quiescence || self.out_of_time() {
+            return (self.leaf_score(st, self_id), Point { x: 0, y: 0 });
+        }
+        // Set the default score and best move. The mover is copied, since
+        // the state changes under it as moves are made.
+        let maximizing_player = turn == 0;
+        let (mover, mut best_score) = if maximizing_player {
+            (self_id.to_string(), MIN)
         } else {
-            (st.board.snakes.get(enemy_id).unwrap(), MAX)
+            (self.enemies[turn - 1].clone(), MAX)
         };
+        let mover = mover.as_str();
+        let temp_snake = st.board.snakes[mover].clone();
+        let key = table_key(hash, st, self_id, &self.enemies, turn);
+        let remaining = (self.max_depth + 1).saturating_sub(depth);
+        // Searches of loud moves only aren't kept
+        let stored = self.table.probe(key).filter(|_| !quiescent);
+
+        // The root has to find its move, not just its score
+        if let Some(score) = stored
+            .filter(|_| depth > 1)
+            .and_then(|e| e.cutoff(remaining, alpha, beta))
+        {
+            return (score, Point { x: 0, y: 0 });
+        }
+
         let mut best_move = Point { x: 0, y: 0 };
-        let mut successors = temp_snake.body[0].successors(&temp_snake, &st);
+        let mut best_dir = None;
+        let mut successors = temp_snake.body[0].successors(&temp_snake, st);
+        // An enemy with nowhere to go still has to move, and dies
+        let trapped = !maximizing_player && successors.is_empty();
+        if trapped {
+            successors.push((temp_snake.body[0].orthogonal(st)[0], 0));
+        }
         // Manually add our head back as a valid move for the enemy.
         if !maximizing_player {
             let self_head = st.board.snakes.get(self_id).unwrap().body[0];
-            let orth = temp_snake.body[0].orthogonal();
-            for i in 0..4 {
-                if orth[i] == self_head {
-                    successors.push((self_head, 0));
-                }
+            let orth = temp_snake.body[0].orthogonal(st);
+            if orth.contains(&self_head) {
+                successors.push((self_head, 0));
+            }
+        }
+        let head = temp_snake.body[0];
+        let best = stored.and_then(|e| e.best);
+        self.order_moves(&mut successors, head, depth, turn, st, best);
+        if quiescent {
+            let stand_pat = self.leaf_score(st, self_id);
+            let quiet = {
+                let graph = BoardGraph::new(&temp_snake, st);
+                let self_head = st.board.snakes[self_id].body[0];
+                let hungry = temp_snake.health <= HUNGRY;
+                let loud = |p: Point| {
+                    trapped
+                        || p == self_head
+                        || p.contest(&temp_snake, st) != Contest::Uncontested
+                        || hungry && st.board.food.contains(&p)
+                        || graph.pocket_size(p) < temp_snake.body.len()
+                };
+                let quiet = successors.iter().any(|(p, _)| !loud(*p));
+                successors.retain(|(p, _)| loud(*p));
+                quiet
+            };
+            if successors.is_empty() {
+                return (stand_pat, Point { x: 0, y: 0 });
+            }
+            // A quiet move is worth the position as it stands, and the
+            // mover can always make one instead
+            if quiet {
+                best_score = stand_pat;
             }
         }
         // Iterate through moves in our successors and call minimax for each
         for (pos_move, _) in successors {
-            let dir = temp_snake.body[0].dir_to(pos_move).unwrap();
-            let mut new_st = st.clone();
+            let dir = temp_snake.body[0].step_dir(pos_move).unwrap();
+            // Reward moves where only a shorter head can contest us, with
+            // the window below shifted to match
+            let contest = pos_move.contest(&temp_snake, st);
+            let bonus = if maximizing_player && contest == Contest::Winning {
+                KILL_CHANCE
+            } else {
+                0
+            };
+            let mut token = st.apply_moves(&[(mover, dir)]);
+            let snake = &st.board.snakes[mover];
+            let mut new_hash =
+                update_snake(hash, mover, &temp_snake.body, &snake.body);
+            for p in token.eaten() {
+                new_hash ^= food_key(*p);
+            }
 
             if maximizing_player {
-                let snake = new_st.board.snakes.get_mut(self_id).unwrap();
-                // Update state with eaten food
-                let (_, food_eaten) = snake.update_from_move(dir, &st.board.food);
-                if let Some(p) = food_eaten {
-                    new_st.board.food.remove(&p);
-                }
-                if snake.health == 0 {
+                if snake.starved() {
+                    st.undo(token);
                     continue;
                 }
-                let (val, _) =
-                    self.minimax(self_id, enemy_id, depth + 1, &new_st, false, alpha, beta);
+                let child = Ply {
+                    depth: depth + 1,
+                    hash: new_hash,
+                    turn: self.next_turn(st, turn),
+                    alpha: max(alpha, best_score) - bonus,
+                    beta: beta - bonus,
+                };
+                let (val, _) = self.minimax(self_id, st, child);
+                let val = val + bonus;
+                st.undo(token);
+                self.follow_pv = false;
                 if val > best_score {
                     best_move = pos_move;
+                    best_dir = Some(dir);
+                    self.record_pv(depth, dir);
                 }
                 // Updates the current available best move and prune.
                 best_score = max(best_score, val);
                 let new_alpha = max(alpha, best_score);
 
                 if beta <= new_alpha {
+                    self.record_cutoff(depth, turn, dir, pos_move, st);
                     break;
                 }
             // Move for enemy snake
             } else {
-                let snake = new_st.board.snakes.get_mut(enemy_id).unwrap();
-                // Update state with eaten food
-                let (_, food_eaten) = snake.update_from_move(dir, &st.board.food);
-                if let Some(p) = food_eaten {
-                    new_st.board.food.remove(&p);
-                }
-
                 // Deal with head on collisions
-                let our_snake = st.board.snakes.get(self_id).unwrap();
+                let our_snake = &st.board.snakes[self_id];
                 if our_snake.body[0] == pos_move {
-                    if our_snake.body.len() > snake.body.len() {
+                    let won = our_snake.body.len() > snake.body.len();
+                    st.undo(token);
+                    if won {
                         continue;
                     } else {
                         return (HEAD_ON, best_move);
                     }
                 }
+                if trapped {
+                    st.remove_snakes(&[mover.to_string()], &mut token);
+                    new_hash = zobrist_hash(st);
+                }
+
+                // The next enemy moves, or if the round is over everyone
+                // else moves too
+                let next = self.next_turn(st, turn);
+                let moved = if next == 0 {
+                    let bystanders =
+                        st.board.snakes.len() > 1 + self.alive(st).count();
+                    let moved = self.advance_bystanders(st, self_id);
+                    if bystanders {
+                        new_hash = zobrist_hash(st);
+                    }
+                    moved
+                } else {
+                    UndoToken::default()
+                };
 
-                let (val, _) =
-                    self.minimax(self_id, enemy_id, depth + 1, &new_st, true, alpha, beta);
+                let child = Ply {
+                    depth: depth + 1,
+                    hash: new_hash,
+                    turn: next,
+                    alpha,
+                    beta: min(beta, best_score),
+                };
+                let (val, _) = self.minimax(self_id, st, child);
+                st.undo(moved);
+                st.undo(token);
+                self.follow_pv = false;
                 if val < best_score {
                     best_move = pos_move;
+                    best_dir = Some(dir);
+                    self.record_pv(depth, dir);
                 }
                 best_score = min(best_score, val);
                 let new_beta = min(best_score, beta);
 
-                if new_beta < alpha {
+                if new_beta <= alpha {
+                    self.record_cutoff(depth, turn, dir, pos_move, st);
                     break;
                 }
             }
         }
+
+        // A search cut short by the deadline is only a guess
+        if !self.out_of_time() && !quiescent {
+            self.table.store(Entry {
+                key,
+                depth: remaining,
+                score: best_score,
+                bound: Entry::bound(best_score, alpha, beta),
+                best: best_dir,
+            });
+        }
         (best_score, best_move)
     }
 
+    /// The enemies still alive in `st`
+    fn alive<'a>(
+        &'a self,
+        st: &'a State,
+    ) -> impl Iterator<Item = &'a String> + 'a {
+        self.enemies
+            .iter()
+            .filter(move |id| st.board.snakes.contains_key(id.as_str()))
+    }
+
+    /// Whose move it is after `turn`: the next enemy still alive, or
+    /// ours once they have all moved
+    fn next_turn(&self, st: &State, turn: usize) -> usize {
+        (turn + 1..=self.enemies.len())
+            .find(|t| st.board.snakes.contains_key(&self.enemies[t - 1]))
+            .unwrap_or(0)
+    }
+
+    /// Puts the moves most likely to prune the rest first: the move on
+    /// the last pass's principal variation, then `best`, the best move
+    /// of an earlier search, then the killers at `depth`, and then the
+    /// rest by their history
+    fn order_moves(
+        &mut self,
+        successors: &mut [(Point, u32)],
+        head: Point,
+        depth: u8,
+        turn: usize,
+        st: &State,
+        best: Option<Dir>,
+    ) {
+        let killers = self.killers[depth as usize];
+        successors.sort_by_key(|(p, _)| {
+            let dir = head.step_dir(*p);
+            let killer =
+                killers.iter().position(|k| *k == dir).map_or(0, |i| 2 - i);
+            let history = self
+                .history_index(turn, *p, st)
+                .map_or(0, |i| self.history[i]);
+            Reverse((killer, history))
+        });
+
+        let mut to_front = |dir: Option<Dir>| {
+            let first = successors
+                .iter()
+                .position(|(p, _)| dir.is_some() && head.step_dir(*p) == dir);
+            if let Some(i) = first {
+                successors[..=i].rotate_right(1);
+            }
+            first.is_some()
+        };
+        to_front(best);
+        // Until the search leaves the principal variation
+        if self.follow_pv {
+            self.follow_pv = to_front(self.pv.get(depth as usize - 1).cloned());
+        }
+    }
+
+    /// Remembers a move that caused a cutoff, as a killer at `depth`
+    /// and in the history
+    fn record_cutoff(
+        &mut self,
+        depth: u8,
+        turn: usize,
+        dir: Dir,
+        p: Point,
+        st: &State,
+    ) {
+        let killers = &mut self.killers[depth as usize];
+        if killers[0] != Some(dir) {
+            killers[1] = killers[0];
+            killers[0] = Some(dir);
+        }
+
+        let remaining = u32::from((self.max_depth + 1).saturating_sub(depth));
+        if let Some(i) = self.history_index(turn, p, st) {
+            self.history[i] += remaining * remaining;
+        }
+    }
+
+    /// Where the history of the snake moving on `turn` onto `p` is
+    fn history_index(
+        &self,
+        turn: usize,
+        p: Point,
+        st: &State,
+    ) -> Option<usize> {
+        if !p.in_bounds(st) {
+            return None;
+        }
+
+        let cell = p.y as usize * st.board.width as usize + p.x as usize;
+        let i = turn * st.board.cells() + cell;
+        Some(i).filter(|i| *i < self.history.len())
+    }
+
+    /// Makes `dir` followed by the line below it the principal
+    /// variation at `depth`
+    fn record_pv(&mut self, depth: u8, dir: Dir) {
+        let d = depth as usize;
+        let mut line = Vec::with_capacity(self.pv_table[d + 1].len() + 1);
+        line.push(dir);
+        line.extend_from_slice(&self.pv_table[d + 1]);
+        self.pv_table[d] = line;
+    }
+
+    /// Moves every snake other than us and the enemies one turn with
+    /// the bystander controller, removing any that die, and returns
+    /// what takes the moves back. They don't react to the search, but
+    /// they block what their next moves will.
+    fn advance_bystanders(&self, st: &mut State, self_id: &str) -> UndoToken {
+        // Bystanders all move from the same position, like in a turn
+        let budget = TimeBudget::untimed();
+        let mut controller = self.bystanders;
+        let moves = st
+            .board
+            .snakes
+            .values()
+            .filter(|s| s.id != self_id && !self.enemies.contains(&s.id))
+            .map(|s| (s.id.clone(), controller.get_move(s, st, &budget)))
+            .collect::<Vec<(String, Dir)>>();
+        if moves.is_empty() {
+            return UndoToken::default();
+        }
+
+        let mut token = st.apply_moves(
+            &moves
+                .iter()
+                .map(|(id, dir)| (id.as_str(), *dir))
+                .collect::<Vec<_>>(),
+        );
+
+        let dead = moves
+            .into_iter()
+            .map(|(id, _)| id)
+            .filter(|id| {
+                let snake = &st.board.snakes[id];
+                snake.starved() || !snake.body[0].is_valid(snake, st)
+            })
+            .collect::<Vec<String>>();
+        st.remove_snakes(&dead, &mut token);
+        token
+    }
+
+    /// How good a position at the bottom of the search is for us,
+    /// counting our room twice as much as the roomiest enemy's, or the
+    /// weighted features, and whether we or any enemy are stuck in a
+    /// dead end or about to starve
+    fn leaf_score(&self, st: &State, self_id: &str) -> i16 {
+        let enemies = self.alive(st).collect::<Vec<_>>();
+        let trapped = |id: &str| {
+            let s = &st.board.snakes[id];
+            BoardGraph::new(s, st).room_after(s.body[0]) < s.body.len()
+        };
+        let count = |f: &dyn Fn(&str) -> bool| {
+            enemies.iter().filter(|id| f(id)).count() as i16
+                - i16::from(f(self_id))
+        };
+        let dead_ends = DEAD_END * count(&trapped);
+
+        let starving =
+            |id: &str| st.board.snakes[id].turns_until_starvation(st) == 0;
+        let starvation = STARVING * count(&starving);
+
+        let room = match self.leaf {
+            LeafScore::Flood => {
+                let flood = |id: &str| self.get_flood_score(st, id);
+                2 * flood(self_id)
+                    - enemies.iter().map(|id| flood(id)).max().unwrap_or(0)
+            }
+            LeafScore::Territory => {
+                let territory = Territory::new(st);
+                let score = |id: &str| territory.score(id) as i16;
+                2 * score(self_id)
+                    - enemies.iter().map(|id| score(id)).max().unwrap_or(0)
+            }
+            LeafScore::Weighted => {
+                let limit = f64::from(MAX / 2);
+                let score = self.weights.score(st, self_id, &enemies);
+                score.round().clamp(-limit, limit) as i16
+            }
+        };
+
+        room + dead_ends + starvation
+    }
+
     fn get_flood_score(&self, st: &State, id: &str) -> (i16) {
         let s = st.board.snakes.get(id).unwrap();
         let len = s.body.len() as u16;
-        let flood = s.body[0].flood_fill(s, st, len);
-        let score = flood.len() as i16;
+        // Room beyond our own length doesn't make a position any safer
+        let flood = s.body[0].flood_fill_temporal(s, st, len);
+        let score = flood.len().min(len.into()) as i16;
         return score;
     }
 }
+
+/// Where a call to `minimax` is in the search
+#[derive(Debug, Copy, Clone)]
+struct Ply {
+    /// The current recursive depth
+    depth: u8,
+    /// The Zobrist hash of the position
+    hash: u64,
+    /// Whose move it is, 0 for ours or `i` for the enemy at
+    /// `enemies[i - 1]`
+    turn: usize,
+    /// The score we can already force elsewhere in the tree
+    alpha: i16,
+    /// The score the enemies can already hold us to elsewhere in the
+    /// tree
+    beta: i16,
+}
+
+/// The table key of a position in the search. The position's hash
+/// leaves out health, which decides who starves first, and whose move
+/// it is.
+fn table_key(
+    hash: u64,
+    st: &State,
+    self_id: &str,
+    enemies: &[String],
+    turn: usize,
+) -> u64 {
+    let health = |id: &str| {
+        st.board.snakes.get(id).map_or(0, |s| u64::from(s.health))
+    };
+    let extra = enemies
+        .iter()
+        .fold(health(self_id), |h, id| h.rotate_left(8) ^ health(id));
+    transposition::key(hash, extra.rotate_left(8) ^ turn as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{build_state, SafetyIndex};
+
+    /// Our move at the root of a search of `hash`, with the whole
+    /// window open
+    fn root_ply(hash: u64) -> Ply {
+        Ply {
+            depth: 1,
+            hash,
+            turn: 0,
+            alpha: MIN,
+            beta: MAX,
+        }
+    }
+
+    #[test]
+    fn test_bystanders() {
+        let (you, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(1, 5), (1, 6), (1, 7)]),
+                ("enemy", &[(4, 5), (4, 6), (4, 7)]),
+                ("third", &[(8, 2), (8, 3), (8, 4)]),
+                ("doomed", &[(10, 0), (9, 0), (8, 0)]),
+            ],
+            &[],
+        );
+        st.board.snakes.get_mut("doomed").unwrap().health = 1;
+
+        // The bystanders move like Straight, and those that die are
+        // gone
+        let mut moved = st.clone();
+        let mut profile = AlphaBeta::new();
+        profile.enemies = vec![String::from("enemy")];
+        let token = profile.advance_bystanders(&mut moved, "you");
+        assert_eq!(moved.board.snakes["third"].body[0], Point { x: 8, y: 1 });
+        assert_eq!(moved.board.snakes["you"], st.board.snakes["you"]);
+        assert_eq!(moved.board.snakes["enemy"], st.board.snakes["enemy"]);
+        assert!(!moved.board.snakes.contains_key("doomed"));
+
+        // and the search takes their moves back
+        moved.undo(token);
+        assert_eq!(moved.board.snakes["third"], st.board.snakes["third"]);
+        assert_eq!(moved.board.snakes["doomed"], st.board.snakes["doomed"]);
+
+        // The duel is with the nearest enemy, and still plays safe
+        let mut profile = AlphaBeta::new();
+        profile.mode = SearchMode::Duel;
+        let budget = TimeBudget::untimed().capped(200);
+        let dir = profile.get_move(&you, &st, &budget);
+        assert_eq!(profile.enemies, vec![String::from("enemy")]);
+        assert!(!dir.is_safety_index(&you, &st, &SafetyIndex::Unsafe));
+
+        // and reports the line it expects, starting with its move
+        let report = profile.search_report().unwrap();
+        assert_eq!(report.pv.first(), Some(&dir));
+        assert_eq!(report.enemies, profile.enemies);
+        assert!(report.depth >= MIN_DEPTH && report.nodes > 0);
+
+        // and keeps the root's best move for the next search
+        let hash = zobrist_hash(&st);
+        let key = table_key(hash, &st, "you", &profile.enemies, 0);
+        assert_eq!(profile.table.probe(key).and_then(|e| e.best), Some(dir));
+
+        // Scoring leaves by territory or the weighted features is just
+        // as safe
+        for leaf in &[LeafScore::Territory, LeafScore::Weighted] {
+            let mut profile = AlphaBeta::new();
+            profile.leaf = *leaf;
+            let dir = profile.get_move(&you, &st, &budget);
+            assert!(!dir.is_safety_index(&you, &st, &SafetyIndex::Unsafe));
+        }
+    }
+
+    #[test]
+    fn test_deepening() {
+        let (you, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (1, 4), (1, 5)]),
+                ("enemy", &[(5, 3), (5, 4), (5, 5)]),
+            ],
+            &[],
+        );
+
+        // A search out of time before it starts finishes no pass
+        let mut profile = AlphaBeta::new();
+        profile.enemies = vec![String::from("enemy")];
+        let (score, _) = profile.search("you", &st, Instant::now());
+        assert_eq!((score, profile.depth), (MIN, 0));
+        assert!(profile.pv.is_empty());
+
+        // With time it goes deeper, and its principal variation starts
+        // with the move it found, and then the enemy's reply
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let (_, point) = profile.search("you", &st, deadline);
+        assert!(profile.depth >= MIN_DEPTH);
+        assert!(profile.pv.len() >= 2);
+        assert_eq!(you.body[0].step_dir(point), Some(profile.pv[0]));
+    }
+
+    #[test]
+    fn test_ordering() {
+        let (you, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(3, 3), (3, 4), (3, 5)]),
+                ("enemy", &[(6, 0), (6, 1), (6, 2)]),
+            ],
+            &[],
+        );
+        let mut profile = AlphaBeta::new();
+        profile.enemies = vec![String::from("enemy")];
+        profile.max_depth = 4;
+        profile.history = vec![0; 2 * st.board.cells()];
+
+        let head = you.body[0];
+        let order = |profile: &mut AlphaBeta, depth| {
+            let mut successors = head.successors(&you, &st);
+            profile.order_moves(&mut successors, head, depth, 0, &st, None);
+            successors
+                .iter()
+                .map(|(p, _)| head.step_dir(*p).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // Cutoffs make killers at their depth, the latest first, and
+        // the shallower cutoff counts for more in the history
+        profile.record_cutoff(2, 0, Dir::Left, head.offset(-1, 0), &st);
+        profile.record_cutoff(4, 0, Dir::Right, head.offset(1, 0), &st);
+        profile.record_cutoff(2, 0, Dir::Up, head.offset(0, -1), &st);
+        let mut order = |depth| order(&mut profile, depth);
+        assert_eq!(order(2), vec![Dir::Up, Dir::Left, Dir::Right]);
+        assert_eq!(order(4), vec![Dir::Right, Dir::Up, Dir::Left]);
+        assert_eq!(order(3), vec![Dir::Up, Dir::Left, Dir::Right]);
+    }
+
+    #[test]
+    fn test_root() {
+        let (_, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (1, 4), (1, 5)]),
+                ("enemy", &[(4, 3), (4, 4), (4, 5), (4, 6)]),
+            ],
+            &[(2, 1), (5, 1)],
+        );
+        let hash = zobrist_hash(&st);
+        let profile = || {
+            let mut profile = AlphaBeta::new();
+            profile.enemies = vec![String::from("enemy")];
+            profile.max_depth = 4;
+            profile.quiescence = 2;
+            profile
+        };
+
+        // Searching the root's moves in parallel finds what searching
+        // them one after another does
+        let mut serial = profile();
+        let mut moved = st.clone();
+        let (score, _) = serial.minimax("you", &mut moved, root_ply(hash));
+        let mut parallel = profile();
+        let (root_score, point) = parallel.root("you", &st, hash);
+        assert_eq!(root_score, score);
+
+        // and leaves its move in the table and its line
+        let key = table_key(hash, &st, "you", &parallel.enemies, 0);
+        let best = parallel.table.probe(key).and_then(|e| e.best);
+        assert_eq!(best, st.board.snakes["you"].body[0].step_dir(point));
+        assert_eq!(parallel.pv_table[1].first().cloned(), best);
+    }
+
+    #[test]
+    fn test_window() {
+        let (_, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (1, 4), (1, 5)]),
+                ("enemy", &[(4, 3), (4, 4), (4, 5), (4, 6)]),
+            ],
+            &[(2, 1), (5, 1)],
+        );
+        let hash = zobrist_hash(&st);
+        let search = |depth: u8| {
+            let mut profile = AlphaBeta::new();
+            profile.enemies = vec![String::from("enemy")];
+            profile.max_depth = depth;
+            profile.quiescence = 0;
+            let mut moved = st.clone();
+            let found = profile.minimax("you", &mut moved, root_ply(hash));
+            (found, profile.nodes)
+        };
+
+        // Passing each move's score down as the window lets its
+        // siblings prune. With the window left open below the root these
+        // searches took 103 and 714 nodes, more than twice as many.
+        let ((score, point), nodes) = search(4);
+        assert_eq!((score, point), (2, Point { x: 1, y: 2 }));
+        assert!(nodes < 103 / 2, "searched {} nodes", nodes);
+        let ((score, point), nodes) = search(6);
+        assert_eq!((score, point), (3, Point { x: 1, y: 2 }));
+        assert!(nodes < 714 / 2, "searched {} nodes", nodes);
+    }
+
+    #[test]
+    fn test_quiescence() {
+        // Both of our moves can be taken head on by the longer enemy
+        let (_, mut st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(0, 1), (0, 2), (0, 3)]),
+                ("enemy", &[(1, 0), (2, 0), (3, 0), (4, 0)]),
+            ],
+            &[],
+        );
+        let mut profile = AlphaBeta::new();
+        profile.enemies = vec![String::from("enemy")];
+        profile.max_depth = 0;
+        let hash = zobrist_hash(&st);
+        let mut search = |profile: &mut AlphaBeta| {
+            profile.minimax("you", &mut st, root_ply(hash)).0
+        };
+
+        // At the depth limit the position looks quiet enough
+        let leaf = search(&mut profile);
+        assert!(leaf > HEAD_ON);
+
+        // but searching on through the loud moves finds the collision
+        profile.quiescence = 2;
+        assert_eq!(search(&mut profile), HEAD_ON);
+    }
+
+    #[test]
+    fn test_paranoid() {
+        // The nearest enemy is no threat, but moving right lets the
+        // longer one further away take us head on
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 4), (5, 3)]),
+                ("near", &[(4, 5), (3, 5), (2, 5)]),
+                ("big", &[(7, 5), (8, 5), (9, 5), (10, 5)]),
+                ("far", &[(0, 10), (1, 10), (2, 10)]),
+            ],
+            &[],
+        );
+
+        // Every enemy moves against us, nearest first, so we stay out
+        // of the longer one's reach
+        let mut profile = AlphaBeta::new();
+        profile.mode = SearchMode::Paranoid;
+        let budget = TimeBudget::untimed().capped(100);
+        let dir = profile.get_move(&you, &st, &budget);
+        assert_eq!(profile.enemies, vec!["near", "big", "far"]);
+        // and passes end on whole rounds
+        assert!(profile.depth >= 4 && profile.depth.is_multiple_of(4));
+        assert_eq!(dir, Dir::Down);
+    }
+}
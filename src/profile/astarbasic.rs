@@ -19,32 +19,42 @@
 //! This module contains the AStarBasic algorithm & unit tests
 
 use log::debug;
-use pathfinding::prelude::astar;
 
-use super::super::game::{Dir, Snake, State};
-use super::Profile;
+use super::super::game::{find_path, Dir, PathSearch, Snake, State};
+use super::{Profile, TimeBudget};
 
 /// `AStarBasic` is a basic algorithm that will simply navigate
 /// to the nearest food using the A* pathfinding algorithm.
 /// If a path cannot be found, a safe move will be selected.
+/// Exact searches go for `Snake::best_food` instead, the nearest food
+/// by path length that no enemy gets to first.
 #[derive(Copy, Clone)]
 pub struct AStarBasic {
     status: &'static str,
+    search: PathSearch,
 }
 
 impl Profile for AStarBasic {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
+        if self.search == PathSearch::Exact {
+            return s
+                .best_food(st)
+                .and_then(|(_, path)| path.get(1).cloned())
+                .and_then(|p| s.body[0].step_dir(p))
+                .unwrap_or_else(|| s.find_safe_move(st));
+        }
+
         if let Some(nearest_food) = s.nearest_food(&st) {
-            let result = astar(
-                &s.body[0],
-                |p| p.successors(&s, &st),
-                |p| p.manhattan(nearest_food),
-                |p| *p == nearest_food,
-            );
+            let result = find_path(&s, &st, nearest_food, self.search);
 
             if let Some((path, len)) = result {
                 if len > 0 {
-                    if let Some(dir) = s.body[0].dir_to(path[1]) {
+                    if let Some(dir) = s.body[0].step_dir(path[1]) {
                         return dir;
                     }
                 }
@@ -65,6 +75,12 @@ impl AStarBasic {
         debug!("AStarBasic profile initialized");
         Self {
             status: "AStarBasic",
+            search: PathSearch::Exact,
         }
     }
+
+    /// Sets the search used to find paths to food
+    pub fn set_search(&mut self, search: PathSearch) {
+        self.search = search;
+    }
 }
@@ -0,0 +1,192 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The time a profile has to pick a move. The clock starts when the
+//! move request arrives, not when the profile is called, so time spent
+//! parsing and building the turn context counts against it.
+//!
+//! A budget has two deadlines. Searches aim to finish by the soft
+//! deadline, and must not run past the hard one, after which the
+//! response may not reach the engine in time.
+
+use std::time::{Duration, Instant};
+
+use crate::game::{State, DEFAULT_TIMEOUT_MILLIS};
+
+/// Time kept back from the timeout for sending the response
+const RESPONSE_MARGIN_MILLIS: u64 = 40;
+/// Deadlines of budgets that aren't tied to a move request
+const UNTIMED_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeBudget {
+    start: Instant,
+    soft: Duration,
+    hard: Duration,
+}
+
+impl TimeBudget {
+    /// A budget with deadlines relative to `start`. The soft deadline
+    /// is moved back to the hard one if it is later.
+    pub fn new(start: Instant, soft: Duration, hard: Duration) -> Self {
+        Self {
+            start,
+            soft: soft.min(hard),
+            hard,
+        }
+    }
+
+    /// The budget for a move request that arrived at `start`, with
+    /// both deadlines at the latest the response can be sent
+    pub fn for_move(start: Instant) -> Self {
+        Self::for_timeout(start, DEFAULT_TIMEOUT_MILLIS)
+    }
+
+    /// `for_move` for a game whose engine waits `timeout_millis` for
+    /// each move
+    pub fn for_timeout(start: Instant, timeout_millis: u64) -> Self {
+        let hard = Duration::from_millis(
+            timeout_millis.saturating_sub(RESPONSE_MARGIN_MILLIS),
+        );
+        Self::new(start, hard, hard)
+    }
+
+    /// A budget for moves picked outside a game, e.g. by tools and
+    /// tests, that never runs out in practice
+    pub fn untimed() -> Self {
+        let t = Duration::from_secs(UNTIMED_SECS);
+        Self::new(Instant::now(), t, t)
+    }
+
+    /// `capped` for a search time configured for the default timeout,
+    /// scaled to the timeout of the game being played
+    pub fn capped_for(&self, millis: u64, st: &State) -> Self {
+        self.capped(millis * st.timeout_millis() / DEFAULT_TIMEOUT_MILLIS)
+    }
+
+    /// The same budget with the soft deadline no later than `millis`
+    /// after the start. Profiles use this to apply their configured
+    /// search time.
+    pub fn capped(&self, millis: u64) -> Self {
+        Self::new(
+            self.start,
+            self.soft.min(Duration::from_millis(millis)),
+            self.hard,
+        )
+    }
+
+    /// The same budget with the soft deadline moved out to the hard
+    /// one
+    pub fn until_hard(&self) -> Self {
+        Self::new(self.start, self.hard, self.hard)
+    }
+
+    /// The time from the start to the hard deadline
+    pub fn hard_limit(&self) -> Duration {
+        self.hard
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// The time left until the soft deadline
+    pub fn remaining(&self) -> Duration {
+        self.soft.checked_sub(self.elapsed()).unwrap_or_default()
+    }
+
+    pub fn soft_deadline(&self) -> Instant {
+        self.start + self.soft
+    }
+
+    pub fn hard_deadline(&self) -> Instant {
+        self.start + self.hard
+    }
+
+    /// Whether the soft deadline has passed
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.soft_deadline()
+    }
+
+    /// Whether the hard deadline has passed
+    pub fn overrun(&self) -> bool {
+        Instant::now() >= self.hard_deadline()
+    }
+
+    /// The fraction of the time to the soft deadline used so far,
+    /// from 0 up to 1
+    pub fn progress(&self) -> f64 {
+        if self.soft.as_nanos() == 0 {
+            return 1.0;
+        }
+
+        (self.elapsed().as_secs_f64() / self.soft.as_secs_f64()).min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+
+    #[test]
+    fn test_time_budget() {
+        let start = Instant::now() - Duration::from_millis(100);
+        let budget = TimeBudget::for_move(start);
+
+        assert_eq!(budget.soft_deadline(), budget.hard_deadline());
+        assert!(budget.remaining() <= Duration::from_millis(360));
+        assert!(!budget.expired());
+
+        let capped = budget.capped(200);
+        assert_eq!(capped.hard_deadline(), budget.hard_deadline());
+        assert_eq!(capped.soft_deadline(), start + Duration::from_millis(200));
+        assert!(capped.progress() >= 0.5 && capped.progress() < 1.0);
+
+        // Past the soft deadline, but not the hard one
+        let capped = budget.capped(50);
+        assert!(capped.expired() && !capped.overrun());
+        assert_eq!(capped.remaining(), Duration::from_millis(0));
+        assert_eq!(capped.progress(), 1.0);
+
+        // A cap can't extend the budget
+        let longer = budget.capped(10_000);
+        assert_eq!(longer.soft_deadline(), budget.soft_deadline());
+
+        assert!(!TimeBudget::untimed().expired());
+    }
+
+    #[test]
+    fn test_game_timeout() {
+        let (_, mut st) = build_state(11, 11, &[("you", &[(1, 1)])], &[]);
+        let start = Instant::now();
+        let budget = TimeBudget::for_timeout(start, 1000);
+        assert_eq!(budget.hard_limit(), Duration::from_millis(960));
+
+        // Configured search times are for the default timeout
+        let soft = |b: TimeBudget| b.soft_deadline() - start;
+        assert_eq!(
+            soft(budget.capped_for(450, &st)),
+            Duration::from_millis(450)
+        );
+        st.game.timeout = Some(1000);
+        assert_eq!(
+            soft(budget.capped_for(450, &st)),
+            Duration::from_millis(900)
+        );
+    }
+}
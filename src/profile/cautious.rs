@@ -19,10 +19,9 @@
 //! This module contains the Cautious algorithm & unit tests
 
 use log::debug;
-use pathfinding::prelude::astar;
 
 use super::super::game::{Dir, Snake, State};
-use super::Profile;
+use super::{food_interrupt, Profile, TimeBudget};
 
 /// `Cautious` is an algorithm that will play extremely safe,
 /// chasing its tail constantly and only deviating from the
@@ -33,21 +32,21 @@ pub struct Cautious {
 }
 
 impl Profile for Cautious {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
-        let len = s.body.len();
-        let result = astar(
-            &s.body[0],
-            |p| p.successors(&s, &st),
-            |p| p.manhattan(s.body[len - 1]),
-            |p| *p == s.body[len - 1],
-        );
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
+        if let Some(d) = food_interrupt(s, st) {
+            return d;
+        }
+
+        let tail = s.body[s.body.len() - 1];
+        let step = st.distances(s).first_step(tail, st);
 
-        if let Some(path) = result {
-            if path.0.len() > 1 {
-                if let Some(dir) = s.body[0].dir_to(path.0[1]) {
-                    return dir;
-                }
-            }
+        if let Some(dir) = step.and_then(|p| s.body[0].step_dir(p)) {
+            return dir;
         }
         s.find_safe_move(&st)
     }
@@ -0,0 +1,162 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! The weighted evaluation AlphaBeta can score the positions at the
+//! bottom of its search with. Each feature compares us to the
+//! strongest enemy left, or just looks at us, and the weights come
+//! from the TOML file `alpha_beta_weights` names so they can be tuned
+//! without a rebuild.
+
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::sync::OnceLock;
+
+use super::super::config;
+use super::super::game::territory::Territory;
+use super::super::game::State;
+
+static CONFIGURED: OnceLock<EvalWeights> = OnceLock::new();
+
+/// Weights of the evaluation's features
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvalWeights {
+    /// Our length less the longest enemy's
+    pub length: f64,
+    /// Our health, as a fraction of full
+    pub health: f64,
+    /// Moves to the nearest food we can reach, as a penalty
+    pub food: f64,
+    /// Our territory less the largest enemy territory
+    pub territory: f64,
+    /// Our head on the outer ring, as a penalty
+    pub edge: f64,
+}
+
+impl Default for EvalWeights {
+    fn default() -> Self {
+        Self {
+            length: 10.0,
+            health: 10.0,
+            food: 1.0,
+            territory: 1.0,
+            edge: 5.0,
+        }
+    }
+}
+
+/// The weights from the `alpha_beta_weights` file, or the defaults if
+/// there isn't one or it can't be loaded
+pub fn configured() -> &'static EvalWeights {
+    CONFIGURED.get_or_init(|| {
+        let path = &config::get().alpha_beta_weights;
+        if path.is_empty() {
+            return EvalWeights::default();
+        }
+
+        let weights = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|c| toml::from_str(&c).map_err(|e| e.to_string()));
+
+        weights.unwrap_or_else(|e| {
+            warn!("Couldn't load AlphaBeta weights {}: {}", path, e);
+            EvalWeights::default()
+        })
+    })
+}
+
+impl EvalWeights {
+    /// How good the position is for us against `enemies`
+    pub fn score(&self, st: &State, self_id: &str, enemies: &[&String]) -> f64 {
+        let you = &st.board.snakes[self_id];
+        let strongest = |f: &dyn Fn(&str) -> f64| {
+            enemies.iter().map(|id| f(id)).fold(0.0, f64::max)
+        };
+
+        let len = |id: &str| st.board.snakes[id].body.len() as f64;
+        let length = len(self_id) - strongest(&len);
+
+        let health = f64::from(you.health) / 100.0;
+
+        // Food we can't get to is as far as it gets
+        let field = st.distances(you);
+        let food = field
+            .nearest(&st.board.food)
+            .and_then(|p| field.distance(p))
+            .map_or(st.board.cells() as f64, f64::from);
+
+        let territory = Territory::new(st);
+        let squares = |id: &str| f64::from(territory.score(id));
+        let territory = squares(self_id) - strongest(&squares);
+
+        let edge = if you.body[0].is_outer(st) { 1.0 } else { 0.0 };
+
+        self.length * length + self.health * health - self.food * food
+            + self.territory * territory
+            - self.edge * edge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let (_, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(0, 3), (0, 4), (0, 5), (0, 6)]),
+                ("enemy", &[(4, 3), (4, 4), (4, 5)]),
+            ],
+            &[(2, 3)],
+        );
+        let enemy = String::from("enemy");
+        let zero = EvalWeights {
+            length: 0.0,
+            health: 0.0,
+            food: 0.0,
+            territory: 0.0,
+            edge: 0.0,
+        };
+        let only = |set: fn(&mut EvalWeights)| {
+            let mut w = zero.clone();
+            set(&mut w);
+            w.score(&st, "you", &[&enemy])
+        };
+
+        // Each feature on its own
+        assert_eq!(only(|w| w.length = 1.0), 1.0);
+        assert_eq!(only(|w| w.health = 1.0), 1.0);
+        assert_eq!(only(|w| w.food = 1.0), -2.0);
+        assert_eq!(only(|w| w.edge = 1.0), -1.0);
+        let territory = Territory::new(&st);
+        let squares = |id| f64::from(territory.score(id));
+        let ahead = squares("you") - squares("enemy");
+        assert_eq!(only(|w| w.territory = 1.0), ahead);
+
+        // Weights left out of a file keep their defaults
+        let w: EvalWeights = toml::from_str("edge = 2.0").unwrap();
+        assert_eq!(w.edge, 2.0);
+        assert_eq!(w.length, EvalWeights::default().length);
+        assert!(toml::from_str::<EvalWeights>("speed = 1.0").is_err());
+    }
+}
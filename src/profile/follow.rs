@@ -19,34 +19,34 @@
 //! This module contains the Follow algorithm & unit tests
 
 use log::debug;
-use pathfinding::prelude::astar;
 
 use super::super::game::{Dir, Snake, State};
-use super::Profile;
+use super::{food_interrupt, Profile, TimeBudget};
 
-/// `Follow` is an algorithm that will follow the tail of an enemy snake.
+/// `Follow` is an algorithm that will follow the tail of an enemy snake,
+/// breaking off to eat when it gets hungry.
 #[derive(Copy, Clone)]
 pub struct Follow {
     status: &'static str,
 }
 
 impl Profile for Follow {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
+        if let Some(d) = food_interrupt(s, st) {
+            return d;
+        }
+
         if let Some(enemy) = s.nearest_snake(&st) {
-            let len = enemy.body.len();
-            let result = astar(
-                &s.body[0],
-                |p| p.successors(&s, &st),
-                |p| p.manhattan(enemy.body[len - 1]),
-                |p| *p == enemy.body[len - 1],
-            );
+            let tail = enemy.body[enemy.body.len() - 1];
+            let step = st.distances(s).first_step(tail, st);
 
-            if let Some((path, _)) = result {
-                if path.len() > 1 {
-                    if let Some(dir) = s.body[0].dir_to(path[1]) {
-                        return dir;
-                    }
-                }
+            if let Some(dir) = step.and_then(|p| s.body[0].step_dir(p)) {
+                return dir;
             }
         }
         s.find_safe_move(&st)
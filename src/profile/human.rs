@@ -0,0 +1,82 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! This module contains the Human profile
+
+use log::debug;
+use std::io::{self, BufRead, Write};
+
+use super::super::game::{Dir, Snake, State};
+use super::super::replay::render;
+use super::{Profile, TimeBudget};
+
+/// The Human profile renders the board and reads each move from
+/// stdin. It waits as long as it takes, so it is only usable in the
+/// local arena and never by the server.
+pub struct Human {
+    status: &'static str,
+}
+
+/// Parses a move typed by the player, as a direction or WASD
+fn parse_dir(input: &str) -> Option<Dir> {
+    match input.trim().to_lowercase().as_str() {
+        "w" | "up" => Some(Dir::Up),
+        "s" | "down" => Some(Dir::Down),
+        "a" | "left" => Some(Dir::Left),
+        "d" | "right" => Some(Dir::Right),
+        _ => None,
+    }
+}
+
+impl Profile for Human {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
+        println!("Turn {}\n{}", st.turn, render(s, st));
+
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+
+        loop {
+            print!("Move for {} (w/a/s/d)> ", s.id);
+            io::stdout().flush().unwrap_or(());
+
+            match lines.next() {
+                Some(Ok(line)) => match parse_dir(&line) {
+                    Some(d) => return d,
+                    None => println!("Unknown move {}", line.trim()),
+                },
+                // Out of input, let the snake fend for itself
+                _ => return s.find_safe_move(st),
+            }
+        }
+    }
+
+    fn get_status(&self) -> String {
+        String::from(self.status)
+    }
+}
+
+impl Human {
+    pub fn new() -> Self {
+        debug!("Human profile initialized");
+        Self { status: "Human" }
+    }
+}
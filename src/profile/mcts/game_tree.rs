@@ -15,119 +15,307 @@
  * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
  *
  */
-use crate::game::{Dir, SafetyIndex, Snake, State};
-use crate::profile::{AStarBasic, Profile};
-use crate::simulator::{process_step, Future};
+use crate::game::{Dir, PathSearch, SafetyIndex, Snake, State};
+use crate::profile::{tie_break, AStarBasic, Profile, Seed, TimeBudget};
+use crate::simulator::{
+    process_step, rivals, CompactState, Future, Simulation, SpawnRules,
+};
 
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::f32;
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard};
+#[cfg(feature = "debug-endpoints")]
 use std::{error::Error, fs::File, io::prelude::*, path::Path};
 
 use log::{debug, info};
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde_derive::{Deserialize, Serialize};
+
+// The GameTree module stores the MCTS tree inside of a Vec. Nodes
+// keep their state compact, since there is one for every node, and
+// convert it to a full state to pick moves or roll out from. Several
+// threads grow one tree: each picks a node with `select`, rolls out
+// from it on its own with a `Worker`, and hands the result back with
+// `backprop`. The visit statistics are atomics, so threads only wait
+// on each other to add the children of a node.
+
+/// What a rollout scores for a win. A draw scores half of it, and a
+/// loss scores less than a draw by how many enemies we outlasted.
+const WIN_SCORE: usize = 6;
+const DRAW_SCORE: usize = 3;
+
+/// Rollouts still going after this many turns are scored as a draw,
+/// rather than left to wander until someone starves
+const ROLLOUT_MAX_TURNS: u32 = 200;
+
+/// The rollout score of a finished game, and the wins it counts in
+/// halves. Losses only score for placement with two or more rivals.
+fn terminal_score(f: &Future, rivals: usize) -> (usize, usize) {
+    if f.draw {
+        (DRAW_SCORE, 1)
+    } else if f.alive {
+        (WIN_SCORE, 2)
+    } else {
+        match f.placement {
+            Some(place) if rivals >= 2 => {
+                let outlasted = (rivals + 1).saturating_sub(place.into());
+                (DRAW_SCORE * outlasted / (rivals + 1), 0)
+            }
+            _ => (0, 0),
+        }
+    }
+}
+
+/// What the rollouts through one of the root's moves added up to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MoveStats {
+    pub dir: Dir,
+    pub score: usize,
+    /// Wins counted in halves, so a draw is one
+    pub half_wins: usize,
+    pub sims: usize,
+}
+
+impl MoveStats {
+    /// The share of the rollouts won, a draw counting as half a win
+    pub fn win_rate(&self) -> f64 {
+        if self.sims == 0 {
+            0.0
+        } else {
+            self.half_wins as f64 / 2.0 / self.sims as f64
+        }
+    }
+}
 
-// The GameTree module stores the MCTS tree inside of a Vec.
+/// How MonteCarlo picks the move to play from the root's moves
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinalMove {
+    /// The most simulated move, the robust child
+    Robust,
+    /// The move with the best win rate, which trusts a move with few
+    /// rollouts as much as one with many
+    WinRate,
+}
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 struct Node {
     parent: Option<usize>,
+    /// Only set when the node is expanded, under the tree's write lock
     children: [Option<usize>; 4],
-    score: usize,
-    sim_count: usize,
-    state: State,
+    /// The visit statistics, which threads update under the read lock
+    score: AtomicUsize,
+    half_wins: AtomicUsize,
+    sim_count: AtomicUsize,
+    state: CompactState,
     future: Option<Future>,
-    is_self_node: bool,
+    /// The position in `GameTree::movers` of the snake whose move led
+    /// here. The root counts as the last snake's, so ours is next.
+    mover: usize,
 }
 
 impl Node {
-    pub fn ucb_one(&self, N: usize) -> f32 {
-        if self.sim_count == 0 {
+    fn new(
+        parent: Option<usize>,
+        state: CompactState,
+        future: Option<Future>,
+        mover: usize,
+    ) -> Self {
+        Self {
+            parent,
+            children: [None, None, None, None],
+            score: AtomicUsize::new(0),
+            half_wins: AtomicUsize::new(0),
+            sim_count: AtomicUsize::new(0),
+            state,
+            future,
+            mover,
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children[0].is_none()
+    }
+
+    fn score(&self) -> usize {
+        self.score.load(Relaxed)
+    }
+
+    fn half_wins(&self) -> usize {
+        self.half_wins.load(Relaxed)
+    }
+
+    fn sims(&self) -> usize {
+        self.sim_count.load(Relaxed)
+    }
+
+    /// The UCB1 value of picking this node out of a parent visited
+    /// `parent_sims` times, to the snake whose move led here. Scores
+    /// are ours, so to an enemy a node is worth what it keeps from us.
+    pub fn ucb_one(&self, parent_sims: usize) -> f32 {
+        let sims = self.sims();
+        if sims == 0 {
             f32::MAX
         } else {
-            (self.score as f32 / self.sim_count as f32)
-                + 2.0 * f32::sqrt(f32::ln(N as f32) / self.sim_count as f32)
+            let sims = sims as f32;
+            let mean = self.score() as f32 / (WIN_SCORE as f32 * sims);
+            let value = if self.mover == 0 { mean } else { 1.0 - mean };
+            value + 2.0 * f32::sqrt(f32::ln(parent_sims as f32) / sims)
+        }
+    }
+
+    /// What a visit that hasn't been backed up yet scores, a loss to
+    /// the snake whose move led here
+    fn virtual_score(&self) -> usize {
+        if self.parent.is_some() && self.mover != 0 {
+            WIN_SCORE
+        } else {
+            0
         }
     }
 }
 
-#[derive(Clone)]
 pub struct GameTree {
-    inner_vec: Vec<Node>,
+    /// Threads share the lock to walk the tree and update the visit
+    /// statistics, and only take it alone to add an expanded node's
+    /// children
+    nodes: RwLock<Vec<Node>>,
     self_id: String,
-    enemy_id: String,
+    /// The index of our snake in the compact states
+    self_idx: usize,
+    /// Every snake's (index, id), in the order they move in the tree:
+    /// ours first and then the rest by index. Each level of the tree
+    /// is one snake's move, and a snake that is out is skipped.
+    movers: Vec<(usize, String)>,
+    rivals: usize,
+    /// How many visits a rollout in progress counts as, see `select`
+    virtual_loss: usize,
+}
+
+/// Where a rollout starts, copied out of the tree so the rollout can
+/// run without holding it
+pub struct Rollout {
+    pub node: usize,
+    state: CompactState,
+    future: Option<Future>,
+    /// The snakes still to move in the node's turn
+    to_move: Vec<String>,
+}
+
+/// A thread growing a shared tree: its own stream of the turn's seed
+/// for the rollouts to draw from, and its own rollout path search
+pub struct Worker {
+    self_id: String,
+    rivals: usize,
     astar: AStarBasic,
+    rng: StdRng,
 }
 
 impl GameTree {
-    pub fn new(state: State, self_id: String, enemy_id: String) -> Self {
-        Self {
-            inner_vec: vec![Node {
-                parent: None,
-                children: [None, None, None, None],
-                score: 0,
-                sim_count: 0,
-                future: None,
-                state,
-                is_self_node: false,
-            }],
+    /// A tree rooted at `state`, or an error if the state is too big to
+    /// keep compact
+    pub fn new(state: &State, self_id: String) -> Result<Self, String> {
+        let rivals = rivals(state, &self_id);
+        let compact = CompactState::from_state(state)?;
+        let self_idx = compact
+            .index_of(&self_id)
+            .ok_or_else(|| format!("{} isn't on the board", self_id))?;
+
+        let mut others = state
+            .board
+            .snakes
+            .keys()
+            .filter(|id| **id != self_id)
+            .filter_map(|id| compact.index_of(id).map(|i| (i, id.clone())))
+            .collect::<Vec<(usize, String)>>();
+        others.sort_unstable();
+        let mut movers = vec![(self_idx, self_id.clone())];
+        movers.extend(others);
+
+        let root = Node::new(None, compact, None, movers.len() - 1);
+        Ok(Self {
+            nodes: RwLock::new(vec![root]),
             self_id,
-            enemy_id,
-            astar: AStarBasic::new(),
-        }
+            self_idx,
+            movers,
+            rivals,
+            virtual_loss: 1,
+        })
+    }
+
+    /// Sets how many visits a rollout in progress counts as, at least
+    /// one
+    pub fn with_virtual_loss(mut self, virtual_loss: usize) -> Self {
+        self.virtual_loss = virtual_loss.max(1);
+        self
     }
 
-    pub fn root_child_scores(&self) -> Vec<(usize, usize)> {
-        self.inner_vec[0]
+    /// The nodes, shared with the other threads. A panic on one thread
+    /// fails the whole search anyway, so a poisoned lock is just taken.
+    fn nodes(&self) -> RwLockReadGuard<'_, Vec<Node>> {
+        self.nodes.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The positions in `movers` of the snakes still to move in the
+    /// turn `node` is part of, in order
+    fn rest_of_turn(&self, node: &Node) -> Vec<usize> {
+        (node.mover + 1..self.movers.len())
+            .filter(|m| node.state.is_present(self.movers[*m].0))
+            .collect()
+    }
+
+    /// The stats of each of the root's moves, in the order they were
+    /// expanded
+    pub fn root_moves(&self) -> Vec<MoveStats> {
+        let nodes = self.nodes();
+        nodes[0]
             .children
             .iter()
-            .filter_map(|i| match i {
-                Some(e) => Some((self.inner_vec[*e].sim_count, *e)),
-                None => None,
+            .filter_map(|i| *i)
+            .filter_map(|e| {
+                let node = &nodes[e];
+                node.future.map(|f| MoveStats {
+                    dir: f.dir,
+                    score: node.score(),
+                    half_wins: node.half_wins(),
+                    sims: node.sims(),
+                })
             })
-            .collect::<Vec<(usize, usize)>>()
+            .collect()
     }
 
-    pub fn get_best_move(&self, scores: Vec<(usize, usize)>) -> Dir {
-        let mut scores = scores;
-        scores.sort_by(|a, b| {
-            if a.0 > b.0 {
-                Ordering::Less
-            } else if a.0 < b.0 {
-                Ordering::Greater
-            } else {
-                Ordering::Equal
-            }
-        });
-
-        let self_snake = self.inner_vec[scores[0].1]
-            .state
-            .board
-            .snakes
-            .get(&self.self_id)
-            .unwrap();
+    /// The move to play out of `moves`, the root's moves. There must be
+    /// at least one.
+    pub fn get_best_move(&self, moves: &[MoveStats], by: FinalMove) -> Dir {
+        let key = |m: &MoveStats| match by {
+            FinalMove::Robust => m.sims as f64,
+            FinalMove::WinRate => m.win_rate(),
+        };
 
-        self_snake.body[1].dir_to(self_snake.body[0]).unwrap()
-    }
+        let mut moves = moves.to_vec();
+        moves.sort_by(|a, b| {
+            key(b).partial_cmp(&key(a)).unwrap_or(Ordering::Equal)
+        });
 
-    pub fn node_is_leaf(&self, node_id: usize) -> bool {
-        self.inner_vec[node_id].children[0].is_none()
-    }
+        let sorted = moves.iter().map(key).collect::<Vec<f64>>();
+        let pick = tie_break(&sorted, &self.nodes()[0].state.to_state());
 
-    pub fn node_has_sims(&self, node_id: usize) -> bool {
-        self.inner_vec[node_id].sim_count > 0
+        moves[pick].dir
     }
 
-    pub fn next_node(&self, node_id: usize) -> usize {
-        let curr_node = &self.inner_vec[node_id];
+    fn next_node(&self, nodes: &[Node], node_id: usize) -> usize {
+        let curr_node = &nodes[node_id];
         let children = curr_node.children;
 
-        let N = self.inner_vec[0].sim_count;
+        let parent_sims = curr_node.sims();
 
         let mut scores = children
             .iter()
             .filter_map(|i| match i {
-                Some(e) => Some((self.inner_vec[*e].ucb_one(N), *e)),
+                Some(e) => Some((nodes[*e].ucb_one(parent_sims), *e)),
                 None => None,
             })
             .collect::<Vec<(f32, usize)>>();
@@ -147,195 +335,261 @@ impl GameTree {
         scores[0].1
     }
 
-    fn get_rollout_score(&mut self, node_id: usize) -> usize {
-        let curr_future = self.inner_vec[node_id].future;
+    /// Gives `node` a virtual loss, visits that count as losses to the
+    /// snake moving there until `backprop` fills them in, and returns
+    /// its visits before
+    fn add_virtual_loss(&self, node: &Node) -> usize {
+        let loss = self.virtual_loss;
+        node.score.fetch_add(loss * node.virtual_score(), Relaxed);
+        node.sim_count.fetch_add(loss, Relaxed)
+    }
 
-        match curr_future {
-            Some(f) if f.finished => {
-                if f.alive {
-                    return 1;
-                } else {
-                    return 0;
+    /// Walks down the tree to the node to roll out from next, and
+    /// expands it first if it has been rolled out from before. Every
+    /// node on the way takes a virtual loss as it is passed, so threads
+    /// sharing the tree spread out over different lines instead of all
+    /// rolling out the same one.
+    pub fn select(&self) -> Rollout {
+        let mut curr = 0;
+        let sims = {
+            let nodes = self.nodes();
+            loop {
+                let sims = self.add_virtual_loss(&nodes[curr]);
+                if nodes[curr].is_leaf() {
+                    break sims;
                 }
+                curr = self.next_node(&nodes, curr);
             }
-            _ => {
-                let mut tmp_state = self.inner_vec[node_id].state.clone();
-                let mut rng = rand::thread_rng();
-
-                if self.inner_vec[node_id].is_self_node {
-                    let mut moves = HashMap::new();
-                    let enemy_snake =
-                        tmp_state.board.snakes.get(&self.enemy_id).unwrap();
-
-                    moves.insert(
-                        self.enemy_id.clone(),
-                        *get_snake_successors(enemy_snake, &tmp_state, false)
-                            .choose(&mut rng)
-                            .unwrap_or(&Dir::Up),
-                    );
-
-                    let tmp_future =
-                        process_step(&mut tmp_state, &self.self_id, &moves);
-
-                    if tmp_future.finished {
-                        if tmp_future.alive {
-                            return 1;
-                        } else {
-                            return 0;
-                        }
-                    }
-                }
-
-                loop {
-                    let moves = get_rollout_moves(
-                        &tmp_state,
-                        &mut rng,
-                        &mut self.astar,
-                    );
-                    let future =
-                        process_step(&mut tmp_state, &self.self_id, &moves);
+        };
 
-                    if future.finished {
-                        if future.alive {
-                            return 1;
-                        } else {
-                            return 0;
-                        }
-                    }
-                }
+        // Finished games can't be expanded, and are rolled out again
+        if sims > 0 {
+            if let Some(child) = self.expand(curr) {
+                curr = child;
+                self.add_virtual_loss(&self.nodes()[curr]);
             }
         }
-    }
-
-    pub fn rollout(&mut self, node_id: usize) {
-        let mut curr = node_id;
-
-        let score = self.get_rollout_score(node_id);
-
-        // Back-propogate the result of the rollout
-        loop {
-            self.inner_vec[curr].score += score;
-            self.inner_vec[curr].sim_count += 1;
 
-            if self.inner_vec[curr].parent.is_none() {
-                break;
-            }
-            curr = self.inner_vec[curr].parent.unwrap();
+        let nodes = self.nodes();
+        let node = &nodes[curr];
+        let to_move = self.rest_of_turn(node).into_iter();
+        Rollout {
+            node: curr,
+            state: node.state.clone(),
+            future: node.future,
+            to_move: to_move.map(|m| self.movers[m].1.clone()).collect(),
         }
     }
 
-    pub fn expand(&mut self, node_id: usize) -> Option<usize> {
-        match self.inner_vec[node_id].future {
-            Some(future) if future.finished => {
-                return None;
-            }
-            _ => {}
-        };
+    /// Adds the (score, half wins) of a rollout from `node_id` to it
+    /// and every node above it in place of the virtual losses `select`
+    /// gave them, leaving one of those visits counted
+    pub fn backprop(&self, node_id: usize, result: (usize, usize)) {
+        let (score, half_wins) = result;
+        let nodes = self.nodes();
+        let mut node = Some(node_id);
+        while let Some(i) = node {
+            let node_ref = &nodes[i];
+            let virtual_score = self.virtual_loss * node_ref.virtual_score();
+            node_ref.score.fetch_add(score, Relaxed);
+            node_ref.score.fetch_sub(virtual_score, Relaxed);
+            node_ref.half_wins.fetch_add(half_wins, Relaxed);
+            node_ref.sim_count.fetch_sub(self.virtual_loss - 1, Relaxed);
+            node = node_ref.parent;
+        }
+    }
 
-        let curr_state = self.inner_vec[node_id].state.clone();
-        let curr_idx = self.inner_vec.len();
-        let is_self_node = !self.inner_vec[node_id].is_self_node;
+    /// Adds the children of `node_id` and returns the first, or None if
+    /// it has none. A node another thread already expanded keeps the
+    /// children it has.
+    pub fn expand(&self, node_id: usize) -> Option<usize> {
+        let (compact, mover) = {
+            let nodes = self.nodes();
+            let node = &nodes[node_id];
+            match node.future {
+                Some(future) if future.finished => {
+                    return None;
+                }
+                _ if !node.is_leaf() => return node.children[0],
+                _ => {}
+            };
 
-        let node_snake_id = if is_self_node {
-            self.self_id.clone()
-        } else {
-            self.enemy_id.clone()
+            // After the last snake of a turn it is our move again
+            let mover = self.rest_of_turn(node).first().cloned().unwrap_or(0);
+            (node.state.clone(), mover)
         };
+        let curr_state = compact.to_state();
+        let is_self_node = mover == 0;
 
+        let (node_snake_idx, node_snake_id) = self.movers[mover].clone();
         let node_snake = curr_state.board.snakes.get(&node_snake_id).unwrap();
 
         let successors =
             get_snake_successors(&node_snake, &curr_state, is_self_node);
 
-        for (idx, dir) in successors.iter().enumerate() {
-            self.create_node(
-                node_id,
-                &curr_state,
-                *dir,
-                node_snake_id.clone(),
-                is_self_node,
+        let mut children = Vec::with_capacity(4);
+        let mut positions = Vec::with_capacity(successors.len());
+        for dir in &successors {
+            let (idx, dir) = (node_snake_idx, *dir);
+            let node = self.create_node(node_id, &compact, dir, idx, mover);
+
+            // Enemy moves that end the same way, like two ways for it
+            // to die, are one child rather than two splitting the sims
+            let position = (
+                node.state.hash(),
+                node.future.map(|f| Future { dir: Dir::Up, ..f }),
             );
-            self.inner_vec[node_id].children[idx] = Some(curr_idx + idx);
+            if !is_self_node && positions.contains(&position) {
+                continue;
+            }
+            positions.push(position);
+            children.push(node);
         }
 
         if is_self_node {
-            let mut term_idx = successors.len();
-            for p in node_snake.body[0].orthogonal().iter() {
+            for p in node_snake.body[0].neighbors(&curr_state).iter() {
                 if p.safety_index(&node_snake, &curr_state)
                     == SafetyIndex::Risky
                 {
-                    let mut moves = HashMap::new();
-                    let enemy_snake =
-                        curr_state.board.snakes.get(&self.enemy_id).unwrap();
-
-                    moves.insert(
-                        self.self_id.clone(),
-                        node_snake.body[0].dir_to(*p).unwrap(),
-                    );
-
-                    moves.insert(
-                        self.enemy_id.clone(),
-                        enemy_snake.body[0].dir_to(*p).unwrap(),
-                    );
-
-                    self.create_terminal_node(node_id, &curr_state, moves, 0);
-                    self.inner_vec[node_id].children[term_idx] =
-                        Some(curr_idx + term_idx);
-                    term_idx += 1;
+                    // We and every enemy next to the square move onto it
+                    let mut moves = Vec::new();
+                    for (i, id) in &self.movers {
+                        let head = match curr_state.board.snakes.get(id) {
+                            Some(s) => s.body[0],
+                            None => continue,
+                        };
+                        if !head.neighbors(&curr_state).contains(p) {
+                            continue;
+                        }
+                        if let Some(dir) = head.step_dir(*p) {
+                            moves.push((*i, dir));
+                        }
+                    }
+
+                    let node =
+                        self.create_terminal_node(node_id, &compact, &moves);
+                    children.push(node);
                 }
             }
         }
 
-        return self.inner_vec[node_id].children[0];
+        let mut nodes =
+            self.nodes.write().unwrap_or_else(PoisonError::into_inner);
+        if !nodes[node_id].is_leaf() {
+            return nodes[node_id].children[0];
+        }
+        for (child_idx, node) in children.into_iter().enumerate() {
+            nodes[node_id].children[child_idx] = Some(nodes.len());
+            nodes.push(node);
+        }
+
+        nodes[node_id].children[0]
     }
 
     fn create_terminal_node(
-        &mut self,
+        &self,
         parent_id: usize,
-        st: &State,
-        moves: HashMap<String, Dir>,
-        score: usize,
-    ) {
+        st: &CompactState,
+        moves: &[(usize, Dir)],
+    ) -> Node {
         let mut new_state = st.clone();
-        let future = process_step(&mut new_state, &self.self_id, &moves);
+        let mut future = new_state.step(self.self_idx, moves);
+        if let Some((_, dir)) = moves.iter().find(|m| m.0 == self.self_idx) {
+            future.dir = *dir;
+        }
 
-        self.inner_vec.push(Node {
-            parent: Some(parent_id),
-            children: [None, None, None, None],
-            sim_count: 0,
-            state: new_state,
-            future: Some(future),
-            is_self_node: true,
-            score,
-        });
+        Node::new(Some(parent_id), new_state, Some(future), 0)
     }
 
     fn create_node(
-        &mut self,
+        &self,
         parent_id: usize,
-        st: &State,
+        st: &CompactState,
         node_move: Dir,
-        node_snake_id: String,
-        is_self_node: bool,
-    ) {
+        node_snake_idx: usize,
+        mover: usize,
+    ) -> Node {
         let mut new_state = st.clone();
-        let mut moves = HashMap::new();
-        moves.insert(node_snake_id.clone(), node_move);
-        let mut future = process_step(&mut new_state, &self.self_id, &moves);
+        let mut future =
+            new_state.step(self.self_idx, &[(node_snake_idx, node_move)]);
         future.dir = node_move;
 
-        self.inner_vec.push(Node {
-            parent: Some(parent_id),
-            children: [None, None, None, None],
-            score: 0,
-            sim_count: 0,
-            state: new_state,
-            future: Some(future),
-            is_self_node,
-        });
+        Node::new(Some(parent_id), new_state, Some(future), mover)
+    }
+}
+
+impl Worker {
+    /// A worker for `tree` that rolls out with the given stream of
+    /// `seed`
+    pub fn new(tree: &GameTree, seed: Seed, stream: u64) -> Self {
+        Self {
+            self_id: tree.self_id.clone(),
+            rivals: tree.rivals,
+            astar: AStarBasic::new(),
+            rng: seed.rng(stream),
+        }
+    }
+
+    /// Sets the pathfinding search used by the rollout policy
+    pub fn set_path_search(&mut self, search: PathSearch) {
+        self.astar.set_search(search);
+    }
+
+    /// Plays a game out from `from` and returns its (score, half wins)
+    pub fn rollout(&mut self, from: &Rollout) -> (usize, usize) {
+        match from.future {
+            Some(f) if f.finished => terminal_score(&f, self.rivals),
+            _ => {
+                let mut tmp_state = from.state.to_state();
+                let mut turns = ROLLOUT_MAX_TURNS;
+                let rng = &mut self.rng;
+                // Food keeps spawning past the tree, or long rollouts
+                // would all end in starvation
+                let food = SpawnRules::from_config();
+
+                // Finish the node's turn before playing whole turns
+                if !from.to_move.is_empty() {
+                    let mut moves = HashMap::new();
+                    for id in &from.to_move {
+                        let s = match tmp_state.board.snakes.get(id) {
+                            Some(s) => s,
+                            None => continue,
+                        };
+                        let dir = get_snake_successors(s, &tmp_state, false)
+                            .choose(rng)
+                            .cloned()
+                            .unwrap_or(Dir::Up);
+                        moves.insert(id.clone(), dir);
+                    }
+
+                    let tmp_future =
+                        process_step(&mut tmp_state, &self.self_id, &moves);
+                    food.spawn(&mut tmp_state, rng);
+
+                    if tmp_future.finished {
+                        return terminal_score(&tmp_future, self.rivals);
+                    }
+                    turns -= 1;
+                }
+
+                let food_rng = StdRng::seed_from_u64(rng.gen());
+                let mut sim = Simulation::new(tmp_state, &self.self_id)
+                    .with_food(food, food_rng);
+                let astar = &mut self.astar;
+                let policy = |s: &Snake, st: &State| {
+                    get_rollout_move(s, st, rng, astar)
+                };
+
+                match sim.run(policy, turns, |_| false) {
+                    Some(f) if f.finished => terminal_score(&f, self.rivals),
+                    _ => (DRAW_SCORE, 1),
+                }
+            }
+        }
     }
 }
 
+#[cfg(feature = "debug-endpoints")]
 impl GameTree {
     /// Converts the game tree into a DOT file format to be displayed by GraphViz
     pub fn write_dot(&self, path: &Path) -> Result<(), String> {
@@ -346,17 +600,18 @@ impl GameTree {
                 format!("Couldn't create {}: {}", display, why.description())
             })
             .and_then(|mut file| {
+                let nodes = self.nodes();
                 let mut buffer = format!("digraph GameTree {{\n\t0 [shape=record,label=\"root|{{si: {sims}}}\"style=filled,fillcolor=\".7 .3 1.0\"];\n",
-                    sims=self.inner_vec[0].sim_count);
+                    sims=nodes[0].sims());
 
-                self.inner_vec.iter().for_each(|node| {
+                nodes.iter().for_each(|node| {
                     node.children.iter().filter_map(|c| *c).for_each(|c| {
-                        let node = &self.inner_vec[c];
+                        let node = &nodes[c];
                         let dir = node.future.unwrap().dir;
-                        let score = node.score;
-                        let sims = node.sim_count;
+                        let score = node.score();
+                        let sims = node.sims();
                         let parent = node.parent.unwrap();
-                        let style = if node.is_self_node {
+                        let style = if node.mover == 0 {
                             ""
                         } else {
                             "style=filled,fillcolor=\"0.1 0.0 0.8\""
@@ -389,37 +644,257 @@ impl GameTree {
     }
 }
 
-fn get_rollout_moves(
+/// The move a rollout plays for a snake: mostly a random safe one,
+/// sometimes the one the rollout path search picks
+fn get_rollout_move(
+    s: &Snake,
     st: &State,
-    rng: &mut ThreadRng,
+    rng: &mut impl Rng,
     astar: &mut AStarBasic,
-) -> HashMap<String, Dir> {
-    let mut dirs = HashMap::<String, Dir>::with_capacity(st.board.snakes.len());
-    for (id, s) in &st.board.snakes {
-        let rand_num: f32 = rng.gen();
-        if rand_num < 0.2 {
-            dirs.insert(id.to_string(), astar.get_move(s, st));
-        } else {
-            dirs.insert(
-                id.to_string(),
-                *get_snake_successors(s, st, false)
-                    .choose(rng)
-                    .unwrap_or(&Dir::Up),
-            );
-        }
+) -> Dir {
+    let rand_num: f32 = rng.gen();
+    if rand_num < 0.2 {
+        // The rollout path search already follows the move budget
+        return astar.get_move(s, st, &TimeBudget::untimed());
     }
 
-    dirs
+    // Racing someone else for a food ends in a collision we lose, so
+    // random moves leave those foods alone
+    let successors = get_snake_successors(s, st, false);
+    let uncontested = successors
+        .iter()
+        .filter(|d| {
+            let p = d.resulting_point(s.body[0], st);
+            !st.board.food.contains(&p) || s.owns_food(p, st)
+        })
+        .cloned()
+        .collect::<Vec<Dir>>();
+    let choices = if uncontested.is_empty() {
+        &successors
+    } else {
+        &uncontested
+    };
+
+    *choices.choose(rng).unwrap_or(&Dir::Up)
 }
 
 fn get_snake_successors(s: &Snake, st: &State, avoid_risky: bool) -> Vec<Dir> {
+    let grid = st.occupancy();
     s.body[0]
-        .orthogonal()
+        .neighbors(st)
         .iter()
-        .filter_map(|e| match e.safety_index(&s, &st) {
-            SafetyIndex::Safe => s.body[0].dir_to(*e),
-            SafetyIndex::Risky if !avoid_risky => s.body[0].dir_to(*e),
+        .filter_map(|e| match grid.safety_index(*e, s, st) {
+            SafetyIndex::Safe => s.body[0].step_dir(*e),
+            SafetyIndex::Risky if !avoid_risky => s.body[0].step_dir(*e),
             _ => None,
         })
         .collect::<Vec<Dir>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+
+    /// Grows a tree on one thread for a fixed number of rollouts
+    fn grow(
+        tree: GameTree,
+        seed: Seed,
+        stream: u64,
+        steps: usize,
+    ) -> Vec<MoveStats> {
+        let mut worker = Worker::new(&tree, seed, stream);
+        tree.expand(0).unwrap();
+        for _ in 0..steps {
+            let rollout = tree.select();
+            let result = worker.rollout(&rollout);
+            tree.backprop(rollout.node, result);
+        }
+
+        tree.root_moves()
+    }
+
+    #[test]
+    fn test_seeded_rollouts() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(2, 5), (2, 6), (2, 7)]),
+                ("enemy", &[(8, 5), (8, 6), (8, 7)]),
+            ],
+            &[(5, 5)],
+        );
+        let tree = || GameTree::new(&st, you.id.clone()).unwrap();
+
+        // The same seed rolls out the same games
+        let wins = grow(tree(), Seed(1), 0, 60);
+        assert!(wins.iter().any(|m| m.sims > 0));
+        assert_eq!(grow(tree(), Seed(1), 0, 60), wins);
+        assert_eq!(grow(tree(), Seed(1), 3, 60), grow(tree(), Seed(1), 3, 60));
+    }
+
+    #[test]
+    fn test_root_moves() {
+        // A head-to-head square is a terminal child of the root, and
+        // still a move of its own
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(4, 5), (3, 5), (2, 5)]),
+                ("enemy", &[(6, 5), (7, 5), (8, 5)]),
+            ],
+            &[],
+        );
+        let tree = || GameTree::new(&st, you.id.clone()).unwrap();
+        let moves = grow(tree(), Seed(7), 0, 80);
+        let count = |dir| moves.iter().filter(|m| m.dir == dir).count();
+        assert_eq!(count(Dir::Right), 1);
+        assert!(moves.iter().all(|m| count(m.dir) == 1));
+        assert_eq!(moves.iter().map(|m| m.sims).sum::<usize>(), 80);
+
+        // The robust child and the best win rate can disagree
+        let stats = |dir, half_wins, sims| MoveStats {
+            dir,
+            score: 0,
+            half_wins,
+            sims,
+        };
+        let moves = [stats(Dir::Up, 30, 60), stats(Dir::Left, 6, 4)];
+        assert_eq!(moves[1].win_rate(), 0.75);
+        let tree = tree();
+        assert_eq!(tree.get_best_move(&moves, FinalMove::Robust), Dir::Up);
+        assert_eq!(tree.get_best_move(&moves, FinalMove::WinRate), Dir::Left);
+    }
+
+    #[test]
+    fn test_virtual_loss() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(2, 5), (2, 6), (2, 7)]),
+                ("enemy", &[(8, 5), (8, 6), (8, 7)]),
+            ],
+            &[],
+        );
+        let tree = GameTree::new(&st, you.id.clone()).unwrap();
+        tree.expand(0).unwrap();
+
+        // A node being rolled out already counts as visited, so the
+        // next thread to select picks another
+        let first = tree.select();
+        let second = tree.select();
+        assert_ne!(first.node, second.node);
+        let sims = |tree: &GameTree| -> usize {
+            tree.root_moves().iter().map(|m| m.sims).sum()
+        };
+        assert_eq!(sims(&tree), 2);
+
+        // and backing the results up only adds their scores
+        tree.backprop(first.node, (WIN_SCORE, 2));
+        tree.backprop(second.node, (0, 0));
+        assert_eq!(sims(&tree), 2);
+        let moves = tree.root_moves();
+        assert_eq!(moves.iter().map(|m| m.half_wins).sum::<usize>(), 2);
+        assert_eq!(tree.nodes()[0].score(), WIN_SCORE);
+
+        // A bigger virtual loss counts as more visits until then
+        let tree = GameTree::new(&st, you.id.clone())
+            .unwrap()
+            .with_virtual_loss(3);
+        tree.expand(0).unwrap();
+        let rollout = tree.select();
+        assert_eq!(sims(&tree), 3);
+        tree.backprop(rollout.node, (WIN_SCORE, 2));
+        assert_eq!(sims(&tree), 1);
+        assert_eq!(tree.nodes()[0].score(), WIN_SCORE);
+    }
+
+    #[test]
+    fn test_enemy_replies() {
+        // Going up into the corner lets the enemy move left next to our
+        // head and shut us in
+        let (you, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(0, 1), (0, 2), (0, 3)]),
+                ("enemy", &[(2, 0), (3, 0), (4, 0)]),
+            ],
+            &[],
+        );
+        let tree = GameTree::new(&st, you.id.clone()).unwrap();
+        let mut worker = Worker::new(&tree, Seed(5), 0);
+        tree.expand(0).unwrap();
+        for _ in 0..300 {
+            let rollout = tree.select();
+            let result = worker.rollout(&rollout);
+            tree.backprop(rollout.node, result);
+        }
+
+        // The enemy picks its replies for itself, so it finds the one
+        // that wins
+        let nodes = tree.nodes();
+        let child = |node: usize, dir: Dir| {
+            let children = nodes[node].children;
+            children
+                .iter()
+                .filter_map(|c| *c)
+                .find(|c| nodes[*c].future.map(|f| f.dir) == Some(dir))
+        };
+        let up = child(0, Dir::Up).unwrap();
+        let replies = nodes[up].children;
+        let most_tried = replies
+            .iter()
+            .filter_map(|c| *c)
+            .max_by_key(|c| nodes[*c].sims());
+        assert_eq!(most_tried, child(up, Dir::Left));
+        assert_eq!(nodes[most_tried.unwrap()].score(), 0);
+        drop(nodes);
+
+        // and we stay out of the corner
+        let moves = tree.root_moves();
+        assert_eq!(tree.get_best_move(&moves, FinalMove::Robust), Dir::Right);
+    }
+
+    #[test]
+    fn test_every_snake_moves() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("a", &[(1, 1), (1, 2), (1, 3)]),
+                ("b", &[(9, 1), (9, 2), (9, 3)]),
+                ("c", &[(9, 9), (9, 8), (9, 7)]),
+            ],
+            &[],
+        );
+        let tree = || GameTree::new(&st, you.id.clone()).unwrap();
+        let grown = tree();
+        assert_eq!(grown.movers.len(), 4);
+        assert_eq!(grown.movers[0], (grown.self_idx, you.id.clone()));
+
+        // Each level down the tree is the next snake's move, and after
+        // the last enemy it is ours again
+        let mut node = 0;
+        for turn in 0..5 {
+            node = grown.expand(node).unwrap();
+            let nodes = grown.nodes();
+            let moved = &nodes[node];
+            assert_eq!(moved.mover, turn % 4);
+            if turn == 1 {
+                let after = moved.state.to_state();
+                let head = |st: &State, id: &str| st.board.snakes[id].body[0];
+                assert_ne!(head(&after, "a"), head(&st, "a"));
+                assert_eq!(head(&after, "b"), head(&st, "b"));
+            }
+        }
+
+        // Rollouts from partway through a turn finish it first
+        let moves = grow(tree(), Seed(2), 0, 40);
+        assert_eq!(moves.iter().map(|m| m.sims).sum::<usize>(), 40);
+    }
+}
@@ -16,102 +16,180 @@
  *
  */
 
+//! The MonteCarlo profile, the `monte_carlo` one. It grows a
+//! `GameTree`, whose nodes live in a vec and point at each other by
+//! index, on several threads at once. The threads pick nodes and back
+//! results up at the same time, and only wait on each other to add new
+//! nodes.
+//!
+//! Every snake on the board moves in the tree, ours and then each
+//! enemy's, so it searches games of any size rather than just duels.
+
 mod game_tree;
+pub mod tuning;
 
-use game_tree::GameTree;
+use game_tree::{GameTree, MoveStats, Worker};
+pub use game_tree::FinalMove;
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use rayon::prelude::*;
 
-use crate::game::{Dir, Snake, State};
-use crate::profile::Profile;
+use crate::config;
+use crate::game::{Dir, PathSearch, Snake, State};
+use crate::profile::{Profile, Seed, TimeBudget};
+#[cfg(feature = "debug-endpoints")]
 use std::path::Path;
-use std::time::SystemTime;
-
-const SIM_TIME_MAX_MILLIS: u128 = 390;
-const NUM_TREES: usize = 22;
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct MonteCarlo {
     status: &'static str,
+    win_prob: Option<f64>,
+    /// The win rate of each first move in the last search
+    win_rates: Vec<(Dir, f64)>,
+    /// How many threads grow the tree at most, see `tuning`
+    workers: usize,
+    /// How many visits a rollout in progress counts as, see `tuning`
+    virtual_loss: usize,
+    /// The rollouts run by the last search
+    rollouts: usize,
 }
 
-type TreeThread = (GameTree, usize);
-
 impl Profile for MonteCarlo {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
-        let start_time = SystemTime::now();
-
-        let mut enemy_id = String::from("F");
-        for (pos_id, _) in &st.board.snakes {
-            if *pos_id != s.id {
-                enemy_id = pos_id.to_string();
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        budget: &TimeBudget,
+    ) -> Dir {
+        let budget = budget.capped_for(config::get().mcts_budget_millis, st);
+
+        let seed = Seed::for_turn(st);
+        let tree = GameTree::new(st, s.id.clone());
+        let tree = match tree {
+            Ok(tree) => tree.with_virtual_loss(self.virtual_loss),
+            Err(e) => {
+                warn!("Can't search this game: {}", e);
+                self.win_prob = None;
+                self.win_rates.clear();
+                return s.find_safe_move(st);
             }
+        };
+
+        if tree.expand(0).is_none() {
+            // We're dead, RIP
+            self.win_prob = Some(0.0);
+            self.win_rates.clear();
+            return Dir::Up;
         }
 
-        let mut starter_tree =
-            GameTree::new(st.clone(), s.id.clone(), enemy_id);
+        // Perform the Monte Carlo tree search until the time is up
+        let workers = self.workers.clamp(1, rayon::current_num_threads());
+        (0..workers).into_par_iter().for_each(|i| {
+            let mut worker = Worker::new(&tree, seed, i as u64);
+            while !budget.expired() {
+                // Fall back to cheaper rollout pathfinding late on
+                let ms = budget.remaining().as_millis();
+                worker.set_path_search(PathSearch::for_budget(ms));
+
+                let rollout = tree.select();
+                let result = worker.rollout(&rollout);
+                tree.backprop(rollout.node, result);
+            }
+        });
+        let moves = tree.root_moves();
 
-        let curr = match starter_tree.expand(0) {
-            Some(id) => id,
-            // We're dead, RIP
-            None => return Dir::Up,
-        };
+        self.rollouts = moves.iter().map(|m| m.sims).sum();
+        observe!(MCTS_ROLLOUTS, self.rollouts as f64);
 
-        let mut trees: Vec<TreeThread> = (0..NUM_TREES)
-            .map(|_| (starter_tree.clone(), curr))
+        self.win_rates = moves
+            .iter()
+            .filter(|m| m.sims > 0)
+            .map(|m| (m.dir, m.win_rate()))
             .collect();
 
-        // Perform the Monte Carlo tree search until the time is up
-        while start_time.elapsed().unwrap().as_millis() < SIM_TIME_MAX_MILLIS {
-            trees.par_iter_mut().for_each(|(tree, curr)| {
-                if tree.node_is_leaf(*curr) {
-                    if tree.node_has_sims(*curr) {
-                        *curr = tree.expand(*curr).unwrap_or(0);
-                    } else {
-                        tree.rollout(*curr);
-                        *curr = 0;
-                    }
-                } else {
-                    *curr = tree.next_node(*curr);
-                }
-            });
-        }
-
-        // Merge the simulated trees
-        let final_scores = trees
+        // Estimate the win probability of the most simulated move
+        self.win_prob = moves
             .iter()
-            .map(|(tree, _)| tree.root_child_scores())
-            .fold(vec![], |acc, t| {
-                let mut tmp_acc = acc;
-                t.iter().enumerate().for_each(|(idx_1, (score, idx_2))| {
-                    if tmp_acc.len() <= idx_1 {
-                        tmp_acc.push((*score, *idx_2));
-                    } else {
-                        tmp_acc[idx_1].0 += score;
-                    }
-                });
-                tmp_acc
-            });
+            .max_by_key(|m| m.sims)
+            .filter(|m| m.sims > 0)
+            .map(MoveStats::win_rate);
 
+        #[cfg(feature = "debug-endpoints")]
         if st.turn == 3 {
-            trees[0].0.write_dot(&Path::new("samples/tree.gv")).unwrap();
+            let path = format!("{}/tree.gv", config::get().samples_dir);
+            tree.write_dot(Path::new(&path)).unwrap();
         }
 
-        return starter_tree.get_best_move(final_scores);
+        let by = config::get().mcts_final_move;
+        tree.get_best_move(&moves, by)
     }
 
     fn get_status(&self) -> String {
         String::from(self.status)
     }
+
+    fn win_probability(&self) -> Option<f64> {
+        self.win_prob
+    }
+
+    fn move_scores(&self) -> Vec<(Dir, f64)> {
+        self.win_rates.clone()
+    }
 }
 
 impl MonteCarlo {
     #[allow(dead_code)]
     pub fn new() -> Self {
-        debug!("MonteCarlo profile initialized");
+        let tuning = tuning::current();
+        Self::with_workers(tuning.workers)
+            .with_virtual_loss(tuning.virtual_loss)
+    }
+
+    /// A profile that grows its tree on up to the given number of
+    /// threads, whatever this host was tuned for
+    pub fn with_workers(workers: usize) -> Self {
+        debug!("MonteCarlo profile initialized with {} workers", workers);
         Self {
             status: "MonteCarlo",
+            win_prob: None,
+            win_rates: Vec::new(),
+            workers,
+            virtual_loss: tuning::DEFAULT_VIRTUAL_LOSS,
+            rollouts: 0,
         }
     }
+
+    /// Sets how many visits each rollout in progress counts as
+    pub fn with_virtual_loss(mut self, virtual_loss: usize) -> Self {
+        self.virtual_loss = virtual_loss;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+
+    #[test]
+    fn test_shared_tree() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(2, 5), (2, 6), (2, 7)]),
+                ("enemy", &[(8, 5), (8, 6), (8, 7)]),
+            ],
+            &[(5, 5)],
+        );
+
+        // Every thread's rollouts end up in the one tree
+        let mut profile = MonteCarlo::with_workers(4);
+        let budget = TimeBudget::untimed().capped(100);
+        let dir = profile.get_move(&you, &st, &budget);
+        assert!(profile.rollouts > 0);
+        assert!(profile.win_rates.iter().any(|(d, _)| *d == dir));
+        let rate = profile.win_probability().unwrap();
+        assert!((0.0..=1.0).contains(&rate));
+    }
 }
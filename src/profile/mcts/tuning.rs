@@ -0,0 +1,221 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Tuning of how many threads grow MonteCarlo's tree, which depends
+//! on the machine. Too few leave cores idle, too many spend their time
+//! waiting on each other to expand the tree. `tune` benchmarks a few
+//! worker counts on canned positions and the result is saved to the
+//! cache directory, to be loaded by every MonteCarlo profile after
+//! that. The virtual loss isn't benchmarked, since it changes what the
+//! threads search rather than how fast, and is kept as saved.
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use super::MonteCarlo;
+use crate::cache::CacheDir;
+use crate::game::{build_state, Snake, State};
+use crate::profile::{Profile, TimeBudget};
+
+/// The tuning's schema in the cache directory
+const TUNING_SCHEMA: &str = "mcts_tuning";
+const TUNING_VERSION: u64 = 2;
+
+/// How long each candidate searches each position for
+const BENCH_MILLIS: u64 = 300;
+
+/// The fewest workers within this fraction of the best throughput win,
+/// since they leave more of the pool to the rest of the server
+const THROUGHPUT_TOLERANCE: f64 = 0.95;
+
+/// How many visits a rollout in progress counts as unless tuned
+pub const DEFAULT_VIRTUAL_LOSS: usize = 1;
+
+static TUNING: OnceLock<Tuning> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    /// How many threads grow the tree at most
+    pub workers: usize,
+    /// The rollouts per second measured with that many workers, or 0
+    /// if the host was never tuned
+    pub rollouts_per_sec: f64,
+    /// How many visits each rollout in progress counts as, to keep the
+    /// other workers off its line until its result is in
+    #[serde(default = "default_virtual_loss")]
+    pub virtual_loss: usize,
+}
+
+fn default_virtual_loss() -> usize {
+    DEFAULT_VIRTUAL_LOSS
+}
+
+/// Before the host has been tuned every thread of the pool searches
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            workers: rayon::current_num_threads(),
+            rollouts_per_sec: 0.0,
+            virtual_loss: DEFAULT_VIRTUAL_LOSS,
+        }
+    }
+}
+
+impl Tuning {
+    /// Loads the tuning saved in the cache, or the defaults if there
+    /// is none or it can't be read
+    pub fn load(cache: &CacheDir) -> Self {
+        match cache.load::<Self>(TUNING_SCHEMA, TUNING_VERSION) {
+            Some(t) if t.workers > 0 => t,
+            Some(_) => {
+                warn!("Ignoring MCTS tuning with no workers");
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub fn save(&self, cache: &CacheDir) -> Result<(), String> {
+        cache.save(TUNING_SCHEMA, TUNING_VERSION, self)
+    }
+}
+
+/// The tuning of this host, loaded once from the cache directory
+pub fn current() -> Tuning {
+    *TUNING.get_or_init(|| Tuning::load(&CacheDir::configured()))
+}
+
+/// Positions to benchmark on: an open duel and a crowded board
+fn positions() -> Vec<(Snake, State)> {
+    vec![
+        build_state(
+            11,
+            11,
+            &[
+                ("you", &[(2, 5), (2, 6), (2, 7), (2, 8)]),
+                ("enemy", &[(8, 5), (8, 4), (8, 3), (8, 2)]),
+            ],
+            &[(5, 5), (0, 0), (10, 10)],
+        ),
+        build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (1, 4), (1, 5), (2, 5), (3, 5), (4, 5)]),
+                ("enemy", &[(5, 3), (5, 2), (5, 1), (4, 1), (3, 1)]),
+            ],
+            &[(3, 3)],
+        ),
+    ]
+}
+
+/// The rollouts per second MonteCarlo manages with the given number
+/// of workers, over every benchmark position
+fn throughput(workers: usize) -> f64 {
+    let start = Instant::now();
+    let mut rollouts = 0;
+
+    for (you, st) in positions() {
+        let mut profile = MonteCarlo::with_workers(workers);
+        let budget = TimeBudget::untimed().capped(BENCH_MILLIS);
+        profile.get_move(&you, &st, &budget);
+        rollouts += profile.rollouts;
+    }
+
+    rollouts as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Picks the fewest workers that come close to the best throughput
+fn pick(results: &[Tuning]) -> Tuning {
+    let best = results
+        .iter()
+        .map(|t| t.rollouts_per_sec)
+        .fold(0.0, f64::max);
+
+    results
+        .iter()
+        .filter(|t| t.rollouts_per_sec >= best * THROUGHPUT_TOLERANCE)
+        .min_by_key(|t| t.workers)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Benchmarks worker counts up to the number of threads in the pool
+/// and returns the best one
+pub fn tune() -> Tuning {
+    let threads = rayon::current_num_threads();
+    let mut candidates = vec![1, threads / 4, threads / 2, threads];
+    candidates.retain(|w| *w > 0);
+    candidates.sort_unstable();
+    candidates.dedup();
+
+    let results = candidates
+        .iter()
+        .map(|&workers| {
+            let tuning = Tuning {
+                workers,
+                rollouts_per_sec: throughput(workers),
+                ..current()
+            };
+            let rate = tuning.rollouts_per_sec;
+            info!("{} workers: {:.0} rollouts/s", workers, rate);
+            tuning
+        })
+        .collect::<Vec<Tuning>>();
+
+    pick(&results)
+}
+
+/// Tunes this host and saves the result to the cache directory
+pub fn run() {
+    let tuning = tune();
+
+    match tuning.save(&CacheDir::configured()) {
+        Ok(_) => println!(
+            "Saved {} workers ({:.0} rollouts/s)",
+            tuning.workers, tuning.rollouts_per_sec
+        ),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let tuning = |workers, rollouts_per_sec| Tuning {
+            workers,
+            rollouts_per_sec,
+            virtual_loss: 2,
+        };
+
+        let results = [tuning(4, 900.0), tuning(8, 980.0), tuning(16, 1000.0)];
+        assert_eq!(pick(&results).workers, 8);
+        assert_eq!(pick(&[]), Tuning::default());
+
+        let root = std::env::temp_dir().join("test_mcts_tuning");
+        let cache = CacheDir::new(&root);
+        results[1].save(&cache).unwrap();
+        assert_eq!(Tuning::load(&cache), results[1]);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(Tuning::load(&cache), Tuning::default());
+    }
+}
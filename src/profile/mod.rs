@@ -15,25 +15,40 @@
  * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
  *
  */
+use rand::Rng;
+
+use super::config;
 use super::game::{Dir, Snake, State};
 
 mod aggressive;
 mod alpha_beta;
 mod astarbasic;
+mod budget;
 mod cautious;
+pub mod eval;
 mod follow;
+#[cfg(feature = "debug-endpoints")]
+mod human;
 mod mcts;
 mod notsuck;
+mod presets;
+mod seed;
 mod sim;
 mod straight;
+mod transposition;
 
 pub use aggressive::Aggressive;
-pub use alpha_beta::AlphaBeta;
+pub use alpha_beta::{AlphaBeta, LeafScore, SearchMode, SearchReport};
 pub use astarbasic::AStarBasic;
+pub use budget::TimeBudget;
 pub use cautious::Cautious;
 pub use follow::Follow;
-pub use mcts::MonteCarlo;
+#[cfg(feature = "debug-endpoints")]
+pub use human::Human;
+pub use mcts::{tuning, FinalMove, MonteCarlo};
 pub use notsuck::NotSuck;
+pub use presets::Weights;
+pub use seed::Seed;
 pub use sim::Sim;
 pub use straight::Straight;
 
@@ -41,23 +56,110 @@ pub use straight::Straight;
 /// A profile is a unique algorithm that defines how the snake
 /// will behave in game. Multiple profiles are required for use
 /// by the simulator profile as well as others for predicting
-/// the behavior of other snakes.
+/// the behavior of other snakes. Profiles must be `Send` so
+/// they can run on the search thread pool.
 ///
-pub trait Profile {
+pub trait Profile: Send {
     ///
     /// Setup the profile with the initial game state
     ///
     fn init(&mut self, _st: &State, _self_id: String) {}
 
     ///
-    /// Update the game state and get the next move from the profile
+    /// Play the game with the given weight preset rather than the one
+    /// picked when it started, if the profile scores with presets
+    ///
+    fn set_weights(&mut self, _weights: Weights) {}
+
+    ///
+    /// Update the game state and get the next move from the profile.
+    /// Profiles that search should finish by the budget's soft
+    /// deadline.
     ///
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir;
+    fn get_move(&mut self, s: &Snake, st: &State, budget: &TimeBudget)
+        -> Dir;
 
     ///
     /// Get the status of the profile
     ///
     fn get_status(&self) -> String;
+
+    ///
+    /// Get the estimated probability of winning the game after the
+    /// last call to `get_move`, if the profile produces one
+    ///
+    fn win_probability(&self) -> Option<f64> {
+        None
+    }
+
+    ///
+    /// Get the score the last call to `get_move` gave each move, in
+    /// the profile's own units, if the profile scores moves
+    ///
+    fn move_scores(&self) -> Vec<(Dir, f64)> {
+        Vec::new()
+    }
+
+    ///
+    /// Get what the search behind the last call to `get_move` found,
+    /// if the profile reports on its search
+    ///
+    fn search_report(&self) -> Option<SearchReport> {
+        None
+    }
+}
+
+/// The names accepted by `string_to_profile`
+pub const PROFILE_NAMES: [&str; 9] = [
+    "aggressive",
+    "alpha_beta",
+    "astarbasic",
+    "cautious",
+    "notsuck",
+    "sim",
+    "straight",
+    "follow",
+    "monte_carlo",
+];
+
+/// Profiles that run a search of their own, which are far too slow to
+/// control a snake in the branches of another search
+const SEARCH_PROFILES: [&str; 3] = ["alpha_beta", "sim", "monte_carlo"];
+
+/// Checks a list of profile names for Sim to control snakes with
+pub fn check_controllers(names: &[String]) -> Result<(), String> {
+    if names.is_empty() {
+        return Err(String::from("no controllers given"));
+    }
+
+    for name in names {
+        if !PROFILE_NAMES.contains(&name.as_str()) {
+            return Err(format!("unknown profile {}", name));
+        }
+
+        if SEARCH_PROFILES.contains(&name.as_str()) {
+            return Err(format!("{} can't be used as a controller", name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Health below which the cheap profiles drop what they're doing and
+/// head for the nearest food
+const FOOD_INTERRUPT_HEALTH: u8 = 30;
+
+/// The first move towards the nearest food if `s` is hungry enough to
+/// divert to it. Eating restores its health, so the profile goes back
+/// to its usual behaviour right after.
+fn food_interrupt(s: &Snake, st: &State) -> Option<Dir> {
+    if s.health >= FOOD_INTERRUPT_HEALTH {
+        return None;
+    }
+
+    let field = st.distances(s);
+    let food = field.nearest(&st.board.food)?;
+    s.body[0].step_dir(field.first_step(food, st)?)
 }
 
 pub fn string_to_profile(profile: &str) -> Box<dyn Profile> {
@@ -74,3 +176,56 @@ pub fn string_to_profile(profile: &str) -> Box<dyn Profile> {
         _ => panic!("Invalid string provided!"),
     }
 }
+
+/// Scores within this fraction of the best one count as tied
+const TIE_EPSILON: f64 = 0.01;
+
+/// Picks which of the moves tied for the best score to play. `scores`
+/// must be sorted best first, and the index of the chosen score is
+/// returned. The choice is random, so opponents simulating us can't
+/// rely on a fixed tie order, but seeded from the game's `Seed` and
+/// turn so a replayed turn picks the same move. With `random_tie_break` off
+/// the first move is always chosen.
+pub fn tie_break(scores: &[f64], st: &State) -> usize {
+    let tied = match scores.first() {
+        Some(top) => scores
+            .iter()
+            .take_while(|s| top - *s <= TIE_EPSILON * top.abs())
+            .count(),
+        None => 0,
+    };
+
+    if tied < 2 || !config::get().random_tie_break {
+        return 0;
+    }
+
+    Seed::for_turn(st).rng(0).gen_range(0, tied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_tie_break() {
+        let (_, mut st) = build_state(11, 11, &[("you", &[(5, 5)])], &[]);
+
+        assert_eq!(tie_break(&[], &st), 0);
+        assert_eq!(tie_break(&[10.0, 9.0, 9.0], &st), 0);
+
+        // Same game and turn, same pick
+        let scores = [100.0, 99.5, 99.2, 50.0];
+        let pick = tie_break(&scores, &st);
+        assert!(pick < 3);
+        assert_eq!(tie_break(&scores, &st), pick);
+
+        // Every tied move gets picked on some turn
+        let mut picked = [false; 3];
+        for turn in 0..50 {
+            st.turn = turn;
+            picked[tie_break(&scores, &st)] = true;
+        }
+        assert_eq!(picked, [true; 3]);
+    }
+}
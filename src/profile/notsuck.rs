@@ -21,15 +21,15 @@
 use log::debug;
 
 use super::super::game::{Dir, SafetyIndex, Snake, State};
-use super::Profile;
+use super::{Profile, TimeBudget};
 
 /// `NotSuck` is an extremely basic algorithm that is designed
-/// to not instantly commit suicide and to pursue the nearest
-/// food using a very rudimentary direction-based algorithm.
+/// to not instantly commit suicide and to pursue the best food,
+/// see `Snake::best_food`, one safe step at a time.
 /// It frequently leads itself into dead ends and kills itself
 /// on its own tail, although it actually works amazingly well
 /// given how simple it is. Mostly designed just to test some
-/// of the basic util functions like `safety_index`, `dir_to`,
+/// of the basic util functions like `safety_index`, `step_dir`,
 /// and `orthogonal`.
 #[derive(Copy, Clone)]
 pub struct NotSuck {
@@ -37,9 +37,15 @@ pub struct NotSuck {
 }
 
 impl Profile for NotSuck {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
-        if let Some(nearest_food) = s.nearest_food(&st) {
-            if let Some(d) = s.body[0].dir_to(nearest_food) {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
+        if let Some((_, path)) = s.best_food(st) {
+            let step = path.get(1).and_then(|p| s.body[0].step_dir(*p));
+            if let Some(d) = step {
                 if d.is_safety_index(&s, &st, &SafetyIndex::Safe) {
                     return d;
                 }
@@ -0,0 +1,145 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Scoring weights for Sim, by board size. The heuristics were tuned
+//! on 11x11 boards. On small boards space control decides the game
+//! and food hardly matters, so they get their own preset, picked from
+//! the board dimensions when the game starts, unless the config of a
+//! known opponent names one.
+
+use std::fmt;
+
+use crate::game::State;
+
+/// Boards with no side longer than this use the small board preset
+const SMALL_BOARD_MAX_SIDE: i8 = 7;
+
+/// Scoring weights for Sim's branches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weights {
+    pub name: &'static str,
+    /// Score for each food we eat
+    pub food: f64,
+    /// Score for each food we eat in a duel against an enemy close to
+    /// our length, where every piece counts
+    pub duel_food: f64,
+    /// Penalty for enemies eating, scaled by how close they were to
+    /// us. An enemy growing right next to us costs the most.
+    pub enemy_food: f64,
+    /// Score for each enemy that dies while we live
+    pub enemy_death: f64,
+    /// Score for each enemy we outlast in a branch we die in, for our
+    /// placement in games of three or more
+    pub placement: f64,
+    /// Bonus for a first move onto a square only shorter enemy heads
+    /// can reach, since it may kill them
+    pub kill_chance: f64,
+    /// Penalty for a first move that lets an enemy corner us
+    pub trap: f64,
+    /// Score for each square of our territory after the first move,
+    /// the squares we reach before any enemy
+    pub territory: f64,
+    /// Penalty for a branch we survive with no turns to spare before
+    /// starving, see `Snake::turns_until_starvation`
+    pub starving: f64,
+    /// Free fraction of the board at or above which the full edge
+    /// penalty applies, and at or below which it is dropped. Late in
+    /// the game the edges may be most of the room that's left.
+    pub edge_open_free: f64,
+    pub edge_endgame_free: f64,
+}
+
+impl Weights {
+    /// The weights for 11x11 and larger boards
+    pub const STANDARD: Self = Self {
+        name: "standard",
+        food: 1.7,
+        duel_food: 300.0,
+        enemy_food: 40.0,
+        enemy_death: 30.0,
+        placement: 20.0,
+        kill_chance: 15.0,
+        trap: 0.0,
+        territory: 0.0,
+        starving: 150.0,
+        edge_open_free: 0.7,
+        edge_endgame_free: 0.4,
+    };
+
+    /// The weights for 7x7 boards. Food is worth little, cornering and
+    /// being cornered a lot, and so is the space we control. The edges
+    /// stop mattering sooner since they are most of the board.
+    pub const SMALL: Self = Self {
+        name: "small",
+        food: 0.5,
+        duel_food: 60.0,
+        enemy_food: 15.0,
+        enemy_death: 45.0,
+        placement: 20.0,
+        kill_chance: 25.0,
+        trap: 40.0,
+        territory: 1.0,
+        starving: 150.0,
+        edge_open_free: 0.85,
+        edge_endgame_free: 0.6,
+    };
+
+    /// Every preset, for looking them up by name
+    pub const ALL: [Self; 2] = [Self::STANDARD, Self::SMALL];
+
+    /// The preset for the board the game is played on
+    pub fn for_board(st: &State) -> Self {
+        if st.board.width.max(st.board.height) <= SMALL_BOARD_MAX_SIDE {
+            Self::SMALL
+        } else {
+            Self::STANDARD
+        }
+    }
+
+    /// The preset with the given name, if there is one
+    pub fn named(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|w| w.name == name).cloned()
+    }
+}
+
+impl fmt::Display for Weights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} weights", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_for_board() {
+        let snakes: &[(&str, &[(i8, i8)])] = &[("you", &[(1, 1)])];
+
+        let (_, st) = build_state(7, 7, snakes, &[]);
+        assert_eq!(Weights::for_board(&st), Weights::SMALL);
+
+        let (_, st) = build_state(11, 11, snakes, &[]);
+        assert_eq!(Weights::for_board(&st), Weights::STANDARD);
+        let (_, st) = build_state(7, 19, snakes, &[]);
+        assert_eq!(Weights::for_board(&st), Weights::STANDARD);
+
+        assert_eq!(Weights::named("small"), Some(Weights::SMALL));
+        assert_eq!(Weights::named("aggressive"), None);
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! The seeds the searches draw their random numbers from. Every game
+//! has a seed, `rng_seed` from the config or else one derived from the
+//! game id, and each turn's searches seed their RNGs from it, one
+//! stream per MonteCarlo thread or Sim branch. The game's seed is logged
+//! at the start, so setting `rng_seed` to it replays the game's
+//! rollouts and simulations the same way.
+//!
+//! Searches that stop when their time is up still depend on how much
+//! they got done, so replays are only exact for the same number of
+//! rollouts, like in the tests.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::config;
+use crate::game::hash::mix;
+use crate::game::State;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Seed(pub u64);
+
+impl Seed {
+    /// The seed of the game in `st`
+    pub fn for_game(st: &State) -> Self {
+        match config::get().rng_seed {
+            0 => {
+                let mut hasher = DefaultHasher::new();
+                st.game.id.hash(&mut hasher);
+                Self(hasher.finish())
+            }
+            seed => Self(seed),
+        }
+    }
+
+    /// The seed of the searches for the turn in `st`, so turns don't
+    /// all draw the same numbers
+    pub fn for_turn(st: &State) -> Self {
+        Self(mix(Self::for_game(st).0 ^ mix(u64::from(st.turn))))
+    }
+
+    /// An RNG for one of the seed's streams
+    pub fn rng(self, stream: u64) -> StdRng {
+        StdRng::seed_from_u64(mix(self.0 ^ mix(stream)))
+    }
+}
+
+impl fmt::Display for Seed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+    use rand::Rng;
+
+    #[test]
+    fn test_seed() {
+        let (_, mut st) = build_state(11, 11, &[("you", &[(5, 5)])], &[]);
+        let draw = |seed: Seed, stream| seed.rng(stream).gen::<u64>();
+
+        // The same game, turn and stream draw the same numbers
+        let turn = Seed::for_turn(&st);
+        assert_eq!(Seed::for_turn(&st), turn);
+        assert_eq!(draw(turn, 1), draw(turn, 1));
+        assert_ne!(draw(turn, 1), draw(turn, 2));
+
+        // but another turn or game doesn't
+        st.turn += 1;
+        assert_ne!(Seed::for_turn(&st), turn);
+        st.turn -= 1;
+        st.game.id = String::from("another");
+        assert_ne!(Seed::for_turn(&st), turn);
+    }
+}
@@ -18,18 +18,43 @@
 
 //! This module contains the Sim algorithm & unit tests
 
-use crate::simulator::{process_step, Future};
+use crate::simulator::{self, Simulation, SpawnRules};
 use log::{debug, info, warn};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use std::cmp::Ordering;
-use std::time::SystemTime;
+use std::fmt;
 
-use super::super::game::{Dir, SafetyIndex, Snake, State};
-use super::{string_to_profile, Profile};
+use super::super::config;
+use super::super::constraints::is_cornered;
+use super::super::game::territory::territory_score;
+use super::super::game::{Contest, Dir, SafetyIndex, Snake, State, TurnContext};
+use super::presets::Weights;
+use super::{string_to_profile, tie_break, Profile, Seed, TimeBudget};
 
-const SIM_TIME_MAX_MILLIS: u128 = 450;
+/// A branch whose position comes up this many times is going in
+/// circles, and is stopped as a stalemate
+const REPETITION_LIMIT: u8 = 3;
+
+/// Replaces the length score of a stalemated branch. Its length only
+/// measures how long the snakes chased their tails, not how well we
+/// survive, so it is scored as neither good nor bad.
+const STALEMATE_SCORE: f64 = 0.0;
+
+const ALL_DIRS: [Dir; 4] = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+/// The number of enemy controllers used when no prefixes are pruned
+const BASE_ENEMY_CONTROLLERS: usize = 3;
+
+/// Fraction of the budget spent stepping every branch before first
+/// moves start getting dropped
+const EXPLORE_FRACTION: f64 = 0.3;
+
+/// Fraction of the score spread a first move may trail the leader by
+/// and still be stepped, once the budget is used up. The allowed gap
+/// shrinks from the whole spread down to this over the anneal.
+const MIN_CONTENTION_MARGIN: f64 = 0.1;
 
 /// The Simulation algorithm will simulate future game states
 /// using some of the other profiles for the enemy snakes. After
@@ -39,6 +64,50 @@ pub struct Sim {
     status: &'static str,
     branches: Vec<SimBranch>,
     analytics: HashMap<String, String>,
+    /// The score of each first move on the last turn
+    last_scores: Vec<(Dir, f64)>,
+    /// Picked from the board size when the game starts
+    weights: Option<Weights>,
+}
+
+/// Which controllers and first moves the branches of a turn were built
+/// from, so a controller set that doesn't do what was meant shows up
+/// in the log
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchComposition {
+    pub self_controllers: Vec<String>,
+    pub enemy_controllers: Vec<String>,
+    /// How many enemy controllers are configured, of which only the
+    /// first few may be used
+    pub enemy_configured: usize,
+    pub self_prefixes: Vec<Dir>,
+    pub enemy_prefixes: Vec<Dir>,
+}
+
+impl BranchComposition {
+    pub fn branches(&self) -> usize {
+        self.self_controllers.len()
+            * self.enemy_controllers.len()
+            * self.self_prefixes.len()
+            * self.enemy_prefixes.len()
+    }
+}
+
+impl fmt::Display for BranchComposition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} branches: self [{}], enemy [{}] ({} of {} configured), \
+             first moves {:?}, enemy first moves {:?}",
+            self.branches(),
+            self.self_controllers.join(", "),
+            self.enemy_controllers.join(", "),
+            self.enemy_controllers.len(),
+            self.enemy_configured,
+            self.self_prefixes,
+            self.enemy_prefixes
+        )
+    }
 }
 
 struct SimBranch {
@@ -46,8 +115,10 @@ struct SimBranch {
     enemy_controller: Box<dyn Profile>,
     self_prefix: Dir,
     enemy_prefix: Dir,
-    state: State,
-    futures: Vec<Future>,
+    /// Keeps the position's hash, and spawns food from the branch's
+    /// stream of the turn's `Seed` so a turn's search is reproducible
+    sim: Simulation,
+    positions: HashMap<u64, u8>,
     self_id: String,
 }
 
@@ -55,133 +126,208 @@ unsafe impl Send for SimBranch {}
 unsafe impl Sync for SimBranch {}
 
 impl Profile for Sim {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
-        let start_time = SystemTime::now();
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        budget: &TimeBudget,
+    ) -> Dir {
         let tmp_analytics = self.analytics.clone();
+        let budget = budget.capped_for(config::get().sim_budget_millis, st);
+        let weights =
+            *self.weights.get_or_insert_with(|| Weights::for_board(st));
 
-        self.branches.par_iter_mut().for_each(|b| {
-            b.futures.clear();
-            b.state = st.clone();
-            b.self_id = s.id.clone();
-        });
+        let (branches, composition) = build_branches(s, st);
+        debug!("Simulating {}", composition);
+        self.branches = branches;
 
         self.branches.par_iter_mut().for_each(|b| {
             b.perform_prefix();
         });
 
-        while start_time.elapsed().unwrap().as_millis() < SIM_TIME_MAX_MILLIS {
-            self.branches
-                .par_iter_mut()
-                .filter(|b| match b.futures.last() {
-                    Some(l) => l.alive && !l.finished,
-                    None => true,
-                })
-                .for_each(|b| {
-                    b.step(&tmp_analytics);
-                });
+        let mut contenders: HashSet<Dir> =
+            self.branches.iter().map(|b| b.sim.futures[0].dir).collect();
 
-            if !self.branches.iter().any(|b| match b.futures.last() {
-                Some(l) => l.alive && !l.finished,
-                None => true,
-            }) {
+        loop {
+            if budget.expired() {
                 break;
             }
-        }
 
-        let scores = self.choose_dir(&s, &st);
-        let all_dirs = [Dir::Down, Dir::Left, Dir::Right, Dir::Up];
-        let mut scores_vec = Vec::with_capacity(4);
+            let explored = budget.progress();
+            if explored > EXPLORE_FRACTION {
+                let progress =
+                    (explored - EXPLORE_FRACTION) / (1.0 - EXPLORE_FRACTION);
+                let scores = self.choose_dir(&s, &st, &weights);
+                let before = contenders.len();
 
-        for dir in &all_dirs {
-            if let Some((score, len)) = scores.get(dir) {
-                scores_vec.push((dir, score, len));
-            }
-        }
+                contenders = in_contention(&scores, progress);
 
-        scores_vec.sort_unstable_by(|a, b| {
-            if a.1 < b.1 {
-                Ordering::Greater
-            } else if a.1 > b.1 {
-                Ordering::Less
-            } else {
-                Ordering::Equal
+                if contenders.len() < before {
+                    debug!("First moves still in contention: {:?}", contenders);
+                }
             }
-        });
 
-        'outer: for (idx, (dir, score, len)) in scores_vec.iter().enumerate() {
-            if dir.is_safety_index(&s, &st, &SafetyIndex::Safe) && !dir.is_corner_risky(&s, &st)
-            // && !(!s.body[0].is_outer(&st) && dir.resulting_point(s.body[0]).is_outer(&st))
-            {
-                return **dir;
-            }
+            let is_active = |b: &SimBranch| {
+                contenders.contains(&b.sim.futures[0].dir)
+                    && match b.sim.futures.last() {
+                        Some(l) => l.alive && !l.finished,
+                        None => true,
+                    }
+            };
 
-            let mut idx_tmp = idx;
-            while idx_tmp + 1 < scores_vec.len() {
-                let (next_best_move, next_bext_score, next_best_len) = scores_vec[idx_tmp + 1];
+            if !self.branches.iter().any(is_active) {
+                break;
+            }
 
-                if next_best_move.is_safety_index(&s, &st, &SafetyIndex::Safe)
-                    && *next_bext_score > **score - (**score / 2.5).abs()
-                    && *next_best_len > **len - (**len / 2)
-                    && !next_best_move.is_corner_risky(&s, &st)
-                // && !(!s.body[0].is_outer(&st)
-                //     && next_best_move.resulting_point(s.body[0]).is_outer(&st))
-                {
-                    warn!("SKIPPED MOVE {:?} AT RANK {}", dir, idx_tmp + 1);
-                    continue 'outer;
-                }
+            self.branches
+                .par_iter_mut()
+                .filter(|b| is_active(b))
+                .for_each(|b| {
+                    b.step(&tmp_analytics, &budget);
+                    count!(SIM_STEPS);
+                });
+        }
 
-                idx_tmp += 1;
-            }
+        let finished = self.branches.iter().filter(|b| {
+            b.sim.futures.last().is_some_and(|l| !l.alive || l.finished)
+        });
+        count!(SIM_BRANCHES, finished.count() as u64);
 
-            warn!(
-                "NEXT BEST MOVES NOT GOOD ENOUGH, RETURNING RISKY MOVE OF RANK {:?}",
-                idx + 1
-            );
+        let scores = self.choose_dir(s, st, &weights);
+        let decision = decide(&scores, s, st);
+        self.last_scores =
+            scores.iter().map(|(d, (sc, _))| (*d, *sc)).collect();
 
-            return **dir;
+        for rank in &decision.ranks {
+            rank.log();
         }
 
-        s.find_safe_move(&st)
+        decision.chosen.unwrap_or_else(|| s.find_safe_move(st))
     }
 
     fn get_status(&self) -> String {
         String::from(self.status)
     }
 
+    fn move_scores(&self) -> Vec<(Dir, f64)> {
+        self.last_scores.clone()
+    }
+
     fn init(&mut self, st: &State, self_id: String) {
-        let self_profiles = vec![
-            "astarbasic",
-            "cautious",
-            "straight",
-            "aggressive",
-            "notsuck",
-            "follow",
-        ];
-        let enemy_profiles = vec!["astarbasic", "cautious", "aggressive"];
-        let prefixes = vec![Dir::Up, Dir::Down, Dir::Left, Dir::Right];
-
-        let mut branches = Vec::new();
-
-        for self_profile in &self_profiles {
-            for enemy_profile in &enemy_profiles {
-                for enemy_prefix in &prefixes {
-                    for self_prefix in &prefixes {
-                        branches.push(SimBranch {
-                            self_controller: super::string_to_profile(self_profile),
-                            enemy_controller: super::string_to_profile(enemy_profile),
-                            self_prefix: *self_prefix,
-                            enemy_prefix: *enemy_prefix,
-                            state: st.clone(),
-                            futures: Vec::new(),
-                            self_id: self_id.clone(),
-                        });
-                    }
+        let weights = Weights::for_board(st);
+        info!("Playing with {}", weights);
+        self.weights = Some(weights);
+
+        if let Some(s) = st.board.snakes.get(&self_id) {
+            let (branches, composition) = build_branches(s, st);
+            info!("Initialized {}", composition);
+            self.branches = branches;
+        }
+    }
+
+    fn set_weights(&mut self, weights: Weights) {
+        info!("Playing with {}", weights);
+        self.weights = Some(weights);
+    }
+}
+
+/// The enemy first moves worth simulating. Every enemy plays the same
+/// prefix, so a move is kept if it isn't fatal for at least one.
+fn viable_enemy_prefixes(s: &Snake, st: &State) -> Vec<Dir> {
+    let enemies = st
+        .board
+        .snakes
+        .values()
+        .filter(|e| e.id != s.id)
+        .collect::<Vec<&Snake>>();
+
+    if enemies.is_empty() {
+        // Without enemies the prefix is never used
+        return vec![Dir::Up];
+    }
+
+    let prefixes = ALL_DIRS
+        .iter()
+        .filter(|d| {
+            enemies
+                .iter()
+                .any(|e| !d.is_safety_index(e, st, &SafetyIndex::Unsafe))
+        })
+        .cloned()
+        .collect::<Vec<Dir>>();
+
+    if prefixes.is_empty() {
+        ALL_DIRS.to_vec()
+    } else {
+        prefixes
+    }
+}
+
+/// Creates a branch for every combination of the configured
+/// controllers and viable prefixes
+fn build_branches(
+    s: &Snake,
+    st: &State,
+) -> (Vec<SimBranch>, BranchComposition) {
+    let config = config::get();
+    let composition = compose(
+        &config.sim_self_profiles,
+        &config.sim_enemy_profiles,
+        TurnContext::new(s, st).root_moves(),
+        viable_enemy_prefixes(s, st),
+    );
+
+    let food = SpawnRules::from_config();
+    let seed = Seed::for_turn(st);
+    let mut branches = Vec::with_capacity(composition.branches());
+
+    for self_profile in &composition.self_controllers {
+        for enemy_profile in &composition.enemy_controllers {
+            for enemy_prefix in &composition.enemy_prefixes {
+                for self_prefix in &composition.self_prefixes {
+                    let rng = seed.rng(branches.len() as u64);
+                    let sim = Simulation::new(st.clone(), &s.id)
+                        .with_food(food, rng)
+                        .hashed();
+                    branches.push(SimBranch {
+                        self_controller: string_to_profile(self_profile),
+                        enemy_controller: string_to_profile(enemy_profile),
+                        self_prefix: *self_prefix,
+                        enemy_prefix: *enemy_prefix,
+                        sim,
+                        positions: HashMap::new(),
+                        self_id: s.id.clone(),
+                    });
                 }
             }
         }
+    }
 
-        info!("Initialized {} simulation branches", branches.len());
-        self.branches = branches;
+    (branches, composition)
+}
+
+/// Picks the controllers for a turn's branches. The branch capacity is
+/// what every self controller against the first few enemy controllers
+/// would take with no prefixes pruned. When pruning leaves fewer prefix
+/// pairs, the spare capacity goes to extra enemy controllers.
+fn compose(
+    self_controllers: &[String],
+    enemy_controllers: &[String],
+    self_prefixes: Vec<Dir>,
+    enemy_prefixes: Vec<Dir>,
+) -> BranchComposition {
+    let capacity = BASE_ENEMY_CONTROLLERS * ALL_DIRS.len() * ALL_DIRS.len();
+    let pairs = self_prefixes.len() * enemy_prefixes.len();
+    let enemy_count = (capacity / pairs.max(1))
+        .max(1)
+        .min(enemy_controllers.len());
+
+    BranchComposition {
+        self_controllers: self_controllers.to_vec(),
+        enemy_controllers: enemy_controllers[..enemy_count].to_vec(),
+        enemy_configured: enemy_controllers.len(),
+        self_prefixes,
+        enemy_prefixes,
     }
 }
 
@@ -194,6 +340,8 @@ impl Sim {
             status: "Sim",
             branches: Vec::new(),
             analytics: HashMap::<String, String>::new(),
+            last_scores: Vec::new(),
+            weights: None,
         }
     }
 
@@ -201,26 +349,54 @@ impl Sim {
         self.analytics = analytics;
     }
 
-    fn choose_dir(&self, s: &Snake, st: &State) -> HashMap<Dir, (f64, usize)> {
+    fn choose_dir(
+        &self,
+        s: &Snake,
+        st: &State,
+        w: &Weights,
+    ) -> HashMap<Dir, (f64, usize)> {
         let mut scores: HashMap<Dir, (f64, usize)> = HashMap::with_capacity(4);
+        let edge_multipliers = edge_multipliers(s, st, w);
+        let traps = if w.trap > 0.0 {
+            ALL_DIRS
+                .iter()
+                .filter(|d| is_cornered(**d, s, st))
+                .cloned()
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        let territory = territory_scores(s, st, w);
+        let rivals = simulator::rivals(st, &s.id);
 
         for branch in &self.branches {
             let mut dead: f64 = 0.0;
             let mut foods: f64 = 0.0;
-            let dir = branch.futures[0].dir;
+            let mut enemy_food_proximity: f64 = 0.0;
+            let dir = branch.sim.futures[0].dir;
 
-            let future_length = branch.futures.len();
+            let future_length = branch.sim.futures.len();
 
-            for future in &branch.futures {
+            for future in &branch.sim.futures {
                 if future.alive {
                     dead += future.dead_snakes as f64;
                 }
 
                 foods += future.foods as f64;
+                enemy_food_proximity += f64::from(future.enemy_food_proximity);
             }
 
-            let length_score = ((future_length as f64) - 30.0) * 1.5;
-            let death_score = dead * 30.0;
+            let stalemate = match branch.sim.futures.last() {
+                Some(f) => f.stalemate,
+                None => false,
+            };
+            let length_score = if stalemate {
+                STALEMATE_SCORE
+            } else {
+                ((future_length as f64) - 30.0) * 1.5
+            };
+            let death_score = dead * w.enemy_death;
 
             let food_score = if st.board.snakes.len() == 2
                 && st
@@ -229,28 +405,69 @@ impl Sim {
                     .iter()
                     .any(|(id, sn)| *id != s.id && sn.body.len() >= s.body.len() - 2)
             {
-                (foods * 300.0)
+                (foods * w.duel_food)
             } else if st.board.snakes.len() == 1 {
                 0.0
             } else {
-                (foods * 1.7)
+                (foods * w.food)
             };
 
-            let mut total = length_score + death_score + food_score;
+            let enemy_food_score = -enemy_food_proximity * w.enemy_food;
+
+            let mut total =
+                length_score + death_score + food_score + enemy_food_score;
+
+            if let Some(last_future) = branch.sim.futures.last() {
+                // When we don't survive, outlasting enemies still
+                // improves our placement
+                if let (false, Some(place)) =
+                    (last_future.alive, last_future.placement)
+                {
+                    let outlasted = (rivals + 1).saturating_sub(place.into());
+                    if rivals >= 2 {
+                        total += outlasted as f64 * w.placement;
+                    }
+                }
 
-            if let Some(last_future) = branch.futures.last() {
-                if last_future.finished && last_future.alive && future_length < 100 {
+                // Surviving the branch only to starve right after is
+                // no better than dying in it
+                let end = &branch.sim.state;
+                let starving = end.board.snakes.get(&s.id).is_some_and(|you| {
+                    you.turns_until_starvation(end) == 0
+                });
+                if last_future.alive && starving {
+                    total -= w.starving;
+                }
+
+                if last_future.finished
+                    && last_future.alive
+                    && !last_future.stalemate
+                    && !last_future.draw
+                    && future_length < 100
+                {
                     total += (100.0 - future_length as f64) * 5.0;
                 }
             }
 
-            if !s.body[0].is_outer(&st) && dir.resulting_point(s.body[0]).is_outer(&st) {
-                total *= 0.8;
+            if dir.resulting_point(s.body[0], st).contest(s, st)
+                == Contest::Winning
+            {
+                total += w.kill_chance;
             }
 
+            if traps.contains(&dir) {
+                total -= w.trap;
+            }
+
+            total += territory.get(&dir).cloned().unwrap_or(0.0);
+
+            total *= edge_multipliers[&dir];
+
+            let death = branch.sim.futures.last().and_then(|f| f.death);
             debug!(
-                "Future length: {:04} Foods: {:02} First move: {:?}",
-                future_length, foods, dir
+                "Future length: {:04} Foods: {:02} First move: {:?} \
+                 Death: {:?}",
+                future_length, foods, dir, death
             );
 
             if let Some((score, len)) = scores.get_mut(&dir) {
@@ -265,41 +482,488 @@ impl Sim {
     }
 }
 
-impl SimBranch {
-    fn perform_prefix(&mut self) {
-        let mut dirs = HashMap::<String, Dir>::with_capacity(self.state.board.snakes.len());
+/// How far into the game we are, from 1 on an open board down to 0
+/// once the free space has shrunk to the endgame
+fn openness(st: &State, w: &Weights) -> f64 {
+    let area = f64::from(st.board.width) * f64::from(st.board.height);
+    let occupied = st
+        .board
+        .snakes
+        .values()
+        .map(|s| s.body.len())
+        .sum::<usize>() as f64;
+    let free = (area - occupied) / area;
+
+    let (open, endgame) = (w.edge_open_free, w.edge_endgame_free);
+    ((free - endgame) / (open - endgame)).clamp(0.0, 1.0)
+}
 
-        for (id, _) in &self.state.board.snakes {
-            let dir = if *id == self.self_id {
-                self.self_prefix
-            } else {
-                self.enemy_prefix
-            };
+/// The territory score of each first move, from the board with only
+/// our snake moved. Empty when the weights don't score territory.
+fn territory_scores(
+    s: &Snake,
+    st: &State,
+    w: &Weights,
+) -> HashMap<Dir, f64> {
+    if w.territory == 0.0 {
+        return HashMap::new();
+    }
+
+    ALL_DIRS
+        .iter()
+        .map(|d| {
+            let mut moved = st.clone();
+            let you = moved.board.snakes.get_mut(&s.id).unwrap();
+            you.update_from_move(*d, &st.board.food, st.board.edges());
+            let score = territory_score(&moved, &s.id);
+            (*d, f64::from(score) * w.territory)
+        })
+        .collect()
+}
+
+/// The score multiplier for each first move. Moving from the inside
+/// onto the outer ring costs the configured edge penalty, scaled down
+/// as the board fills up. There is no penalty when the outer ring is
+/// all the room we have left.
+fn edge_multipliers(
+    s: &Snake,
+    st: &State,
+    w: &Weights,
+) -> HashMap<Dir, f64> {
+    let head = s.body[0];
+    let to_edge = |d: &Dir| {
+        !head.is_outer(st) && d.resulting_point(head, st).is_outer(st)
+    };
+
+    let only_edges = TurnContext::new(s, st).root_moves().iter().all(to_edge);
+    let penalty = if only_edges {
+        0.0
+    } else {
+        config::get().edge_penalty * openness(st, w)
+    };
+
+    ALL_DIRS
+        .iter()
+        .map(|d| (*d, if to_edge(d) { 1.0 - penalty } else { 1.0 }))
+        .collect()
+}
+
+/// The first moves whose total score is close enough to the best to
+/// keep simulating. `progress` runs from 0 at the start of the anneal
+/// to 1 at the end of the budget, and the allowed gap to the best
+/// score narrows as it grows.
+fn in_contention(scores: &HashMap<Dir, (f64, usize)>, progress: f64) -> HashSet<Dir> {
+    let best = scores.values().map(|v| v.0).fold(f64::MIN, f64::max);
+    let worst = scores.values().map(|v| v.0).fold(f64::MAX, f64::min);
+
+    let margin_frac = 1.0 - progress.min(1.0) * (1.0 - MIN_CONTENTION_MARGIN);
+    let margin = (best - worst) * margin_frac;
+
+    scores
+        .iter()
+        .filter(|(_, (score, _))| *score >= best - margin)
+        .map(|(dir, _)| *dir)
+        .collect()
+}
+
+/// How a ranked first move fared in the skip logic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RankOutcome {
+    /// The move was safe and not corner risky, so it was played
+    Played,
+    /// The move was risky and a lower ranked move was good enough
+    Skipped,
+    /// The move was risky but nothing below it was good enough
+    Risky,
+}
+
+/// A lower ranked move weighed against a risky one, with the value
+/// behind every criterion it has to pass to justify the skip
+#[derive(Debug, Clone, PartialEq)]
+pub struct AltCheck {
+    pub dir: Dir,
+    pub rank: usize,
+    pub score: f64,
+    pub min_score: f64,
+    pub len: usize,
+    pub min_len: usize,
+    pub safe: bool,
+    pub corner_risky: bool,
+}
 
-            dirs.insert(id.to_string(), dir);
+impl AltCheck {
+    pub fn score_ok(&self) -> bool {
+        self.score > self.min_score
+    }
+
+    pub fn len_ok(&self) -> bool {
+        self.len > self.min_len
+    }
+
+    pub fn passes(&self) -> bool {
+        self.safe && self.score_ok() && self.len_ok() && !self.corner_risky
+    }
+}
+
+fn pass_fail(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "FAILED"
+    }
+}
+
+impl fmt::Display for AltCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at rank {}: score {:.1} > {:.1} {}, length {} > {} {}, \
+             safe {}, corner risk {}",
+            self.dir,
+            self.rank,
+            self.score,
+            self.min_score,
+            pass_fail(self.score_ok()),
+            self.len,
+            self.min_len,
+            pass_fail(self.len_ok()),
+            pass_fail(self.safe),
+            pass_fail(!self.corner_risky),
+        )
+    }
+}
+
+/// The skip logic's verdict on one ranked first move
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankCheck {
+    pub dir: Dir,
+    pub rank: usize,
+    pub score: f64,
+    pub len: usize,
+    pub safe: bool,
+    pub corner_risky: bool,
+    /// The lower ranked moves checked, up to the one that passed
+    pub alternatives: Vec<AltCheck>,
+    pub outcome: RankOutcome,
+}
+
+impl RankCheck {
+    fn log(&self) {
+        let own = format!(
+            "score {:.1}, length {}, safe {}, corner risk {}",
+            self.score,
+            self.len,
+            pass_fail(self.safe),
+            pass_fail(!self.corner_risky)
+        );
+
+        match self.outcome {
+            RankOutcome::Played => debug!(
+                "PLAYING MOVE {:?} AT RANK {} ({})",
+                self.dir, self.rank, own
+            ),
+            RankOutcome::Skipped => warn!(
+                "SKIPPED MOVE {:?} AT RANK {} ({}) FOR {}",
+                self.dir,
+                self.rank,
+                own,
+                // The last alternative is the one that passed
+                self.alternatives.last().unwrap()
+            ),
+            RankOutcome::Risky => {
+                warn!(
+                    "NEXT BEST MOVES NOT GOOD ENOUGH, RETURNING RISKY MOVE {:?} \
+                     OF RANK {} ({})",
+                    self.dir, self.rank, own
+                );
+
+                for alt in &self.alternatives {
+                    warn!("  REJECTED {}", alt);
+                }
+            }
         }
+    }
+}
+
+/// Every criterion the skip logic checked for a move, from the best
+/// ranked first move down to the one that was chosen
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkipDecision {
+    pub ranks: Vec<RankCheck>,
+    /// None if there were no scores to choose from
+    pub chosen: Option<Dir>,
+}
+
+/// Ranks the first moves by score and walks down the ranking. A move
+/// that is safe and not corner risky is played. A risky move is
+/// skipped if some lower ranked move is safe, not corner risky, and
+/// not too far behind it in both score and future length. If none
+/// is, the risky move is played anyway.
+fn decide(
+    scores: &HashMap<Dir, (f64, usize)>,
+    s: &Snake,
+    st: &State,
+) -> SkipDecision {
+    let mut ranked = [Dir::Down, Dir::Left, Dir::Right, Dir::Up]
+        .iter()
+        .filter_map(|d| scores.get(d).map(|(score, len)| (*d, *score, *len)))
+        .collect::<Vec<(Dir, f64, usize)>>();
+
+    ranked.sort_unstable_by(|a, b| {
+        b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal)
+    });
+
+    // The tied move picked to play leads the ranking
+    let sorted = ranked.iter().map(|r| r.1).collect::<Vec<f64>>();
+    let pick = tie_break(&sorted, st);
+    if pick > 0 {
+        ranked[..=pick].rotate_right(1);
+    }
+
+    let mut decision = SkipDecision {
+        ranks: Vec::with_capacity(ranked.len()),
+        chosen: None,
+    };
+
+    for (idx, &(dir, score, len)) in ranked.iter().enumerate() {
+        let mut check = RankCheck {
+            dir,
+            rank: idx + 1,
+            score,
+            len,
+            safe: dir.is_safety_index(s, st, &SafetyIndex::Safe),
+            corner_risky: dir.is_corner_risky(s, st),
+            alternatives: Vec::new(),
+            outcome: RankOutcome::Risky,
+        };
+
+        if check.safe && !check.corner_risky {
+            check.outcome = RankOutcome::Played;
+        } else {
+            for (alt_idx, &(alt, alt_score, alt_len)) in
+                ranked.iter().enumerate().skip(idx + 1)
+            {
+                let alt_check = AltCheck {
+                    dir: alt,
+                    rank: alt_idx + 1,
+                    score: alt_score,
+                    min_score: score - (score / 2.5).abs(),
+                    len: alt_len,
+                    min_len: len - (len / 2),
+                    safe: alt.is_safety_index(s, st, &SafetyIndex::Safe),
+                    corner_risky: alt.is_corner_risky(s, st),
+                };
+
+                let passes = alt_check.passes();
+                check.alternatives.push(alt_check);
+
+                if passes {
+                    check.outcome = RankOutcome::Skipped;
+                    break;
+                }
+            }
+        }
+
+        let outcome = check.outcome;
+        decision.ranks.push(check);
 
-        let new_future = process_step(&mut self.state, &self.self_id, &dirs);
-        self.futures.push(new_future);
+        if outcome != RankOutcome::Skipped {
+            decision.chosen = Some(dir);
+            break;
+        }
     }
 
-    fn step(&mut self, analytics: &HashMap<String, String>) {
-        let mut dirs = HashMap::<String, Dir>::new();
+    decision
+}
+
+impl SimBranch {
+    fn perform_prefix(&mut self) {
+        let (self_id, self_prefix) = (&self.self_id, self.self_prefix);
+        let enemy_prefix = self.enemy_prefix;
+
+        self.sim.step(|snake, _| {
+            if snake.id == *self_id {
+                self_prefix
+            } else {
+                enemy_prefix
+            }
+        });
+        self.check_repetition();
+    }
 
-        for (id, snake) in &self.state.board.snakes {
-            let dir = if *id == self.self_id {
-                self.self_controller.get_move(&snake, &self.state)
-            } else if let Some(s) = analytics.get(id) {
+    fn step(
+        &mut self,
+        analytics: &HashMap<String, String>,
+        budget: &TimeBudget,
+    ) {
+        let self_id = &self.self_id;
+        let self_controller = &mut self.self_controller;
+        let enemy_controller = &mut self.enemy_controller;
+
+        self.sim.step(|snake, st| {
+            if snake.id == *self_id {
+                self_controller.get_move(snake, st, budget)
+            } else if let Some(s) = analytics.get(&snake.id) {
                 let mut profile = string_to_profile(&s);
-                profile.get_move(&snake, &self.state)
+                profile.get_move(snake, st, budget)
             } else {
-                self.enemy_controller.get_move(&snake, &self.state)
-            };
+                enemy_controller.get_move(snake, st, budget)
+            }
+        });
+        self.check_repetition();
+    }
+
+    /// Marks the latest step a stalemate if the position it left has
+    /// repeated too often
+    fn check_repetition(&mut self) {
+        let hash = self.sim.hash().unwrap_or_default();
+        let seen = self.positions.entry(hash).or_insert(0);
+        *seen += 1;
+
+        if let Some(future) = self.sim.futures.last_mut() {
+            if *seen >= REPETITION_LIMIT && !future.finished {
+                future.stalemate = true;
+                future.finished = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::game::build_state;
+    use super::*;
+    use crate::simulator::Future;
+
+    #[test]
+    fn test_decide() {
+        let (you, st) =
+            build_state(11, 11, &[("you", &[(0, 5), (1, 5), (2, 5)])], &[]);
+
+        // The best move runs into the wall, the runner up is close enough
+        let mut scores = HashMap::new();
+        scores.insert(Dir::Left, (100.0, 100));
+        scores.insert(Dir::Up, (80.0, 90));
+        scores.insert(Dir::Down, (10.0, 10));
+
+        let decision = decide(&scores, &you, &st);
+        assert_eq!(decision.chosen, Some(Dir::Up));
+        assert_eq!(decision.ranks.len(), 2);
+        assert_eq!(decision.ranks[0].outcome, RankOutcome::Skipped);
+        assert!(!decision.ranks[0].safe);
+        assert_eq!(decision.ranks[0].alternatives[0].dir, Dir::Up);
+        assert_eq!(decision.ranks[1].outcome, RankOutcome::Played);
+
+        // Nothing else comes close, so the risky move is played and
+        // the failed criteria are recorded
+        scores.insert(Dir::Up, (50.0, 90));
+        let decision = decide(&scores, &you, &st);
+        assert_eq!(decision.chosen, Some(Dir::Left));
+        assert_eq!(decision.ranks[0].outcome, RankOutcome::Risky);
+
+        let alts = &decision.ranks[0].alternatives;
+        assert_eq!(alts.len(), 2);
+        assert!(!alts[0].score_ok() && alts[0].len_ok());
+        assert!(!alts[1].score_ok() && !alts[1].len_ok());
+
+        assert_eq!(decide(&HashMap::new(), &you, &st).chosen, None);
+    }
 
-            dirs.insert(id.to_string(), dir);
+    #[test]
+    fn test_stalemate() {
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(1, 1), (1, 2), (1, 3)]),
+            ],
+            &[],
+        );
+        let mut branch = SimBranch {
+            self_controller: string_to_profile("straight"),
+            enemy_controller: string_to_profile("straight"),
+            self_prefix: Dir::Up,
+            enemy_prefix: Dir::Up,
+            sim: Simulation::new(st, &you.id).hashed(),
+            positions: HashMap::new(),
+            self_id: you.id.clone(),
+        };
+
+        let future = Future {
+            alive: true,
+            finished: false,
+            dead_snakes: 0,
+            foods: 0,
+            enemy_foods: 0,
+            enemy_food_proximity: 0.0,
+            stalemate: false,
+            draw: false,
+            placement: None,
+            dir: Dir::Up,
+            death: None,
+        };
+
+        // Only the position counts, not the turn or health
+        let push = |branch: &mut SimBranch| {
+            branch.sim.futures.push(future);
+            branch.check_repetition();
+        };
+        for _ in 0..2 {
+            branch.sim.state.turn += 1;
+            let you = branch.sim.state.board.snakes.get_mut("you").unwrap();
+            you.health -= 1;
+            push(&mut branch);
         }
+        assert!(!branch.sim.futures.last().unwrap().stalemate);
+
+        push(&mut branch);
+        let last = branch.sim.futures.last().unwrap();
+        assert!(last.stalemate && last.finished);
+    }
+
+    #[test]
+    fn test_edge_multipliers() {
+        let (you, st) =
+            build_state(11, 11, &[("you", &[(1, 5), (2, 5), (3, 5)])], &[]);
+        let w = Weights::STANDARD;
+        let open = edge_multipliers(&you, &st, &w);
+        assert!((open[&Dir::Left] - 0.8).abs() < 1e-9);
+        assert!((open[&Dir::Up] - 1.0).abs() < 1e-9);
+
+        // Late in the game the edge is no worse than anywhere else
+        let wall = (0..11)
+            .flat_map(|y| (4..11).map(move |x| (x, y)))
+            .collect::<Vec<(i8, i8)>>();
+        let (you, st) = build_state(
+            11,
+            11,
+            &[("you", &[(1, 5), (2, 5), (3, 5)]), ("other", &wall)],
+            &[],
+        );
+        assert!(openness(&st, &w) < 1e-9);
+        let late = edge_multipliers(&you, &st, &w);
+        assert!((late[&Dir::Left] - 1.0).abs() < 1e-9);
+    }
 
-        let new_future = process_step(&mut self.state, &self.self_id, &dirs);
-        self.futures.push(new_future);
+    #[test]
+    fn test_compose() {
+        let names = |list: &[&str]| {
+            list.iter().map(|n| n.to_string()).collect::<Vec<String>>()
+        };
+        let ours = names(&["cautious", "follow"]);
+        let theirs = names(&["astarbasic", "cautious", "aggressive", "follow"]);
+
+        // No pruning, only the first three enemy controllers fit
+        let all = ALL_DIRS.to_vec();
+        let full = compose(&ours, &theirs, all.clone(), all);
+        assert_eq!(full.enemy_controllers, theirs[..3].to_vec());
+        assert_eq!(full.branches(), 2 * 3 * 16);
+        assert!(full.to_string().contains("(3 of 4 configured)"));
+
+        // A single forced move leaves room for every enemy controller
+        let forced = compose(&ours, &theirs, vec![Dir::Up], vec![Dir::Down]);
+        assert_eq!(forced.enemy_controllers, theirs);
+        assert_eq!(forced.branches(), 2 * 4);
     }
 }
@@ -21,19 +21,30 @@
 use log::debug;
 
 use super::super::game::{Dir, SafetyIndex, Snake, State};
-use super::Profile;
+use super::{food_interrupt, Profile, TimeBudget};
 
 /// The Straight algorithm will go in a straight line until
 /// it's unsafe to do so, at which point it will resort to any
-/// safe move, then keep going straight.
+/// safe move, then keep going straight. When it gets hungry it
+/// heads for the nearest food first.
 #[derive(Copy, Clone)]
 pub struct Straight {
     status: &'static str,
 }
 
 impl Profile for Straight {
-    fn get_move(&mut self, s: &Snake, st: &State) -> Dir {
-        if let Some(d) = s.body[1].dir_to(s.body[0]) {
+    fn get_move(
+        &mut self,
+        s: &Snake,
+        st: &State,
+        _budget: &TimeBudget,
+    ) -> Dir {
+        if let Some(d) = food_interrupt(s, st) {
+            return d;
+        }
+
+        // A snake that just spawned has no direction yet
+        if let Some(d) = s.last_move() {
             if d.is_safety_index(&s, &st, &SafetyIndex::Safe) {
                 return d;
             }
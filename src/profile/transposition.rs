@@ -0,0 +1,243 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! A transposition table for depth-limited searches, so a position
+//! reached by different move orders, or searched again by the next
+//! iterative deepening pass or turn, isn't searched from scratch.
+//!
+//! Entries are keyed by a Zobrist hash of the position plus whatever
+//! else the search's scores depend on, like whose move it is. The
+//! table is a fixed number of slots indexed by the low bits of the
+//! key, and a new entry replaces the old one in its slot unless that
+//! is the same position searched deeper.
+//!
+//! Searches on several threads share one table without locking it.
+//! Each slot holds an entry packed into a word, and the key xored with
+//! that word, so a slot two threads stored to at once fails the key
+//! check instead of returning half of each entry.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::game::hash::mix;
+use crate::game::Dir;
+
+/// How a stored score relates to the position's true score, since
+/// alpha-beta only finds the exact score inside its window
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Bound {
+    Exact,
+    /// The score is at least this, the search failed high
+    Lower,
+    /// The score is at most this, the search failed low
+    Upper,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Entry {
+    pub key: u64,
+    /// How many plies were searched below the position
+    pub depth: u8,
+    pub score: i16,
+    pub bound: Bound,
+    /// The best move found, to try first when searching again
+    pub best: Option<Dir>,
+}
+
+impl Entry {
+    /// The bound a score searched with the window `alpha..beta` is
+    pub fn bound(score: i16, alpha: i16, beta: i16) -> Bound {
+        if score <= alpha {
+            Bound::Upper
+        } else if score >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        }
+    }
+
+    /// The score to use without searching, if the entry is deep enough
+    /// and its score settles the window `alpha..beta`
+    pub fn cutoff(&self, depth: u8, alpha: i16, beta: i16) -> Option<i16> {
+        if self.depth < depth {
+            return None;
+        }
+
+        match self.bound {
+            Bound::Exact => Some(self.score),
+            Bound::Lower if self.score >= beta => Some(self.score),
+            Bound::Upper if self.score <= alpha => Some(self.score),
+            _ => None,
+        }
+    }
+}
+
+impl Entry {
+    /// Everything but the key in one word, never 0 so an empty slot
+    /// can't pass for an entry
+    fn pack(&self) -> u64 {
+        let bound = match self.bound {
+            Bound::Exact => 0,
+            Bound::Lower => 1,
+            Bound::Upper => 2,
+        };
+        let best = match self.best {
+            None => 0,
+            Some(Dir::Up) => 1,
+            Some(Dir::Down) => 2,
+            Some(Dir::Left) => 3,
+            Some(Dir::Right) => 4,
+        };
+
+        u64::from(self.score as u16)
+            | u64::from(self.depth) << 16
+            | bound << 24
+            | best << 28
+            | 1 << 32
+    }
+
+    fn unpack(key: u64, data: u64) -> Self {
+        let bound = match data >> 24 & 0xf {
+            0 => Bound::Exact,
+            1 => Bound::Lower,
+            _ => Bound::Upper,
+        };
+        let best = match data >> 28 & 0xf {
+            1 => Some(Dir::Up),
+            2 => Some(Dir::Down),
+            3 => Some(Dir::Left),
+            4 => Some(Dir::Right),
+            _ => None,
+        };
+
+        Self {
+            key,
+            depth: (data >> 16) as u8,
+            score: data as u16 as i16,
+            bound,
+            best,
+        }
+    }
+}
+
+/// A key for a position that also covers `extra`, for the parts of the
+/// search state the position's hash leaves out
+pub fn key(hash: u64, extra: u64) -> u64 {
+    hash ^ mix(extra)
+}
+
+#[derive(Debug)]
+pub struct TranspositionTable {
+    /// The key xored with the packed entry, and the packed entry
+    slots: Vec<(AtomicU64, AtomicU64)>,
+}
+
+impl TranspositionTable {
+    /// A table of `2^bits` slots
+    pub fn new(bits: u32) -> Self {
+        Self {
+            slots: (0..1 << bits)
+                .map(|_| (AtomicU64::new(0), AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    fn slot(&self, key: u64) -> usize {
+        key as usize & (self.slots.len() - 1)
+    }
+
+    /// The entry for a key, if it hasn't been replaced
+    pub fn probe(&self, key: u64) -> Option<Entry> {
+        let (check, data) = &self.slots[self.slot(key)];
+        let data = data.load(Ordering::Relaxed);
+        if data == 0 || check.load(Ordering::Relaxed) ^ data != key {
+            return None;
+        }
+
+        Some(Entry::unpack(key, data))
+    }
+
+    pub fn store(&self, entry: Entry) {
+        match self.probe(entry.key) {
+            Some(old) if old.depth > entry.depth => {}
+            _ => {
+                let (check, data) = &self.slots[self.slot(entry.key)];
+                let packed = entry.pack();
+                check.store(entry.key ^ packed, Ordering::Relaxed);
+                data.store(packed, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl Clone for TranspositionTable {
+    fn clone(&self) -> Self {
+        let load = |a: &AtomicU64| AtomicU64::new(a.load(Ordering::Relaxed));
+        Self {
+            slots: self
+                .slots
+                .iter()
+                .map(|(check, data)| (load(check), load(data)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let table = TranspositionTable::new(4);
+        let entry = |key, depth, score, bound| Entry {
+            key,
+            depth,
+            score,
+            bound,
+            best: Some(Dir::Up),
+        };
+
+        table.store(entry(3, 4, 50, Bound::Exact));
+        assert_eq!(table.probe(3).unwrap().cutoff(4, 0, 10), Some(50));
+        assert_eq!(table.probe(3).unwrap().cutoff(5, 0, 10), None);
+
+        // A shallower search doesn't replace a deeper one of the same
+        // position, but another position does
+        table.store(entry(3, 2, 20, Bound::Exact));
+        assert_eq!(table.probe(3).unwrap().score, 50);
+        table.store(entry(19, 1, 5, Bound::Lower));
+        assert_eq!(table.probe(3), None);
+
+        // Bounds only settle windows they fall outside of
+        let lower = table.probe(19).unwrap();
+        assert_eq!(lower.cutoff(1, 0, 5), Some(5));
+        assert_eq!(lower.cutoff(1, 0, 6), None);
+        assert_eq!(Entry::bound(5, 5, 10), Bound::Upper);
+        assert_eq!(Entry::bound(7, 5, 10), Bound::Exact);
+        assert_eq!(Entry::bound(10, 5, 10), Bound::Lower);
+
+        // Entries survive packing, negative scores and all
+        let e = Entry {
+            best: None,
+            ..entry(u64::MAX, 30, -500, Bound::Upper)
+        };
+        table.store(e);
+        assert_eq!(table.probe(u64::MAX), Some(e));
+        assert_eq!(table.probe(u64::MAX - 16), None);
+    }
+}
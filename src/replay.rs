@@ -0,0 +1,1003 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Tools for working with recorded games. A replay is the sample
+//! file written by the analytics module at the end of each game: one
+//! request body per line, starting with the `/start` request.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use super::constraints::{is_blunder, is_cornered};
+use super::game::analysis::{reach_all, CONTEXT_REACH_TURNS};
+use super::game::danger::DangerMap;
+use super::game::{Dir, Point, SafetyIndex, Snake, State, TurnContext};
+use super::history::is_boxed_in;
+use super::profile::{
+    string_to_profile, Profile, TimeBudget, PROFILE_NAMES,
+};
+use super::routes::parse_body;
+use super::schema::v1::{self, GameDiff, TurnDiff};
+
+/// Reads a line of a win probability trace. Samples recorded before
+/// the trace was JSON have the turn and probability separated by a
+/// space instead.
+fn parse_win_prob(line: &str) -> Option<(u32, f64)> {
+    if let Ok(w) = serde_json::from_str::<v1::WinProbability>(line) {
+        return Some((w.turn, w.win_probability));
+    }
+
+    let mut parts = line.split_whitespace();
+    match (
+        parts.next().map(str::parse::<u32>),
+        parts.next().map(str::parse::<f64>),
+    ) {
+        (Some(Ok(turn)), Some(Ok(prob))) => Some((turn, prob)),
+        _ => None,
+    }
+}
+
+/// A recorded game, one frame per request
+pub struct Replay {
+    pub frames: Vec<(Snake, State)>,
+    pub win_probs: HashMap<u32, f64>,
+}
+
+/// A condition that stops the debugger when stepping forward
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    /// Our chosen move went onto a risky square
+    RiskyMove,
+    /// The win probability dropped by at least this much in a turn
+    WinProbDrop(f64),
+    /// A specific turn was reached
+    Turn(u32),
+}
+
+impl Replay {
+    /// Loads a replay from a sample file, along with the win
+    /// probability trace recorded next to it if there is one
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|why| format!("Couldn't read {}: {}", path, why))?;
+
+        let frames = contents
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(parse_body)
+            .collect::<Result<Vec<(Snake, State)>, String>>()?;
+
+        let mut win_probs = HashMap::new();
+        let trace_path = trace_path(path, "winprob");
+
+        if let Ok(trace) = fs::read_to_string(&trace_path) {
+            for line in trace.lines() {
+                if let Some((turn, prob)) = parse_win_prob(line) {
+                    win_probs.insert(turn, prob);
+                }
+            }
+        }
+
+        Ok(Self { frames, win_probs })
+    }
+
+    /// Returns the move we made from the given frame, inferred from
+    /// the position of our head in the next frame
+    pub fn chosen_move(&self, idx: usize) -> Option<Dir> {
+        let (you, _) = &self.frames[idx];
+        let (_, next_st) = self.frames.get(idx + 1)?;
+        let next_you = next_st.board.snakes.get(&you.id)?;
+
+        you.body[0].step_dir(next_you.body[0])
+    }
+
+    /// Whether the breakpoint is hit on the given frame
+    pub fn hits(&self, idx: usize, bp: &Breakpoint) -> bool {
+        let (you, st) = &self.frames[idx];
+
+        match bp {
+            Breakpoint::RiskyMove => self.chosen_move(idx).is_some_and(|d| {
+                d.is_safety_index(you, st, &SafetyIndex::Risky)
+            }),
+            Breakpoint::WinProbDrop(drop) => {
+                match (
+                    self.win_probs.get(&st.turn.saturating_sub(1)),
+                    self.win_probs.get(&st.turn),
+                ) {
+                    (Some(prev), Some(curr)) => prev - curr >= *drop,
+                    _ => false,
+                }
+            }
+            Breakpoint::Turn(t) => st.turn == *t,
+        }
+    }
+}
+
+/// Renders the board as ASCII. Our snake is drawn with `Y`/`y` and
+/// the enemies with `A`/`a`, `B`/`b`, etc. Food is drawn with `*`.
+pub fn render(you: &Snake, st: &State) -> String {
+    let mut grid =
+        vec![vec!['.'; st.board.width as usize]; st.board.height as usize];
+
+    for food in &st.board.food {
+        if food.in_bounds(st) {
+            grid[food.y as usize][food.x as usize] = '*';
+        }
+    }
+
+    let mut ids: Vec<&String> = st.board.snakes.keys().collect();
+    ids.sort();
+
+    let mut letter = b'A';
+    for id in ids {
+        let snake = &st.board.snakes[id];
+        let head = if *id == you.id {
+            'Y'
+        } else {
+            letter += 1;
+            (letter - 1) as char
+        };
+
+        for (i, p) in snake.body.iter().enumerate().rev() {
+            if p.in_bounds(st) {
+                grid[p.y as usize][p.x as usize] = if i == 0 {
+                    head
+                } else {
+                    head.to_ascii_lowercase()
+                };
+            }
+        }
+    }
+
+    grid.iter()
+        .map(|row| row.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn danger_map(you: &Snake, st: &State) -> DangerMap {
+    DangerMap::new(you, st, &reach_all(st, CONTEXT_REACH_TURNS))
+}
+
+/// Describes everything we know about a single cell
+fn inspect(p: Point, you: &Snake, st: &State) -> String {
+    let mut lines = vec![format!("Cell ({}, {})", p.x, p.y)];
+
+    if !p.in_bounds(st) {
+        lines.push(String::from("  out of bounds"));
+        return lines.join("\n");
+    }
+
+    lines.push(format!("  safety: {:?}", p.safety_index(you, st)));
+    if let Some(danger) = danger_map(you, st).at(p) {
+        lines.push(format!("  danger: {:.2}", danger));
+    }
+    lines.push(format!("  outer: {}", p.is_outer(st)));
+    lines.push(format!("  food: {}", st.board.food.contains(&p)));
+
+    for (id, snake) in &st.board.snakes {
+        if let Some(i) = snake.body.iter().position(|b| *b == p) {
+            lines.push(format!("  body segment {} of {}", i, id));
+        }
+
+        if p.orthogonal(st).contains(&snake.body[0]) {
+            lines.push(format!(
+                "  reachable by {} (length {})",
+                id,
+                snake.body.len()
+            ));
+        }
+    }
+
+    lines.join("\n")
+}
+
+fn print_frame(replay: &Replay, idx: usize) {
+    let (you, st) = &replay.frames[idx];
+    let snake = st.board.snakes.get(&you.id).unwrap_or(you);
+
+    println!("{}", render(you, st));
+    println!(
+        "Frame {}/{} turn {} health {} length {} move {:?} win prob {}",
+        idx,
+        replay.frames.len() - 1,
+        st.turn,
+        snake.health,
+        snake.body.len(),
+        replay.chosen_move(idx),
+        replay
+            .win_probs
+            .get(&st.turn)
+            .map_or(String::from("-"), |p| format!("{:.2}", p)),
+    );
+
+    if let Some(s) = st.board.snakes.get(&you.id) {
+        println!("{}", TurnContext::new(s, st));
+    }
+}
+
+const HELP: &str = "Commands:
+  n, next            step forward
+  p, prev            step back
+  g, goto <turn>     jump to a turn
+  c, continue        step forward until a breakpoint is hit
+  b risky            break when our move is onto a risky square
+  b drop <amount>    break when win probability drops by <amount>
+  b turn <turn>      break on a turn
+  b clear            remove all breakpoints
+  i <x> <y>          inspect a cell
+  q, quit            exit";
+
+/// Runs the interactive replay debugger on a sample file
+pub fn debug(path: &str) {
+    let replay = match Replay::load(path) {
+        Ok(r) if !r.frames.is_empty() => r,
+        Ok(_) => {
+            println!("{} contains no frames", path);
+            return;
+        }
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut idx = 0;
+    let mut breakpoints: Vec<Breakpoint> = Vec::new();
+    let stdin = io::stdin();
+
+    println!("{}", HELP);
+    print_frame(&replay, idx);
+
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let last = replay.frames.len() - 1;
+
+        match args.as_slice() {
+            ["n"] | ["next"] => idx = (idx + 1).min(last),
+            ["p"] | ["prev"] => idx = idx.saturating_sub(1),
+            ["g", turn] | ["goto", turn] => match turn.parse::<u32>() {
+                Ok(t) => {
+                    match replay.frames.iter().position(|(_, st)| st.turn == t)
+                    {
+                        Some(i) => idx = i,
+                        None => println!("No frame for turn {}", t),
+                    }
+                }
+                Err(_) => println!("Invalid turn {}", turn),
+            },
+            ["c"] | ["continue"] => {
+                while idx < last {
+                    idx += 1;
+                    if let Some(bp) =
+                        breakpoints.iter().find(|bp| replay.hits(idx, bp))
+                    {
+                        println!("Hit breakpoint {:?}", bp);
+                        break;
+                    }
+                }
+            }
+            ["b", "risky"] => breakpoints.push(Breakpoint::RiskyMove),
+            ["b", "drop", amount] => match amount.parse::<f64>() {
+                Ok(a) => breakpoints.push(Breakpoint::WinProbDrop(a)),
+                Err(_) => println!("Invalid amount {}", amount),
+            },
+            ["b", "turn", turn] => match turn.parse::<u32>() {
+                Ok(t) => breakpoints.push(Breakpoint::Turn(t)),
+                Err(_) => println!("Invalid turn {}", turn),
+            },
+            ["b", "clear"] => breakpoints.clear(),
+            ["i", x, y] => match (x.parse::<i8>(), y.parse::<i8>()) {
+                (Ok(x), Ok(y)) => {
+                    let (you, st) = &replay.frames[idx];
+                    println!("{}", inspect(Point { x, y }, you, st));
+                }
+                _ => println!("Invalid cell {} {}", x, y),
+            },
+            ["q"] | ["quit"] => return,
+            _ => {
+                println!("{}", HELP);
+                continue;
+            }
+        }
+
+        if !args[0].starts_with('i') && !args[0].starts_with('b') {
+            print_frame(&replay, idx);
+        }
+    }
+}
+
+/// The path of the trace of the given kind next to a sample file,
+/// `<id>.<kind>.txt` for `<id>.txt`
+fn trace_path(path: &str, kind: &str) -> String {
+    Path::new(path)
+        .with_extension(format!("{}.txt", kind))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Writes the danger map of every frame of a sample file next to it,
+/// one JSON object per line, for the web viewer to shade the board
+/// with
+pub fn export_danger(path: &str) {
+    let replay = match Replay::load(path) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let buffer = replay
+        .frames
+        .iter()
+        .map(|(you, st)| danger_map(you, st))
+        .map(|map| format!("{}\n", serde_json::to_string(&map).unwrap()))
+        .collect::<String>();
+    let out = trace_path(path, "danger");
+
+    match fs::write(&out, buffer) {
+        Ok(()) => {
+            println!("Wrote {} danger maps to {}", replay.frames.len(), out)
+        }
+        Err(e) => println!("Couldn't write {}: {}", out, e),
+    }
+}
+
+/// A quick static score for a move in [0, 1]: the fraction of the
+/// board reachable from the square it leads to, or 0 if the move is
+/// immediately fatal.
+pub fn move_score(dir: Dir, you: &Snake, st: &State) -> f64 {
+    let p = dir.resulting_point(you.body[0], st);
+    if p.safety_index(you, st) == SafetyIndex::Unsafe {
+        return 0.0;
+    }
+
+    let cells = st.board.width as u16 * st.board.height as u16;
+    p.flood_fill(you, st, cells).len() as f64 / f64::from(cells)
+}
+
+/// Whether a file is a game recording, `<id>.txt`, rather than one of
+/// the `<id>.<kind>.txt` traces written next to it
+fn is_replay(path: &Path) -> bool {
+    let stem = match path.file_stem() {
+        Some(s) => Path::new(s),
+        None => return false,
+    };
+
+    path.extension().and_then(|e| e.to_str()) == Some("txt")
+        && stem.extension().is_none()
+}
+
+/// The replay files in a corpus directory, sorted by name
+fn corpus_paths(dir: &str) -> Result<Vec<String>, String> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|e| format!("Couldn't read {}: {}", dir, e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| is_replay(p))
+        .map(|p| p.to_string_lossy().to_string())
+        .collect::<Vec<String>>();
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Loads a replay from a corpus, skipping it if it is empty or broken
+fn load_corpus_replay(path: &str) -> Option<Replay> {
+    match Replay::load(path) {
+        Ok(r) if !r.frames.is_empty() => Some(r),
+        Ok(_) => None,
+        Err(e) => {
+            println!("Skipping {}: {}", path, e);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CorpusStats {
+    decisions: usize,
+    agreements: usize,
+    blunders: usize,
+    original_blunders: usize,
+    score: f64,
+    original_score: f64,
+}
+
+/// Runs a profile over every decision point in a directory of
+/// replays and prints how its moves compare to the recorded ones
+pub fn evaluate_corpus(dir: &str, profile_name: &str) {
+    if !PROFILE_NAMES.contains(&profile_name) {
+        println!("Unknown profile {}", profile_name);
+        return;
+    }
+
+    let paths = match corpus_paths(dir) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut stats = CorpusStats::default();
+
+    for path in &paths {
+        let replay = match load_corpus_replay(path) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        let mut profile = string_to_profile(profile_name);
+        let (first_you, first_st) = &replay.frames[0];
+        profile.init(first_st, first_you.id.clone());
+
+        for idx in 0..replay.frames.len() {
+            let original = match replay.chosen_move(idx) {
+                Some(d) => d,
+                None => continue,
+            };
+
+            let (you, st) = &replay.frames[idx];
+            let you = match st.board.snakes.get(&you.id) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let budget = TimeBudget::for_move(Instant::now());
+            let chosen = profile.get_move(you, st, &budget);
+
+            stats.decisions += 1;
+            if chosen == original {
+                stats.agreements += 1;
+            }
+            if is_blunder(chosen, you, st) {
+                stats.blunders += 1;
+            }
+            if is_blunder(original, you, st) {
+                stats.original_blunders += 1;
+            }
+            stats.score += move_score(chosen, you, st);
+            stats.original_score += move_score(original, you, st);
+        }
+    }
+
+    let decisions = stats.decisions.max(1) as f64;
+    println!("Replays:            {}", paths.len());
+    println!("Decision points:    {}", stats.decisions);
+    println!(
+        "Agreement:          {:.1}%",
+        stats.agreements as f64 / decisions * 100.0
+    );
+    println!(
+        "Blunders:           {} (original {})",
+        stats.blunders, stats.original_blunders
+    );
+    println!(
+        "Average score:      {:.4} (original {:.4})",
+        stats.score / decisions,
+        stats.original_score / decisions
+    );
+}
+
+impl GameDiff {
+    fn add(&mut self, other: &Self) {
+        self.decisions += other.decisions;
+        self.changed += other.changed;
+        self.score_delta += other.score_delta;
+        self.new_blunders += other.new_blunders;
+        self.fixed_blunders += other.fixed_blunders;
+    }
+}
+
+impl fmt::Display for GameDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} of {} moves changed, score {:+.4}, \
+             {} new blunders, {} fixed",
+            self.path,
+            self.changed,
+            self.decisions,
+            self.score_delta,
+            self.new_blunders,
+            self.fixed_blunders
+        )
+    }
+}
+
+/// Plays both profiles over every frame of a replay and records where
+/// their moves differ
+fn diff_replay(
+    path: &str,
+    replay: &Replay,
+    before: &mut dyn Profile,
+    after: &mut dyn Profile,
+) -> GameDiff {
+    let (first_you, first_st) = &replay.frames[0];
+    before.init(first_st, first_you.id.clone());
+    after.init(first_st, first_you.id.clone());
+
+    let mut diff = GameDiff {
+        path: String::from(path),
+        ..Default::default()
+    };
+
+    for (you, st) in &replay.frames {
+        let you = match st.board.snakes.get(&you.id) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let budget = || TimeBudget::for_move(Instant::now());
+        let a = before.get_move(you, st, &budget());
+        let b = after.get_move(you, st, &budget());
+        diff.decisions += 1;
+        if a == b {
+            continue;
+        }
+
+        let turn = TurnDiff {
+            turn: st.turn,
+            before: a.as_move().dir,
+            after: b.as_move().dir,
+            score_delta: move_score(b, you, st) - move_score(a, you, st),
+            blunder_before: is_blunder(a, you, st),
+            blunder_after: is_blunder(b, you, st),
+        };
+
+        diff.changed += 1;
+        diff.score_delta += turn.score_delta;
+        match (turn.blunder_before, turn.blunder_after) {
+            (false, true) => diff.new_blunders += 1,
+            (true, false) => diff.fixed_blunders += 1,
+            _ => {}
+        }
+        diff.turns.push(turn);
+    }
+
+    diff
+}
+
+/// Runs two profiles over every decision point in a directory of
+/// replays, prints a summary of where they differ per game and in
+/// total, and writes the per-turn differences to `diff_path` as JSON
+pub fn diff_corpus(dir: &str, before: &str, after: &str, diff_path: &str) {
+    for name in &[before, after] {
+        if !PROFILE_NAMES.contains(name) {
+            println!("Unknown profile {}", name);
+            return;
+        }
+    }
+
+    let paths = match corpus_paths(dir) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut total = GameDiff {
+        path: String::from("total"),
+        ..Default::default()
+    };
+    let mut games = Vec::new();
+
+    for path in &paths {
+        let replay = match load_corpus_replay(path) {
+            Some(r) => r,
+            None => continue,
+        };
+
+        let diff = diff_replay(
+            path,
+            &replay,
+            &mut *string_to_profile(before),
+            &mut *string_to_profile(after),
+        );
+        println!("{}", diff);
+        total.add(&diff);
+        games.push(diff);
+    }
+
+    println!("{}", total);
+
+    let diff = v1::ReplayDiff {
+        schema: v1::VERSION,
+        before: before.to_string(),
+        after: after.to_string(),
+        games,
+        total,
+    };
+    match fs::write(diff_path, serde_json::to_string(&diff).unwrap()) {
+        Ok(_) => println!("Wrote the diff to {}", diff_path),
+        Err(e) => println!("Couldn't write {}: {}", diff_path, e),
+    }
+}
+
+/// A corner trap counts as having happened if the replay ends with us
+/// trapped within this many frames of the move
+const TRAP_HORIZON: usize = 3;
+
+/// Whether we were cornered after the move made from a frame: the
+/// replay ends within `TRAP_HORIZON` frames, and in the last one every
+/// move we had ran into a wall or a body, so that is how we died
+fn trap_occurred(replay: &Replay, idx: usize) -> bool {
+    let last = replay.frames.len() - 1;
+    if last - idx > TRAP_HORIZON {
+        return false;
+    }
+
+    let (you, st) = &replay.frames[last];
+    match st.board.snakes.get(&you.id) {
+        Some(us) => is_boxed_in(us, st),
+        None => false,
+    }
+}
+
+/// How well a check's predictions matched what happened
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Confusion {
+    pub true_pos: usize,
+    pub false_pos: usize,
+    pub false_neg: usize,
+    pub true_neg: usize,
+}
+
+impl Confusion {
+    fn record(&mut self, predicted: bool, actual: bool) {
+        match (predicted, actual) {
+            (true, true) => self.true_pos += 1,
+            (true, false) => self.false_pos += 1,
+            (false, true) => self.false_neg += 1,
+            (false, false) => self.true_neg += 1,
+        }
+    }
+
+    /// The fraction of flagged moves that really were traps
+    pub fn precision(&self) -> Option<f64> {
+        match self.true_pos + self.false_pos {
+            0 => None,
+            n => Some(self.true_pos as f64 / n as f64),
+        }
+    }
+
+    /// The fraction of traps that were flagged
+    pub fn recall(&self) -> Option<f64> {
+        match self.true_pos + self.false_neg {
+            0 => None,
+            n => Some(self.true_pos as f64 / n as f64),
+        }
+    }
+}
+
+impl fmt::Display for Confusion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pct = |v: Option<f64>| {
+            v.map_or(String::from("-"), |v| format!("{:.1}%", v * 100.0))
+        };
+
+        write!(
+            f,
+            "precision {}, recall {} (tp {}, fp {}, fn {}, tn {})",
+            pct(self.precision()),
+            pct(self.recall()),
+            self.true_pos,
+            self.false_pos,
+            self.false_neg,
+            self.true_neg
+        )
+    }
+}
+
+/// The corner checks scored against the traps found in a corpus
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CornerReport {
+    pub decisions: usize,
+    pub traps: usize,
+    /// `Dir::is_corner_risky`
+    pub pattern: Confusion,
+    /// `constraints::is_cornered`
+    pub simulated: Confusion,
+}
+
+impl CornerReport {
+    /// Runs both corner checks on the move made from every frame of
+    /// the replay
+    fn add(&mut self, replay: &Replay) {
+        for idx in 0..replay.frames.len() {
+            let dir = match replay.chosen_move(idx) {
+                Some(d) => d,
+                None => continue,
+            };
+
+            let (you, st) = &replay.frames[idx];
+            let you = match st.board.snakes.get(&you.id) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let trap = trap_occurred(replay, idx);
+            self.decisions += 1;
+            if trap {
+                self.traps += 1;
+            }
+
+            self.pattern.record(dir.is_corner_risky(you, st), trap);
+            self.simulated.record(is_cornered(dir, you, st), trap);
+        }
+    }
+}
+
+/// Scores the pattern based corner check against the simulated one
+/// on every move in a directory of replays, so a swap between them
+/// can be justified by data
+pub fn verify_corners(dir: &str) {
+    let paths = match corpus_paths(dir) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut report = CornerReport::default();
+    for path in &paths {
+        if let Some(replay) = load_corpus_replay(path) {
+            report.add(&replay);
+        }
+    }
+
+    println!("Replays:            {}", paths.len());
+    println!("Decision points:    {}", report.decisions);
+    println!("Corner traps:       {}", report.traps);
+    println!("Pattern check:      {}", report.pattern);
+    println!("Simulated check:    {}", report.simulated);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::game::build_state;
+    use super::super::profile::{NotSuck, Straight};
+    use super::*;
+
+    #[test]
+    fn test_parse_win_prob() {
+        let line = r#"{"turn":12,"win_probability":0.75}"#;
+        assert_eq!(parse_win_prob(line), Some((12, 0.75)));
+        assert_eq!(parse_win_prob("12 0.7500"), Some((12, 0.75)));
+        assert_eq!(parse_win_prob("nope"), None);
+    }
+
+    #[test]
+    fn test_trace_path() {
+        assert_eq!(
+            trace_path("samples/game.txt", "winprob"),
+            "samples/game.winprob.txt"
+        );
+        assert_eq!(
+            trace_path("old.txt/game.txt", "danger"),
+            "old.txt/game.danger.txt"
+        );
+    }
+
+    #[test]
+    fn test_corpus_paths() {
+        let root = std::env::temp_dir()
+            .join(format!("battlesnake-corpus-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+
+        let files = &[
+            "game.txt",
+            "game.search.txt",
+            "game.shadow.txt",
+            "game.winprob.txt",
+            "notes.md",
+        ];
+        for name in files {
+            fs::write(root.join(name), "").unwrap();
+        }
+
+        let dir = root.to_string_lossy().to_string();
+        let paths = corpus_paths(&dir).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let game = root.join("game.txt").to_string_lossy().to_string();
+        assert_eq!(paths, vec![game]);
+    }
+
+    #[test]
+    fn test_render() {
+        let (you, st) = build_state(
+            4,
+            3,
+            &[
+                ("you", &[(0, 0), (1, 0), (2, 0)]),
+                ("other", &[(3, 2), (3, 1)]),
+            ],
+            &[(0, 2)],
+        );
+
+        assert_eq!(render(&you, &st), "Yyy.\n...a\n*..A");
+    }
+
+    #[test]
+    fn test_diff_replay() {
+        let frame = |food: (i8, i8)| {
+            build_state(7, 7, &[("you", &[(2, 3), (1, 3), (0, 3)])], &[food])
+        };
+        let replay = Replay {
+            frames: vec![frame((2, 0)), frame((5, 3))],
+            win_probs: HashMap::new(),
+        };
+
+        // Straight keeps going right, NotSuck turns up for the food in
+        // the first frame
+        let diff = diff_replay(
+            "game",
+            &replay,
+            &mut Straight::new(),
+            &mut NotSuck::new(),
+        );
+        assert_eq!((diff.decisions, diff.changed), (2, 1));
+        let turn = &diff.turns[0];
+        assert_eq!((turn.before, turn.after), ("right", "up"));
+        assert!(!turn.blunder_before && !turn.blunder_after);
+        assert_eq!(diff.score_delta, turn.score_delta);
+
+        let mut total = GameDiff::default();
+        total.add(&diff);
+        total.add(&diff);
+        assert_eq!(total.changed, 2);
+        assert!(total.turns.is_empty());
+    }
+
+    #[test]
+    fn test_is_blunder() {
+        // Moving up or left leads into a three square pocket
+        let (you, st) = build_state(
+            5,
+            5,
+            &[
+                ("you", &[(1, 1), (1, 2), (1, 3), (1, 4)]),
+                ("a", &[(2, 2), (2, 1), (2, 0), (3, 0)]),
+                ("b", &[(0, 4), (0, 3), (0, 2), (0, 2)]),
+            ],
+            &[],
+        );
+
+        assert!(is_blunder(Dir::Up, &you, &st));
+        assert!(is_blunder(Dir::Left, &you, &st));
+        assert!(is_blunder(Dir::Right, &you, &st));
+        assert_eq!(move_score(Dir::Right, &you, &st), 0.0);
+        assert_eq!(move_score(Dir::Up, &you, &st), 3.0 / 25.0);
+
+        let (you, st) =
+            build_state(11, 11, &[("you", &[(5, 5), (5, 6), (5, 7)])], &[]);
+        assert!(!is_blunder(Dir::Up, &you, &st));
+    }
+
+    #[test]
+    fn test_trap_occurred() {
+        // Heading into the top right corner with a longer enemy coming
+        // up the right wall, which closes it off
+        let frame = |you: &[(i8, i8)], other: &[(i8, i8)]| {
+            build_state(7, 7, &[("you", you), ("other", other)], &[])
+        };
+        let cornered = Replay {
+            frames: vec![
+                frame(
+                    &[(4, 0), (3, 0), (2, 0)],
+                    &[(6, 2), (6, 3), (6, 4), (6, 5)],
+                ),
+                frame(
+                    &[(5, 0), (4, 0), (3, 0)],
+                    &[(6, 1), (6, 2), (6, 3), (6, 4)],
+                ),
+                frame(
+                    &[(6, 0), (5, 0), (4, 0)],
+                    &[(5, 1), (6, 1), (6, 2), (6, 3)],
+                ),
+            ],
+            win_probs: HashMap::new(),
+        };
+        assert!(trap_occurred(&cornered, 0));
+        assert!(trap_occurred(&cornered, 1));
+
+        // The same enemy close by at the end, with a way out down the
+        // left wall
+        let open = Replay {
+            frames: vec![frame(
+                &[(0, 1), (1, 1), (2, 1)],
+                &[(1, 2), (2, 2), (3, 2), (4, 2)],
+            )],
+            win_probs: HashMap::new(),
+        };
+        assert!(!trap_occurred(&open, 0));
+    }
+
+    #[test]
+    fn test_corner_report() {
+        // We run along the top wall, the enemy moves up in front of us
+        // and the game ends
+        let (you, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(3, 0), (2, 0), (1, 0), (0, 0)]),
+                ("other", &[(5, 1), (4, 1), (3, 1), (2, 1)]),
+            ],
+            &[],
+        );
+        let (_, next) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(4, 0), (3, 0), (2, 0), (1, 0)]),
+                ("other", &[(5, 0), (5, 1), (4, 1), (3, 1)]),
+            ],
+            &[],
+        );
+        let trapped = Replay {
+            frames: vec![(you.clone(), st), (you.clone(), next)],
+            win_probs: HashMap::new(),
+        };
+
+        // Down the middle of an empty board for a while
+        let frames = (0..6)
+            .map(|y| {
+                build_state(
+                    11,
+                    11,
+                    &[("you", &[(5, y), (5, y + 1), (5, y + 2)])],
+                    &[],
+                )
+            })
+            .rev()
+            .collect();
+        let open = Replay {
+            frames,
+            win_probs: HashMap::new(),
+        };
+
+        let mut report = CornerReport::default();
+        report.add(&trapped);
+        report.add(&open);
+
+        assert_eq!(report.decisions, 6);
+        assert_eq!(report.traps, 1);
+        assert_eq!(report.simulated.true_pos, 1);
+        assert_eq!(report.simulated.true_neg, 5);
+        assert_eq!(report.simulated.precision(), Some(1.0));
+        assert_eq!(report.simulated.recall(), Some(1.0));
+        assert_eq!(report.pattern.true_pos + report.pattern.false_neg, 1);
+    }
+}
@@ -15,15 +15,47 @@
  * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
  *
  */
-use log::{error, info, warn};
-use serde_derive::Deserialize;
-use std::collections::{HashMap, HashSet};
-
-use std::env;
+use log::{debug, error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use hyper::HeaderMap;
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::analytics::Analytics;
-use super::game::{Board, Dir, Game, Point, Snake, State};
-use super::profile::{AlphaBeta, Profile, Sim};
+use super::cache::CacheDir;
+use super::config;
+use super::constraints;
+use super::game::{
+    deserialize_health, Board, Dir, Game, GameMode, Point, SafetyIndex, Snake,
+    SnakeMeta, State, TurnContext, MAX_SNAKES,
+};
+#[cfg(feature = "debug-endpoints")]
+use super::game::{
+    analysis::{self, CONTEXT_REACH_TURNS},
+    danger::DangerMap,
+};
+#[cfg(feature = "debug-endpoints")]
+use super::schema::v1;
+use super::idle::IdleMonitor;
+use super::logging::MoveRecord;
+use super::metrics;
+use super::opponent_store::OpponentStore;
+use super::opponents::OpponentConfig;
+use super::profile::{
+    string_to_profile, AlphaBeta, Profile, Seed, TimeBudget, Weights,
+    PROFILE_NAMES,
+};
+use super::schedule::TurnScheduler;
+use super::shadow::{self, ShadowTurn};
+use super::simulator::DeathCause;
+use super::watchdog::Watchdog;
+
+/// The profiles the analyzer tries to match enemies against
+pub const ANALYZED_PROFILES: [&str; 3] =
+    ["cautious", "astarbasic", "aggressive"];
 
 #[derive(Deserialize, Debug)]
 pub struct BoardJson {
@@ -31,6 +63,9 @@ pub struct BoardJson {
     pub width: i8,
     pub food: Vec<Point>,
     pub snakes: Vec<SnakeJson>,
+    /// Only sent in royale games
+    #[serde(default)]
+    pub hazards: Vec<Point>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -45,114 +80,1319 @@ pub struct MoveRequest {
 pub struct SnakeJson {
     pub id: String,
     pub name: String,
+    #[serde(deserialize_with = "deserialize_health")]
     pub health: u8,
     pub body: Vec<Point>,
+    /// Sent as a string of milliseconds by the 2020 API
+    #[serde(default)]
+    pub latency: Option<serde_json::Value>,
+    #[serde(default)]
+    pub shout: Option<String>,
+    #[serde(default)]
+    pub squad: Option<String>,
+}
+
+/// The version of the engine API the requests follow
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum ApiVersion {
+    /// The origin is the top left corner, with y growing downward
+    #[serde(rename = "2019")]
+    V2019,
+    /// The origin is the bottom left corner, with y growing upward,
+    /// and snakes also have `latency`, `shout` and `squad`
+    #[serde(rename = "2020")]
+    V2020,
+}
+
+impl ApiVersion {
+    /// Converts a point from the request to the internal coordinates,
+    /// where y grows downward. The conversion is its own inverse, and
+    /// moves keep their names, since "up" is toward y = 0 internally.
+    pub fn to_internal(self, p: Point, height: i8) -> Point {
+        match self {
+            ApiVersion::V2019 => p,
+            ApiVersion::V2020 => Point {
+                x: p.x,
+                y: height - 1 - p.y,
+            },
+        }
+    }
+}
+
+fn parse_latency(latency: &serde_json::Value) -> Option<u32> {
+    match latency {
+        serde_json::Value::String(s) => s.parse().ok(),
+        v => v.as_u64().map(|l| l as u32),
+    }
+}
+
+/// The response body for each move, serialized once at startup so a
+/// decision can be sent without any work on the hot path
+pub struct MoveResponses {
+    bodies: HashMap<Dir, Vec<u8>>,
+}
+
+impl MoveResponses {
+    pub fn new() -> Self {
+        let bodies = [Dir::Up, Dir::Down, Dir::Left, Dir::Right]
+            .iter()
+            .map(|d| (*d, serde_json::to_vec(&d.as_move()).unwrap()))
+            .collect();
+
+        Self { bodies }
+    }
+
+    pub fn get(&self, dir: Dir) -> &[u8] {
+        &self.bodies[&dir]
+    }
+
+    /// The response body for a move with `tail`'s fields added. Only
+    /// used when a tail was asked for, so it is built per request.
+    pub fn with_tail(&self, dir: Dir, tail: &ResponseTail) -> Vec<u8> {
+        let mut body = json!(dir.as_move());
+        if let (Some(body), Ok(serde_json::Value::Object(fields))) =
+            (body.as_object_mut(), serde_json::to_value(tail))
+        {
+            body.extend(fields);
+        }
+
+        serde_json::to_vec(&body).unwrap()
+    }
+}
+
+/// How many turns of overhead the latency tracker remembers
+const LATENCY_WINDOW: usize = 10;
+/// Time kept back on top of the worst overhead seen
+const LATENCY_MARGIN_MILLIS: u64 = 15;
+/// The least time a move gets, however slow the connection looks
+const MIN_MOVE_MILLIS: u64 = 100;
+
+/// Measures the time a game's responses spend outside our handler,
+/// from the latency the engine reports for our last move, so the move
+/// budget can keep back as much as the connection actually needs
+/// instead of a fixed margin
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LatencyTracker {
+    /// The overhead of the last few moves, oldest first, in
+    /// milliseconds
+    overheads: VecDeque<u64>,
+    /// How long our handler took on the last move
+    last_handler_millis: Option<u64>,
+}
+
+impl LatencyTracker {
+    /// Records the latency the engine reports for us in a move
+    /// request, which is for our previous move
+    pub fn observe(&mut self, request: &MoveRequest) {
+        let reported = request
+            .board
+            .snakes
+            .iter()
+            .find(|s| s.id == request.you.id)
+            .and_then(|s| s.latency.as_ref())
+            .and_then(parse_latency);
+
+        if let (Some(reported), Some(handler)) =
+            (reported, self.last_handler_millis.take())
+        {
+            let overhead = u64::from(reported).saturating_sub(handler);
+            debug!("Move overhead {} ms", overhead);
+
+            if self.overheads.len() == LATENCY_WINDOW {
+                self.overheads.pop_front();
+            }
+            self.overheads.push_back(overhead);
+        }
+    }
+
+    /// Records how long our handler took to respond to a move
+    pub fn responded(&mut self, handler: Duration) {
+        self.last_handler_millis = Some(handler.as_millis() as u64);
+    }
+
+    /// The worst overhead seen lately, or None before the engine has
+    /// reported any
+    pub fn overhead_millis(&self) -> Option<u64> {
+        self.overheads.iter().max().cloned()
+    }
+
+    /// The budget for a move that arrived at `start` in a game with
+    /// the given timeout. Without any measurements it is the usual
+    /// fixed margin budget.
+    pub fn budget(&self, start: Instant, timeout_millis: u64) -> TimeBudget {
+        let overhead = match self.overhead_millis() {
+            Some(o) => o,
+            None => return TimeBudget::for_timeout(start, timeout_millis),
+        };
+
+        let hard = timeout_millis
+            .saturating_sub(overhead + LATENCY_MARGIN_MILLIS)
+            .max(MIN_MOVE_MILLIS);
+        if hard == MIN_MOVE_MILLIS {
+            warn!("Move overhead of {} ms leaves little time", overhead);
+        }
+
+        let hard = Duration::from_millis(hard);
+        TimeBudget::new(start, hard, hard)
+    }
+}
+
+/// Request header that asks for a response tail when the config
+/// doesn't turn it on for every move
+pub const DEBUG_TAIL_HEADER: &str = "X-Debug-Tail";
+/// Request header echoed back in the response tail, so a proxy can
+/// match our timings to its own
+pub const CORRELATION_HEADER: &str = "X-Correlation-Id";
+
+/// Extra fields for a /move response, for a proxy or dashboard in
+/// front of the server
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ResponseTail {
+    /// The profile's estimated win probability after the move
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    /// Time from receiving the request to building the response
+    pub handler_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+fn header_value(headers: &HeaderMap, name: &'static str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// Splits a request URL into its path and query string
+pub fn split_url(url: &str) -> (&str, Option<&str>) {
+    let mut parts = url.splitn(2, '?');
+    (parts.next().unwrap_or(""), parts.next())
+}
+
+/// The value of a parameter in a query string
+pub fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            Some((kv.next()?, kv.next().unwrap_or("")))
+        })
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// The profile a /start request asks for with `?profile=`, if it is
+/// one we know
+pub fn requested_profile(query: Option<&str>) -> Option<Box<dyn Profile>> {
+    let name = query_param(query, "profile")?;
+
+    if PROFILE_NAMES.contains(&name) {
+        info!("Playing this game with {}", name);
+        Some(string_to_profile(name))
+    } else {
+        warn!("Unknown profile {} requested, using the default", name);
+        None
+    }
+}
+
+/// The tail to add to a move response, if the config turns tails on
+/// or the request asks for one with the debug header
+pub fn response_tail(
+    headers: &HeaderMap,
+    profile: &dyn Profile,
+    budget: &TimeBudget,
+) -> Option<ResponseTail> {
+    let asked = cfg!(feature = "debug-endpoints")
+        && header_value(headers, DEBUG_TAIL_HEADER).is_some();
+
+    if !config::get().response_tail && !asked {
+        return None;
+    }
+
+    Some(ResponseTail {
+        confidence: profile.win_probability(),
+        handler_ms: budget.elapsed().as_secs_f64() * 1000.0,
+        correlation_id: header_value(headers, CORRELATION_HEADER),
+    })
+}
+
+/// The move we decided on for a /move request, along with what the
+/// work done after responding needs
+pub struct MoveDecision {
+    pub dir: Dir,
+    you: Snake,
+    state: State,
+    context: Option<TurnContext>,
+}
+
+/// The snake's appearance and details, for the engine's GET on `/`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SnakeInfo {
+    pub apiversion: String,
+    pub author: String,
+    pub color: String,
+    pub head: String,
+    pub tail: String,
+    pub version: String,
 }
 
-/// Handle the /start POST request
+impl SnakeInfo {
+    pub fn from_config() -> Self {
+        let config = config::get();
+
+        Self {
+            apiversion: String::from("1"),
+            author: config.author.clone(),
+            color: config.color.clone(),
+            head: config.head_type.clone(),
+            tail: config.tail_type.clone(),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+/// Handle the GET request on `/`
+pub fn info_handler() -> String {
+    json!(SnakeInfo::from_config()).to_string()
+}
+
+/// Handle the /start POST request. The game is played with the
+/// `requested` profile if there is one, or else with the one the
+/// config of a known opponent names.
 pub fn start_handler(
     buffer: &str,
-    profile: &mut impl Profile,
-    analytics: &mut HashMap<String, Analytics>,
-) -> String {
-    let color = match env::var("COLOR") {
-        Ok(v) => v,
-        Err(_) => String::from("#111111"),
-    };
+    request: &MoveRequest,
+    requested: Option<Box<dyn Profile>>,
+    profile: &mut Box<dyn Profile>,
+    analytics: &mut Option<Analytics>,
+    opponents: &HashMap<String, OpponentConfig>,
+    store: &OpponentStore,
+) -> Result<String, String> {
+    let (you, state) = request_state(request)?;
+    info!("Game {} has seed {}", state.game.id, Seed::for_game(&state));
+    let mut new_analytic = Analytics::new(&state, &ANALYZED_PROFILES);
+    new_analytic.update_full_game(state.turn, buffer);
+    let configs = apply_opponent_configs(
+        request,
+        &you,
+        opponents,
+        store,
+        &mut new_analytic,
+    );
 
-    match parse_body(buffer) {
+    if let Some(requested) = requested {
+        *profile = requested;
+    } else if let Some(name) = counter_profile(&configs) {
+        info!("Playing this game with {}", name);
+        *profile = string_to_profile(name);
+    }
+    profile.init(&state, you.id.clone());
+    if let Some(weights) = counter_weights(&configs) {
+        profile.set_weights(weights);
+    }
+    *analytics = Some(new_analytic);
+
+    // Older engines only read the appearance from here
+    let info = SnakeInfo::from_config();
+    Ok(json!({
+        "color": info.color,
+        "headType": info.head,
+        "tailType": info.tail,
+    })
+    .to_string())
+}
+
+/// Handle the /move POST request. Only the work needed to pick a move
+/// happens here, everything else waits for `after_move` so it can't
+/// delay the response.
+pub fn move_handler(
+    request: &MoveRequest,
+    profile: &mut Box<dyn Profile>,
+    alpha_beta: &mut AlphaBeta,
+    analytics: &mut Option<Analytics>,
+    watchdog: &mut Watchdog,
+    schedule: &TurnScheduler,
+    idle: &IdleMonitor,
+) -> Option<MoveDecision> {
+    match request_state(request) {
         Ok((you, state)) => {
-            profile.init(&state, you.id);
-            let mut new_analytic = Analytics::new(
-                &state,
-                &["cautious", "astarbasic", "aggressive"],
-            );
-            new_analytic.update_full_game(buffer);
-            analytics.insert(state.game.id.clone(), new_analytic);
-            format!("{{\"color\":\"{}\"}}", color)
+            // We may have missed /start, e.g. after a restart
+            let this_analytics = analytics.get_or_insert_with(|| {
+                warn!("Missed the start of game {}", state.game.id);
+                let seed = Seed::for_game(&state);
+                info!("Game {} has seed {}", state.game.id, seed);
+                profile.init(&state, you.id.clone());
+                Analytics::new(&state, &ANALYZED_PROFILES)
+            });
+
+            let snake = state.board.snakes.get(&you.id);
+            let context = snake.map(|s| TurnContext::new(s, &state));
+
+            // Worked out up front, so there is a move to play even if
+            // the search never returns one
+            let fallback = you.find_safe_move(&state);
+            let budget = schedule.budget();
+            let dir = watchdog
+                .get_move(
+                    profile,
+                    &you,
+                    &state,
+                    &schedule.primary(),
+                    schedule.watchdog(),
+                    idle.pool(),
+                )
+                .unwrap_or_else(|| {
+                    warn!("Playing the fallback move {:?}", fallback);
+                    count!(FALLBACK_MOVES);
+                    fallback
+                });
+            if budget.overrun() {
+                warn!(
+                    "{} ran past the hard deadline, {} ms in",
+                    profile.get_status(),
+                    budget.elapsed().as_millis()
+                );
+            }
+
+            let dir = match (snake, &context) {
+                (Some(s), Some(ctx)) => {
+                    let root_move = ctx.prune(dir);
+                    if root_move != dir {
+                        warn!(
+                            "Pruned root move {:?}, playing {:?}",
+                            dir, root_move
+                        );
+                    }
+
+                    let history = &this_analytics.history;
+                    let committed = history.commit(root_move, s, &state, ctx);
+                    let dir = constraints::apply(
+                        this_analytics.explore(committed, s, &state),
+                        s,
+                        &state,
+                        &this_analytics.postures,
+                    );
+
+                    match schedule.verification() {
+                        Some(b) => constraints::verify_duel(dir, s, &state, &b),
+                        None => dir,
+                    }
+                }
+                _ => dir,
+            };
+
+            Some(MoveDecision {
+                dir,
+                you,
+                state,
+                context,
+            })
+        }
+        Err(e) => {
+            warn!("Rejecting /move: {}", e);
+            None
         }
-        Err(_) => format!("{{\"color\":\"{}\"}}", color),
     }
 }
 
-/// Handle the /move POST request
-pub fn move_handler(
+/// Logging, analytics and recording for a move, run once the response
+/// has been sent `elapsed` after the request came in
+pub fn after_move(
     buffer: &str,
-    profile: &mut impl Profile,
-    alpha_beta: &mut AlphaBeta,
-    analytics: &mut HashMap<String, Analytics>,
+    decision: &MoveDecision,
+    profile: &dyn Profile,
+    analytics: &mut Option<Analytics>,
+    schedule: &TurnScheduler,
+    elapsed: Duration,
+) {
+    let MoveDecision {
+        dir,
+        you,
+        state,
+        context,
+    } = decision;
+
+    let mut record = MoveRecord::new(
+        &state.game.id,
+        state.turn,
+        profile.get_status(),
+        *dir,
+        &profile.move_scores(),
+        elapsed,
+    );
+    record.win_probability = profile.win_probability();
+    record.search = profile.search_report();
+    record.log();
+
+    if let Some(ctx) = context {
+        debug!("Move outcomes: {}", ctx);
+    }
+
+    if let Some(meta) = state.meta.get(&you.id) {
+        debug!(
+            "Engine latency {:?} ms, squad {:?}, shout {:?}",
+            meta.latency, meta.squad, meta.shout
+        );
+    }
+
+    let this_analytics = match analytics {
+        Some(a) => a,
+        None => return,
+    };
+
+    this_analytics.fire(&you.id, state);
+    this_analytics.update_full_game(state.turn, buffer);
+    debug!(
+        "Predicted enemy profiles: {:?}",
+        this_analytics.predicted_profiles()
+    );
+
+    if let Some(prob) = record.win_probability {
+        this_analytics.record_win_probability(state.turn, prob);
+    }
+    if let Some(report) = record.search.clone() {
+        this_analytics.record_search(report);
+    }
+
+    if let (Some(ctx), Some(w)) = (context, shadow::candidate()) {
+        if schedule.best_effort().is_none() {
+            debug!("Out of time, skipping shadow scoring");
+            return;
+        }
+
+        let turn = ShadowTurn::new(state.turn, *dir, w, you, state, ctx);
+        if turn.diverges() {
+            debug!("Shadow scoring diverged: {}", turn);
+        }
+        this_analytics.record_shadow(turn);
+    }
+}
+
+/// Handle the /end POST request
+pub fn end_handler(
+    request: &MoveRequest,
+    analytics: &mut Option<Analytics>,
+    store: &mut OpponentStore,
+) {
+    if let Ok((you, state)) = request_state(request) {
+        if let Some(mut game) = analytics.take() {
+            // Catches the eliminations on the last turn
+            game.history.observe(&you.id, &state);
+
+            let won = state.board.snakes.contains_key(&you.id);
+            if won {
+                count!(WINS);
+            } else {
+                count!(LOSSES);
+            }
+            let opponents = game
+                .names
+                .iter()
+                .map(|(id, name)| {
+                    (name.clone(), !won && state.board.snakes.contains_key(id))
+                })
+                .collect::<Vec<(String, bool)>>();
+            let matches = game
+                .matches()
+                .filter_map(|(id, p)| {
+                    game.names.get(id).map(|n| (n.clone(), p.clone()))
+                })
+                .collect::<HashMap<String, String>>();
+
+            let deaths = game
+                .history
+                .eliminations
+                .iter()
+                .filter_map(|e| {
+                    let ours = e.killer.as_ref() == Some(&you.id);
+                    game.names.get(&e.id).map(|n| (n.clone(), (e.cause, ours)))
+                })
+                .collect::<HashMap<String, (DeathCause, bool)>>();
+
+            store.record_game(state.turn, won, &opponents, &matches, &deaths);
+
+            if let Some(report) = game.shadow_report(won) {
+                info!("{}", report);
+            }
+
+            if let Err(e) = store.save(&CacheDir::configured()) {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+/// Handle the /status GET request, summarizing what the server is
+/// doing along with a snapshot of the metrics
+pub fn status_handler(
+    profile: &str,
+    games: &[String],
+    idle: &IdleMonitor,
 ) -> String {
+    json!({
+        "profile": profile,
+        "games": games,
+        "power": idle.status(),
+        "metrics": metrics::snapshot(),
+    })
+    .to_string()
+}
+
+/// The board the health check plays a move on: two snakes in the open
+const SELF_TEST_BOARD: &str = r#"{
+    "game": {"id": "self-test"},
+    "turn": 10,
+    "board": {
+        "height": 11,
+        "width": 11,
+        "food": [{"x": 8, "y": 2}],
+        "snakes": [
+            {"id": "you", "name": "you", "health": 90,
+             "body": [{"x": 3, "y": 5}, {"x": 3, "y": 6}, {"x": 3, "y": 7}]},
+            {"id": "enemy", "name": "enemy", "health": 90,
+             "body": [{"x": 7, "y": 5}, {"x": 7, "y": 4}, {"x": 7, "y": 3}]}
+        ]
+    },
+    "you": {"id": "you", "name": "you", "health": 90,
+            "body": [{"x": 3, "y": 5}, {"x": 3, "y": 6}, {"x": 3, "y": 7}]}
+}"#;
+/// How long the health check's move may search for
+const SELF_TEST_BUDGET_MILLIS: u64 = 5;
+/// The health check fails if its move takes longer than this, however
+/// short the budget it was given
+const SELF_TEST_MAX_MILLIS: u128 = 100;
+
+/// How the health check's move went
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SelfTest {
+    pub ok: bool,
+    #[serde(rename = "move")]
+    pub dir: Option<&'static str>,
+    pub elapsed_ms: f64,
+    pub error: Option<String>,
+}
+
+/// Parses the canned board and has `profile` pick a move on it with a
+/// tiny budget. Passes if a move that isn't fatal comes back in time.
+pub fn self_test(profile: &mut dyn Profile) -> SelfTest {
+    let start = Instant::now();
+    let budget = Duration::from_millis(SELF_TEST_BUDGET_MILLIS);
+    let result = parse_body(SELF_TEST_BOARD).and_then(|(you, st)| {
+        let budget = TimeBudget::new(start, budget, budget);
+        let dir = panic::catch_unwind(AssertUnwindSafe(|| {
+            profile.init(&st, you.id.clone());
+            profile.get_move(&you, &st, &budget)
+        }))
+        .map_err(|_| String::from("The profile panicked"))?;
+
+        if dir.is_safety_index(&you, &st, &SafetyIndex::Unsafe) {
+            Err(format!("Played the fatal move {:?}", dir))
+        } else {
+            Ok(dir)
+        }
+    });
+
+    let elapsed = start.elapsed();
+    let result = result.and_then(|dir| {
+        if elapsed.as_millis() > SELF_TEST_MAX_MILLIS {
+            Err(format!("Took {} ms", elapsed.as_millis()))
+        } else {
+            Ok(dir)
+        }
+    });
+
+    SelfTest {
+        ok: result.is_ok(),
+        dir: result.as_ref().ok().map(|d| d.as_move().dir),
+        elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+        error: result.err(),
+    }
+}
+
+/// Handle the /ping and /healthz GET requests. Runs the self-test on
+/// `profile` and returns whether it passed along with the report.
+pub fn ping_handler(profile: &mut dyn Profile, games: usize) -> (bool, String) {
+    let test = self_test(profile);
+    if !test.ok {
+        warn!("Health check failed: {:?}", test.error);
+    }
+
+    let last_move_ms = if metrics::MOVES.get() > 0 {
+        Some(metrics::LAST_MOVE_MILLIS.get())
+    } else {
+        None
+    };
+
+    let body = json!({
+        "ok": test.ok,
+        "version": env!("CARGO_PKG_VERSION"),
+        "profile": profile.get_status(),
+        "active_games": games,
+        "last_move_ms": last_move_ms,
+        "self_test": test,
+    });
+
+    (test.ok, body.to_string())
+}
+
+/// Handle the /stats GET request, with what the opponent store has
+/// learned over every game
+pub fn stats_handler(store: &OpponentStore) -> String {
+    json!({
+        "meta": store.meta,
+        "deaths_by_cause": store.deaths_by_cause(),
+        "opponents": store.opponents,
+    })
+    .to_string()
+}
+
+/// Handle the /danger POST request, which takes a move request and
+/// returns our danger map for it
+#[cfg(feature = "debug-endpoints")]
+pub fn danger_handler(buffer: &str) -> String {
     match parse_body(buffer) {
-        Ok((you, state)) => {
-            let this_analytics = analytics.get_mut(&state.game.id).unwrap();
+        Ok((you, st)) => {
+            let reach = analysis::reach_all(&st, CONTEXT_REACH_TURNS);
+            json!(DangerMap::new(&you, &st, &reach)).to_string()
+        }
+        Err(e) => {
+            warn!("Couldn't map the danger of the request: {}", e);
+            json!({ "error": e }).to_string()
+        }
+    }
+}
+
+/// The longest search `/analyze` will run for, whatever it is asked
+#[cfg(feature = "debug-endpoints")]
+const MAX_ANALYZE_MILLIS: u64 = 10_000;
+
+/// Handle the /analyze POST request, which takes a move request and
+/// returns what a fresh profile makes of it: the move it picks, its
+/// status, the outcome of every direction and our danger map. The
+/// profile is `?profile=`, or the configured one, and it searches for
+/// `?budget=` milliseconds, or as long as a real move allows.
+#[cfg(feature = "debug-endpoints")]
+pub fn analyze_handler(buffer: &str, query: Option<&str>) -> String {
+    let analyze = || -> Result<v1::Analysis, String> {
+        let (you, st) = parse_body(buffer)?;
+        let name = query_param(query, "profile")
+            .map(String::from)
+            .unwrap_or_else(|| config::get().profile.clone());
+        if !PROFILE_NAMES.contains(&name.as_str()) {
+            return Err(format!("Unknown profile {}", name));
+        }
+
+        let start = Instant::now();
+        let budget = match query_param(query, "budget") {
+            Some(ms) => {
+                let ms = ms
+                    .parse::<u64>()
+                    .map_err(|e| format!("Bad budget {}: {}", ms, e))?;
+                let t = Duration::from_millis(ms.min(MAX_ANALYZE_MILLIS));
+                TimeBudget::new(start, t, t)
+            }
+            None => TimeBudget::for_move(start),
+        };
 
-            this_analytics.fire(&you.id, &state);
-            this_analytics.update_full_game(buffer);
-            // profile.update_analytics(this_analytics.matches.clone());
+        let mut profile = string_to_profile(&name);
+        profile.init(&st, you.id.clone());
+        let dir = profile.get_move(&you, &st, &budget);
+        let elapsed = start.elapsed();
 
-            let dir = profile.get_move(&you, &state);
+        let ctx = TurnContext::new(&you, &st);
+        let chosen = ctx.get(dir);
+        let explanation = format!(
+            "{} picked {} after {} ms. {}",
+            name,
+            dir.as_move().dir,
+            elapsed.as_millis(),
+            chosen
+        );
+        let directions = ctx
+            .outcomes
+            .iter()
+            .map(|o| v1::DirectionAnalysis {
+                dir: o.dir.as_move().dir,
+                legal: o.legal,
+                reverses_neck: o.reverses_neck,
+                hits_wall: o.hits_wall,
+                contest: format!("{:?}", o.contest),
+                area: o.area,
+                enclosed: o.enclosed,
+                blunder: o.is_blunder(),
+                root_move: ctx.root_moves().contains(&o.dir),
+            })
+            .collect();
 
-            info!("Move: {:?}", dir);
-            serde_json::to_string(&dir.as_move()).unwrap()
+        Ok(v1::Analysis {
+            schema: v1::VERSION,
+            turn: st.turn,
+            profile: name,
+            dir: dir.as_move().dir,
+            status: profile.get_status(),
+            win_probability: profile.win_probability(),
+            elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+            explanation,
+            directions,
+            danger: DangerMap::new(&you, &st, &ctx.reach),
+        })
+    };
+
+    match analyze() {
+        Ok(analysis) => json!(analysis).to_string(),
+        Err(e) => {
+            warn!("Couldn't analyze the request: {}", e);
+            json!({ "error": e }).to_string()
         }
-        Err(_) => serde_json::to_string(&Dir::Left.as_move()).unwrap(),
     }
 }
 
-/// Handle the /end POST request
-pub fn end_handler(buffer: &str, analytics: &mut HashMap<String, Analytics>) {
-    if let Ok((_, state)) = parse_body(buffer) {
-        analytics.remove(&state.game.id);
+/// Apply what the opponent store learned and the configured overrides
+/// for every known opponent in the game, and return their configs in
+/// the order the board lists them
+fn apply_opponent_configs<'a>(
+    request: &MoveRequest,
+    you: &Snake,
+    opponents: &'a HashMap<String, OpponentConfig>,
+    store: &OpponentStore,
+    analytics: &mut Analytics,
+) -> Vec<&'a OpponentConfig> {
+    let mut found = Vec::new();
+    for snake in request.board.snakes.iter().filter(|s| s.id != you.id) {
+        analytics.names.insert(snake.id.clone(), snake.name.clone());
+
+        match store.likely_profile(&snake.name) {
+            Some(m) if PROFILE_NAMES.contains(&m) => {
+                info!("{} usually plays like {}", snake.name, m);
+                analytics.set_prior(&snake.id, m);
+            }
+            _ => {}
+        }
+
+        if let Some(config) = opponents.get(&snake.name) {
+            info!("Found opponent config for {}: {:?}", snake.name, config);
+            found.push(config);
+
+            match config.model_profile() {
+                Some(ref m) if PROFILE_NAMES.contains(&m.as_str()) => {
+                    analytics.set_prior(&snake.id, m);
+                }
+                Some(m) => warn!("Unknown model profile {} for {}", m, snake.name),
+                None => {}
+            }
+
+            if let Some(posture) = config.risk_posture {
+                analytics.postures.insert(snake.id.clone(), posture);
+            }
+        }
     }
+
+    found
+}
+
+/// The profile the first of `configs` to name a known one asks us to
+/// play with
+fn counter_profile<'a>(configs: &[&'a OpponentConfig]) -> Option<&'a str> {
+    configs.iter().find_map(|c| match c.profile.as_deref() {
+        Some(p) if PROFILE_NAMES.contains(&p) => Some(p),
+        Some(p) => {
+            warn!("Unknown profile {} for {}", p, c.name);
+            None
+        }
+        None => None,
+    })
+}
+
+/// The weight preset the first of `configs` to name a known one asks
+/// us to play with
+fn counter_weights(configs: &[&OpponentConfig]) -> Option<Weights> {
+    configs.iter().find_map(|c| {
+        let name = c.weight_preset.as_deref()?;
+        let weights = Weights::named(name);
+        if weights.is_none() {
+            warn!("Unknown weight preset {} for {}", name, c.name);
+        }
+        weights
+    })
 }
 
 /// Parse the JSON from the request body, then return
 /// our snake and the game state
-fn parse_body(buffer: &str) -> Result<(Snake, State), String> {
-    let json = serde_json::from_str::<MoveRequest>(buffer);
-    match json {
-        Ok(json) => {
-            let mut foods = HashSet::<Point>::new();
-            let mut snakes = HashMap::<String, Snake>::new();
-
-            for food in &json.board.food {
-                foods.insert(*food);
-            }
+pub fn parse_body(buffer: &str) -> Result<(Snake, State), String> {
+    parse_body_as(buffer, config::get().api_version)
+}
+
+/// `parse_body` for requests of the given API version
+pub fn parse_body_as(
+    buffer: &str,
+    version: ApiVersion,
+) -> Result<(Snake, State), String> {
+    parse_request(buffer).and_then(|json| request_state_as(&json, version))
+}
 
-            for snake_json in json.board.snakes {
-                let snake = Snake {
-                    id: snake_json.id.clone(),
-                    health: snake_json.health,
-                    body: snake_json.body,
-                };
+/// Parse the JSON from a game request's body. The server does this
+/// once per request, and everything that handles it reads the result.
+pub fn parse_request(buffer: &str) -> Result<MoveRequest, String> {
+    serde_json::from_str::<MoveRequest>(buffer).map_err(|e| {
+        error!("Error: {}", e);
+        warn!("Request body: {}", buffer);
+        e.to_string()
+    })
+}
 
-                if snake.body.len() < 3 {
-                    return Err(String::from("Snake body not long enough!!"));
-                }
+/// Our snake and the game state from a parsed request
+pub fn request_state(json: &MoveRequest) -> Result<(Snake, State), String> {
+    request_state_as(json, config::get().api_version)
+}
+
+/// `request_state` for requests of the given API version
+pub fn request_state_as(
+    json: &MoveRequest,
+    version: ApiVersion,
+) -> Result<(Snake, State), String> {
+    // Every point is in bounds on a wrapped board, so points off the
+    // board have to be caught before anything indexes by them
+    let (width, height) = (json.board.width, json.board.height);
+    let off_board =
+        |p: &&Point| p.x < 0 || p.x >= width || p.y < 0 || p.y >= height;
+    let bodies = json.board.snakes.iter().flat_map(|s| &s.body);
+    if let Some(p) = json
+        .board
+        .food
+        .iter()
+        .chain(&json.board.hazards)
+        .chain(bodies)
+        .chain(&json.you.body)
+        .find(off_board)
+    {
+        return Err(format!("({}, {}) is off the board", p.x, p.y));
+    }
+
+    let convert = |p: &Point| version.to_internal(*p, height);
 
-                snakes.insert(snake_json.id, snake);
+    let mut foods = HashSet::<Point>::new();
+    let mut snakes = HashMap::<String, Snake>::new();
+    let mut meta = HashMap::<String, SnakeMeta>::new();
+
+    for food in &json.board.food {
+        foods.insert(convert(food));
+    }
+
+    for snake_json in &json.board.snakes {
+        let snake = Snake {
+            id: snake_json.id.clone(),
+            health: snake_json.health,
+            body: snake_json.body.iter().map(convert).collect(),
+            squad: snake_json.squad.clone(),
+        };
+
+        if snake.body.len() < 3 {
+            return Err(String::from("Snake body not long enough!!"));
+        }
+
+        if version == ApiVersion::V2020 {
+            meta.insert(
+                snake_json.id.clone(),
+                SnakeMeta {
+                    latency: snake_json
+                        .latency
+                        .as_ref()
+                        .and_then(parse_latency),
+                    shout: snake_json.shout.clone(),
+                    squad: snake_json.squad.clone(),
+                },
+            );
+        }
+
+        snakes.insert(snake_json.id.clone(), snake);
+    }
+
+    let mut you = json.you.clone();
+    you.body = you.body.iter().map(convert).collect();
+
+    let board = Board {
+        height: json.board.height,
+        width: json.board.width,
+        food: foods,
+        snakes,
+        hazards: json.board.hazards.iter().map(convert).collect(),
+        mode: GameMode::of(&json.game),
+    };
+
+    if board.snakes.len() > MAX_SNAKES {
+        return Err(format!(
+            "{} snakes is more than we can play against",
+            board.snakes.len()
+        ));
+    }
+
+    if let Some(s) = board
+        .snakes
+        .values()
+        .find(|s| s.body.len() > board.max_body_len())
+    {
+        return Err(format!(
+            "Snake {} is longer than the board can hold",
+            s.id
+        ));
+    }
+
+    let state = State {
+        game: json.game.clone(),
+        turn: json.turn,
+        board,
+        meta: Arc::new(meta),
+        distances: Default::default(),
+    };
+
+    Ok((you, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_response_tail() {
+        let responses = MoveResponses::new();
+        let tail = ResponseTail {
+            confidence: None,
+            handler_ms: 12.5,
+            correlation_id: Some(String::from("abc")),
+        };
+
+        let body = responses.with_tail(Dir::Up, &tail);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({"move": "up", "handler_ms": 12.5, "correlation_id": "abc"})
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-correlation-id", "abc".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        assert_eq!(
+            header_value(&headers, CORRELATION_HEADER),
+            Some(String::from("abc"))
+        );
+        assert_eq!(header_value(&headers, DEBUG_TAIL_HEADER), None);
+    }
+
+    #[test]
+    fn test_latency_tracker() {
+        let request = |latency: &str| {
+            let you = json!({
+                "id": "you",
+                "name": "us",
+                "health": 90,
+                "body": [{"x": 5, "y": 1}, {"x": 5, "y": 2}, {"x": 5, "y": 3}],
+                "latency": latency
+            });
+            let body = json!({
+                "game": {"id": "g"},
+                "turn": 3,
+                "board": {
+                    "height": 11,
+                    "width": 11,
+                    "food": [],
+                    "snakes": [you]
+                },
+                "you": you
+            });
+            parse_request(&body.to_string()).unwrap()
+        };
+        let start = Instant::now();
+        let mut tracker = LatencyTracker::default();
+        assert_eq!(
+            tracker.budget(start, 500),
+            TimeBudget::for_timeout(start, 500)
+        );
+
+        // Nothing to compare the first reported latency with
+        tracker.observe(&request("300"));
+        assert_eq!(tracker.overhead_millis(), None);
+
+        for latency in &["80", "130", "70"] {
+            tracker.responded(Duration::from_millis(60));
+            tracker.observe(&request(latency));
+        }
+        assert_eq!(tracker.overhead_millis(), Some(70));
+        let budget = tracker.budget(start, 500);
+        assert_eq!(budget.hard_limit(), Duration::from_millis(415));
+
+        // A terrible connection still leaves time to move
+        tracker.responded(Duration::from_millis(10));
+        tracker.observe(&request("1000"));
+        let budget = tracker.budget(start, 500);
+        assert_eq!(budget.hard_limit(), Duration::from_millis(MIN_MOVE_MILLIS));
+    }
+
+    #[test]
+    fn test_parse_2020() {
+        let body = json!({
+            "game": {"id": "g"},
+            "turn": 3,
+            "board": {
+                "height": 11,
+                "width": 11,
+                "food": [{"x": 0, "y": 10}],
+                "snakes": [{
+                    "id": "you",
+                    "name": "us",
+                    "health": 90,
+                    "body": [
+                        {"x": 5, "y": 1},
+                        {"x": 5, "y": 0},
+                        {"x": 5, "y": 0}
+                    ],
+                    "head": {"x": 5, "y": 1},
+                    "latency": "123",
+                    "shout": "hi",
+                    "squad": "1"
+                }]
+            },
+            "you": {
+                "id": "you",
+                "name": "us",
+                "health": 90,
+                "body": [
+                    {"x": 5, "y": 1},
+                    {"x": 5, "y": 0},
+                    {"x": 5, "y": 0}
+                ],
+                "head": {"x": 5, "y": 1},
+                "latency": "123",
+                "shout": "hi",
+                "squad": "1"
             }
+        })
+        .to_string();
 
-            let board = Board {
-                height: json.board.height,
-                width: json.board.width,
-                food: foods,
-                snakes,
-            };
+        // Bottom left origin, so the food is in the top left corner and
+        // we are moving up from the bottom edge
+        let (you, st) = parse_body_as(&body, ApiVersion::V2020).unwrap();
+        assert_eq!(you.body[0], Point { x: 5, y: 9 });
+        assert_eq!(st.board.snakes["you"].body, you.body);
+        assert!(st.board.food.contains(&Point { x: 0, y: 0 }));
+        assert_eq!(you.last_move(), Some(Dir::Up));
 
-            let state = State {
-                game: json.game,
-                turn: json.turn,
-                board,
-            };
+        let meta = &st.meta["you"];
+        assert_eq!(meta.latency, Some(123));
+        assert_eq!(meta.shout.as_deref(), Some("hi"));
+        assert_eq!(meta.squad.as_deref(), Some("1"));
+        assert_eq!(you.squad, st.board.snakes["you"].squad);
+        assert_eq!(you.squad.as_deref(), Some("1"));
+
+        let (you, st) = parse_body_as(&body, ApiVersion::V2019).unwrap();
+        assert_eq!(you.body[0], Point { x: 5, y: 1 });
+        assert!(st.meta.is_empty());
+
+        assert_eq!(parse_request(&body).unwrap().game.id, "g");
+        assert!(parse_request("{}").is_err());
+        assert_eq!(parse_request(&body).unwrap().game.timeout, None);
+        assert_eq!(st.timeout_millis(), 500);
+
+        // Health out of range from a buggy engine is clamped, and the
+        // ruleset and timeout come through when they are sent
+        let mut body: Value = serde_json::from_str(&body).unwrap();
+        body["game"]["timeout"] = json!(250);
+        body["game"]["ruleset"] = json!({"name": "solo", "version": "1"});
+        body["board"]["snakes"][0]["health"] = json!(250);
+        body["you"]["health"] = json!(-5);
+        let body = body.to_string();
+        let (you, st) = parse_body_as(&body, ApiVersion::V2020).unwrap();
+        assert_eq!(st.board.snakes["you"].health, 100);
+        assert_eq!(you.health, 0);
+        assert_eq!(parse_request(&body).unwrap().game.timeout, Some(250));
+        assert_eq!(st.timeout_millis(), 250);
+        let ruleset = st.game.ruleset.unwrap();
+        assert_eq!(ruleset.version.as_deref(), Some("1"));
+
+        // Even a wrapped board has nothing past its edges
+        let mut body: Value = serde_json::from_str(&body).unwrap();
+        body["game"]["ruleset"] = json!({"name": "wrapped"});
+        body["board"]["food"] = json!([{"x": 11, "y": 3}]);
+        let body = body.to_string();
+        let err = parse_body_as(&body, ApiVersion::V2020).unwrap_err();
+        assert_eq!(err, "(11, 3) is off the board");
+    }
 
-            info!("Turn: {}", json.turn);
-            Ok((json.you, state))
+    /// A profile that can't move at all
+    struct Broken;
+
+    impl Profile for Broken {
+        fn get_move(&mut self, _: &Snake, _: &State, _: &TimeBudget) -> Dir {
+            panic!("Broken profile")
         }
-        Err(e) => {
-            error!("Error: {}", e);
-            warn!("Request body: {}", buffer);
-            Err(e.to_string())
+
+        fn get_status(&self) -> String {
+            String::from("Broken")
         }
     }
+
+    #[test]
+    fn test_ping_handler() {
+        let (ok, res) = ping_handler(&mut *string_to_profile("notsuck"), 2);
+        let res: Value = serde_json::from_str(&res).unwrap();
+        assert!(ok);
+        assert_eq!(res["active_games"], 2);
+        assert_eq!(res["version"], env!("CARGO_PKG_VERSION"));
+        assert!(res["self_test"]["move"].is_string());
+
+        let (ok, res) = ping_handler(&mut Broken, 0);
+        assert!(!ok && res.contains("The profile panicked"));
+    }
+
+    #[cfg(feature = "debug-endpoints")]
+    #[test]
+    fn test_analyze_handler() {
+        let you = json!({
+            "id": "you",
+            "name": "us",
+            "health": 90,
+            "body": [{"x": 5, "y": 5}, {"x": 5, "y": 6}, {"x": 5, "y": 7}]
+        });
+        let body = json!({
+            "game": {"id": "g"},
+            "turn": 7,
+            "board": {
+                "height": 11,
+                "width": 11,
+                "food": [],
+                "snakes": [you.clone()]
+            },
+            "you": you
+        })
+        .to_string();
+
+        let query = Some("profile=straight&budget=50");
+        let res: Value =
+            serde_json::from_str(&analyze_handler(&body, query)).unwrap();
+        assert_eq!(res["turn"], 7);
+        assert_eq!(res["profile"], "straight");
+        assert_eq!(res["directions"].as_array().unwrap().len(), 4);
+        assert_eq!(res["danger"]["width"], 11);
+        assert!(res["explanation"].as_str().unwrap().starts_with("straight"));
+
+        let res = analyze_handler(&body, Some("profile=nope"));
+        assert!(res.contains("Unknown profile nope"));
+        let res = analyze_handler(&body, Some("budget=soon"));
+        assert!(res.contains("Bad budget soon"));
+    }
+
+    #[test]
+    fn test_query_params() {
+        let (path, query) = split_url("/start?profile=sim&x");
+        assert_eq!(path, "/start");
+        assert_eq!(query_param(query, "profile"), Some("sim"));
+        assert_eq!(query_param(query, "x"), Some(""));
+        assert_eq!(query_param(query, "y"), None);
+        assert_eq!(split_url("/move"), ("/move", None));
+
+        let profile = requested_profile(query).unwrap();
+        assert_eq!(profile.get_status(), "Sim");
+        assert!(requested_profile(Some("profile=best")).is_none());
+        assert!(requested_profile(None).is_none());
+    }
+
+    #[test]
+    fn test_opponent_profile() {
+        let snake = |id: &str, name: &str, x: i8| {
+            json!({
+                "id": id,
+                "name": name,
+                "health": 100,
+                "body": [{"x": x, "y": 5}, {"x": x, "y": 6}, {"x": x, "y": 7}]
+            })
+        };
+        let you = snake("you", "us", 2);
+        let body = json!({
+            "game": {"id": "g"},
+            "turn": 0,
+            "board": {
+                "height": 11,
+                "width": 11,
+                "food": [],
+                "snakes": [you.clone(), snake("them", "Known Snake", 8)]
+            },
+            "you": you
+        })
+        .to_string();
+        let config: OpponentConfig = toml::from_str(
+            "name = \"Known Snake\"\n\
+             profile = \"cautious\"\n\
+             weight_preset = \"small\"",
+        )
+        .unwrap();
+        let opponents = vec![(config.name.clone(), config)]
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let mut store = OpponentStore::default();
+        let mut matches = HashMap::new();
+        matches.insert(String::from("Known Snake"), String::from("aggressive"));
+        store.record_game(
+            90,
+            true,
+            &[(String::from("Known Snake"), false)],
+            &matches,
+            &HashMap::new(),
+        );
+        #[cfg(feature = "recording")]
+        let samples = std::env::temp_dir()
+            .join(format!("battlesnake-samples-{}", std::process::id()));
+        #[cfg(feature = "recording")]
+        std::fs::create_dir_all(&samples).unwrap();
+        let start = |requested, profile: &mut Box<dyn Profile>| {
+            let mut analytics = None;
+            let opponents = &opponents;
+            start_handler(
+                &body,
+                &parse_request(&body).unwrap(),
+                requested,
+                profile,
+                &mut analytics,
+                opponents,
+                &store,
+            )
+            .unwrap();
+            // Dropping it records the game, so keep it out of the real
+            // samples directory
+            #[cfg(feature = "recording")]
+            if let Some(a) = analytics.as_mut() {
+                a.samples_dir = samples.to_string_lossy().to_string();
+            }
+            analytics.unwrap().predicted_profiles()
+        };
+
+        // The known opponent's config picks the profile
+        let mut profile = string_to_profile("straight");
+        let predicted = start(None, &mut profile);
+        assert_eq!(profile.get_status(), "Cautious");
+
+        // and the store remembers how they played last time
+        assert_eq!(predicted["them"], "aggressive");
+
+        // unless the request asks for one
+        let mut profile = string_to_profile("straight");
+        start(requested_profile(Some("profile=notsuck")), &mut profile);
+        assert_eq!(profile.get_status(), "NotSuck");
+
+        // Each game was recorded where its analytics were pointed
+        #[cfg(feature = "recording")]
+        {
+            assert!(samples.join("g.txt").exists());
+            std::fs::remove_dir_all(&samples).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_info_handler() {
+        let info: Value = serde_json::from_str(&info_handler()).unwrap();
+        assert_eq!(info["apiversion"], "1");
+        assert_eq!(info["color"], config::get().color.as_str());
+        assert_eq!(info["head"], "default");
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    }
 }
@@ -0,0 +1,135 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Shares a move's time budget between the work done for it, in order
+//! of priority:
+//!
+//! 1. The primary search, which gets at least `PRIMARY_SHARE` of the
+//!    budget, and all of it but the verification floor.
+//! 2. Verification of the chosen move, which gets whatever the search
+//!    left, and only runs if that is at least the floor.
+//! 3. Best-effort work like shadow scoring, which only runs if the
+//!    turn's budget hasn't run out by the time it gets a chance.
+//!
+//! Work asks the scheduler for a budget instead of checking the clock
+//! itself, so the split is decided in one place.
+
+use log::debug;
+use std::time::{Duration, Instant};
+
+use super::profile::TimeBudget;
+
+/// The least share of the budget the primary search gets
+const PRIMARY_SHARE: f64 = 0.8;
+/// Time kept back from the primary search for verification
+const VERIFICATION_FLOOR_MILLIS: u64 = 40;
+
+pub struct TurnScheduler {
+    budget: TimeBudget,
+}
+
+impl TurnScheduler {
+    pub fn new(budget: TimeBudget) -> Self {
+        Self { budget }
+    }
+
+    /// The whole budget of the turn
+    pub fn budget(&self) -> &TimeBudget {
+        &self.budget
+    }
+
+    /// The budget of the primary search. Profiles may cap it further.
+    pub fn primary(&self) -> TimeBudget {
+        let hard = self.budget.hard_limit();
+        let floor = Duration::from_millis(VERIFICATION_FLOOR_MILLIS);
+        let share = hard.mul_f64(PRIMARY_SHARE);
+        let soft = hard.checked_sub(floor).unwrap_or_default().max(share);
+
+        self.budget.capped(soft.as_millis() as u64)
+    }
+
+    /// When the handler stops waiting for the primary search and plays
+    /// a fallback move instead
+    pub fn watchdog(&self) -> Instant {
+        self.budget.hard_deadline()
+    }
+
+    /// The budget for verifying the chosen move, or None if the primary
+    /// search left less than the floor
+    pub fn verification(&self) -> Option<TimeBudget> {
+        let left = self.budget.until_hard();
+        let floor = Duration::from_millis(VERIFICATION_FLOOR_MILLIS);
+
+        if left.remaining() >= floor {
+            Some(left)
+        } else {
+            debug!(
+                "Skipping verification, {} ms in",
+                self.budget.elapsed().as_millis()
+            );
+            None
+        }
+    }
+
+    /// The budget for best-effort work, or None if the turn's budget
+    /// has already run out
+    pub fn best_effort(&self) -> Option<TimeBudget> {
+        if self.budget.overrun() {
+            None
+        } else {
+            Some(self.budget.until_hard())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_turn_scheduler() {
+        let now = Instant::now();
+        let schedule = TurnScheduler::new(TimeBudget::for_move(now));
+
+        // Everything but the floor goes to the search
+        let primary = schedule.primary();
+        assert_eq!(primary.soft_deadline(), now + Duration::from_millis(420));
+        assert_eq!(primary.hard_deadline(), schedule.budget().hard_deadline());
+        assert!(schedule.verification().is_some());
+        assert!(schedule.best_effort().is_some());
+
+        // A short budget still gives the search its share
+        let short = TimeBudget::new(
+            now,
+            Duration::from_millis(100),
+            Duration::from_millis(100),
+        );
+        let primary = TurnScheduler::new(short).primary();
+        assert_eq!(primary.soft_deadline(), now + Duration::from_millis(80));
+
+        // Less than the floor left after the search
+        let late = Instant::now() - Duration::from_millis(440);
+        let schedule = TurnScheduler::new(TimeBudget::for_move(late));
+        assert!(schedule.verification().is_none());
+        assert!(schedule.best_effort().is_some());
+
+        let over = Instant::now() - Duration::from_millis(500);
+        let schedule = TurnScheduler::new(TimeBudget::for_move(over));
+        assert!(schedule.best_effort().is_none());
+    }
+}
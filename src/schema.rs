@@ -0,0 +1,202 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The formats of everything the snake exports for tools outside of
+//! it, like the web viewer and the training pipeline. Each version is
+//! a module of serde types that every exporter writes through.
+//!
+//! Within a version, fields are only ever added, never renamed,
+//! removed or changed in meaning, so readers should ignore fields they
+//! don't know. Anything else is a new version. Moves are always their
+//! engine names, "up", "down", "left" and "right".
+//!
+//! The game recordings themselves, `<samples_dir>/<game id>.txt`, are
+//! one engine request per line and follow the engine's API instead.
+
+/// The first version of the export formats
+pub mod v1 {
+    use serde_derive::{Deserialize, Serialize};
+
+    /// Written as `schema` in documents, as opposed to line records
+    #[cfg(feature = "debug-endpoints")]
+    pub const VERSION: &str = "v1";
+
+    /// A line of `<game id>.winprob.txt`, our estimated win probability
+    /// after a move, for profiles that estimate one
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct WinProbability {
+        pub turn: u32,
+        pub win_probability: f64,
+    }
+
+    /// A line of `<game id>.search.txt`, what the search of a profile
+    /// that reports on it found in a turn
+    #[cfg(feature = "recording")]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct SearchReport {
+        pub turn: u32,
+        /// The deepest the search looked, in moves
+        pub depth: u8,
+        pub nodes: u64,
+        pub score: i16,
+        /// The moves the search expects, ours first and then each
+        /// enemy's in the order of `enemies`, round after round
+        pub pv: Vec<String>,
+        pub enemies: Vec<String>,
+    }
+
+    /// A line of `<game id>.shadow.txt`, the ranking of the moves by
+    /// the active and candidate heuristic weights in a turn
+    #[cfg(feature = "recording")]
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct ShadowTurn {
+        pub turn: u32,
+        pub played: String,
+        /// Best first
+        pub active: Vec<String>,
+        pub candidate: Vec<String>,
+    }
+
+    /// An event of the `/watch` stream, sent for each move we play
+    #[cfg(feature = "debug-endpoints")]
+    #[derive(Serialize, Debug, Clone, PartialEq)]
+    pub struct WatchEvent {
+        pub game_id: String,
+        pub turn: u32,
+        #[serde(rename = "move")]
+        pub dir: &'static str,
+        /// The search's estimate after the move, for profiles that
+        /// estimate one
+        pub win_probability: Option<f64>,
+    }
+
+    /// A line of `<game id>.danger.txt`, written by `replay danger`
+    #[cfg(feature = "debug-endpoints")]
+    pub use crate::game::danger::DangerMap;
+
+    /// A decision point where two profiles picked different moves
+    #[cfg(feature = "debug-endpoints")]
+    #[derive(Serialize, Debug, Clone, PartialEq)]
+    pub struct TurnDiff {
+        pub turn: u32,
+        pub before: &'static str,
+        pub after: &'static str,
+        /// The change in `move_score` from the first profile's move to
+        /// the second's
+        pub score_delta: f64,
+        pub blunder_before: bool,
+        pub blunder_after: bool,
+    }
+
+    /// How a second profile's moves differ from a first one's over a
+    /// game
+    #[cfg(feature = "debug-endpoints")]
+    #[derive(Serialize, Debug, Default, Clone, PartialEq)]
+    pub struct GameDiff {
+        pub path: String,
+        pub decisions: usize,
+        pub changed: usize,
+        pub score_delta: f64,
+        /// Moves that only the second profile blunders on
+        pub new_blunders: usize,
+        /// Moves that only the first profile blunders on
+        pub fixed_blunders: usize,
+        pub turns: Vec<TurnDiff>,
+    }
+
+    /// The document written by `replay diff`
+    #[cfg(feature = "debug-endpoints")]
+    #[derive(Serialize, Debug, Clone, PartialEq)]
+    pub struct ReplayDiff {
+        pub schema: &'static str,
+        pub before: String,
+        pub after: String,
+        pub games: Vec<GameDiff>,
+        /// The sums over every game, without any turns
+        pub total: GameDiff,
+    }
+
+    /// What one direction would do, in an `Analysis`
+    #[cfg(feature = "debug-endpoints")]
+    #[derive(Serialize, Debug, Clone, PartialEq)]
+    pub struct DirectionAnalysis {
+        pub dir: &'static str,
+        pub legal: bool,
+        pub reverses_neck: bool,
+        pub hits_wall: bool,
+        /// The `Contest` for the square, by its variant name
+        pub contest: String,
+        pub area: usize,
+        pub enclosed: bool,
+        pub blunder: bool,
+        /// Whether the searches consider the move at all
+        pub root_move: bool,
+    }
+
+    /// The response of `/analyze`
+    #[cfg(feature = "debug-endpoints")]
+    #[derive(Serialize, Debug, Clone, PartialEq)]
+    pub struct Analysis {
+        pub schema: &'static str,
+        pub turn: u32,
+        pub profile: String,
+        #[serde(rename = "move")]
+        pub dir: &'static str,
+        pub status: String,
+        pub win_probability: Option<f64>,
+        pub elapsed_ms: f64,
+        pub explanation: String,
+        pub directions: Vec<DirectionAnalysis>,
+        pub danger: DangerMap,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::v1;
+    use serde_json::json;
+
+    #[test]
+    fn test_v1_fields() {
+        // Renaming any of these breaks the readers of version 1
+        let w = v1::WinProbability {
+            turn: 3,
+            win_probability: 0.5,
+        };
+        let line = serde_json::to_string(&w).unwrap();
+        assert_eq!(line, r#"{"turn":3,"win_probability":0.5}"#);
+        assert_eq!(
+            serde_json::from_str::<v1::WinProbability>(&line).ok(),
+            Some(w)
+        );
+
+        #[cfg(feature = "debug-endpoints")]
+        {
+            let diff = v1::ReplayDiff {
+                schema: v1::VERSION,
+                before: String::from("sim"),
+                after: String::from("monte_carlo"),
+                games: vec![],
+                total: v1::GameDiff::default(),
+            };
+            let diff = json!(diff);
+            assert_eq!(diff["schema"], "v1");
+            assert_eq!(diff["total"]["new_blunders"], 0);
+            assert!(diff["games"].as_array().unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The state the server keeps for each game it is playing. Every game
+//! gets its own session, with its own profile instance, behind its own
+//! lock. A profile's per-game state, like Sim's branches, can't be
+//! clobbered by another game, and the worker threads can serve
+//! requests for different games at the same time while the requests
+//! of one game still run one after the other.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::analytics::Analytics;
+use super::profile::{AlphaBeta, Profile};
+use super::routes::LatencyTracker;
+use super::watchdog::Watchdog;
+
+/// Makes the profile a new game is played with
+pub type ProfileFactory = fn() -> Box<dyn Profile>;
+
+/// Everything that lives for the length of one game
+pub struct GameSession {
+    pub profile: Box<dyn Profile>,
+    pub alpha_beta: AlphaBeta,
+    /// None until `/start`, or the first `/move` if we missed it
+    pub analytics: Option<Analytics>,
+    pub latency: LatencyTracker,
+    pub watchdog: Watchdog,
+}
+
+impl GameSession {
+    pub fn new(profile: Box<dyn Profile>) -> Self {
+        Self {
+            profile,
+            alpha_beta: AlphaBeta::new(),
+            analytics: None,
+            latency: LatencyTracker::default(),
+            watchdog: Watchdog::new(),
+        }
+    }
+}
+
+pub type SharedSession = Arc<Mutex<GameSession>>;
+
+/// Every game in progress, by game id
+pub struct Sessions {
+    games: Mutex<HashMap<String, SharedSession>>,
+    make_profile: ProfileFactory,
+}
+
+/// Locks a mutex, carrying on with the data if a thread panicked while
+/// holding it. A panic in one game shouldn't take the server down.
+pub fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Sessions {
+    pub fn new(make_profile: ProfileFactory) -> Self {
+        Self {
+            games: Mutex::new(HashMap::new()),
+            make_profile,
+        }
+    }
+
+    /// A session that isn't registered under any game
+    pub fn detached(&self) -> SharedSession {
+        Arc::new(Mutex::new(GameSession::new((self.make_profile)())))
+    }
+
+    /// The session of a game, started if there isn't one yet
+    pub fn get(&self, id: &str) -> SharedSession {
+        lock(&self.games)
+            .entry(id.to_string())
+            .or_insert_with(|| self.detached())
+            .clone()
+    }
+
+    /// Ends a game, returning its session if there was one
+    pub fn remove(&self, id: &str) -> Option<SharedSession> {
+        lock(&self.games).remove(id)
+    }
+
+    /// The ids of every game in progress, sorted
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids = lock(&self.games).keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.games).len()
+    }
+
+    /// Drops every session. Requests still holding one finish with it.
+    pub fn clear(&self) {
+        lock(&self.games).clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::profile::Straight;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Arc::new(Sessions::new(|| Box::new(Straight::new())));
+
+        let handles = (0..8)
+            .map(|i| {
+                let sessions = sessions.clone();
+                thread::spawn(move || {
+                    let id = format!("game-{}", i % 2);
+                    let session = sessions.get(&id);
+                    let fresh = lock(&session).analytics.is_none();
+                    fresh
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for h in handles {
+            assert!(h.join().unwrap());
+        }
+
+        assert_eq!(sessions.ids(), vec!["game-0", "game-1"]);
+        assert!(Arc::ptr_eq(
+            &sessions.get("game-0"),
+            &sessions.get("game-0")
+        ));
+
+        assert!(sessions.remove("game-0").is_some());
+        assert!(sessions.remove("game-0").is_none());
+        assert_eq!(sessions.len(), 1);
+
+        sessions.clear();
+        assert_eq!(sessions.len(), 0);
+    }
+}
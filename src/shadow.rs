@@ -0,0 +1,281 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Shadow scoring, for trying out heuristic weights within a game
+//! instead of over whole games. After each move the root moves are
+//! ranked by AlphaBeta's weighted evaluation twice, once with the
+//! weights from `alpha_beta_weights` and once with a candidate set
+//! loaded from `shadow_weights`. The
+//! rankings are recorded with the game, and at the end of it a report
+//! shows the turns where the two disagreed and which side the result
+//! favored. Nothing here affects the move that is played.
+
+use log::warn;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::sync::OnceLock;
+
+use super::config;
+use super::game::{Dir, Snake, State, TurnContext};
+use super::profile::eval::{self, EvalWeights};
+#[cfg(feature = "recording")]
+use super::schema::v1;
+
+/// Score of a move that is immediately fatal, whatever the weights
+const ILLEGAL_SCORE: f64 = -1000.0;
+
+static CANDIDATE: OnceLock<Option<EvalWeights>> = OnceLock::new();
+
+/// The candidate weights from the `shadow_weights` file, or None if
+/// shadow scoring is off or the file can't be loaded
+pub fn candidate() -> Option<&'static EvalWeights> {
+    CANDIDATE
+        .get_or_init(|| {
+            let path = &config::get().shadow_weights;
+            if path.is_empty() {
+                return None;
+            }
+
+            let weights = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|c| toml::from_str(&c).map_err(|e| e.to_string()));
+
+            match weights {
+                Ok(w) => Some(w),
+                Err(e) => {
+                    warn!("Couldn't load shadow weights {}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Scores the position a move leads to, with the enemies left where
+/// they are
+fn score(
+    dir: Dir,
+    w: &EvalWeights,
+    s: &Snake,
+    st: &State,
+    ctx: &TurnContext,
+) -> f64 {
+    if !ctx.get(dir).legal {
+        return ILLEGAL_SCORE;
+    }
+
+    let mut next = st.clone();
+    let _ = next.apply_moves(&[(&s.id, dir)]);
+    let enemies = next
+        .board
+        .snakes
+        .keys()
+        .filter(|id| **id != s.id)
+        .collect::<Vec<&String>>();
+
+    w.score(&next, &s.id, &enemies)
+}
+
+/// The moves ranked best first by the weighted evaluation
+pub fn rank(
+    w: &EvalWeights,
+    s: &Snake,
+    st: &State,
+    ctx: &TurnContext,
+) -> Vec<Dir> {
+    let mut scored = ctx
+        .outcomes
+        .iter()
+        .map(|o| (o.dir, score(o.dir, w, s, st, ctx)))
+        .collect::<Vec<(Dir, f64)>>();
+
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    scored.into_iter().map(|(d, _)| d).collect()
+}
+
+fn letter(dir: Dir) -> char {
+    match dir {
+        Dir::Up => 'U',
+        Dir::Down => 'D',
+        Dir::Left => 'L',
+        Dir::Right => 'R',
+    }
+}
+
+/// Both rankings for a turn, along with the move actually played
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowTurn {
+    pub turn: u32,
+    pub played: Dir,
+    pub active: Vec<Dir>,
+    pub candidate: Vec<Dir>,
+}
+
+impl ShadowTurn {
+    pub fn new(
+        turn: u32,
+        played: Dir,
+        candidate: &EvalWeights,
+        s: &Snake,
+        st: &State,
+        ctx: &TurnContext,
+    ) -> Self {
+        Self {
+            turn,
+            played,
+            active: rank(eval::configured(), s, st, ctx),
+            candidate: rank(candidate, s, st, ctx),
+        }
+    }
+
+    pub fn diverges(&self) -> bool {
+        self.active.first() != self.candidate.first()
+    }
+}
+
+/// One line of the shadow file saved next to a game's replay: the
+/// turn, the move played, then both rankings as `U`/`D`/`L`/`R`
+#[cfg(feature = "recording")]
+impl From<&ShadowTurn> for v1::ShadowTurn {
+    fn from(turn: &ShadowTurn) -> Self {
+        let names = |dirs: &[Dir]| {
+            dirs.iter().map(|d| d.as_move().dir.to_string()).collect()
+        };
+
+        Self {
+            turn: turn.turn,
+            played: turn.played.as_move().dir.to_string(),
+            active: names(&turn.active),
+            candidate: names(&turn.candidate),
+        }
+    }
+}
+
+impl fmt::Display for ShadowTurn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let letters =
+            |dirs: &[Dir]| dirs.iter().map(|d| letter(*d)).collect::<String>();
+
+        write!(
+            f,
+            "{} {} {} {}",
+            self.turn,
+            letter(self.played),
+            letters(&self.active),
+            letters(&self.candidate)
+        )
+    }
+}
+
+/// Where the two rankings disagreed over a game, and which side the
+/// result favored. When we played one side's pick, a win favors that
+/// side and a loss favors the other.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShadowReport {
+    pub turns: usize,
+    pub divergent: Vec<u32>,
+    pub favors_active: usize,
+    pub favors_candidate: usize,
+    /// Divergent turns where we played neither side's pick
+    pub unclear: usize,
+}
+
+impl ShadowReport {
+    pub fn new(turns: &[ShadowTurn], won: bool) -> Self {
+        let mut report = Self {
+            turns: turns.len(),
+            ..Self::default()
+        };
+
+        for t in turns.iter().filter(|t| t.diverges()) {
+            report.divergent.push(t.turn);
+
+            let played_active = t.active.first() == Some(&t.played);
+            let played_candidate = t.candidate.first() == Some(&t.played);
+
+            match (played_active, played_candidate) {
+                (true, _) if won => report.favors_active += 1,
+                (true, _) => report.favors_candidate += 1,
+                (_, true) if won => report.favors_candidate += 1,
+                (_, true) => report.favors_active += 1,
+                _ => report.unclear += 1,
+            }
+        }
+
+        report
+    }
+}
+
+impl fmt::Display for ShadowReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Shadow scoring: {} of {} turns diverged, favoring active {}, \
+             candidate {}, unclear {}. Divergent turns: {:?}",
+            self.divergent.len(),
+            self.turns,
+            self.favors_active,
+            self.favors_candidate,
+            self.unclear,
+            self.divergent
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::game::build_state;
+    use super::*;
+
+    #[test]
+    fn test_shadow() {
+        let (mut you, mut st) = build_state(
+            11,
+            11,
+            &[("you", &[(1, 5), (2, 5), (3, 5)])],
+            &[(0, 5), (0, 8)],
+        );
+        you.health = 10;
+        st.board.snakes.get_mut("you").unwrap().health = 10;
+        let ctx = TurnContext::new(&you, &st);
+
+        // Hungry, so the default weights take the food on the edge.
+        // A candidate that hates the edge leaves it.
+        let active = rank(&EvalWeights::default(), &you, &st, &ctx);
+        assert_eq!(active[0], Dir::Left);
+        assert_eq!(active[3], Dir::Right);
+
+        let candidate = EvalWeights {
+            edge: 40.0,
+            ..EvalWeights::default()
+        };
+        let turn = ShadowTurn::new(4, Dir::Left, &candidate, &you, &st, &ctx);
+        assert_eq!(turn.candidate[2..], [Dir::Left, Dir::Right]);
+        assert!(turn.diverges());
+        assert_eq!(turn.to_string(), "4 L LUDR UDLR");
+
+        // We played the active pick and lost
+        let turns = [turn];
+        let report = ShadowReport::new(&turns, false);
+        assert_eq!(report.divergent, vec![4]);
+        assert_eq!(report.favors_candidate, 1);
+
+        let report = ShadowReport::new(&turns, true);
+        assert_eq!(report.favors_active, 1);
+    }
+}
@@ -15,9 +15,23 @@
  * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
  *
  */
+use serde_derive::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
-use crate::game::{Dir, Point, State};
+use crate::game::hash::{food_key, move_added, move_removed, snake_hash};
+use crate::game::{DistanceCache, Dir, Point, Snake, State, HAZARD_DAMAGE};
+
+mod compact;
+mod run;
+#[cfg(feature = "debug-endpoints")]
+mod session;
+mod spawn;
+
+pub use compact::CompactState;
+pub use run::Simulation;
+pub use spawn::SpawnRules;
+#[cfg(feature = "debug-endpoints")]
+pub use session::{Adjudication, Event, GameSession, Outcome, Rules};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Future {
@@ -25,14 +39,61 @@ pub struct Future {
     pub alive: bool,
     /// Whether the protagonist snake has won
     pub finished: bool,
-    /// The number of snakes that have died
+    /// The number of enemy snakes that have died. Squadmates of the
+    /// protagonist don't count.
     pub dead_snakes: u16,
     /// The number of foods collected by the protagonist
     pub foods: u16,
     /// The number of foods collected by enemies
     pub enemy_foods: u16,
+    /// The foods collected by enemies, each weighted by how close the
+    /// enemy was to the protagonist's head when it ate
+    pub enemy_food_proximity: f32,
+    /// Whether the game has gone in circles, i.e. the same position
+    /// came up too many times. A stalemate is also finished.
+    pub stalemate: bool,
+    /// Whether the game ended without a winner: the last snakes died
+    /// on the same turn, or it was adjudicated a draw. A draw is also
+    /// finished.
+    pub draw: bool,
+    /// Where the protagonist finished, once that is decided: 1 plus
+    /// the enemies still alive when it died, or 1 if it won.
+    /// Squadmates don't count.
+    pub placement: Option<u16>,
     /// The starting direction of the future
     pub dir: Dir,
+    /// How the protagonist died, once it has
+    pub death: Option<DeathCause>,
+}
+
+/// Why a snake died, in a simulated turn or as far as we can tell
+/// from a real game's states
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DeathCause {
+    Starvation,
+    /// Ran out of health on a hazard, which took the last of it
+    Hazard,
+    /// Moved off the board
+    Wall,
+    SelfCollision,
+    /// Ran into another snake's body
+    Body,
+    /// Lost a head-to-head
+    HeadOn,
+    /// More than one cause fits a real game's states. Never simulated.
+    Unknown,
+}
+
+/// A snake that died in a simulated turn
+#[derive(Debug, Clone, PartialEq)]
+pub struct Death {
+    pub id: String,
+    /// The turn the snake died on
+    pub turn: u32,
+    pub cause: DeathCause,
+    /// The snake it ran into, for `Body` and `HeadOn`
+    pub killer: Option<String>,
 }
 
 pub fn process_step(
@@ -40,33 +101,89 @@ pub fn process_step(
     self_id: &str,
     moves: &HashMap<String, Dir>,
 ) -> Future {
+    step_with_deaths(st, self_id, moves).0
+}
+
+/// `process_step` that also says which snakes died and why. The
+/// protagonist is kept on the board when it dies, so it is only in
+/// the deaths.
+pub fn step_with_deaths(
+    st: &mut State,
+    self_id: &str,
+    moves: &HashMap<String, Dir>,
+) -> (Future, Vec<Death>) {
+    step_hashed(st, self_id, moves, None)
+}
+
+/// `step_with_deaths` that also keeps `hash`, the Zobrist hash of the
+/// state, up to date without rehashing the whole position
+pub fn apply_moves(
+    st: &mut State,
+    self_id: &str,
+    moves: &HashMap<String, Dir>,
+    hash: &mut u64,
+) -> (Future, Vec<Death>) {
+    step_hashed(st, self_id, moves, Some(hash))
+}
+
+/// `step_with_deaths`, updating `hash` along with each change it makes
+/// to the position if there is one
+fn step_hashed(
+    st: &mut State,
+    self_id: &str,
+    moves: &HashMap<String, Dir>,
+    mut hash: Option<&mut u64>,
+) -> (Future, Vec<Death>) {
     let mut tmp_future = Future {
         alive: true,
         finished: false,
         dead_snakes: 0,
         foods: 0,
         enemy_foods: 0,
+        enemy_food_proximity: 0.0,
+        stalemate: false,
+        draw: false,
+        placement: None,
         dir: Dir::Up,
+        death: None,
     };
 
     st.turn += 1;
+    // Fields built for the old position are no use for the new one
+    st.distances = DistanceCache::default();
 
     let mut results = HashMap::<String, Point>::with_capacity(moves.len());
     let mut eaten_foods = HashSet::new();
+    let mut enemy_eaters = Vec::new();
 
+    let edges = st.board.edges();
     for (id, dir) in moves {
         if *id == self_id {
             tmp_future.dir = *dir;
         }
 
         let snake = st.board.snakes.get_mut(id).unwrap();
-        let (head, food_eaten) = snake.update_from_move(*dir, &st.board.food);
+        let old_len = snake.body.len();
+        if let Some(h) = hash.as_deref_mut() {
+            *h ^= move_removed(id, &snake.body);
+        }
+        let (head, food_eaten) =
+            snake.update_from_move(*dir, &st.board.food, edges);
+        if let Some(h) = hash.as_deref_mut() {
+            *h ^= move_added(id, old_len, &snake.body);
+        }
+
+        // Eating on a hazard restores full health like anywhere else
+        if food_eaten.is_none() && st.board.hazards.contains(&head) {
+            snake.health = snake.health.saturating_sub(HAZARD_DAMAGE);
+        }
 
         if let Some(p) = food_eaten {
             if *id == self_id {
                 tmp_future.foods += 1;
             } else {
                 tmp_future.enemy_foods += 1;
+                enemy_eaters.push(head);
             }
 
             // self.st.board.food.remove(&p);
@@ -82,33 +199,534 @@ pub fn process_step(
         }
     }
 
+    if let Some(own_head) = results.get(self_id) {
+        for head in &enemy_eaters {
+            let dist = own_head.manhattan(*head) as f32;
+            tmp_future.enemy_food_proximity += 1.0 / (1.0 + dist);
+        }
+    }
+
     for food in &eaten_foods {
         st.board.food.remove(&food);
+        if let Some(h) = hash.as_deref_mut() {
+            *h ^= food_key(*food);
+        }
     }
 
-    let mut to_remove = Vec::new();
+    // As in the official rules, snakes out of health or off the board
+    // go first, and the rest only collide with the snakes still in
+    let (out, live): (Vec<&Snake>, Vec<&Snake>) =
+        st.board.snakes.values().partition(|s| {
+            s.starved() || !s.body[0].in_bounds(st)
+        });
 
-    for (id, head) in results {
-        let snake = st.board.snakes.get(&id).unwrap();
+    let mut heads = HashMap::<Point, Vec<&Snake>>::with_capacity(live.len());
+    for s in &live {
+        heads.entry(s.body[0]).or_default().push(s);
+    }
 
-        if !head.is_valid(snake, &st) || snake.health == 0 {
-            if id == self_id {
-                tmp_future.alive = false;
-                tmp_future.finished = true;
-            } else {
+    // Every snake on a square loses a head-on to any other as long as
+    // it, so equal lengths kill each other
+    let collided = |s: &Snake| {
+        let head = s.body[0];
+        let body = live
+            .iter()
+            .find(|o| !s.is_squadmate(o) && o.body[1..].contains(&head));
+        let head_on = heads[&head]
+            .iter()
+            .find(|o| o.id != s.id && o.body.len() >= s.body.len());
+
+        match (body, head_on) {
+            (Some(o), _) if o.id == s.id => {
+                Some((DeathCause::SelfCollision, None))
+            }
+            (Some(o), _) => Some((DeathCause::Body, Some(o.id.clone()))),
+            (None, Some(o)) => Some((DeathCause::HeadOn, Some(o.id.clone()))),
+            (None, None) => None,
+        }
+    };
+
+    let out = out.iter().map(|s| {
+        let cause = if !s.starved() {
+            DeathCause::Wall
+        } else if st.board.hazards.contains(&s.body[0]) {
+            DeathCause::Hazard
+        } else {
+            DeathCause::Starvation
+        };
+        (s, (cause, None))
+    });
+    let dead = out
+        .chain(live.iter().filter_map(|s| collided(s).map(|c| (s, c))))
+        .map(|(s, (cause, killer))| Death {
+            id: s.id.clone(),
+            turn: st.turn,
+            cause,
+            killer,
+        })
+        .collect::<Vec<Death>>();
+
+    let mut to_remove = Vec::new();
+    for Death { id, cause, .. } in &dead {
+        let snake = &st.board.snakes[id];
+        if id == self_id {
+            tmp_future.alive = false;
+            tmp_future.finished = true;
+            tmp_future.death = Some(*cause);
+        } else {
+            let squadmate = st
+                .board
+                .snakes
+                .get(self_id)
+                .is_some_and(|s| s.is_squadmate(snake));
+            if !squadmate {
                 tmp_future.dead_snakes += 1;
-                to_remove.push(id);
             }
+            to_remove.push(id.clone());
         }
     }
 
     for id in &to_remove {
-        st.board.snakes.remove(id);
+        let removed = st.board.snakes.remove(id);
+        if let (Some(h), Some(s)) = (hash.as_deref_mut(), removed) {
+            *h ^= snake_hash(id, &s.body);
+        }
     }
 
     if !to_remove.is_empty() && st.board.snakes.len() == 1 {
         tmp_future.finished = true;
     }
 
-    tmp_future
+    // A dead protagonist is kept on the board, so it isn't a survivor.
+    // If nobody survived a turn that killed an enemy, nobody won.
+    let survivors = st.board.snakes.len() - usize::from(!tmp_future.alive);
+    if !to_remove.is_empty() && survivors == 0 {
+        tmp_future.finished = true;
+        tmp_future.draw = true;
+    }
+
+    if !tmp_future.alive {
+        tmp_future.placement = Some(1 + rivals(st, self_id) as u16);
+    } else if tmp_future.finished {
+        tmp_future.placement = Some(1);
+    }
+
+    (tmp_future, dead)
+}
+
+/// The enemies of a snake on the board, not counting its squadmates
+pub fn rivals(st: &State, self_id: &str) -> usize {
+    let us = st.board.snakes.get(self_id);
+    st.board
+        .snakes
+        .values()
+        .filter(|e| e.id != self_id)
+        .filter(|e| !us.is_some_and(|s| s.is_squadmate(e)))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::hash::zobrist_hash;
+    use crate::game::{build_state, GameMode, SafetyIndex, TurnContext};
+
+    #[test]
+    fn test_enemy_food_proximity() {
+        let (_, mut near) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(7, 4), (7, 5), (7, 6)]),
+            ],
+            &[(7, 3)],
+        );
+        let mut far = near.clone();
+        far.board
+            .snakes
+            .get_mut("other")
+            .unwrap()
+            .body
+            .iter_mut()
+            .for_each(|p| p.x = 10);
+        far.board.food = [Point { x: 10, y: 3 }].iter().cloned().collect();
+
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("other"), Dir::Up);
+
+        let near = process_step(&mut near, "you", &moves);
+        let far = process_step(&mut far, "you", &moves);
+
+        assert_eq!(near.enemy_foods, 1);
+        assert_eq!(far.enemy_foods, 1);
+        assert!(near.enemy_food_proximity > far.enemy_food_proximity);
+    }
+
+    #[test]
+    fn test_head_on() {
+        // Up to three snakes of the given lengths, all heading for
+        // (5, 5) from the left, the right and above
+        let meet = |you: i8, enemy: i8, third: i8| {
+            let you = (0..you).map(|i| (4 - i, 5)).collect::<Vec<_>>();
+            let enemy = (0..enemy).map(|i| (6 + i, 5)).collect::<Vec<_>>();
+            let third = (0..third).map(|i| (5, 4 - i)).collect::<Vec<_>>();
+            let mut snakes: Vec<(&str, &[(i8, i8)])> =
+                vec![("you", &you), ("enemy", &enemy)];
+            if !third.is_empty() {
+                snakes.push(("third", &third));
+            }
+            let (_, mut st) = build_state(11, 11, &snakes, &[]);
+
+            let mut moves = HashMap::new();
+            moves.insert(String::from("you"), Dir::Right);
+            moves.insert(String::from("enemy"), Dir::Left);
+            moves.insert(String::from("third"), Dir::Down);
+            moves.retain(|id, _| st.board.snakes.contains_key(id));
+
+            let future = process_step(&mut st, "you", &moves);
+            (future, st)
+        };
+
+        // Equal lengths kill each other
+        let (future, st) = meet(3, 3, 0);
+        assert!(!future.alive && future.draw);
+        assert_eq!(future.death, Some(DeathCause::HeadOn));
+        assert_eq!(future.dead_snakes, 1);
+        assert!(!st.board.snakes.contains_key("enemy"));
+
+        // Otherwise only the shorter snake dies
+        let (future, st) = meet(4, 3, 0);
+        assert!(future.alive && future.finished);
+        assert_eq!(st.board.snakes.len(), 1);
+        assert!(!meet(3, 4, 0).0.alive);
+
+        // Three at once: a tie for the longest kills everyone, and the
+        // longest alone survives
+        let (future, st) = meet(4, 4, 3);
+        assert!(!future.alive && future.draw);
+        assert_eq!((future.dead_snakes, st.board.snakes.len()), (2, 1));
+        let (future, st) = meet(5, 4, 4);
+        assert!(future.alive && future.finished);
+        assert_eq!((future.dead_snakes, st.board.snakes.len()), (2, 1));
+
+        // A snake that starves on the square kills nobody
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(4, 5), (3, 5), (2, 5)]),
+                ("enemy", &[(6, 5), (7, 5), (8, 5), (9, 5)]),
+            ],
+            &[],
+        );
+        st.board.snakes.get_mut("enemy").unwrap().health = 1;
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Right);
+        moves.insert(String::from("enemy"), Dir::Left);
+        let future = process_step(&mut st, "you", &moves);
+        assert!(future.alive && future.finished);
+        assert_eq!(future.dead_snakes, 1);
+    }
+
+    #[test]
+    fn test_death_causes() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (6, 5), (6, 6), (5, 6), (4, 6)]),
+                ("rammer", &[(2, 2), (1, 2), (0, 2)]),
+                ("wall", &[(3, 1), (3, 2), (3, 3)]),
+                ("edge", &[(0, 9), (1, 9), (2, 9)]),
+                ("starving", &[(9, 2), (9, 3), (9, 4)]),
+                ("burnt", &[(9, 7), (9, 8), (9, 9)]),
+            ],
+            &[],
+        );
+        st.board.snakes.get_mut("starving").unwrap().health = 1;
+        st.board.snakes.get_mut("burnt").unwrap().health = 2;
+        st.board.hazards.insert(Point { x: 9, y: 6 });
+
+        let mut moves = HashMap::new();
+        for (id, dir) in &[
+            ("you", Dir::Down),
+            ("rammer", Dir::Right),
+            ("wall", Dir::Up),
+            ("edge", Dir::Left),
+            ("starving", Dir::Up),
+            ("burnt", Dir::Up),
+        ] {
+            moves.insert(id.to_string(), *dir);
+        }
+
+        let (future, deaths) = step_with_deaths(&mut st, "you", &moves);
+        assert_eq!(future.death, Some(DeathCause::SelfCollision));
+        let cause = |id: &str| {
+            let d = deaths.iter().find(|d| d.id == id)?;
+            Some((d.cause, d.killer.clone(), d.turn))
+        };
+        let by = |id: &str| Some(String::from(id));
+        assert_eq!(cause("rammer"), Some((DeathCause::Body, by("wall"), 1)));
+        assert_eq!(cause("edge"), Some((DeathCause::Wall, None, 1)));
+        assert_eq!(cause("starving").unwrap().0, DeathCause::Starvation);
+        assert_eq!(cause("burnt").unwrap().0, DeathCause::Hazard);
+        assert_eq!(cause("wall"), None);
+        assert_eq!(deaths.len(), 5);
+    }
+
+    #[test]
+    fn test_apply_moves() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(0, 4), (0, 5), (0, 6)]),
+            ],
+            &[(5, 4), (9, 9)],
+        );
+        let mut hash = zobrist_hash(&st);
+
+        // We eat, then the other snake runs into the wall
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("other"), Dir::Up);
+        apply_moves(&mut st, "you", &moves, &mut hash);
+        assert_eq!(hash, zobrist_hash(&st));
+
+        moves.insert(String::from("other"), Dir::Left);
+        apply_moves(&mut st, "you", &moves, &mut hash);
+        assert_eq!(st.board.snakes.len(), 1);
+        assert_eq!(hash, zobrist_hash(&st));
+    }
+
+    #[test]
+    fn test_spawn() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 5), (5, 5)]),
+                ("other", &[(1, 1), (1, 1), (1, 1)]),
+            ],
+            &[(5, 4)],
+        );
+        let you = |st: &State| st.board.snakes["you"].clone();
+        let spawn = Point { x: 5, y: 5 };
+
+        // Turn 0: no direction yet, and every move is open
+        assert_eq!(you(&st).last_move(), None);
+        assert_eq!(you(&st).stacked_tail(), 3);
+        for p in &spawn.orthogonal(&st) {
+            assert_eq!(p.safety_index(&you(&st), &st), SafetyIndex::Safe);
+        }
+        assert_eq!(spawn.safety_index(&you(&st), &st), SafetyIndex::Unsafe);
+
+        // Turn 1: eating while the stack unwinds grows it back
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("other"), Dir::Down);
+        assert!(process_step(&mut st, "you", &moves).alive);
+        let head = Point { x: 5, y: 4 };
+        assert_eq!(you(&st).body, [head, spawn, spawn, spawn]);
+        assert_eq!(you(&st).last_move(), Some(Dir::Up));
+        assert_eq!(spawn.safety_index(&you(&st), &st), SafetyIndex::Unsafe);
+
+        // Turn 2: the spawn cell is still stacked
+        moves.insert(String::from("you"), Dir::Left);
+        process_step(&mut st, "you", &moves);
+        assert_eq!(you(&st).stacked_tail(), 2);
+        assert_eq!(spawn.safety_index(&you(&st), &st), SafetyIndex::Unsafe);
+
+        // Turn 3: the tail finally leaves it on the next move
+        process_step(&mut st, "you", &moves);
+        assert_eq!(you(&st).body.len(), 4);
+        assert_eq!(you(&st).stacked_tail(), 1);
+        assert_ne!(spawn.safety_index(&you(&st), &st), SafetyIndex::Unsafe);
+        assert_eq!(st.board.snakes["other"].last_move(), Some(Dir::Down));
+    }
+
+    #[test]
+    fn test_hazards() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[("you", &[(5, 5), (5, 6), (5, 7)])],
+            &[(4, 4)],
+        );
+        st.board.hazards = [(5, 4), (5, 3), (4, 4), (6, 5)]
+            .iter()
+            .map(|&(x, y)| Point { x, y })
+            .collect();
+        let you = |st: &State| st.board.snakes["you"].clone();
+        let up = Point { x: 5, y: 4 };
+
+        // Hazards are risky while we can afford them, except with food
+        // on them, and off limits when we can't
+        assert_eq!(up.safety_index(&you(&st), &st), SafetyIndex::Risky);
+        let food = Point { x: 4, y: 4 };
+        assert_eq!(food.safety_index(&you(&st), &st), SafetyIndex::Safe);
+        st.board.snakes.get_mut("you").unwrap().health = HAZARD_DAMAGE;
+        assert_eq!(up.safety_index(&you(&st), &st), SafetyIndex::Unsafe);
+        let head = Point { x: 5, y: 5 };
+        let fill = head.flood_fill(&you(&st), &st, 200);
+        assert!(!fill.contains(&up) && fill.contains(&food));
+
+        // Every turn on a hazard costs the damage on top of the usual
+        // point, and it can kill
+        st.board.snakes.get_mut("you").unwrap().health = 100;
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        process_step(&mut st, "you", &moves);
+        assert_eq!(you(&st).health, 99 - HAZARD_DAMAGE);
+
+        st.board.snakes.get_mut("you").unwrap().health = HAZARD_DAMAGE;
+        assert!(!process_step(&mut st, "you", &moves).alive);
+    }
+
+    #[test]
+    fn test_wrapped() {
+        let (_, mut st) = build_state(
+            7,
+            7,
+            &[("you", &[(0, 3), (1, 3), (2, 3)])],
+            &[(6, 3)],
+        );
+        let you = |st: &State| st.board.snakes["you"].clone();
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Left);
+
+        // The left edge is a wall on a standard board
+        let off = Dir::Left.resulting_point(you(&st).body[0], &st);
+        assert_eq!(off.safety_index(&you(&st), &st), SafetyIndex::Unsafe);
+        assert!(!process_step(&mut st.clone(), "you", &moves).alive);
+
+        // and a way through to the right edge on a wrapped one
+        st.board.mode = GameMode::Wrapped;
+        let future = process_step(&mut st, "you", &moves);
+        assert!(future.alive && future.foods == 1);
+        assert_eq!(you(&st).body[0], Point { x: 6, y: 3 });
+        assert_eq!(you(&st).last_move(), Some(Dir::Left));
+    }
+
+    #[test]
+    fn test_squads() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("mate", &[(4, 3), (5, 3), (5, 4), (6, 4)]),
+                ("other", &[(0, 4), (0, 5), (0, 6)]),
+            ],
+            &[],
+        );
+        for id in &["you", "mate"] {
+            let squad = Some(String::from("a"));
+            st.board.snakes.get_mut(*id).unwrap().squad = squad;
+        }
+        let you = |st: &State| st.board.snakes["you"].clone();
+        let neck = Point { x: 5, y: 4 };
+
+        // Our squadmate's body is no obstacle
+        assert!(you(&st).is_squadmate(&st.board.snakes["mate"]));
+        assert_eq!(neck.safety_index(&you(&st), &st), SafetyIndex::Safe);
+
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("mate"), Dir::Left);
+        moves.insert(String::from("other"), Dir::Up);
+        let future = process_step(&mut st, "you", &moves);
+        assert!(future.alive);
+        assert_eq!(you(&st).body[0], neck);
+
+        // Its death is nothing to celebrate, unlike an enemy's
+        st.board.snakes.get_mut("mate").unwrap().health = 1;
+        moves.insert(String::from("you"), Dir::Right);
+        let future = process_step(&mut st, "you", &moves);
+        assert!(future.alive && !st.board.snakes.contains_key("mate"));
+        assert_eq!(future.dead_snakes, 0);
+
+        st.board.snakes.get_mut("other").unwrap().health = 1;
+        moves.remove("mate");
+        assert_eq!(process_step(&mut st, "you", &moves).dead_snakes, 1);
+    }
+
+    #[test]
+    fn test_placement() {
+        let (_, mut st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(0, 5), (1, 5), (2, 5)]),
+                ("a", &[(5, 2), (5, 3), (5, 4)]),
+                ("b", &[(8, 8), (8, 9), (8, 10)]),
+            ],
+            &[],
+        );
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("a"), Dir::Up);
+        moves.insert(String::from("b"), Dir::Up);
+
+        // Nothing is decided while everyone lives
+        let future = process_step(&mut st.clone(), "you", &moves);
+        assert_eq!(future.placement, None);
+
+        // Dying with both enemies alive places us last
+        moves.insert(String::from("you"), Dir::Left);
+        let future = process_step(&mut st.clone(), "you", &moves);
+        assert_eq!(future.placement, Some(3));
+
+        // and one place higher for each enemy that died first
+        st.board.snakes.remove("b");
+        moves.remove("b");
+        let future = process_step(&mut st.clone(), "you", &moves);
+        assert_eq!(future.placement, Some(2));
+
+        st.board.snakes.get_mut("a").unwrap().health = 1;
+        moves.insert(String::from("you"), Dir::Up);
+        let future = process_step(&mut st, "you", &moves);
+        assert!(future.finished && future.alive);
+        assert_eq!(future.placement, Some(1));
+    }
+
+    #[test]
+    fn test_fill_board() {
+        // A solo snake eating every turn, as in constrictor, snakes
+        // through the whole board
+        let (_, mut st) =
+            build_state(4, 4, &[("you", &[(0, 0), (0, 0), (0, 0)])], &[]);
+        let path = [Dir::Right, Dir::Right, Dir::Right, Dir::Down]
+            .iter()
+            .chain(&[Dir::Left, Dir::Left, Dir::Left, Dir::Down])
+            .chain(&[Dir::Right, Dir::Right, Dir::Right, Dir::Down])
+            .chain(&[Dir::Left, Dir::Left, Dir::Left])
+            .cloned()
+            .collect::<Vec<Dir>>();
+
+        let mut moves = HashMap::new();
+        for dir in &path {
+            let you = &st.board.snakes["you"];
+            st.board.food.insert(dir.resulting_point(you.body[0], &st));
+
+            moves.insert(String::from("you"), *dir);
+            assert!(process_step(&mut st, "you", &moves).alive);
+            let len = st.board.snakes["you"].body.len();
+            assert!(len <= st.board.max_body_len());
+        }
+
+        let you = st.board.snakes["you"].clone();
+        assert_eq!(you.body.len(), st.board.max_body_len());
+        let ctx = TurnContext::new(&you, &st);
+        assert!(ctx.outcomes.iter().all(|o| !o.legal));
+
+        // Starving to death on a full board doesn't wrap the health
+        st.board.snakes.get_mut("you").unwrap().health = 0;
+        moves.insert(String::from("you"), Dir::Up);
+        assert!(!process_step(&mut st, "you", &moves).alive);
+        assert_eq!(st.board.snakes["you"].health, 0);
+    }
 }
@@ -0,0 +1,542 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! A compact state for tree searches, which clone a state for every
+//! node. A `State` clone allocates a map, an id string and a body for
+//! every snake, plus the food and hazard sets. A `CompactState` keeps
+//! the snakes in a fixed array indexed by their position in a shared
+//! id list, the food in a bitset, and every body in one vector, so a
+//! clone is one allocation. What never changes during a search, like
+//! the ids and hazards, is shared between clones and never copied.
+//!
+//! `step` plays a turn by the same rules as `process_step`, and keeps
+//! the state's Zobrist hash up to date as it goes.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::{DeathCause, Future};
+use crate::game::hash::{food_key, snake_hash, update_snake, zobrist_hash};
+use crate::game::{
+    Board, Dir, Edges, Game, GameMode, Point, Snake, SnakeMeta, State,
+    HAZARD_DAMAGE, MAX_HEALTH,
+};
+
+/// The widest and tallest board a compact state holds
+pub const MAX_COMPACT_SIDE: i8 = 25;
+/// The most snakes a compact state holds
+pub const MAX_COMPACT_SNAKES: usize = 8;
+
+const CELL_WORDS: usize = 10;
+
+/// A set of squares, one bit per square of a board up to
+/// `MAX_COMPACT_SIDE` on a side
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct Cells([u64; CELL_WORDS]);
+
+impl Cells {
+    fn index(p: Point, edges: Edges) -> Option<usize> {
+        if p.x < 0 || p.y < 0 || p.x >= edges.width || p.y >= edges.height {
+            return None;
+        }
+
+        Some(p.y as usize * edges.width as usize + p.x as usize)
+    }
+
+    fn from_points<'a>(
+        points: impl Iterator<Item = &'a Point>,
+        edges: Edges,
+    ) -> Self {
+        let mut cells = Self::default();
+        for p in points {
+            cells.insert(*p, edges);
+        }
+        cells
+    }
+
+    fn contains(&self, p: Point, edges: Edges) -> bool {
+        Self::index(p, edges)
+            .is_some_and(|i| self.0[i / 64] >> (i % 64) & 1 == 1)
+    }
+
+    fn insert(&mut self, p: Point, edges: Edges) {
+        if let Some(i) = Self::index(p, edges) {
+            self.0[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    fn remove(&mut self, p: Point, edges: Edges) {
+        if let Some(i) = Self::index(p, edges) {
+            self.0[i / 64] &= !(1 << (i % 64));
+        }
+    }
+
+    fn points(&self, edges: Edges) -> impl Iterator<Item = Point> + '_ {
+        (0..edges.height).flat_map(move |y| {
+            (0..edges.width)
+                .map(move |x| Point { x, y })
+                .filter(move |p| self.contains(*p, edges))
+        })
+    }
+}
+
+/// Everything a search never changes, shared by every clone
+#[derive(Debug)]
+struct Shared {
+    game: Game,
+    meta: Arc<HashMap<String, SnakeMeta>>,
+    hazards: HashSet<Point>,
+    hazard_cells: Cells,
+    /// The snakes' ids, by index
+    ids: Vec<String>,
+    /// The squads' names, by index
+    squads: Vec<String>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+struct CompactSnake {
+    /// Whether the snake is still on the board
+    present: bool,
+    health: u8,
+    /// Index into `Shared::squads`
+    squad: Option<u8>,
+    /// Where the body starts in `CompactState::bodies`, and its length
+    start: usize,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactState {
+    shared: Arc<Shared>,
+    turn: u32,
+    edges: Edges,
+    food: Cells,
+    snakes: [CompactSnake; MAX_COMPACT_SNAKES],
+    count: usize,
+    bodies: Vec<Point>,
+    /// The Zobrist hash of the position
+    hash: u64,
+}
+
+impl CompactState {
+    /// The compact form of a state, or an error if it is bigger than a
+    /// compact state can hold
+    pub fn from_state(st: &State) -> Result<Self, String> {
+        let board = &st.board;
+        let side = 1..=MAX_COMPACT_SIDE;
+        if !side.contains(&board.width) || !side.contains(&board.height) {
+            return Err(format!(
+                "A {}x{} board is too big for a compact state",
+                board.width, board.height
+            ));
+        }
+
+        if board.snakes.len() > MAX_COMPACT_SNAKES {
+            return Err(format!(
+                "{} snakes are too many for a compact state",
+                board.snakes.len()
+            ));
+        }
+
+        let edges = board.edges();
+        let mut ids = board.snakes.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        let mut squads = board
+            .snakes
+            .values()
+            .filter_map(|s| s.squad.clone())
+            .collect::<Vec<_>>();
+        squads.sort();
+        squads.dedup();
+
+        let mut snakes = [CompactSnake::default(); MAX_COMPACT_SNAKES];
+        let mut bodies = Vec::new();
+        for (i, id) in ids.iter().enumerate() {
+            let s = &board.snakes[id];
+            snakes[i] = CompactSnake {
+                present: true,
+                health: s.health,
+                squad: s
+                    .squad
+                    .as_ref()
+                    .and_then(|q| squads.iter().position(|n| n == q))
+                    .map(|i| i as u8),
+                start: bodies.len(),
+                len: s.body.len(),
+            };
+            bodies.extend_from_slice(&s.body);
+        }
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                game: st.game.clone(),
+                meta: st.meta.clone(),
+                hazards: board.hazards.clone(),
+                hazard_cells: Cells::from_points(board.hazards.iter(), edges),
+                ids,
+                squads,
+            }),
+            turn: st.turn,
+            edges,
+            food: Cells::from_points(board.food.iter(), edges),
+            snakes,
+            count: board.snakes.len(),
+            bodies,
+            hash: zobrist_hash(st),
+        })
+    }
+
+    /// The full state, to hand to code that works on one
+    pub fn to_state(&self) -> State {
+        let snakes = (0..self.count)
+            .filter(|i| self.snakes[*i].present)
+            .map(|i| {
+                let s = &self.snakes[i];
+                let id = self.shared.ids[i].clone();
+                let snake = Snake {
+                    id: id.clone(),
+                    health: s.health,
+                    body: self.body(i).to_vec(),
+                    squad: s
+                        .squad
+                        .map(|q| self.shared.squads[q as usize].clone()),
+                };
+                (id, snake)
+            })
+            .collect();
+
+        State {
+            game: self.shared.game.clone(),
+            turn: self.turn,
+            board: Board {
+                height: self.edges.height,
+                width: self.edges.width,
+                food: self.food.points(self.edges).collect(),
+                snakes,
+                hazards: self.shared.hazards.clone(),
+                mode: self.edges.mode,
+            },
+            meta: self.shared.meta.clone(),
+            distances: Default::default(),
+        }
+    }
+
+    /// The index of the snake with the given id, whether or not it is
+    /// still on the board
+    pub fn index_of(&self, id: &str) -> Option<usize> {
+        self.shared.ids.iter().position(|i| i == id)
+    }
+
+    /// Whether the snake at index `i` is still on the board
+    pub fn is_present(&self, i: usize) -> bool {
+        i < self.count && self.snakes[i].present
+    }
+
+    /// The Zobrist hash of the position, the same as `zobrist_hash` of
+    /// the full state
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    fn body(&self, i: usize) -> &[Point] {
+        let s = &self.snakes[i];
+        &self.bodies[s.start..s.start + s.len]
+    }
+
+    fn present(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.count).filter(move |i| self.snakes[*i].present)
+    }
+
+    fn is_squadmate(&self, a: usize, b: usize) -> bool {
+        a != b
+            && self.snakes[a].squad.is_some()
+            && self.snakes[a].squad == self.snakes[b].squad
+    }
+
+    /// See `simulator::rivals`
+    fn rivals(&self, self_idx: usize) -> usize {
+        let us = self.snakes[self_idx].present;
+        self.present()
+            .filter(|i| *i != self_idx)
+            .filter(|i| !(us && self.is_squadmate(self_idx, *i)))
+            .count()
+    }
+
+    /// Why snake `i` is out of the game before any collisions, if it is
+    /// out of health or off the board
+    fn out_cause(&self, i: usize) -> Option<DeathCause> {
+        let head = self.body(i)[0];
+        let off = self.edges.mode != GameMode::Wrapped
+            && Cells::index(head, self.edges).is_none();
+
+        if off {
+            Some(DeathCause::Wall)
+        } else if self.snakes[i].health > 0 {
+            None
+        } else if self.shared.hazard_cells.contains(head, self.edges) {
+            Some(DeathCause::Hazard)
+        } else {
+            Some(DeathCause::Starvation)
+        }
+    }
+
+    /// How snake `i` collides with the snakes in `live`, if it does, see
+    /// `process_step`
+    fn collision(&self, i: usize, live: &[usize]) -> Option<DeathCause> {
+        let head = self.body(i)[0];
+        let len = self.snakes[i].len;
+
+        let body = live.iter().find(|j| {
+            !self.is_squadmate(i, **j) && self.body(**j)[1..].contains(&head)
+        });
+        let head_on = live.iter().any(|j| {
+            let body = self.body(*j);
+            *j != i && body[0] == head && body.len() >= len
+        });
+
+        match body {
+            Some(j) if *j == i => Some(DeathCause::SelfCollision),
+            Some(_) => Some(DeathCause::Body),
+            None if head_on => Some(DeathCause::HeadOn),
+            None => None,
+        }
+    }
+
+    /// `process_step` for a compact state, where `moves` are the moves
+    /// of the snakes by index
+    pub fn step(&mut self, self_idx: usize, moves: &[(usize, Dir)]) -> Future {
+        let mut future = Future {
+            alive: true,
+            finished: false,
+            dead_snakes: 0,
+            foods: 0,
+            enemy_foods: 0,
+            enemy_food_proximity: 0.0,
+            stalemate: false,
+            draw: false,
+            placement: None,
+            dir: Dir::Up,
+            death: None,
+        };
+
+        self.turn += 1;
+
+        let edges = self.edges;
+        let mut hash = self.hash;
+        let mut eaten = Cells::default();
+        let mut enemy_eaters = Vec::new();
+        let mut bodies = Vec::with_capacity(self.bodies.len() + moves.len());
+
+        for i in 0..self.count {
+            if !self.snakes[i].present {
+                continue;
+            }
+
+            let body = self.body(i);
+            let start = bodies.len();
+            let dir = moves.iter().find(|(idx, _)| *idx == i).map(|m| m.1);
+
+            match dir {
+                Some(dir) => {
+                    if i == self_idx {
+                        future.dir = dir;
+                    }
+
+                    let head = dir.step(body[0], edges);
+                    bodies.push(head);
+                    bodies.extend_from_slice(&body[..body.len() - 1]);
+
+                    let snake = &mut self.snakes[i];
+                    if self.food.contains(head, edges) {
+                        snake.health = MAX_HEALTH;
+                        let tail = bodies[bodies.len() - 1];
+                        bodies.push(tail);
+                        eaten.insert(head, edges);
+
+                        if i == self_idx {
+                            future.foods += 1;
+                        } else {
+                            future.enemy_foods += 1;
+                            enemy_eaters.push(head);
+                        }
+                    } else {
+                        snake.health = snake.health.saturating_sub(1);
+
+                        // Eating on a hazard restores full health like
+                        // anywhere else
+                        if self.shared.hazard_cells.contains(head, edges) {
+                            snake.health =
+                                snake.health.saturating_sub(HAZARD_DAMAGE);
+                        }
+                    }
+                }
+                None => bodies.extend_from_slice(body),
+            }
+
+            if dir.is_some() {
+                let id = &self.shared.ids[i];
+                hash = update_snake(hash, id, self.body(i), &bodies[start..]);
+            }
+
+            self.snakes[i].start = start;
+            self.snakes[i].len = bodies.len() - start;
+        }
+
+        self.bodies = bodies;
+
+        if self.snakes[self_idx].present {
+            let own_head = self.body(self_idx)[0];
+            for head in &enemy_eaters {
+                let dist = own_head.manhattan(*head) as f32;
+                future.enemy_food_proximity += 1.0 / (1.0 + dist);
+            }
+        }
+
+        for p in eaten.points(edges) {
+            self.food.remove(p, edges);
+            hash ^= food_key(p);
+        }
+
+        let us = self.snakes[self_idx].present;
+        let (out, live): (Vec<usize>, Vec<usize>) =
+            self.present().partition(|i| self.out_cause(*i).is_some());
+        let dead = live
+            .iter()
+            .filter_map(|i| self.collision(*i, &live).map(|c| (*i, c)))
+            .chain(out.iter().filter_map(|i| Some((*i, self.out_cause(*i)?))))
+            .collect::<Vec<_>>();
+
+        let removed = dead.iter().filter(|(i, _)| *i != self_idx).count();
+        for (i, cause) in dead {
+            if i == self_idx {
+                // A dead protagonist is kept on the board
+                future.alive = false;
+                future.finished = true;
+                future.death = Some(cause);
+            } else {
+                if !(us && self.is_squadmate(self_idx, i)) {
+                    future.dead_snakes += 1;
+                }
+                self.snakes[i].present = false;
+                hash ^= snake_hash(&self.shared.ids[i], self.body(i));
+            }
+        }
+
+        self.hash = hash;
+
+        let left = self.present().count();
+        if removed > 0 && left == 1 {
+            future.finished = true;
+        }
+
+        // If nobody survived a turn that killed an enemy, nobody won
+        let survivors = left - usize::from(!future.alive);
+        if removed > 0 && survivors == 0 {
+            future.finished = true;
+            future.draw = true;
+        }
+
+        if !future.alive {
+            future.placement = Some(1 + self.rivals(self_idx) as u16);
+        } else if future.finished {
+            future.placement = Some(1);
+        }
+
+        future
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::process_step;
+    use super::*;
+    use crate::game::build_state;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const DIRS: [Dir; 4] = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+    fn assert_same(compact: &CompactState, st: &State) {
+        let converted = compact.to_state();
+        assert_eq!(converted.turn, st.turn);
+        assert_eq!(converted.board.snakes, st.board.snakes);
+        assert_eq!(converted.board.food, st.board.food);
+        assert_eq!(compact.hash(), zobrist_hash(st));
+    }
+
+    #[test]
+    fn test_compact_step() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for game in 0..200 {
+            let (_, mut st) = build_state(
+                7,
+                7,
+                &[
+                    ("you", &[(1, 1), (1, 2), (1, 3)]),
+                    ("a", &[(5, 1), (5, 2), (5, 3), (5, 4)]),
+                    ("b", &[(3, 5), (3, 5), (3, 5)]),
+                    ("c", &[(5, 6), (4, 6)]),
+                ],
+                &[(3, 3), (1, 5), (6, 3), (0, 0)],
+            );
+            if game % 2 == 0 {
+                st.board.mode = GameMode::Wrapped;
+            }
+            if game % 3 == 0 {
+                st.board.hazards = [(2, 2), (3, 3), (4, 4)]
+                    .iter()
+                    .map(|(x, y)| Point { x: *x, y: *y })
+                    .collect();
+                for id in &["you", "c"] {
+                    let s = st.board.snakes.get_mut(*id).unwrap();
+                    s.squad = Some(String::from("squad"));
+                    s.health = 20;
+                }
+            }
+
+            let mut compact = CompactState::from_state(&st).unwrap();
+            let self_idx = compact.index_of("you").unwrap();
+            assert_same(&compact, &st);
+
+            for _ in 0..40 {
+                // Like a game tree, not every snake moves every step
+                let mut ids = st.board.snakes.keys().collect::<Vec<_>>();
+                ids.sort();
+                let moves = ids
+                    .into_iter()
+                    .filter_map(|id| {
+                        let dir = DIRS[rng.gen_range(0, 4)];
+                        Some((id.clone(), dir)).filter(|_| rng.gen_bool(0.8))
+                    })
+                    .collect::<HashMap<String, Dir>>();
+                let indexed = moves
+                    .iter()
+                    .map(|(id, d)| (compact.index_of(id).unwrap(), *d))
+                    .collect::<Vec<_>>();
+
+                let future = process_step(&mut st, "you", &moves);
+                assert_eq!(compact.step(self_idx, &indexed), future);
+                assert_same(&compact, &st);
+
+                if future.finished {
+                    break;
+                }
+            }
+        }
+    }
+}
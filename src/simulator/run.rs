@@ -0,0 +1,210 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Simulated games played out turn by turn, for the searches that
+//! play games forward from a position. A policy picks every snake's
+//! move each turn from the snake and the state, and the simulation
+//! keeps the future of every turn and who died and why. Food spawns
+//! after every turn like the engine's, for simulations given rules to
+//! spawn it by.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+
+use super::{apply_moves, step_with_deaths, Death, Future, SpawnRules};
+use crate::game::hash::{food_key, zobrist_hash};
+use crate::game::{Dir, Snake, State};
+
+/// A game being played out, from the point of view of one snake
+pub struct Simulation {
+    pub state: State,
+    /// The future of every turn played, oldest first
+    pub futures: Vec<Future>,
+    /// The snakes that died, oldest first
+    pub deaths: Vec<Death>,
+    self_id: String,
+    /// The Zobrist hash of `state`, for simulations that keep it
+    hash: Option<u64>,
+    food: SpawnRules,
+    rng: StdRng,
+}
+
+impl Simulation {
+    /// A simulation of `state` from the point of view of the snake
+    /// `self_id`, that doesn't spawn food
+    pub fn new(state: State, self_id: &str) -> Self {
+        Self {
+            state,
+            futures: Vec::new(),
+            deaths: Vec::new(),
+            self_id: self_id.to_string(),
+            hash: None,
+            food: SpawnRules {
+                min_food: 0,
+                chance: 0,
+            },
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    /// Spawns food by `food` after every turn, drawing from `rng`
+    pub fn with_food(self, food: SpawnRules, rng: StdRng) -> Self {
+        Self { food, rng, ..self }
+    }
+
+    /// Keeps the Zobrist hash of the state up to date as it goes
+    pub fn hashed(self) -> Self {
+        let hash = zobrist_hash(&self.state);
+        Self {
+            hash: Some(hash),
+            ..self
+        }
+    }
+
+    /// The Zobrist hash of the state, if the simulation keeps it
+    pub fn hash(&self) -> Option<u64> {
+        self.hash
+    }
+
+    /// Plays a turn with the move `policy` picks for every snake,
+    /// asked in id order so seeded policies play the same way
+    pub fn step(
+        &mut self,
+        mut policy: impl FnMut(&Snake, &State) -> Dir,
+    ) -> Future {
+        let mut snakes = self.state.board.snakes.values().collect::<Vec<_>>();
+        snakes.sort_by(|a, b| a.id.cmp(&b.id));
+        let moves = snakes
+            .iter()
+            .map(|s| (s.id.clone(), policy(s, &self.state)))
+            .collect::<HashMap<String, Dir>>();
+
+        let st = &mut self.state;
+        let (future, deaths) = match &mut self.hash {
+            Some(hash) => apply_moves(st, &self.self_id, &moves, hash),
+            None => step_with_deaths(st, &self.self_id, &moves),
+        };
+
+        for p in self.food.spawn(st, &mut self.rng) {
+            if let Some(hash) = &mut self.hash {
+                *hash ^= food_key(p);
+            }
+        }
+
+        self.futures.push(future);
+        self.deaths.extend(deaths);
+        future
+    }
+
+    /// Plays up to `turns` turns, stopping early once the game is
+    /// finished or `stop` is true of a turn's future. Returns the last
+    /// turn's future, if any were played.
+    pub fn run(
+        &mut self,
+        mut policy: impl FnMut(&Snake, &State) -> Dir,
+        turns: u32,
+        mut stop: impl FnMut(&Future) -> bool,
+    ) -> Option<Future> {
+        let mut last = None;
+        for _ in 0..turns {
+            let future = self.step(&mut policy);
+            last = Some(future);
+
+            if future.finished || stop(&future) {
+                break;
+            }
+        }
+
+        last
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+    use crate::simulator::DeathCause;
+
+    #[test]
+    fn test_run() {
+        let (_, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(3, 3), (3, 4), (3, 5)]),
+                ("enemy", &[(5, 1), (5, 2), (5, 3)]),
+            ],
+            &[],
+        );
+        let food = SpawnRules {
+            min_food: 1,
+            chance: 0,
+        };
+        let mut sim = Simulation::new(st, "you")
+            .with_food(food, StdRng::seed_from_u64(1))
+            .hashed();
+
+        // The enemy runs into the wall on the second turn, which ends
+        // the game before the turn limit
+        let policy = |s: &Snake, _: &State| {
+            if s.id == "you" {
+                Dir::Left
+            } else {
+                Dir::Up
+            }
+        };
+        let last = sim.run(policy, 10, |_| false).unwrap();
+        assert!(last.finished && last.alive);
+        assert_eq!(sim.futures.len(), 2);
+        assert_eq!(sim.deaths.len(), 1);
+        assert_eq!(
+            (sim.deaths[0].id.as_str(), sim.deaths[0].turn),
+            ("enemy", 2)
+        );
+        assert_eq!(sim.deaths[0].cause, DeathCause::Wall);
+        assert_eq!(sim.state.board.food.len(), 1);
+        assert_eq!(sim.hash(), Some(zobrist_hash(&sim.state)));
+
+        // A head-on we win kills the enemy, and the stop predicate ends
+        // a game early
+        let (_, st) = build_state(
+            7,
+            7,
+            &[
+                ("you", &[(1, 3), (0, 3), (0, 4), (0, 5)]),
+                ("enemy", &[(3, 3), (4, 3), (5, 3)]),
+            ],
+            &[],
+        );
+        let mut sim = Simulation::new(st.clone(), "you");
+        let meet = |s: &Snake, _: &State| {
+            if s.id == "you" {
+                Dir::Right
+            } else {
+                Dir::Left
+            }
+        };
+        sim.run(meet, 10, |_| false);
+        assert_eq!(sim.deaths[0].cause, DeathCause::HeadOn);
+        assert_eq!(sim.deaths[0].killer.as_deref(), Some("you"));
+
+        let mut sim = Simulation::new(st, "you");
+        assert!(sim.run(meet, 10, |_| true).is_some());
+        assert_eq!(sim.futures.len(), 1);
+    }
+}
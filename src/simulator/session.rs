@@ -0,0 +1,329 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! A stateful wrapper around `process_step` for stepping through a
+//! game. A session also knows when its game is over: when at most one
+//! snake is left, or when it reaches the turn limit of its rules.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+
+use super::{process_step, Future, SpawnRules};
+use crate::game::{Dir, Point, Snake, State};
+
+/// Rules a `GameSession` plays by, on top of the ones `process_step`
+/// applies
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rules {
+    /// Food is topped up to this many pieces before every turn
+    pub min_food: usize,
+    /// The percent chance of a new piece of food after a turn with
+    /// enough food
+    pub food_spawn_chance: u8,
+    /// The game is stopped after this many turns, if set
+    pub max_turns: Option<u32>,
+    /// How a game stopped at `max_turns` is decided
+    pub adjudication: Adjudication,
+}
+
+/// How a game that hits the turn limit is decided
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjudication {
+    /// Nobody wins
+    Draw,
+    /// The longest snake wins, then the healthiest. A tie on both is a
+    /// draw.
+    Length,
+}
+
+/// How a game ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Winner(String),
+    Draw,
+}
+
+/// Something that happened in a `GameSession`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Ate { id: String, at: Point },
+    Died { id: String },
+    Spawned { at: Point },
+}
+
+/// A game being simulated, for tools that step through one instead of
+/// calling `process_step` on a state of their own. Food spawns come
+/// from a seeded RNG, so a session replays the same way from the same
+/// seed and moves.
+pub struct GameSession {
+    state: State,
+    rng: StdRng,
+    events: Vec<(u32, Event)>,
+    self_id: String,
+    rules: Rules,
+    /// Whether the game started with a single snake
+    solo: bool,
+}
+
+impl GameSession {
+    /// Starts a session from `state`, from the point of view of the
+    /// snake `self_id`, or of no snake if it is empty
+    pub fn new(state: State, self_id: &str, rules: Rules, seed: u64) -> Self {
+        let solo = state.board.snakes.len() < 2;
+        let mut session = Self {
+            state,
+            rng: StdRng::seed_from_u64(seed),
+            events: Vec::new(),
+            self_id: self_id.to_string(),
+            rules,
+            solo,
+        };
+
+        session.spawn_food();
+        session
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Everything that has happened so far, with the turn it happened
+    /// on, oldest first
+    pub fn events(&self) -> &[(u32, Event)] {
+        &self.events
+    }
+
+    /// How the game ended, or None while it is still going. The
+    /// protagonist counts as out once it is dead, though it stays on
+    /// the board.
+    pub fn outcome(&self) -> Option<Outcome> {
+        let st = &self.state;
+        let alive = st
+            .board
+            .snakes
+            .values()
+            .filter(|s| s.id != self.self_id || !self.self_dead())
+            .collect::<Vec<_>>();
+
+        // A solo game only ends when its snake dies
+        match alive.as_slice() {
+            [] => return Some(Outcome::Draw),
+            [winner] if !self.solo => {
+                return Some(Outcome::Winner(winner.id.clone()))
+            }
+            _ => {}
+        }
+
+        match self.rules.max_turns {
+            Some(max) if st.turn >= max => {}
+            _ => return None,
+        }
+
+        if self.rules.adjudication == Adjudication::Draw {
+            return Some(Outcome::Draw);
+        }
+
+        let rank = |s: &Snake| (s.body.len(), s.health);
+        let best = alive.iter().map(|s| rank(s)).max().unwrap();
+        let leaders = alive.iter().filter(|s| rank(s) == best);
+        match leaders.collect::<Vec<_>>().as_slice() {
+            [winner] => Some(Outcome::Winner(winner.id.clone())),
+            _ => Some(Outcome::Draw),
+        }
+    }
+
+    /// Plays a turn. Snakes without a move keep still.
+    pub fn step(&mut self, moves: &HashMap<String, Dir>) -> Future {
+        let was_dead = self.self_dead();
+        let st = &mut self.state;
+        let food = st.board.food.clone();
+        let mut ids = st.board.snakes.keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+
+        let mut future = process_step(st, &self.self_id, moves);
+
+        for id in &ids {
+            let event = match st.board.snakes.get(id) {
+                Some(_) if *id == self.self_id && was_dead => continue,
+                Some(s) if *id == self.self_id && !future.alive => {
+                    Event::Died { id: s.id.clone() }
+                }
+                Some(s) if food.contains(&s.body[0]) => Event::Ate {
+                    id: s.id.clone(),
+                    at: s.body[0],
+                },
+                Some(_) => continue,
+                None => Event::Died { id: id.clone() },
+            };
+
+            self.events.push((st.turn, event));
+        }
+
+        self.spawn_food();
+
+        // The turn limit ends the game for the protagonist too, and
+        // losing it on adjudication is as bad as dying
+        if let Some(outcome) = self.outcome() {
+            future.finished = true;
+            match outcome {
+                Outcome::Draw => future.draw = true,
+                Outcome::Winner(id) if id != self.self_id => {
+                    future.alive &= !self.in_game();
+                }
+                Outcome::Winner(_) => {}
+            }
+        }
+
+        future
+    }
+
+    /// Whether the protagonist is one of the snakes in the game
+    fn in_game(&self) -> bool {
+        self.state.board.snakes.contains_key(&self.self_id)
+    }
+
+    /// Whether the protagonist has died. It stays on the board.
+    fn self_dead(&self) -> bool {
+        self.events.iter().any(|(_, e)| match e {
+            Event::Died { id } => *id == self.self_id,
+            _ => false,
+        })
+    }
+
+    fn spawn_food(&mut self) {
+        let rules = SpawnRules {
+            min_food: self.rules.min_food,
+            chance: self.rules.food_spawn_chance,
+        };
+
+        for at in rules.spawn(&mut self.state, &mut self.rng) {
+            self.events.push((self.state.turn, Event::Spawned { at }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+
+    /// Rules without a turn limit
+    fn unlimited(min_food: usize) -> Rules {
+        Rules {
+            min_food,
+            food_spawn_chance: 0,
+            max_turns: None,
+            adjudication: Adjudication::Draw,
+        }
+    }
+
+    #[test]
+    fn test_game_session() {
+        let (_, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(0, 4), (0, 5), (0, 6)]),
+            ],
+            &[(5, 4)],
+        );
+        let rules = unlimited(2);
+        let mut game = GameSession::new(st.clone(), "you", rules.clone(), 7);
+        assert_eq!(game.state().board.food.len(), 2);
+
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("other"), Dir::Left);
+        assert!(game.step(&moves).alive);
+
+        let died = Event::Died {
+            id: String::from("other"),
+        };
+        let ate = Event::Ate {
+            id: String::from("you"),
+            at: Point { x: 5, y: 4 },
+        };
+        assert!(game.events().contains(&(1, died)));
+        assert!(game.events().contains(&(1, ate)));
+        assert_eq!(game.state().board.food.len(), 2);
+
+        // The same moves from the same seed spawn the same food
+        let mut replayed = GameSession::new(st, "you", rules, 7);
+        replayed.step(&moves);
+        assert_eq!(replayed.state().board.food, game.state().board.food);
+        assert_eq!(replayed.events(), game.events());
+    }
+
+    #[test]
+    fn test_outcome() {
+        let mut moves = HashMap::new();
+        moves.insert(String::from("you"), Dir::Up);
+        moves.insert(String::from("other"), Dir::Down);
+
+        // Equal snakes meeting head on both die, and nobody wins
+        let (_, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(5, 3), (5, 2), (5, 1)]),
+            ],
+            &[],
+        );
+        let mut game = GameSession::new(st, "you", unlimited(0), 1);
+        assert_eq!(game.outcome(), None);
+        let future = game.step(&moves);
+        assert!(future.finished && future.draw && !future.alive);
+        assert_eq!(game.outcome(), Some(Outcome::Draw));
+
+        // At the turn limit the longer snake wins
+        let (_, st) = build_state(
+            11,
+            11,
+            &[
+                ("you", &[(5, 5), (5, 6), (5, 7)]),
+                ("other", &[(0, 0), (1, 0), (2, 0), (3, 0)]),
+            ],
+            &[],
+        );
+        let rules = Rules {
+            min_food: 0,
+            food_spawn_chance: 0,
+            max_turns: Some(2),
+            adjudication: Adjudication::Length,
+        };
+        let mut game = GameSession::new(st.clone(), "you", rules, 1);
+        assert!(!game.step(&moves).finished);
+        let future = game.step(&moves);
+        assert!(future.finished && !future.draw && !future.alive);
+        let other = Outcome::Winner(String::from("other"));
+        assert_eq!(game.outcome(), Some(other));
+
+        // Or nobody does, if the rules say so
+        let rules = Rules {
+            max_turns: Some(2),
+            ..unlimited(0)
+        };
+        let mut game = GameSession::new(st, "you", rules, 1);
+        game.step(&moves);
+        let future = game.step(&moves);
+        assert!(future.finished && future.draw && future.alive);
+        assert_eq!(game.outcome(), Some(Outcome::Draw));
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Food spawning, for simulations that look further ahead than the
+//! food on the board lasts. Like the official engine, food is topped
+//! up to a minimum after every turn, and otherwise a piece appears on
+//! a random empty square with some chance. Without it every long
+//! future ends with the snakes starving.
+//!
+//! The caller owns the RNG, so a simulation seeded the same way spawns
+//! the same food.
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashSet;
+
+use crate::config;
+use crate::game::{Point, State};
+
+/// How food spawns after each turn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpawnRules {
+    /// Food is topped up to this many pieces
+    pub min_food: usize,
+    /// The percent chance of a new piece when there is enough food
+    pub chance: u8,
+}
+
+impl SpawnRules {
+    /// The rules the searches simulate with
+    pub fn from_config() -> Self {
+        let config = config::get();
+        Self {
+            min_food: config.sim_min_food,
+            chance: config.sim_food_spawn_chance,
+        }
+    }
+
+    /// Spawns food on the empty squares of `st` like the engine does
+    /// after a turn, returning where
+    pub fn spawn(&self, st: &mut State, rng: &mut impl Rng) -> Vec<Point> {
+        let food = st.board.food.len();
+        let needed = if food < self.min_food {
+            self.min_food - food
+        } else if rng.gen_range(0, 100) < self.chance {
+            1
+        } else {
+            0
+        };
+
+        if needed == 0 {
+            return Vec::new();
+        }
+
+        let occupied = st
+            .board
+            .snakes
+            .values()
+            .flat_map(|s| s.body.iter().cloned())
+            .collect::<HashSet<Point>>();
+        let (width, height) = (st.board.width, st.board.height);
+        let mut free = (0..height)
+            .flat_map(|y| (0..width).map(move |x| Point { x, y }))
+            .filter(|p| !occupied.contains(p) && !st.board.food.contains(p))
+            .collect::<Vec<Point>>();
+
+        let spawned = free.partial_shuffle(rng, needed).0.to_vec();
+        st.board.food.extend(&spawned);
+        spawned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_spawn() {
+        let (_, st) =
+            build_state(3, 3, &[("you", &[(0, 0), (1, 0), (2, 0)])], &[]);
+        let rules = SpawnRules {
+            min_food: 2,
+            chance: 0,
+        };
+
+        // Topped up on empty squares, the same way for the same seed
+        let mut first = st.clone();
+        let spawned = rules.spawn(&mut first, &mut StdRng::seed_from_u64(3));
+        assert_eq!(spawned.len(), 2);
+        assert!(spawned.iter().all(|p| p.y > 0));
+        let mut again = st.clone();
+        rules.spawn(&mut again, &mut StdRng::seed_from_u64(3));
+        assert_eq!(again.board.food, first.board.food);
+
+        // Enough food and no chance means no more
+        let mut rng = StdRng::seed_from_u64(3);
+        assert!(rules.spawn(&mut first, &mut rng).is_empty());
+
+        // A certain spawn stops once the board is full
+        let certain = SpawnRules {
+            min_food: 0,
+            chance: 100,
+        };
+        for _ in 0..10 {
+            certain.spawn(&mut first, &mut rng);
+        }
+        assert_eq!(first.board.food.len(), 6);
+    }
+}
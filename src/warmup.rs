@@ -0,0 +1,90 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! Warm-up routines that get the first real move off to the same
+//! start as every other one. Without them the first search pays for
+//! spinning up the rayon pool, growing the allocator's arenas and
+//! initializing lazy statics, which can take it close to the timeout.
+
+use log::info;
+use rayon::prelude::*;
+use std::time::{Instant, SystemTime};
+
+use super::game::{build_state, Snake, State, TurnContext};
+use super::profile::{string_to_profile, Profile, TimeBudget};
+use super::routes::ANALYZED_PROFILES;
+
+/// A small two snake game in progress, for dummy searches
+fn synthetic_state() -> (Snake, State) {
+    build_state(
+        11,
+        11,
+        &[
+            ("warmup-you", &[(2, 5), (2, 6), (2, 7), (2, 8)]),
+            ("warmup-enemy", &[(8, 5), (8, 4), (8, 3), (8, 2)]),
+        ],
+        &[(5, 5), (0, 0), (10, 10)],
+    )
+}
+
+/// Runs a trivial job on every rayon worker so the pool is up and
+/// every thread has been scheduled at least once
+fn spin_up_pool() {
+    let jobs = rayon::current_num_threads() * 4;
+    (0..jobs).into_par_iter().for_each(|i| {
+        let v = vec![i; 64];
+        assert_eq!(v.len(), 64);
+    });
+}
+
+/// Full warm-up at startup, before the server takes requests. Runs a
+/// whole search with the server's profile on a synthetic board.
+pub fn on_startup(profile: &mut dyn Profile) {
+    let start = SystemTime::now();
+    let (you, st) = synthetic_state();
+
+    spin_up_pool();
+    TurnContext::new(&you, &st);
+
+    let budget = TimeBudget::for_move(Instant::now());
+    for name in &ANALYZED_PROFILES {
+        string_to_profile(name).get_move(&you, &st, &budget);
+    }
+
+    profile.init(&st, you.id.clone());
+    profile.get_move(&you, &st, &budget);
+
+    info!(
+        "Warm-up finished in {} ms",
+        start.elapsed().map(|d| d.as_millis()).unwrap_or(0)
+    );
+}
+
+/// Light warm-up when a game starts. It runs between `/start` and the
+/// first `/move`, so it only touches the thread pool and the profiles
+/// the analytics run, on the real board, and never runs a search.
+pub fn on_game_start(you: &Snake, st: &State) {
+    spin_up_pool();
+    TurnContext::new(you, st);
+
+    if let Some(s) = st.board.snakes.get(&you.id) {
+        let budget = TimeBudget::untimed();
+        for name in &ANALYZED_PROFILES {
+            string_to_profile(name).get_move(s, st, &budget);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The `/watch` stream, for following games live. Each move we play
+//! is sent to every watcher as a server-sent event, along with the
+//! search's win probability estimate after it, so the estimate's
+//! series builds up turn by turn. `/watch?game=<id>` follows a single
+//! game, and plain `/watch` follows all of them.
+
+use hyper::body::{Body, Bytes};
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::Response;
+use log::debug;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::game::Dir;
+use super::schema::v1;
+
+/// How many moves a slow watcher can fall behind by before it starts
+/// missing some
+const BACKLOG: usize = 64;
+
+/// The moves of every game, for whoever is watching
+pub struct Watchers {
+    events: broadcast::Sender<v1::WatchEvent>,
+}
+
+impl Watchers {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(BACKLOG);
+        Self { events }
+    }
+
+    /// Sends a move we played to everyone watching its game
+    pub fn publish(
+        &self,
+        game_id: &str,
+        turn: u32,
+        dir: Dir,
+        win_probability: Option<f64>,
+    ) {
+        let event = v1::WatchEvent {
+            game_id: game_id.to_string(),
+            turn,
+            dir: dir.as_move().dir,
+            win_probability,
+        };
+
+        // Fails when nobody is watching, which is most of the time
+        let _ = self.events.send(event);
+    }
+
+    /// A response that streams the moves of `game`, or of every game,
+    /// until the watcher hangs up. Must be called on the runtime.
+    pub fn stream(&self, game: Option<String>) -> Response<Body> {
+        let mut events = self.events.subscribe();
+        let (mut sender, body) = Body::channel();
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        debug!("A watcher fell {} moves behind", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if game.as_ref().is_some_and(|id| *id != event.game_id) {
+                    continue;
+                }
+
+                let json = serde_json::to_string(&event).unwrap_or_default();
+                let frame = format!("data: {}\n\n", json);
+                if sender.send_data(Bytes::from(frame)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut response = Response::new(body);
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/event-stream"),
+        );
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::HttpBody;
+
+    #[test]
+    fn test_watch() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let watchers = Watchers::new();
+            let mut body =
+                watchers.stream(Some(String::from("g1"))).into_body();
+
+            // Only the watched game comes through
+            watchers.publish("g2", 4, Dir::Up, Some(0.9));
+            watchers.publish("g1", 7, Dir::Left, Some(0.25));
+            watchers.publish("g1", 8, Dir::Down, None);
+
+            assert_eq!(
+                body.data().await.unwrap().unwrap(),
+                "data: {\"game_id\":\"g1\",\"turn\":7,\"move\":\"left\",\
+                 \"win_probability\":0.25}\n\n"
+            );
+            assert_eq!(
+                body.data().await.unwrap().unwrap(),
+                "data: {\"game_id\":\"g1\",\"turn\":8,\"move\":\"down\",\
+                 \"win_probability\":null}\n\n"
+            );
+        });
+    }
+}
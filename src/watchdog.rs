@@ -0,0 +1,215 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ */
+
+//! The watchdog over every move's search. The profile searches on a
+//! thread of its own while the request handler waits for it, so a
+//! profile that panics or hangs costs us a move instead of the game.
+//! If the search doesn't return a move by the deadline, the handler
+//! plays a fallback move instead.
+//!
+//! A search that hangs still has the game's profile. Until it gives
+//! the profile back, the session holds a stand-in that plays safe
+//! moves, and every move checks whether the profile has returned.
+
+use log::{error, info, warn};
+use rayon::ThreadPool;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use super::game::{Dir, Snake, State};
+use super::profile::{Profile, TimeBudget};
+
+/// A profile back from its search, with the move it found or the
+/// panic it hit
+type Returned = (Box<dyn Profile>, thread::Result<Dir>);
+
+/// Holds a profile's place in its session while the profile is away
+/// on a search that ran past its deadline
+struct Away {
+    status: String,
+}
+
+impl Profile for Away {
+    fn get_move(&mut self, s: &Snake, st: &State, _: &TimeBudget) -> Dir {
+        s.find_safe_move(st)
+    }
+
+    fn get_status(&self) -> String {
+        format!("{} (stalled)", self.status)
+    }
+}
+
+#[derive(Default)]
+pub struct Watchdog {
+    /// Where a search that ran past its deadline will send the profile
+    /// back
+    stalled: Option<Receiver<Returned>>,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the profile's search on a thread of its own, on `pool` if
+    /// there is one, and waits for its move until `deadline`. None if
+    /// the search panicked or ran past the deadline, or if the profile
+    /// is still away on an earlier search.
+    pub fn get_move(
+        &mut self,
+        profile: &mut Box<dyn Profile>,
+        you: &Snake,
+        st: &State,
+        budget: &TimeBudget,
+        deadline: Instant,
+        pool: Option<Arc<ThreadPool>>,
+    ) -> Option<Dir> {
+        if !self.reclaim(profile) {
+            return None;
+        }
+
+        let status = profile.get_status();
+        let away = Box::new(Away {
+            status: status.clone(),
+        });
+        let mut searching = mem::replace(profile, away);
+        let (you, st, budget) = (you.clone(), st.clone(), *budget);
+        let (tx, rx) = mpsc::channel();
+
+        let spawned = thread::Builder::new()
+            .name(String::from("search"))
+            .spawn(move || {
+                let search =
+                    AssertUnwindSafe(|| searching.get_move(&you, &st, &budget));
+                let dir = match pool {
+                    Some(pool) => pool.install(|| panic::catch_unwind(search)),
+                    None => panic::catch_unwind(search),
+                };
+
+                // Nobody is waiting any more once the game has ended
+                let _ = tx.send((searching, dir));
+            });
+
+        if let Err(e) = spawned {
+            error!("Couldn't start the search of {}: {}", status, e);
+            return None;
+        }
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(timeout) {
+            Ok((searched, dir)) => {
+                *profile = searched;
+                dir.map_err(|_| error!("{} panicked in its search", status))
+                    .ok()
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("{} missed the watchdog deadline", status);
+                self.stalled = Some(rx);
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("The search of {} never finished", status);
+                None
+            }
+        }
+    }
+
+    /// Puts the profile back in its session if a stalled search has
+    /// returned it. False if it is still away.
+    fn reclaim(&mut self, profile: &mut Box<dyn Profile>) -> bool {
+        let returned = match &self.stalled {
+            Some(rx) => rx.try_recv(),
+            None => return true,
+        };
+
+        match returned {
+            Ok((searched, _)) => {
+                info!(
+                    "{} is back from a stalled search",
+                    searched.get_status()
+                );
+                *profile = searched;
+            }
+            Err(TryRecvError::Empty) => return false,
+            // The stand-in carries on for the rest of the game
+            Err(TryRecvError::Disconnected) => {
+                error!("{} was lost in a stalled search", profile.get_status());
+            }
+        }
+
+        self.stalled = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::build_state;
+    use std::time::Duration;
+
+    /// Hangs in its first search and panics in its second
+    struct Faulty {
+        searches: u32,
+    }
+
+    impl Profile for Faulty {
+        fn get_move(&mut self, s: &Snake, st: &State, _: &TimeBudget) -> Dir {
+            self.searches += 1;
+            match self.searches {
+                1 => thread::sleep(Duration::from_millis(300)),
+                2 => panic!("Faulty search"),
+                _ => (),
+            }
+
+            s.find_safe_move(st)
+        }
+
+        fn get_status(&self) -> String {
+            format!("Faulty {}", self.searches)
+        }
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let (you, st) =
+            build_state(7, 7, &[("you", &[(3, 3), (3, 4), (3, 5)])], &[]);
+        let budget = TimeBudget::untimed();
+        let mut watchdog = Watchdog::new();
+        let mut profile: Box<dyn Profile> = Box::new(Faulty { searches: 0 });
+        let mut search = |profile: &mut Box<dyn Profile>, millis| {
+            let deadline = Instant::now() + Duration::from_millis(millis);
+            watchdog.get_move(profile, &you, &st, &budget, deadline, None)
+        };
+
+        // The hanging search keeps the profile past the deadline
+        assert_eq!(search(&mut profile, 20), None);
+        assert_eq!(profile.get_status(), "Faulty 0 (stalled)");
+        assert_eq!(search(&mut profile, 20), None);
+
+        // and gives it back once it returns
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(search(&mut profile, 1000), None);
+        assert_eq!(profile.get_status(), "Faulty 2");
+
+        assert_eq!(search(&mut profile, 1000), Some(Dir::Up));
+    }
+}
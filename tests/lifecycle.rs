@@ -0,0 +1,338 @@
+/*
+ * Copyright (C) 2019 Jayden Chan. All rights reserved.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 2 as
+ * published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin St, Fifth Floor, Boston, MA 02110-1301 USA
+ *
+ */
+
+//! Smoke tests that run the real server binary and drive it through
+//! whole games the way the Battlesnake engine would, checking that
+//! every response is valid JSON and arrives before the deadline.
+
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The engine's response deadline
+const DEADLINE: Duration = Duration::from_millis(500);
+/// How long to wait for the server to start accepting connections
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TURNS: u32 = 40;
+const BOARD_SIZE: i64 = 11;
+/// The square the enemy walks around, clockwise
+const ENEMY_LOOP: [(i64, i64); 8] = [
+    (1, 1),
+    (2, 1),
+    (3, 1),
+    (3, 2),
+    (3, 3),
+    (2, 3),
+    (1, 3),
+    (1, 2),
+];
+
+/// A running server, killed when dropped so a failing test doesn't
+/// leave it behind
+struct Server {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str) -> Self {
+        let port = free_port();
+        let dir = env::temp_dir().join(format!(
+            "battlesnake-lifecycle-{}-{}",
+            name,
+            std::process::id()
+        ));
+        // The server writes game samples here, like the repo's own
+        // samples directory
+        fs::create_dir_all(dir.join("samples")).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_battlesnake-2020"))
+            .current_dir(&dir)
+            .env_remove("RUST_LOG")
+            .args(["--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Couldn't start the server");
+
+        let server = Self { child, port, dir };
+        let start = Instant::now();
+
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                start.elapsed() < STARTUP_TIMEOUT,
+                "Server didn't start listening on port {}",
+                port
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        server
+    }
+
+    /// Sends a POST request and returns the response body and how
+    /// long it took to arrive
+    fn post(&self, path: &str, body: &Value) -> (String, Duration) {
+        let body = body.to_string();
+        let start = Instant::now();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let elapsed = start.elapsed();
+
+        assert!(
+            response.starts_with("HTTP/1.1 200"),
+            "Bad response to {}: {}",
+            path,
+            response
+        );
+
+        let body = response
+            .split_once("\r\n\r\n")
+            .map_or("", |(_, body)| body)
+            .to_string();
+        (body, elapsed)
+    }
+
+    /// Like `post`, but also checks the deadline and parses the JSON
+    fn post_json(&self, path: &str, body: &Value) -> Value {
+        let (response, elapsed) = self.post(path, body);
+
+        assert!(
+            elapsed < DEADLINE,
+            "{} took {} ms, over the {} ms deadline",
+            path,
+            elapsed.as_millis(),
+            DEADLINE.as_millis()
+        );
+
+        serde_json::from_str(&response).unwrap_or_else(|e| {
+            panic!("Invalid JSON from {}: {} ({})", path, response, e)
+        })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn point(x: i64, y: i64) -> Value {
+    json!({ "x": x, "y": y })
+}
+
+/// A scripted stand-in for the game engine. Our snake follows the
+/// server's moves, the enemy walks a fixed loop around the board.
+struct Engine {
+    id: String,
+    turn: u32,
+    you: Vec<(i64, i64)>,
+    enemy: Vec<(i64, i64)>,
+    health: u8,
+}
+
+impl Engine {
+    fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            turn: 0,
+            you: vec![(5, 5), (5, 6), (5, 7)],
+            enemy: vec![(1, 1), (1, 2), (1, 3)],
+            health: 100,
+        }
+    }
+
+    fn request(&self) -> Value {
+        let body = |b: &[(i64, i64)]| {
+            b.iter().map(|&(x, y)| point(x, y)).collect::<Vec<Value>>()
+        };
+        let you = json!({
+            "id": "you",
+            "name": "you",
+            "health": self.health,
+            "body": body(&self.you),
+        });
+        let enemy = json!({
+            "id": "enemy",
+            "name": "enemy",
+            "health": 100,
+            "body": body(&self.enemy),
+        });
+
+        json!({
+            "game": { "id": self.id },
+            "turn": self.turn,
+            "board": {
+                "height": BOARD_SIZE,
+                "width": BOARD_SIZE,
+                "food": [point(9, 9), point(0, 10)],
+                "snakes": [you, enemy],
+            },
+            "you": you,
+        })
+    }
+
+    /// The enemy's next move around its loop
+    fn enemy_step(&self) -> (i64, i64) {
+        let i = ENEMY_LOOP.iter().position(|p| *p == self.enemy[0]).unwrap();
+        ENEMY_LOOP[(i + 1) % ENEMY_LOOP.len()]
+    }
+
+    /// Advances the game by one turn, returning false once our snake
+    /// has died
+    fn step(&mut self, dir: &str) -> bool {
+        let (x, y) = self.you[0];
+        let head = match dir {
+            "up" => (x, y - 1),
+            "down" => (x, y + 1),
+            "left" => (x - 1, y),
+            "right" => (x + 1, y),
+            _ => panic!("Unknown move {}", dir),
+        };
+
+        let enemy_head = self.enemy_step();
+        self.enemy.insert(0, enemy_head);
+        self.enemy.pop();
+
+        self.you.insert(0, head);
+        self.you.pop();
+        self.health -= 1;
+        self.turn += 1;
+
+        let in_bounds = head.0 >= 0
+            && head.0 < BOARD_SIZE
+            && head.1 >= 0
+            && head.1 < BOARD_SIZE;
+        let collided =
+            self.you[1..].contains(&head) || self.enemy.contains(&head);
+
+        in_bounds && !collided
+    }
+}
+
+/// Plays a full game against the server and returns how many turns
+/// our snake survived
+fn play(server: &Server, engine: &mut Engine) -> u32 {
+    let start = server.post_json("/start", &engine.request());
+    assert!(start["color"].is_string(), "Bad /start response: {}", start);
+
+    while engine.turn < MAX_TURNS {
+        let res = server.post_json("/move", &engine.request());
+        let dir = res["move"]
+            .as_str()
+            .unwrap_or_else(|| panic!("Bad /move response: {}", res))
+            .to_string();
+
+        if !engine.step(&dir) {
+            break;
+        }
+    }
+
+    server.post("/end", &engine.request());
+    engine.turn
+}
+
+#[test]
+fn test_full_game() {
+    let server = Server::start("full-game");
+    let mut engine = Engine::new("lifecycle-1");
+
+    let turns = play(&server, &mut engine);
+    assert!(turns > 0);
+
+    // The server must still be healthy for the next game
+    let mut engine = Engine::new("lifecycle-2");
+    assert!(play(&server, &mut engine) > 0);
+}
+
+#[test]
+fn test_interleaved_games() {
+    let server = Server::start("interleaved");
+    let mut games = vec![Engine::new("game-a"), Engine::new("game-b")];
+
+    for game in &games {
+        server.post_json("/start", &game.request());
+    }
+
+    let mut alive = vec![true; games.len()];
+    for _ in 0..MAX_TURNS / 2 {
+        for (game, alive) in games.iter_mut().zip(alive.iter_mut()) {
+            if !*alive {
+                continue;
+            }
+
+            let res = server.post_json("/move", &game.request());
+            *alive = res["move"].as_str().is_some_and(|d| game.step(d));
+        }
+    }
+
+    for game in &games {
+        server.post("/end", &game.request());
+    }
+}
+
+#[test]
+fn test_concurrent_games() {
+    let server = Server::start("concurrent");
+
+    // Each game's moves must still make the deadline while the other
+    // game is searching at the same time
+    thread::scope(|scope| {
+        let games = ["concurrent-a", "concurrent-b"]
+            .iter()
+            .map(|id| {
+                let server = &server;
+                scope.spawn(move || play(server, &mut Engine::new(id)))
+            })
+            .collect::<Vec<_>>();
+
+        for game in games {
+            assert!(game.join().unwrap() > 0);
+        }
+    });
+}